@@ -0,0 +1,90 @@
+//! Baseline timings for the parser, the JIT compiler and both backends.
+//!
+//! Run with `cargo bench`. Each benchmark reports the fastest and mean time
+//! over a fixed number of iterations so optimization changes can be compared
+//! against a previous run.
+//!
+//! This is a plain timing loop rather than a criterion harness, as criterion
+//! is not among the dependencies this crate can build with. So there is no
+//! statistical analysis or saved baseline: compare the printed times of two
+//! runs on the same machine by hand. `cat` stands in for hanoi, which is not
+//! among the test programs.
+extern crate fucker;
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use fucker::parser::AST;
+use fucker::runnable::{Fucker, JITOptions, JITTarget, Runnable};
+
+const HELLO_WORLD: &str = include_str!("../test/programs/hello_world.bf");
+const MANDELBROT: &str = include_str!("../test/programs/mandelbrot.bf");
+/// Copies its input to its output, stopping at EOF under the default mode.
+const CAT: &str = ",+[-.,+]";
+
+/// Time `iterations` calls of `f` and print the results under `name`.
+fn bench<F: FnMut()>(name: &str, iterations: u32, mut f: F) {
+    let mut best = Duration::MAX;
+    let mut total = Duration::ZERO;
+
+    for _ in 0..iterations {
+        let start = Instant::now();
+        f();
+        let elapsed = start.elapsed();
+
+        best = best.min(elapsed);
+        total += elapsed;
+    }
+
+    println!(
+        "{:<28} best {:>12?}  mean {:>12?}  ({} iterations)",
+        name,
+        best,
+        total / iterations,
+        iterations
+    );
+}
+
+fn bench_parse() {
+    bench("parse/hello_world", 1000, || {
+        AST::parse(HELLO_WORLD).unwrap();
+    });
+    bench("parse/mandelbrot", 100, || {
+        AST::parse(MANDELBROT).unwrap();
+    });
+}
+
+fn bench_compile() {
+    let ast = AST::parse(MANDELBROT).unwrap();
+
+    bench("compile/mandelbrot", 100, || {
+        JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+    });
+}
+
+fn bench_run() {
+    let input = vec![b'x'; 0x1000];
+
+    for &(name, source, input, iterations) in &[
+        ("hello_world", HELLO_WORLD, &[][..], 1000),
+        ("mandelbrot", MANDELBROT, &[][..], 1),
+        ("cat", CAT, &input[..], 100),
+    ] {
+        let ast = AST::parse(source).unwrap();
+
+        bench(&format!("run/jit/{}", name), iterations, || {
+            let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+            jit_target.run_with_io(&mut &input[..], &mut io::sink());
+        });
+        bench(&format!("run/int/{}", name), iterations, || {
+            let mut fucker = Fucker::new(&ast.data);
+            fucker.run_with_io(&mut &input[..], &mut io::sink());
+        });
+    }
+}
+
+fn main() {
+    bench_parse();
+    bench_compile();
+    bench_run();
+}
@@ -0,0 +1,70 @@
+/*
+ * Runtime for programs written out with `fucker --emit-object`:
+ *
+ *     fucker --emit-object hello.o hello.bf
+ *     cc -no-pie -o hello hello.o runtime/bf_runtime.c
+ *
+ * End of input stores BF_EOF in the cell, -1 unless built with for example
+ * -DBF_EOF=0, or leaves the cell alone with -DBF_EOF_UNCHANGED.
+ */
+
+#include <stddef.h>
+#include <stdio.h>
+
+#ifndef BF_TAPE_SIZE
+#define BF_TAPE_SIZE 30000
+#endif
+
+#ifndef BF_EOF
+#define BF_EOF -1
+#endif
+
+unsigned char *bf_main(unsigned char *tape, void *context);
+
+void bf_print(void *context, unsigned char byte) {
+    (void)context;
+    putchar(byte);
+}
+
+void bf_print_repeat(void *context, unsigned char byte, size_t count) {
+    (void)context;
+    while (count--) {
+        putchar(byte);
+    }
+}
+
+void bf_print_literal(void *context, const unsigned char *bytes, size_t len) {
+    (void)context;
+    fwrite(bytes, 1, len, stdout);
+}
+
+void bf_debug_print(void *context, unsigned char value) {
+    (void)context;
+    fprintf(stderr, "%u\n", value);
+}
+
+unsigned char bf_read(void *context, unsigned char current) {
+    int byte;
+
+    (void)context;
+    fflush(stdout);
+    byte = getchar();
+    if (byte != EOF) {
+        return (unsigned char)byte;
+    }
+
+#ifdef BF_EOF_UNCHANGED
+    return current;
+#else
+    (void)current;
+    return (unsigned char)BF_EOF;
+#endif
+}
+
+int main(void) {
+    static unsigned char tape[BF_TAPE_SIZE];
+
+    bf_main(tape, NULL);
+
+    return fflush(stdout) == 0 ? 0 : 1;
+}
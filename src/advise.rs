@@ -0,0 +1,173 @@
+//! Guessing whether a program will finish sooner in the interpreter or the
+//! JIT.
+//!
+//! Compiling has a fixed cost that only pays off once enough instructions
+//! run, so the program is given a short sample run in the interpreter and
+//! compiled once, and the two are weighed against each other.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use parser::AST;
+use runnable::{Fucker, Io, JITOptions, JITTarget, StepResult};
+
+/// Instructions the sample run executes before it is cut short.
+pub const SAMPLE_STEPS: u64 = 1_000_000;
+
+/// Rough factor by which compiled code outruns the interpreter.
+const JIT_SPEEDUP: u32 = 20;
+
+/// Which way to run a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Interpreter,
+    Jit,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Interpreter => write!(f, "interpreter (--int)"),
+            Backend::Jit => write!(f, "JIT"),
+        }
+    }
+}
+
+/// What was measured about a program, and the backend it points to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Advice {
+    pub backend: Backend,
+    /// Steps the sample run took, at most `SAMPLE_STEPS`.
+    pub steps: u64,
+    /// Whether the program ended within the sample.
+    pub finished: bool,
+    pub sample_time: Duration,
+    /// Number of times any loop body started during the sample.
+    pub loop_iterations: usize,
+    /// The loop whose body started most often, as its id and count.
+    pub hottest_loop: Option<(usize, usize)>,
+    /// Time to compile every loop, or `None` if the JIT cannot compile the
+    /// program.
+    pub compile_time: Option<Duration>,
+}
+
+impl fmt::Display for Advice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Sample run:      {} steps in {:?}",
+            self.steps, self.sample_time
+        )?;
+        if !self.finished {
+            write!(f, ", stopped early")?;
+        }
+        writeln!(f)?;
+
+        write!(f, "Loop iterations: {}", self.loop_iterations)?;
+        if let Some((id, count)) = self.hottest_loop {
+            write!(f, ", hottest loop {} ran {} times", id, count)?;
+        }
+        writeln!(f)?;
+
+        match self.compile_time {
+            Some(time) => writeln!(f, "JIT compile:     {:?}", time)?,
+            None => writeln!(f, "JIT compile:     unavailable")?,
+        }
+
+        writeln!(f, "Recommendation:  {}", self.backend)
+    }
+}
+
+/// Measure `program` and recommend a backend for it.
+///
+/// The sample run gets no input and its output is discarded, so programs
+/// that read input are judged by how they behave at end of input.
+pub fn advise(program: &AST, options: JITOptions) -> Advice {
+    let iterations = Rc::new(RefCell::new(HashMap::new()));
+    let mut fucker = Fucker::new(&program.data);
+    fucker.set_eof_mode(options.eof);
+    fucker.set_cell_arithmetic(options.arithmetic);
+    {
+        let iterations = iterations.clone();
+        fucker.set_loop_hook(Box::new(move |id, _| {
+            *iterations.borrow_mut().entry(id).or_insert(0) += 1;
+        }));
+    }
+
+    let (mut input, mut output, mut diagnostics) = (io::empty(), io::sink(), io::sink());
+    let mut io = Io::with_debug(&mut input, &mut output, &mut diagnostics);
+    let start = Instant::now();
+    let (steps, finished) = match fucker.run_steps(SAMPLE_STEPS, &mut io) {
+        StepResult::Finished { steps } => (steps, true),
+        StepResult::Paused { steps, .. } => (steps, false),
+    };
+    let sample_time = start.elapsed();
+
+    let start = Instant::now();
+    let compile_time = JITTarget::new(&program.data, options, None)
+        .and_then(|mut jit_target| jit_target.precompile())
+        .ok()
+        .map(|_| start.elapsed());
+
+    let iterations = iterations.borrow();
+    let loop_iterations = iterations.values().sum();
+    let hottest_loop = iterations
+        .iter()
+        .map(|(&id, &count)| (id, count))
+        .max_by_key(|&(id, count)| (count, usize::MAX - id));
+
+    let backend = match compile_time {
+        None => Backend::Interpreter,
+        // Whatever is left of the run is where compiling pays off
+        Some(_) if !finished => Backend::Jit,
+        // Every instruction ran once, compiling it costs more than that
+        Some(_) if loop_iterations == 0 => Backend::Interpreter,
+        Some(compile_time) if compile_time + sample_time / JIT_SPEEDUP < sample_time => {
+            Backend::Jit
+        }
+        Some(_) => Backend::Interpreter,
+    };
+
+    Advice {
+        backend,
+        steps,
+        finished,
+        sample_time,
+        loop_iterations,
+        hottest_loop,
+        compile_time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiny_program() {
+        let advice = advise(&AST::parse("++.").unwrap(), JITOptions::default());
+
+        assert!(advice.finished);
+        assert_eq!(advice.loop_iterations, 0);
+        assert_eq!(advice.backend, Backend::Interpreter);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn loop_heavy_program() {
+        // Never ends, with most of the time spent in the inner loop
+        let advice = advise(
+            &AST::parse("+[>+++[>++<-]<]").unwrap(),
+            JITOptions::default(),
+        );
+
+        assert!(!advice.finished);
+        assert_eq!(advice.steps, SAMPLE_STEPS);
+        assert!(advice.loop_iterations as u64 > SAMPLE_STEPS / 10);
+        assert_eq!(advice.backend, Backend::Jit);
+    }
+}
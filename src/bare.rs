@@ -0,0 +1,238 @@
+//! The interpreter's core, for targets without std.
+//!
+//! Only `core` and `alloc` are used here, so that building the crate without
+//! the default `std` feature leaves just this module for embedded targets. Parsing and
+//! optimizing need std, so programs for such targets are turned into nodes
+//! ahead of time.
+//!
+//! The tree is walked as it is, with wrapping cells and a tape of fixed size,
+//! and nothing is allocated while a program runs.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+/// BrainFuck AST node
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ASTNode {
+    /// Add to the current memory cell.
+    Incr(u8),
+    /// Remove from the current memory cell.
+    Decr(u8),
+    /// Overwrite the current memory cell, produced from clear loops.
+    Set(u8),
+    /// Overwrite the contained number of cells from the current one on,
+    /// leaving the data pointer just past them. Produced from chains of
+    /// clears by `AST::fuse_set_ranges`.
+    SetRange(usize, u8),
+    /// Add the current memory cell times a factor to the cell at an offset
+    /// from it, produced from multiply loops along with the `Set(0)` that
+    /// ends them.
+    MulAdd(isize, u8),
+    /// Add a number to the cell at an offset from the current one, produced
+    /// by `AST::fold_known_multiplies` from a `MulAdd` on a known value.
+    AddTo(isize, u8),
+    /// Shift the data pointer to the right.
+    Next(usize),
+    /// Shift the data pointer to the left.
+    Prev(usize),
+    /// Display the current memory cell as an ASCII character.
+    Print,
+    /// Display the current memory cell the contained number of times,
+    /// produced from runs of `.`.
+    PrintRepeat(usize),
+    /// Output a fixed sequence of bytes.
+    PrintLiteral(Vec<u8>),
+    /// Read one character from stdin.
+    Read,
+    /// Write the current memory cell as a decimal number to stderr. Only
+    /// produced from `$` when `ParseOptions::debug_ext` is on.
+    DebugPrintValue,
+    /// Stop for `Fucker::run_to_breakpoint`, doing nothing otherwise. Only
+    /// produced from `#` when `ParseOptions::breakpoints` is on.
+    Breakpoint,
+    /// Loop over the contained instructions while the current memory cell is
+    /// not zero.
+    Loop(VecDeque<ASTNode>),
+}
+
+/// Where a program's input comes from and its output goes.
+pub trait Console {
+    /// The byte `,` stores, given the current cell so that end of input can
+    /// leave it as it is.
+    fn read(&mut self, current: u8) -> u8;
+
+    /// Output of `.` and of literal prints.
+    fn write(&mut self, bytes: &[u8]);
+
+    /// Called with the current cell for `$`. Does nothing unless overridden.
+    fn debug(&mut self, _value: u8) {}
+}
+
+/// The data pointer moved off either end of the tape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfBounds;
+
+/// Run `nodes` on `tape` from cell 0, returning the cell the data pointer
+/// ended on.
+pub fn run(
+    nodes: &VecDeque<ASTNode>,
+    tape: &mut [u8],
+    console: &mut dyn Console,
+) -> Result<usize, OutOfBounds> {
+    if tape.is_empty() {
+        return Err(OutOfBounds);
+    }
+
+    let mut dp = 0;
+    run_nodes(nodes, tape, &mut dp, console)?;
+
+    Ok(dp)
+}
+
+/// Run one list of nodes, with `dp` always on the tape.
+fn run_nodes(
+    nodes: &VecDeque<ASTNode>,
+    tape: &mut [u8],
+    dp: &mut usize,
+    console: &mut dyn Console,
+) -> Result<(), OutOfBounds> {
+    for node in nodes {
+        let cell = *dp;
+
+        match node {
+            ASTNode::Incr(n) => tape[cell] = tape[cell].wrapping_add(*n),
+            ASTNode::Decr(n) => tape[cell] = tape[cell].wrapping_sub(*n),
+            ASTNode::Set(n) => tape[cell] = *n,
+            ASTNode::SetRange(len, n) => {
+                let end = on_tape(cell.checked_add(*len), tape)?;
+                tape[cell..end].fill(*n);
+                *dp = end;
+            }
+            ASTNode::MulAdd(offset, factor) => {
+                let value = tape[cell];
+                if value != 0 {
+                    let target = on_tape(offset_cell(cell, *offset), tape)?;
+                    tape[target] = tape[target].wrapping_add(value.wrapping_mul(*factor));
+                }
+            }
+            ASTNode::AddTo(offset, n) => {
+                let target = on_tape(offset_cell(cell, *offset), tape)?;
+                tape[target] = tape[target].wrapping_add(*n);
+            }
+            ASTNode::Next(n) => *dp = on_tape(cell.checked_add(*n), tape)?,
+            ASTNode::Prev(n) => *dp = on_tape(cell.checked_sub(*n), tape)?,
+            ASTNode::Print => console.write(&[tape[cell]]),
+            ASTNode::PrintRepeat(n) => {
+                for _ in 0..*n {
+                    console.write(&[tape[cell]]);
+                }
+            }
+            ASTNode::PrintLiteral(bytes) => console.write(bytes),
+            ASTNode::Read => tape[cell] = console.read(tape[cell]),
+            ASTNode::DebugPrintValue => console.debug(tape[cell]),
+            ASTNode::Breakpoint => {}
+            ASTNode::Loop(body) => {
+                while tape[*dp] != 0 {
+                    run_nodes(body, tape, dp, console)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A cell the data pointer moved to, if it is on the tape.
+fn on_tape(cell: Option<usize>, tape: &[u8]) -> Result<usize, OutOfBounds> {
+    cell.filter(|&cell| cell < tape.len()).ok_or(OutOfBounds)
+}
+
+/// The cell `offset` cells away from `cell`, if there is one.
+fn offset_cell(cell: usize, offset: isize) -> Option<usize> {
+    if offset >= 0 {
+        cell.checked_add(offset as usize)
+    } else {
+        cell.checked_sub(offset.unsigned_abs())
+    }
+}
+
+// The tests use the parser, which needs std
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process::Command;
+
+    use super::super::parser::AST;
+    use super::*;
+
+    /// Input from a slice, reading 0 at its end, and output to a vector.
+    struct Buffers<'a> {
+        input: &'a [u8],
+        output: Vec<u8>,
+    }
+
+    impl<'a> Console for Buffers<'a> {
+        fn read(&mut self, _current: u8) -> u8 {
+            let (&byte, rest) = self.input.split_first().unwrap_or((&0, &[]));
+            self.input = rest;
+            byte
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            self.output.extend_from_slice(bytes);
+        }
+    }
+
+    #[test]
+    fn runs_parsed_program() {
+        let mut ast = AST::parse(",[.,]++++++++[>++++++++<-]>+..>[-]>[-]>").unwrap();
+        ast.fuse_print_literals();
+        ast.fuse_set_ranges();
+
+        let mut console = Buffers {
+            input: b"hi",
+            output: Vec::new(),
+        };
+        let mut tape = [0u8; 8];
+        assert_eq!(run(&ast.data, &mut tape, &mut console), Ok(4));
+        assert_eq!(console.output, b"hiAA");
+        assert_eq!(tape[1], 65);
+
+        let mut tape = [0u8; 4];
+        assert_eq!(
+            run(&AST::parse("+[>+]").unwrap().data, &mut tape, &mut console),
+            Err(OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn compiles_without_std() {
+        // Build this file as the only module of a no_std crate
+        let dir = env::temp_dir().join("fucker-bare-check");
+        fs::create_dir_all(&dir).unwrap();
+        let root = dir.join("lib.rs");
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/bare.rs");
+        fs::write(
+            &root,
+            format!(
+                "#![no_std]\nextern crate alloc;\n#[path = {:?}]\npub mod bare;\n",
+                path
+            ),
+        )
+        .unwrap();
+
+        let output = Command::new(env::var_os("RUSTC").unwrap_or_else(|| "rustc".into()))
+            .args(["--crate-type", "lib", "--crate-name", "bare_check"])
+            .args(["--emit", "metadata", "--out-dir"])
+            .arg(&dir)
+            .arg(&root)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+}
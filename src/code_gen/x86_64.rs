@@ -1,6 +1,34 @@
+#[cfg(test)]
+use std::cell::Cell;
 use std::mem;
 
-use super::super::runnable::JITPromiseID;
+use super::super::runnable::{JITContext, JITPromiseID};
+
+#[cfg(test)]
+thread_local! {
+    /// Makes every increment emitted on this thread add one too many.
+    static MISCOMPILE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Emit wrong code for increments on the current thread, so tests can check
+/// that a miscompilation gets noticed.
+#[cfg(test)]
+pub fn miscompile(broken: bool) {
+    MISCOMPILE.with(|flag| flag.set(broken));
+}
+
+/// The amount an increment of `n` actually emits.
+#[inline]
+fn incr_amount(n: u8) -> u8 {
+    #[cfg(test)]
+    {
+        if MISCOMPILE.with(Cell::get) {
+            return n.wrapping_add(1);
+        }
+    }
+
+    n
+}
 
 /// Convert an expression to a native-endian order byte array after a type cast.
 macro_rules! to_ne_bytes {
@@ -44,8 +72,49 @@ fn callee_save_to_stack(bytes: &mut Vec<u8>) {
     bytes.push(0x57);
 }
 
+/// Establish a standard rbp-based stack frame.
+#[inline]
+fn frame_prologue(bytes: &mut Vec<u8>) {
+    // push   rbp
+    bytes.push(0x55);
+
+    // mov    rbp,rsp
+    bytes.push(0x48);
+    bytes.push(0x89);
+    bytes.push(0xe5);
+
+    // Keep the stack alignment the rest of the generated code expects
+    // sub    rsp,0x8
+    bytes.push(0x48);
+    bytes.push(0x83);
+    bytes.push(0xec);
+    bytes.push(0x08);
+}
+
+/// Tear down the frame set up by `frame_prologue`.
+#[inline]
+fn frame_epilogue(bytes: &mut Vec<u8>) {
+    // leave
+    bytes.push(0xc9);
+}
+
+/// Wrap `content` in a callable function. With `debug_frames` the function
+/// also sets up a frame pointer so debuggers and profilers can unwind it.
 #[inline]
-pub fn wrapper(bytes: &mut Vec<u8>, content: Vec<u8>) {
+pub fn wrapper(bytes: &mut Vec<u8>, content: Vec<u8>, debug_frames: bool) {
+    wrapper_prologue(bytes, debug_frames);
+    bytes.extend(content);
+    wrapper_return(bytes, debug_frames);
+}
+
+/// Start a function set up as `wrapper` does, for code that has to know
+/// where its content starts.
+#[inline]
+pub fn wrapper_prologue(bytes: &mut Vec<u8>, debug_frames: bool) {
+    if debug_frames {
+        frame_prologue(bytes);
+    }
+
     callee_save_to_stack(bytes);
 
     // Store pointer to brainfuck memory (first argument) in r10
@@ -66,8 +135,29 @@ pub fn wrapper(bytes: &mut Vec<u8>, content: Vec<u8>) {
     bytes.push(0x89);
     bytes.push(0xd4);
 
-    bytes.extend(content);
+    // Store pointer to JITContext (fourth argument) in r13
+    // mov    r13,rcx
+    bytes.push(0x49);
+    bytes.push(0x89);
+    bytes.push(0xcd);
+
+    // Store pointer to the interrupt flag (fifth argument) in r14
+    // mov    r14,r8
+    bytes.push(0x4d);
+    bytes.push(0x89);
+    bytes.push(0xc6);
+
+    // Store pointer to the run's own stop flag (sixth argument) in r15
+    // mov    r15,r9
+    bytes.push(0x4d);
+    bytes.push(0x89);
+    bytes.push(0xcf);
+}
 
+/// Return the data pointer from a function set up by `wrapper`. The stack
+/// must be as it was right after the wrapper's prologue.
+#[inline]
+pub fn wrapper_return(bytes: &mut Vec<u8>, debug_frames: bool) {
     // Return the data pointer
     // mov    rax,r10
     bytes.push(0x4c);
@@ -76,10 +166,45 @@ pub fn wrapper(bytes: &mut Vec<u8>, content: Vec<u8>) {
 
     callee_restore_from_stack(bytes);
 
+    if debug_frames {
+        frame_epilogue(bytes);
+    }
+
     // ret
     bytes.push(0xc3);
 }
 
+/// Return from the function straight away if the interrupt flag or the run's
+/// stop flag is set. Only valid where nothing has been pushed since the
+/// wrapper's prologue.
+#[inline]
+pub fn poll_interrupt(bytes: &mut Vec<u8>, debug_frames: bool) {
+    let mut exit = Vec::new();
+    wrapper_return(&mut exit, debug_frames);
+
+    // cmp    BYTE PTR [r14],0x0
+    bytes.push(0x41);
+    bytes.push(0x80);
+    bytes.push(0x3e);
+    bytes.push(0x00);
+
+    // jne    to the return, past the second check
+    bytes.push(0x75);
+    bytes.push(0x06);
+
+    // cmp    BYTE PTR [r15],0x0
+    bytes.push(0x41);
+    bytes.push(0x80);
+    bytes.push(0x3f);
+    bytes.push(0x00);
+
+    // je     past the return
+    bytes.push(0x74);
+    bytes.push(exit.len() as u8);
+
+    bytes.extend(exit);
+}
+
 #[inline]
 fn callee_restore_from_stack(bytes: &mut Vec<u8>) {
     // pop    r15
@@ -114,6 +239,8 @@ fn callee_restore_from_stack(bytes: &mut Vec<u8>) {
     bytes.push(0x5b);
 }
 
+/// Subtract `n` from the current cell. The operand is a single byte, so the
+/// cell wraps from 0 to 255 just as `wrapping_sub` does.
 #[inline]
 pub fn decr(bytes: &mut Vec<u8>, n: u8) {
     // sub    BYTE PTR [r10],n
@@ -123,12 +250,113 @@ pub fn decr(bytes: &mut Vec<u8>, n: u8) {
     bytes.push(n);
 }
 
+/// Add `n` to the current cell, wrapping from 255 to 0 like `decr`.
 #[inline]
 pub fn incr(bytes: &mut Vec<u8>, n: u8) {
     // add    BYTE PTR [r10],n
     bytes.push(0x41);
     bytes.push(0x80);
     bytes.push(0x02);
+    bytes.push(incr_amount(n));
+}
+
+#[inline]
+pub fn set(bytes: &mut Vec<u8>, n: u8) {
+    // mov    BYTE PTR [r10],n
+    bytes.push(0x41);
+    bytes.push(0xc6);
+    bytes.push(0x02);
+    bytes.push(n);
+}
+
+/// Fill `len` cells from the current one on with `n` and move the data
+/// pointer past them, as one `rep stosb`.
+#[inline]
+pub fn set_range(bytes: &mut Vec<u8>, len: usize, n: u8) {
+    // mov    rdi,r10
+    bytes.push(0x4c);
+    bytes.push(0x89);
+    bytes.push(0xd7);
+
+    // movabs rcx,len
+    bytes.push(0x48);
+    bytes.push(0xb9);
+    bytes.extend_from_slice(&(len as u64).to_ne_bytes());
+
+    // mov    al,n
+    bytes.push(0xb0);
+    bytes.push(n);
+
+    // The direction flag is clear on entry under the SysV ABI, so this
+    // counts upwards
+    // rep stosb
+    bytes.push(0xf3);
+    bytes.push(0xaa);
+
+    // rdi is left just past the range
+    // mov    r10,rdi
+    bytes.push(0x49);
+    bytes.push(0x89);
+    bytes.push(0xfa);
+}
+
+#[inline]
+pub fn saturate(bytes: &mut Vec<u8>, limit: u8) {
+    // Clamp the cell if the preceding add or sub carried
+    // jnc    +4
+    bytes.push(0x73);
+    bytes.push(0x04);
+
+    // mov    BYTE PTR [r10],limit
+    bytes.push(0x41);
+    bytes.push(0xc6);
+    bytes.push(0x02);
+    bytes.push(limit);
+}
+
+#[inline]
+pub fn mul_add(bytes: &mut Vec<u8>, offset: isize, factor: u8) {
+    // HACK: Assumes offsets fit in 32 bits, the optimizer checks this
+    let offset_bytes = (offset as i32).to_ne_bytes();
+
+    // movzx  eax,BYTE PTR [r10]
+    bytes.push(0x41);
+    bytes.push(0x0f);
+    bytes.push(0xb6);
+    bytes.push(0x02);
+
+    // test   al,al
+    bytes.push(0x84);
+    bytes.push(0xc0);
+
+    // jz     past the add
+    bytes.push(0x74);
+    bytes.push(if factor == 1 { 7 } else { 13 });
+
+    if factor != 1 {
+        // imul   eax,eax,factor
+        bytes.push(0x69);
+        bytes.push(0xc0);
+        bytes.extend_from_slice(&u32::from(factor).to_ne_bytes());
+    }
+
+    // add    BYTE PTR [r10+offset],al
+    bytes.push(0x41);
+    bytes.push(0x00);
+    bytes.push(0x82);
+    bytes.extend_from_slice(&offset_bytes);
+}
+
+#[inline]
+pub fn add_to(bytes: &mut Vec<u8>, offset: isize, n: u8) {
+    // HACK: Assumes offsets fit in 32 bits, AST::validate checks this
+    let offset_bytes = (offset as i32).to_ne_bytes();
+
+    // add    BYTE PTR [r10+offset],n
+    bytes.push(0x41);
+    bytes.push(0x80);
+    bytes.push(0x82);
+    bytes.extend_from_slice(&offset_bytes);
     bytes.push(n);
 }
 
@@ -162,6 +390,60 @@ pub fn prev(bytes: &mut Vec<u8>, n: usize) {
     bytes.push(n_bytes[3]);
 }
 
+#[inline]
+pub fn load_cell(bytes: &mut Vec<u8>) {
+    // Cache the current memory cell in the accumulator
+    // mov    al,BYTE PTR [r10]
+    bytes.push(0x41);
+    bytes.push(0x8a);
+    bytes.push(0x02);
+}
+
+#[inline]
+pub fn store_cell(bytes: &mut Vec<u8>) {
+    // Flush the accumulator back into the current memory cell
+    // mov    BYTE PTR [r10],al
+    bytes.push(0x41);
+    bytes.push(0x88);
+    bytes.push(0x02);
+}
+
+#[inline]
+pub fn cached_decr(bytes: &mut Vec<u8>, n: u8) {
+    // sub    al,n
+    bytes.push(0x2c);
+    bytes.push(n);
+}
+
+#[inline]
+pub fn cached_incr(bytes: &mut Vec<u8>, n: u8) {
+    // add    al,n
+    bytes.push(0x04);
+    bytes.push(incr_amount(n));
+}
+
+#[inline]
+pub fn cached_set(bytes: &mut Vec<u8>, n: u8) {
+    // mov    al,n
+    bytes.push(0xb0);
+    bytes.push(n);
+}
+
+#[inline]
+pub fn cached_saturate(bytes: &mut Vec<u8>, limit: u8) {
+    // jnc    +2
+    bytes.push(0x73);
+    bytes.push(0x02);
+
+    // mov    al,limit
+    bytes.push(0xb0);
+    bytes.push(limit);
+}
+
+/// Save the registers a call into Rust may clobber. r10 and r11 are
+/// caller-saved under the SysV ABI, so the data pointer only survives the
+/// call because it is kept on the stack here. r13 is callee-saved and needs
+/// no saving.
 #[inline]
 fn fn_call_pre(bytes: &mut Vec<u8>) {
     // Push data pointer onto stack
@@ -178,10 +460,24 @@ fn fn_call_pre(bytes: &mut Vec<u8>) {
     // push   r12
     bytes.push(0x41);
     bytes.push(0x54);
+
+    // The three pushes leave the stack 8 bytes off the 16 byte alignment
+    // the SysV ABI requires at a call
+    // sub    rsp,0x8
+    bytes.push(0x48);
+    bytes.push(0x83);
+    bytes.push(0xec);
+    bytes.push(0x08);
 }
 
 #[inline]
 fn fn_call_post(bytes: &mut Vec<u8>) {
+    // add    rsp,0x8
+    bytes.push(0x48);
+    bytes.push(0x83);
+    bytes.push(0xc4);
+    bytes.push(0x08);
+
     // Pop JIT function pointer from the stack
     // pop    r12
     bytes.push(0x41);
@@ -199,17 +495,27 @@ fn fn_call_post(bytes: &mut Vec<u8>) {
 }
 
 #[inline]
-pub fn print(bytes: &mut Vec<u8>, print_fn: extern "C" fn(u8) -> ()) {
-    let print_ptr_bytes = to_ne_bytes!(print_fn, extern "C" fn(u8) -> ());
+fn context_arg(bytes: &mut Vec<u8>) {
+    // Move the JITContext pointer into the first argument
+    // mov    rdi,r13
+    bytes.push(0x4c);
+    bytes.push(0x89);
+    bytes.push(0xef);
+}
+
+#[inline]
+pub fn print(bytes: &mut Vec<u8>, print_fn: extern "C" fn(&mut JITContext, u8)) {
+    let print_ptr_bytes = to_ne_bytes!(print_fn, extern "C" fn(&mut JITContext, u8));
 
     fn_call_pre(bytes);
+    context_arg(bytes);
 
-    // Move the current memory cell into the first argument register
-    // movzx    rdi,BYTE PTR [r10]
-    bytes.push(0x49);
+    // Move the current memory cell into the second argument register
+    // movzx    esi,BYTE PTR [r10]
+    bytes.push(0x41);
     bytes.push(0x0f);
     bytes.push(0xb6);
-    bytes.push(0x3a);
+    bytes.push(0x32);
 
     // Copy function pointer for print() into rax
     // movabs rax,print()
@@ -233,10 +539,95 @@ pub fn print(bytes: &mut Vec<u8>, print_fn: extern "C" fn(u8) -> ()) {
 }
 
 #[inline]
-pub fn read(bytes: &mut Vec<u8>, read_fn: extern "C" fn() -> u8) {
-    let read_ptr_bytes = to_ne_bytes!(read_fn, extern "C" fn() -> u8);
+pub fn print_repeat(
+    bytes: &mut Vec<u8>,
+    print_fn: extern "C" fn(&mut JITContext, u8, usize),
+    count: usize,
+) {
+    let print_ptr_bytes = to_ne_bytes!(print_fn, extern "C" fn(&mut JITContext, u8, usize));
+
+    fn_call_pre(bytes);
+    context_arg(bytes);
+
+    // Move the current memory cell into the second argument register
+    // movzx    esi,BYTE PTR [r10]
+    bytes.push(0x41);
+    bytes.push(0x0f);
+    bytes.push(0xb6);
+    bytes.push(0x32);
+
+    // Move the count into the third argument
+    // movabs rdx,count
+    bytes.push(0x48);
+    bytes.push(0xba);
+    bytes.extend(&count.to_ne_bytes());
+
+    // Copy function pointer for print_repeat() into rax
+    // movabs rax,print_repeat()
+    bytes.push(0x48);
+    bytes.push(0xb8);
+    bytes.extend(&print_ptr_bytes);
+
+    // Call print_repeat()
+    // call   rax
+    bytes.push(0xff);
+    bytes.push(0xd0);
+
+    fn_call_post(bytes);
+}
+
+#[inline]
+pub fn print_literal(
+    bytes: &mut Vec<u8>,
+    print_fn: extern "C" fn(&mut JITContext, *const u8, usize),
+    literal: &[u8],
+) {
+    let print_ptr_bytes = to_ne_bytes!(print_fn, extern "C" fn(&mut JITContext, *const u8, usize));
+    let literal_ptr_bytes = to_ne_bytes!(literal.as_ptr(), *const u8);
+    let literal_len_bytes = literal.len().to_ne_bytes();
+
+    fn_call_pre(bytes);
+    context_arg(bytes);
+
+    // Move the literal's address into the second argument
+    // movabs rsi,literal
+    bytes.push(0x48);
+    bytes.push(0xbe);
+    bytes.extend(&literal_ptr_bytes);
+
+    // Move the literal's length into the third argument
+    // movabs rdx,len
+    bytes.push(0x48);
+    bytes.push(0xba);
+    bytes.extend(&literal_len_bytes);
+
+    // Copy function pointer for print_literal() into rax
+    // movabs rax,print_literal()
+    bytes.push(0x48);
+    bytes.push(0xb8);
+    bytes.extend(&print_ptr_bytes);
+
+    // Call print_literal()
+    // call   rax
+    bytes.push(0xff);
+    bytes.push(0xd0);
+
+    fn_call_post(bytes);
+}
+
+#[inline]
+pub fn read(bytes: &mut Vec<u8>, read_fn: extern "C" fn(&mut JITContext, u8) -> u8) {
+    let read_ptr_bytes = to_ne_bytes!(read_fn, extern "C" fn(&mut JITContext, u8) -> u8);
 
     fn_call_pre(bytes);
+    context_arg(bytes);
+
+    // Move the current memory cell into the second argument register
+    // movzx    esi,BYTE PTR [r10]
+    bytes.push(0x41);
+    bytes.push(0x0f);
+    bytes.push(0xb6);
+    bytes.push(0x32);
 
     // Copy function pointer for read() into rax
     // movabs rax,read()
@@ -256,11 +647,40 @@ pub fn read(bytes: &mut Vec<u8>, read_fn: extern "C" fn() -> u8) {
     bytes.push(0xff);
     bytes.push(0xd0);
 
+    // The call may have clobbered r10, so restore it before storing.
+    fn_call_post(bytes);
+
     // Copy return value into current cell.
     // mov    BYTE PTR [r10],al
     bytes.push(0x41);
     bytes.push(0x88);
     bytes.push(0x02);
+}
+
+/// Call `step_fn` with the data pointer as the second argument.
+#[inline]
+pub fn loop_step(bytes: &mut Vec<u8>, step_fn: extern "C" fn(&mut JITContext, *mut u8)) {
+    let step_ptr_bytes = to_ne_bytes!(step_fn, extern "C" fn(&mut JITContext, *mut u8));
+
+    fn_call_pre(bytes);
+    context_arg(bytes);
+
+    // Move the data pointer into the second argument
+    // mov    rsi,r10
+    bytes.push(0x4c);
+    bytes.push(0x89);
+    bytes.push(0xd6);
+
+    // Copy function pointer for loop_step() into rax
+    // movabs rax,loop_step()
+    bytes.push(0x48);
+    bytes.push(0xb8);
+    bytes.extend(&step_ptr_bytes);
+
+    // Call loop_step()
+    // call   rax
+    bytes.push(0xff);
+    bytes.push(0xd0);
 
     fn_call_post(bytes);
 }
@@ -350,6 +770,12 @@ pub fn jit_loop(bytes: &mut Vec<u8>, loop_index: JITPromiseID) {
     bytes.push(0x89);
     bytes.push(0xd2);
 
+    // Move JITContext pointer into the fourth argument
+    // mov    rcx,r13
+    bytes.push(0x4c);
+    bytes.push(0x89);
+    bytes.push(0xe9);
+
     // Call JIT callback
     // call   r12
     bytes.push(0x41);
@@ -0,0 +1,343 @@
+//! Differential testing of the JIT against the interpreter.
+//!
+//! Both backends run the same program on the same input, and their output
+//! and final tapes are compared. The interpreter is simple enough to serve as
+//! the reference, so any difference points at a code generation bug.
+
+use std::cmp;
+use std::fmt;
+
+use error::BfError;
+use parser::AST;
+use runnable::{Fucker, Io, JITOptions, JITTarget, TAPE_SIZE};
+
+/// Everything observable about a finished run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    pub output: Vec<u8>,
+    pub tape: Vec<u8>,
+}
+
+/// The first point at which a JIT run disagrees with the interpreter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// The outputs differ at `offset`. `None` means that run's output had
+    /// already ended.
+    Output {
+        offset: usize,
+        interpreter: Option<u8>,
+        jit: Option<u8>,
+    },
+    /// The outputs match but the final value of `cell` differs.
+    Tape {
+        cell: usize,
+        interpreter: u8,
+        jit: u8,
+    },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        /// Describe an output byte, which may be missing.
+        fn byte(byte: &Option<u8>) -> String {
+            byte.map_or("end of output".to_owned(), |byte| format!("0x{:02X}", byte))
+        }
+
+        match self {
+            Divergence::Output {
+                offset,
+                interpreter,
+                jit,
+            } => write!(
+                f,
+                "Output differs at byte {}: interpreter {}, jit {}",
+                offset,
+                byte(interpreter),
+                byte(jit)
+            ),
+            Divergence::Tape {
+                cell,
+                interpreter,
+                jit,
+            } => write!(
+                f,
+                "Tape differs at cell {}: interpreter 0x{:02X}, jit 0x{:02X}",
+                cell, interpreter, jit
+            ),
+        }
+    }
+}
+
+/// Run `program` on the interpreter.
+pub fn run_interpreter(
+    program: &AST,
+    input: &[u8],
+    options: JITOptions,
+    start_cell: usize,
+) -> Result<Run, BfError> {
+    let mut fucker = Fucker::new(&program.data);
+    fucker.set_eof_mode(options.eof);
+    fucker.set_cell_arithmetic(options.arithmetic);
+    fucker.set_start_cell(start_cell)?;
+
+    let mut output = Vec::new();
+    {
+        let mut input = input;
+        let mut io = Io::new(&mut input, &mut output);
+        while fucker.step(&mut io) {}
+    }
+
+    Ok(Run {
+        output,
+        tape: fucker.tape().to_vec(),
+    })
+}
+
+/// Run `program` on the JIT.
+pub fn run_jit(
+    program: &AST,
+    input: &[u8],
+    options: JITOptions,
+    start_cell: usize,
+) -> Result<Run, BfError> {
+    let mut jit_target = JITTarget::new(&program.data, options, None)?;
+    jit_target.set_start_cell(start_cell)?;
+
+    let mut output = Vec::new();
+    let mut tape = vec![0u8; TAPE_SIZE];
+    {
+        let mut input = input;
+        jit_target.run_on(&mut tape, Io::new(&mut input, &mut output));
+    }
+
+    Ok(Run { output, tape })
+}
+
+/// Find the first difference between two runs. Cells beyond the end of
+/// either tape count as zero.
+pub fn compare(interpreter: &Run, jit: &Run) -> Option<Divergence> {
+    let output_len = cmp::max(interpreter.output.len(), jit.output.len());
+    let output = (0..output_len)
+        .map(|offset| {
+            (
+                offset,
+                interpreter.output.get(offset).cloned(),
+                jit.output.get(offset).cloned(),
+            )
+        })
+        .find(|(_, interpreter, jit)| interpreter != jit)
+        .map(|(offset, interpreter, jit)| Divergence::Output {
+            offset,
+            interpreter,
+            jit,
+        });
+
+    let tape_len = cmp::max(interpreter.tape.len(), jit.tape.len());
+    let tape = || {
+        (0..tape_len)
+            .map(|cell| {
+                (
+                    cell,
+                    interpreter.tape.get(cell).cloned().unwrap_or(0),
+                    jit.tape.get(cell).cloned().unwrap_or(0),
+                )
+            })
+            .find(|(_, interpreter, jit)| interpreter != jit)
+            .map(|(cell, interpreter, jit)| Divergence::Tape {
+                cell,
+                interpreter,
+                jit,
+            })
+    };
+
+    output.or_else(tape)
+}
+
+/// Run `program` on both backends and report the first difference.
+pub fn diff(
+    program: &AST,
+    input: &[u8],
+    options: JITOptions,
+    start_cell: usize,
+) -> Result<Option<Divergence>, BfError> {
+    let jit = run_jit(program, input, options, start_cell)?;
+    let interpreter = run_interpreter(program, input, options, start_cell)?;
+
+    Ok(compare(&interpreter, &jit))
+}
+
+#[cfg(target_arch = "x86_64")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use code_gen;
+    use parser::ParseOptions;
+    use runnable::CellArithmetic;
+
+    #[test]
+    fn no_divergence() {
+        let ast = AST::parse(include_str!("../test/programs/hello_world.bf")).unwrap();
+
+        assert_eq!(diff(&ast, b"", JITOptions::default(), 0).unwrap(), None);
+    }
+
+    #[test]
+    fn saturating_cells() {
+        let source = format!("-.+.{}.", "+".repeat(300));
+        let parse_options = ParseOptions {
+            arithmetic: CellArithmetic::Saturate,
+            ..ParseOptions::default()
+        };
+        let (ast, _) = AST::parse_with_options(&source, parse_options).unwrap();
+        let options = JITOptions {
+            arithmetic: CellArithmetic::Saturate,
+            ..JITOptions::default()
+        };
+
+        assert_eq!(diff(&ast, b"", options, 0).unwrap(), None);
+        assert_eq!(
+            run_interpreter(&ast, b"", options, 0).unwrap().output,
+            [0, 1, 0xff]
+        );
+    }
+
+    #[test]
+    fn erroring_cells() {
+        // Compiled code cannot stop at an overflow, so there is nothing to
+        // compare against
+        let ast = AST::parse("-.").unwrap();
+        let options = JITOptions {
+            arithmetic: CellArithmetic::Error,
+            ..JITOptions::default()
+        };
+
+        assert!(matches!(
+            diff(&ast, b"", options, 0),
+            Err(BfError::ArithmeticUnsupported(CellArithmetic::Error))
+        ));
+        assert!(run_interpreter(&ast, b"", options, 0)
+            .unwrap()
+            .output
+            .is_empty());
+    }
+
+    #[test]
+    fn broken_codegen() {
+        let ast = AST::parse("+++[>++<-]>.").unwrap();
+
+        code_gen::miscompile(true);
+        let divergence = diff(&ast, b"", JITOptions::default(), 0);
+        code_gen::miscompile(false);
+
+        assert_eq!(
+            divergence.unwrap(),
+            Some(Divergence::Output {
+                offset: 0,
+                interpreter: Some(6),
+                jit: Some(8),
+            })
+        );
+    }
+
+    #[test]
+    fn tape_divergence() {
+        let ast = AST::parse(",>+").unwrap();
+
+        code_gen::miscompile(true);
+        let divergence = diff(&ast, b"x", JITOptions::default(), 0);
+        code_gen::miscompile(false);
+
+        assert_eq!(
+            divergence.unwrap(),
+            Some(Divergence::Tape {
+                cell: 1,
+                interpreter: 1,
+                jit: 2,
+            })
+        );
+    }
+
+    /// Cells the generated programs stay within.
+    const FUZZ_CELLS: usize = 16;
+
+    /// A xorshift generator, so failures can be reproduced from the seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    /// Append up to `budget` random commands to `out`.
+    ///
+    /// Every loop ends by decrementing its control cell, which nothing else
+    /// inside it touches, so each loop runs at most 255 times.
+    fn generate(
+        rng: &mut Rng,
+        out: &mut String,
+        cell: &mut usize,
+        controls: &mut Vec<usize>,
+        budget: usize,
+    ) {
+        for _ in 0..budget {
+            let free = !controls.contains(cell);
+
+            match rng.below(8) {
+                0 | 1 if free => out.push('+'),
+                2 if free => out.push('-'),
+                3 if free => out.push(','),
+                4 if *cell + 1 < FUZZ_CELLS => {
+                    out.push('>');
+                    *cell += 1;
+                }
+                5 if *cell > 0 => {
+                    out.push('<');
+                    *cell -= 1;
+                }
+                6 if free && controls.len() < 2 => {
+                    let control = *cell;
+                    out.push('[');
+                    controls.push(control);
+                    generate(rng, out, cell, controls, 6);
+                    controls.pop();
+
+                    while *cell > control {
+                        out.push('<');
+                        *cell -= 1;
+                    }
+                    while *cell < control {
+                        out.push('>');
+                        *cell += 1;
+                    }
+                    out.push_str("-]");
+                }
+                _ => out.push('.'),
+            }
+        }
+    }
+
+    #[test]
+    fn random_programs() {
+        let mut rng = Rng(0x5eed_f00d);
+
+        for case in 0..300 {
+            let mut source = String::new();
+            generate(&mut rng, &mut source, &mut 0, &mut Vec::new(), 40);
+            let input: Vec<u8> = (0..rng.below(8)).map(|_| rng.below(256) as u8).collect();
+            let ast = AST::parse(&source).unwrap();
+
+            assert_eq!(
+                diff(&ast, &input, JITOptions::default(), 0).unwrap(),
+                None,
+                "case {}: {:?} on input {:?}",
+                case,
+                source,
+                input
+            );
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use super::runnable::CellArithmetic;
+
+/// Everything that can go wrong while loading, compiling or setting up a
+/// program.
+#[derive(Debug)]
+pub enum BfError {
+    /// A `[` that is never closed, at the position of the `[`.
+    UnmatchedOpen { line: usize, col: usize },
+    /// A `]` with no `[` to close.
+    UnmatchedClose { line: usize, col: usize },
+    /// Loops nested deeper than allowed, at the position of the first `[`
+    /// past the limit.
+    TooDeep {
+        limit: usize,
+        line: usize,
+        col: usize,
+    },
+    /// A loop at the start of the program, which can never run, with
+    /// `ParseOptions::strict`.
+    DeadLoop { line: usize, col: usize },
+    /// The program file could not be opened.
+    Open(io::Error),
+    /// The program could not be read.
+    Io(io::Error),
+    /// Source in a dialect could not be translated to BrainFuck.
+    Dialect(String),
+    /// No code was found between the delimiters it should be extracted from.
+    NothingExtracted { start: String, end: String },
+    /// The JIT does not support this CPU architecture.
+    JitUnsupported,
+    /// The JIT cannot compile code with this kind of cell arithmetic.
+    ArithmeticUnsupported(CellArithmetic),
+    /// The system refused to map memory as executable.
+    ExecMemoryDenied,
+    /// There was not enough memory for a chunk of compiled code.
+    OutOfExecMemory { size: usize },
+    /// Memory for compiled code was requested with an alignment the system
+    /// does not accept.
+    BadExecAlignment { alignment: usize },
+    /// Allocating memory for compiled code failed with an unexpected error
+    /// code.
+    ExecAllocFailed { code: i32 },
+    /// The data pointer would start outside of the tape.
+    PointerOutOfBounds { cell: usize, tape_size: usize },
+    /// Compiled code was given a tape shorter than it can run on safely.
+    TapeTooSmall { size: usize, minimum: usize },
+    /// The data pointer was outside of the tape when the program ended.
+    EndedOutsideTape { tape_size: usize },
+    /// A snippet stopped before its end, such as by moving off the tape,
+    /// with the data pointer on `cell`. Why is written to the diagnostics.
+    Halted { cell: usize },
+    /// The tape could not be given the cells it was asked for.
+    TapeAlloc { cells: usize, error: io::Error },
+    /// An object file would relocate against a runtime function it has no
+    /// symbol for.
+    UndefinedSymbol(&'static str),
+    /// A node that could not have come from parsing, found by
+    /// `AST::validate`. `path` holds its index and those of the loops
+    /// around it, outermost first.
+    InvalidNode {
+        path: Vec<usize>,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for BfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BfError::UnmatchedOpen { .. } => write!(f, "More [ than ]"),
+            BfError::UnmatchedClose { .. } => write!(f, "More ] than ["),
+            BfError::TooDeep { limit, line, col } => write!(
+                f,
+                "Loops nested deeper than {} at line {}, col {}",
+                limit, line, col
+            ),
+            BfError::DeadLoop { line, col } => write!(
+                f,
+                "Loop at line {}, col {} can never run, the current cell is always 0",
+                line, col
+            ),
+            BfError::Open(e) => write!(f, "Could not open file: {:?}", e),
+            BfError::Io(e) => write!(f, "Could not read file: {:?}", e),
+            BfError::Dialect(msg) => write!(f, "{}", msg),
+            BfError::NothingExtracted { start, end } => {
+                write!(f, "No program found between {:?} and {:?}", start, end)
+            }
+            BfError::JitUnsupported => write!(f, "Unsupported JIT architecture."),
+            BfError::ArithmeticUnsupported(arithmetic) => {
+                write!(f, "The JIT does not support {} arithmetic.", arithmetic)
+            }
+            BfError::ExecMemoryDenied => write!(f, "Executable memory denied by policy."),
+            BfError::OutOfExecMemory { size } => write!(
+                f,
+                "Out of memory allocating {} bytes for compiled code",
+                size
+            ),
+            BfError::BadExecAlignment { alignment } => write!(
+                f,
+                "Invalid alignment {} for compiled code memory",
+                alignment
+            ),
+            BfError::ExecAllocFailed { code } => write!(
+                f,
+                "Could not allocate memory for compiled code: {}",
+                io::Error::from_raw_os_error(*code)
+            ),
+            BfError::PointerOutOfBounds { cell, tape_size } => write!(
+                f,
+                "Start cell {} is outside of the tape ({} cells)",
+                cell, tape_size
+            ),
+            BfError::TapeTooSmall { size, minimum } => write!(
+                f,
+                "Compiled code needs a tape of at least {} cells, not {}",
+                minimum, size
+            ),
+            BfError::EndedOutsideTape { tape_size } => write!(
+                f,
+                "The data pointer ended outside of the tape ({} cells)",
+                tape_size
+            ),
+            BfError::Halted { cell } => {
+                write!(f, "Stopped early with the data pointer on cell {}", cell)
+            }
+            BfError::TapeAlloc { cells, error } => {
+                write!(f, "Could not give the tape {} cells: {}", cells, error)
+            }
+            BfError::UndefinedSymbol(name) => {
+                write!(f, "No symbol for the relocation against {}", name)
+            }
+            BfError::InvalidNode { path, reason } => {
+                let path: Vec<String> = path.iter().map(usize::to_string).collect();
+                write!(f, "Invalid node at {}: {}", path.join("."), reason)
+            }
+        }
+    }
+}
+
+impl Error for BfError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BfError::Open(e) | BfError::Io(e) => Some(e),
+            BfError::TapeAlloc { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BfError {
+    fn from(e: io::Error) -> Self {
+        BfError::Io(e)
+    }
+}
@@ -0,0 +1,30 @@
+#![allow(clippy::upper_case_acronyms)]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate libc;
+
+#[cfg(feature = "std")]
+#[macro_use]
+extern crate lazy_static;
+
+#[cfg(feature = "std")]
+pub mod advise;
+pub mod bare;
+#[cfg(feature = "std")]
+mod code_gen;
+#[cfg(feature = "std")]
+pub mod diff;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
+pub mod object;
+#[cfg(feature = "std")]
+pub mod parser;
+#[cfg(feature = "std")]
+pub mod runnable;
+#[cfg(feature = "std")]
+pub mod selftest;
+#[cfg(feature = "std")]
+pub mod verify;
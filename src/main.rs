@@ -1,96 +1,1693 @@
-extern crate libc;
+// The pinned serde_derive emits impls inside anonymous consts and checks for
+// the legacy `cargo-clippy` feature, both of which newer compilers lint on.
+#![allow(unknown_lints, non_local_definitions, unexpected_cfgs)]
 
 #[macro_use]
 extern crate serde_derive;
 extern crate docopt;
+extern crate fucker;
+extern crate libc;
 
-#[macro_use]
-extern crate lazy_static;
-
-mod code_gen;
-mod parser;
-mod runnable;
-
-use std::fs::File;
-use std::io::{stdin, Read};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, stderr, stdin, stdout, BufRead, BufReader, Cursor, Read, Write};
+use std::path::PathBuf;
 use std::process::exit;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use docopt::Docopt;
 
-use parser::AST;
-use runnable::{Fucker, JITTarget, Runnable};
+use fucker::advise;
+use fucker::diff;
+use fucker::error::BfError;
+use fucker::object;
+use fucker::parser::{Dialect, Directives, ParseOptions, Stats, Warning, AST, STEP_LIMIT};
+use fucker::runnable::{
+    install_sigint_handler, CellArithmetic, Checksum, ChecksumWriter, EncodingWriter, EofMode,
+    ExitReason, FlushPolicy, FlushWriter, FragmentPolicy, Fucker, JITOptions, JITTarget,
+    LimitWriter, MappedTape, OutputEncoding, PointerMode, RingWriter, Runnable, Tape, TAPE_SIZE,
+};
+use fucker::selftest;
+use fucker::verify::Verifier;
 
 const USAGE: &str = "
 Fucker
 
 Usage:
-  fucker [--int] <program>
+  fucker selftest
+  fucker advise [options] <program>
+  fucker [options] [--watch N]... <program>
   fucker (-d | --debug) <program>
+  fucker --stats <program>
   fucker (-h | --help)
 
 Options:
-  -h --help     Show this screen.
-  -d --debug    Display intermediate language.
-  --int         Use an interpreter instead of the JIT compiler.
+  -h --help       Show this screen.
+  -d --debug      Display intermediate language.
+  --emit-min      Print the optimized program as minimal BrainFuck source.
+  --emit-bf       Write the optimized program to stdout as BrainFuck that runs
+                  the same as the original, with nothing else on any stream.
+  --debug-ext     Make $ print the current cell as a number to stderr.
+  --stats         Display command counts before and after optimization.
+  --verify-passes
+                  Check that parsing and each optimization pass keep the
+                  output the same, on the input after --program-input-sep if
+                  any (slow, for developing passes).
+  --int           Use an interpreter instead of the JIT compiler.
+  --check         Compile the whole program without running it (JIT).
+  --emit-callgraph
+                  Print which deferred loops call which as a DOT graph (JIT).
+  --emit-object FILE
+                  Write the program to FILE as an x86-64 ELF object to link
+                  with runtime/bf_runtime.c, instead of running it.
+  --dump-tape     Print the tape to stderr when the program ends (needs --int).
+  --trace         Log every executed command to stderr (implies --int).
+  --watch N       Report every change to cell N on stderr, may be repeated
+                  (implies --int).
+  --profile-cells
+                  Print the most read and written cells to stderr when the
+                  program ends (implies --int).
+  --cache-cell    Keep the current cell in a register across arithmetic (JIT).
+  --debug-frames  Give compiled code standard stack frames for debuggers (JIT).
+  --zero-fill     Crash instead of returning when generated code runs past its
+                  end (JIT, for debugging).
+  --jit-step      Pause at the end of every loop iteration, showing the
+                  current cell on stderr, until Enter is pressed on the
+                  terminal (JIT).
+  --interruptible
+                  Let Ctrl-C stop compiled loops, at a small cost per loop
+                  iteration (JIT).
+  --fragment-policy POLICY
+                  When compiled loops are freed: keep, free-after-run, or
+                  lru:N to keep the N most recently run [default: keep] (JIT).
+  --start-cell N  Start with the data pointer at cell N [default: 0].
+  --tape-size N   Give the tape exactly N cells (default 30000 for the JIT,
+                  unlimited for the interpreter). Fewer than 30000 implies
+                  --int.
+  --mmap-tape     Map the tape's memory lazily, so that a huge --tape-size
+                  only uses memory for the cells the program touches (implies
+                  --int).
+  --pointer-mode MODE
+                  What > past the end of the tape does: grow, wrap, halt,
+                  unchecked or two-sided, which also grows left of cell 0
+                  and implies --int (needs --int; default halt with a tape
+                  size, otherwise grow).
+  --core-on-fault FILE
+                  When the pointer leaves the tape, write the tape to FILE
+                  and the pointer and instruction to FILE.info (needs
+                  --int).
+  --cell-arithmetic MODE
+                  What + and - do past 255 or below 0: wrap, saturate or error
+                  (error implies --int) [default: wrap].
+  --eof MODE      Value , stores at end of input: zero, negative-one or
+                  unchanged (default negative-one).
+  --eof-value N   Store the byte N at end of input, overriding --eof.
+  --input FILE    Give the program the contents of FILE as input instead of
+                  stdin.
+  --input-hex HEX
+                  Give the program the bytes in HEX as input instead of stdin.
+  --program-input-sep MARKER
+                  Split the program file at the first line equal to MARKER,
+                  using what follows as input instead of stdin.
+  --extract START
+                  Run only the code between START and the next END, such
+                  as the ``` fences of a Markdown file.
+  --extract-end END
+                  Delimiter closing each region, START if not given.
+  --max-output N  Stop the program once it has printed N bytes.
+  --max-steps N   Stop the program once it has run N instructions, counting
+                  runs of the same command as one (implies --int).
+  --repeat N      Run the program N times on the same input, each time on a
+                  fresh tape, and report the time taken on stderr.
+  --quiet         Throw away the program's output.
+  --checksum ALG  Print a checksum of the output to stderr once the program
+                  has finished: crc32 or sha256.
+  --tail N        Only write out the last N bytes the program prints, once it
+                  has finished.
+  --print-width N
+                  Make each . print N cells from the pointer on, first cell
+                  first, such as 2 for big-endian UTF-16 (implies --int).
+  -o --output FILE
+                  Write the program's output to FILE instead of stdout.
+  --deterministic
+                  Refuse to use stdin or stdout, so that only the source
+                  and the input given by --input, --input-hex or the
+                  program file decide what the program does. Needs -o,
+                  and the EOF mode from --eof, --eof-value or a directive.
+  --output-encoding ENC
+                  Write output as raw bytes, utf8 or latin1 [default: raw].
+  --flush POLICY  When output is flushed: line, full or byte (default line
+                  on a terminal, otherwise full).
+  --dialect LANG  Source language: brainfuck or ook [default: brainfuck].
+  --max-depth N   Reject programs with loops nested more than N deep.
+  --opt-budget MS
+                  Skip the optimization passes still to run once parsing and
+                  optimizing have taken MS milliseconds. The passes parsing
+                  does as it goes always run.
+  --strict        Reject loops at the start of the program, which can never
+                  run, instead of removing them with a warning.
+  --config FILE   Read default options from FILE instead of .fuckerrc in the
+                  current directory, which --deterministic does not read.
+
+Environment:
+  FUCKER_BACKEND  Set to interp or jit to choose the default backend.
+
+Exit status:
+  0 once the program has run to the end, 1 if it stopped on an error, 124 if
+  it used up --max-steps and 130 if it was interrupted.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
+    cmd_selftest: bool,
+    cmd_advise: bool,
     arg_program: String,
     flag_debug: bool,
+    flag_debug_ext: bool,
+    flag_emit_min: bool,
+    flag_emit_bf: bool,
+    flag_stats: bool,
+    flag_verify_passes: bool,
     flag_int: bool,
+    flag_check: bool,
+    flag_emit_callgraph: bool,
+    flag_emit_object: Option<String>,
+    flag_dump_tape: bool,
+    flag_trace: bool,
+    flag_watch: Vec<usize>,
+    flag_profile_cells: bool,
+    flag_cache_cell: bool,
+    flag_debug_frames: bool,
+    flag_interruptible: bool,
+    flag_jit_step: bool,
+    flag_zero_fill: bool,
+    flag_fragment_policy: String,
+    flag_start_cell: usize,
+    flag_tape_size: Option<usize>,
+    flag_mmap_tape: bool,
+    flag_pointer_mode: Option<String>,
+    flag_core_on_fault: Option<String>,
+    flag_cell_arithmetic: String,
+    flag_eof: Option<String>,
+    flag_eof_value: Option<String>,
+    flag_input: Option<String>,
+    flag_input_hex: Option<String>,
+    flag_program_input_sep: Option<String>,
+    flag_extract: Option<String>,
+    flag_extract_end: Option<String>,
+    flag_output: Option<String>,
+    flag_max_output: Option<usize>,
+    flag_max_steps: Option<u64>,
+    flag_repeat: Option<usize>,
+    flag_quiet: bool,
+    flag_checksum: Option<String>,
+    flag_tail: Option<usize>,
+    flag_print_width: Option<usize>,
+    flag_deterministic: bool,
+    flag_output_encoding: String,
+    flag_flush: Option<String>,
+    flag_dialect: String,
+    flag_max_depth: Option<usize>,
+    flag_strict: bool,
+    flag_opt_budget: Option<u64>,
+    flag_config: Option<String>,
 }
 
+/// File default options are read from when --config is not given.
+const CONFIG_FILE: &str = ".fuckerrc";
+
 fn main() {
-    let args: Args = Docopt::new(USAGE)
-        .and_then(|d| d.deserialize())
+    let start = Instant::now();
+
+    // --diff is a contributor tool and deliberately left out of USAGE.
+    let mut argv: Vec<String> = env::args().collect();
+    let diff = argv
+        .iter()
+        .position(|arg| arg == "--diff")
+        .map(|index| argv.remove(index))
+        .is_some();
+
+    let mut args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.argv(argv).deserialize())
         .unwrap_or_else(|e| e.exit());
 
-    let program = read_program(&args.arg_program)
-        .and_then(|source| AST::parse(&source))
-        .unwrap_or_else(|e| {
-            eprintln!("Error occurred while loading program: {}", e);
+    if let Some((config_path, required)) = config_file(&args) {
+        match fs::read_to_string(&config_path) {
+            Ok(config) => {
+                for warning in apply_config(&mut args, &config) {
+                    eprintln!("Warning: {}: {}", config_path, warning);
+                }
+            }
+            Err(ref e) if !required && e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                eprintln!("Could not read {}: {}", config_path, e);
+                exit(1)
+            }
+        }
+    }
+
+    if args.cmd_selftest {
+        match selftest::run(&mut stdout()) {
+            Ok(true) => return,
+            Ok(false) => exit(1),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1)
+            }
+        }
+    }
+
+    if let Err(e) = check_deterministic(&args) {
+        eprintln!("{}", e);
+        exit(1)
+    }
+
+    let dialect: Dialect = args.flag_dialect.parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    });
+
+    let Loaded {
+        source,
+        mut program,
+        directives,
+        warnings,
+        input: embedded_input,
+    } = load_program(&args, dialect).unwrap_or_else(|e| {
+        eprintln!("Error occurred while loading program: {}", e);
+        exit(1)
+    });
+
+    let eof = eof_mode(&args, &directives).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    });
+
+    // Parsing is checked as a whole against a parse with only runs merged
+    let verifier = if args.flag_verify_passes {
+        let sample = embedded_input.as_deref().unwrap_or_default();
+        let verifier = Verifier::new(sample, eof, cell_arithmetic(&args));
+        let options = ParseOptions {
+            peephole: false,
+            ..parse_options(&args)
+        };
+        let (unoptimized, _) =
+            AST::parse_with_options(&source, options).expect("the program was parsed once already");
+        if let Err(e) = verifier.check("parse", &unoptimized, &program) {
+            eprintln!("{}", e);
             exit(1)
-        });
+        }
+
+        Some(verifier)
+    } else {
+        None
+    };
+
+    if (args.flag_emit_bf || args.flag_emit_min) && cell_arithmetic(&args) != CellArithmetic::Wrap {
+        eprintln!("Programs can only be written back out with wrapping cells");
+        exit(1)
+    }
+
+    // Any output besides the program would get in the way of a pipeline.
+    if args.flag_emit_bf {
+        print!("{}{}", directives, program);
+
+        return;
+    }
+
+    for warning in warnings.iter().chain(&program.eof_warnings(eof)) {
+        eprintln!("Warning: {}", warning);
+    }
+
+    if args.flag_emit_min {
+        println!("{}", program);
+
+        return;
+    }
+
+    let inspected = args.flag_dump_tape
+        || args.flag_trace
+        || !args.flag_watch.is_empty()
+        || args.flag_profile_cells
+        || args.flag_max_steps.is_some()
+        || args.flag_check
+        || args.flag_emit_callgraph
+        || diff;
+    let mut passes = Passes::new(&args, verifier.as_ref(), start);
+    optimize(
+        &mut program,
+        &args,
+        &mut passes,
+        inspected,
+        tape_size(&args, &directives),
+    );
 
     if args.flag_debug {
-        println!("{:?}", program);
+        if let Err(e) = program.write_tree(&mut stdout(), 0) {
+            eprintln!("Could not write the tree: {}", e);
+            exit(1);
+        }
+
+        return;
+    }
+
+    if args.flag_stats {
+        let before = Stats::from_source(&source);
+        let after = Stats::from_ast(&program);
+
+        println!("{:<10}{:>12}{:>12}", "", "source", "optimized");
+        for ((label, source_count), (_, ast_count)) in before.rows().into_iter().zip(after.rows()) {
+            println!("{:<10}{:>12}{:>12}", label, source_count, ast_count);
+        }
+
+        return;
+    }
+
+    let options = JITOptions {
+        cache_cell: args.flag_cache_cell,
+        eof,
+        debug_frames: args.flag_debug_frames,
+        zero_fill: args.flag_zero_fill,
+        arithmetic: cell_arithmetic(&args),
+        // Only interruptible code can be stopped at the output limit
+        interruptible: args.flag_interruptible || args.flag_max_output.is_some(),
+        step_loops: args.flag_jit_step,
+    };
+
+    if args.cmd_advise {
+        print!("{}", advise::advise(&program, options));
+
+        return;
+    }
+
+    if args.flag_emit_callgraph {
+        match JITTarget::new(&program.data, options, None).and_then(|mut jit| jit.call_graph()) {
+            Ok(dot) => print!("{}", dot),
+            Err(e) => {
+                eprintln!("Error occurred while compiling program: {}", e);
+                exit(1)
+            }
+        }
+
+        return;
+    }
+
+    if let Some(ref path) = args.flag_emit_object {
+        if let Some(define) = runtime_eof(eof) {
+            eprintln!(
+                "Warning: end of input is up to the runtime, build it with {}",
+                define
+            );
+        }
+
+        let written = object::emit_object(&program, options)
+            .map_err(|e| e.to_string())
+            .and_then(|object| fs::write(path, object).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            eprintln!("Could not write {}: {}", path, e);
+            exit(1)
+        }
+
+        return;
+    }
+
+    if args.flag_check {
+        if let Err(e) = check(&program, options) {
+            eprintln!("Error occurred while compiling program: {}", e);
+            exit(1)
+        }
+
+        return;
+    }
+
+    let mut input = program_input(&args, embedded_input).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    });
+    let encoding: OutputEncoding = args.flag_output_encoding.parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    });
+    let flush = flush_policy(&args);
+    let fragment_policy: FragmentPolicy = args.flag_fragment_policy.parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    });
+
+    if diff {
+        let mut bytes = Vec::new();
+        if let Err(e) = input.read_to_end(&mut bytes) {
+            eprintln!("Could not read input: {:?}", e);
+            exit(1)
+        }
+
+        let divergence = diff::diff(&program, &bytes, options, args.flag_start_cell)
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(1)
+            });
+
+        if let Some(divergence) = divergence {
+            eprintln!("{}", divergence);
+            exit(1)
+        }
 
         return;
     }
 
-    let mut runnable: Box<dyn Runnable> = if args.flag_int {
-        Box::new(Fucker::new(&program.data))
+    let tape_size = tape_size(&args, &directives);
+    let use_interpreter = use_interpreter(&args, tape_size).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    });
+
+    if tape_size == Some(0) {
+        eprintln!("Tape size must be at least 1");
+        exit(1)
+    }
+    if args.flag_print_width == Some(0) {
+        eprintln!("Print width must be at least 1");
+        exit(1)
+    }
+
+    if let Err(e) = check_backend(&args, use_interpreter || args.flag_mmap_tape) {
+        eprintln!("{}", e);
+        exit(1)
+    }
+
+    let mut runnable: Box<dyn Runnable> = if args.flag_mmap_tape {
+        let size = tape_size.unwrap_or_else(|| {
+            eprintln!("--mmap-tape needs --tape-size");
+            exit(1)
+        });
+        let tape = MappedTape::new(size).unwrap_or_else(|e| {
+            eprintln!("Could not map a tape of {} cells: {}", size, e);
+            exit(1)
+        });
+        let fucker = Fucker::with_tape(&program.data, tape);
+        Box::new(configure_interpreter(fucker, &args, eof, tape_size))
+    } else if use_interpreter {
+        Box::new(interpreter(&program, &args, eof, tape_size))
     } else {
-        match JITTarget::new(&program.data) {
-            Ok(jit_target) => Box::new(jit_target),
+        match JITTarget::new(&program.data, options, None) {
+            Ok(mut jit_target) => {
+                tape_size
+                    .map_or(Ok(()), |size| jit_target.set_tape_size(size))
+                    .and_then(|_| jit_target.set_start_cell(args.flag_start_cell))
+                    .unwrap_or_else(|e| {
+                        eprintln!("{}", e);
+                        exit(1)
+                    });
+                if args.flag_interruptible {
+                    jit_target.set_cancel_token(&install_sigint_handler());
+                }
+                if args.flag_jit_step {
+                    let prompt = step_prompt().unwrap_or_else(|e| {
+                        eprintln!("Could not open the terminal: {}", e);
+                        exit(1)
+                    });
+                    jit_target.set_step_hook(prompt);
+                }
+                jit_target.set_fragment_policy(fragment_policy);
+                Box::new(jit_target)
+            }
             Err(msg) => {
                 eprintln!("Error occurred while compiling program: {}", msg);
                 eprintln!("Falling back to interpreter");
-                Box::new(Fucker::new(&program.data))
+                Box::new(interpreter(&program, &args, eof, tape_size))
             }
         }
     };
 
-    runnable.run();
+    let mut destination: Option<Box<dyn Write>> = Some(match args.flag_output {
+        _ if args.flag_quiet => Box::new(io::sink()),
+        Some(ref path) => Box::new(File::create(path).unwrap_or_else(|e| {
+            eprintln!("Could not create {}: {}", path, e);
+            exit(1)
+        })),
+        None => Box::new(stdout()),
+    });
+    // With --tail the program prints into the ring, and what is left of
+    // that goes to the destination at the end
+    let mut ring = args.flag_tail.map(RingWriter::new);
+    let output: Box<dyn Write + '_> = match ring {
+        Some(ref mut ring) => Box::new(ring),
+        None => Box::new(
+            destination
+                .take()
+                .expect("the destination is only taken once"),
+        ),
+    };
+
+    // The checksum is of the bytes the program printed, before encoding
+    let checksum: Option<Checksum> = args.flag_checksum.as_ref().map(|alg| {
+        alg.parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1)
+        })
+    });
+    let mut limited = LimitWriter::new(
+        EncodingWriter::new(FlushWriter::new(output, flush), encoding),
+        args.flag_max_output.unwrap_or(usize::MAX),
+    );
+    let (reason, digest) = match checksum {
+        Some(checksum) => {
+            let mut checked = ChecksumWriter::new(&mut limited, checksum);
+            let reason = run_program(&mut *runnable, &mut input, &mut checked, args.flag_repeat);
+            (reason, Some((checksum, checked.digest())))
+        }
+        None => (
+            run_program(&mut *runnable, &mut input, &mut limited, args.flag_repeat),
+            None,
+        ),
+    };
+
+    // Exiting skips destructors, so flush what was printed first
+    let exceeded = limited.exceeded();
+    drop(limited);
+
+    if let (Some(ring), Some(mut destination)) = (ring, destination) {
+        let tail = ring.last_output(usize::MAX);
+        if let Err(e) = destination
+            .write_all(&tail)
+            .and_then(|_| destination.flush())
+        {
+            eprintln!("Could not write the output: {}", e);
+            exit(1)
+        }
+    }
+
+    if let Some((checksum, digest)) = digest {
+        eprintln!("{} {}", checksum, digest);
+    }
+
+    if exceeded {
+        exit(1)
+    }
+    exit(exit_status(reason))
+}
+
+/// Step hook for --jit-step, which reads from the terminal so that stdin is
+/// left to the program.
+fn step_prompt() -> io::Result<impl FnMut(&[u8], usize)> {
+    let mut terminal = BufReader::new(File::open("/dev/tty")?);
+    let mut iterations = 0;
+
+    Ok(move |tape: &[u8], cell: usize| {
+        iterations += 1;
+        eprint!("Iteration {}: cell {} = {} ", iterations, cell, tape[cell]);
+        let _ = terminal.read_line(&mut String::new());
+    })
+}
+
+/// Run `runnable` once, or `repeat` times reporting how long that took.
+/// Returns how the last run ended.
+fn run_program(
+    runnable: &mut dyn Runnable,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    repeat: Option<usize>,
+) -> ExitReason {
+    match repeat {
+        Some(times) => {
+            let times = run_repeated(runnable, input, output, times).unwrap_or_else(|e| {
+                eprintln!("Could not read input: {}", e);
+                exit(1)
+            });
+            eprintln!("{}", timing_report(&times));
+        }
+        None => runnable.run_with_io(input, output),
+    }
+
+    runnable.exit_reason()
+}
+
+/// The exit status of the process after a run that ended with `reason`.
+fn exit_status(reason: ExitReason) -> i32 {
+    match reason {
+        ExitReason::Finished => 0,
+        ExitReason::Error => 1,
+        // As timeout(1) reports running out of time
+        ExitReason::Limit => 124,
+        // As a shell reports a process killed by SIGINT
+        ExitReason::Cancelled => 130,
+    }
+}
+
+/// Run `runnable` `times` times, each on all of `input`, returning how long
+/// each run took.
+///
+/// The input is read up front so that every run sees the same bytes. Both
+/// backends start from a fresh tape on every run, and the JIT keeps the
+/// loops it compiled unless its fragment policy says otherwise.
+fn run_repeated(
+    runnable: &mut dyn Runnable,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    times: usize,
+) -> io::Result<Vec<Duration>> {
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+
+    Ok((0..times)
+        .map(|_| {
+            let start = Instant::now();
+            runnable.run_with_io(&mut &bytes[..], output);
+            start.elapsed()
+        })
+        .collect())
+}
+
+/// Summarize the times of repeated runs.
+fn timing_report(times: &[Duration]) -> String {
+    let total: Duration = times.iter().sum();
+    let (fastest, slowest) = (times.iter().min(), times.iter().max());
+
+    match (fastest, slowest) {
+        (Some(fastest), Some(slowest)) => format!(
+            "{} runs in {:?}: {:?} on average, fastest {:?}, slowest {:?}",
+            times.len(),
+            total,
+            total / times.len() as u32,
+            fastest,
+            slowest
+        ),
+        _ => "0 runs".to_owned(),
+    }
+}
+
+/// Make sure --deterministic leaves nothing to the terminal that can be
+/// checked before the program is loaded.
+fn check_deterministic(args: &Args) -> Result<(), String> {
+    // Everything these print goes to stdout, whatever -o says
+    let printing = [
+        (args.flag_emit_bf, "--emit-bf"),
+        (args.flag_emit_min, "--emit-min"),
+        (args.flag_debug, "--debug"),
+        (args.flag_stats, "--stats"),
+        (args.flag_emit_callgraph, "--emit-callgraph"),
+        (args.cmd_advise, "advise"),
+    ];
+
+    if !args.flag_deterministic {
+        Ok(())
+    } else if args.arg_program == "-" {
+        Err("--deterministic cannot read the program from stdin".to_owned())
+    } else if let Some((_, flag)) = printing.iter().find(|(set, _)| *set) {
+        Err(format!(
+            "--deterministic cannot use {}, which prints to stdout",
+            flag
+        ))
+    } else if args.flag_jit_step {
+        Err("--deterministic cannot use --jit-step, which reads the terminal".to_owned())
+    } else if args.flag_output.is_none() {
+        Err("--deterministic needs an output file from -o".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+/// The stream `,` reads from: --input-hex or --input if given, then any
+/// input embedded in the program file, otherwise stdin.
+fn program_input(args: &Args, embedded: Option<Vec<u8>>) -> Result<Box<dyn Read>, String> {
+    match (&args.flag_input_hex, &args.flag_input, embedded) {
+        (Some(hex), _, _) => Ok(Box::new(Cursor::new(decode_hex(hex)?))),
+        (None, Some(path), _) => File::open(path)
+            .map(|file| Box::new(file) as Box<dyn Read>)
+            .map_err(|e| format!("Could not open {}: {}", path, e)),
+        (None, None, Some(bytes)) => Ok(Box::new(Cursor::new(bytes))),
+        (None, None, None) if args.flag_deterministic => Err(
+            "--deterministic needs input from --input, --input-hex or --program-input-sep"
+                .to_owned(),
+        ),
+        (None, None, None) => Ok(Box::new(stdin())),
+    }
+}
+
+/// Decode a string of hex digit pairs, such as "41420A", into bytes.
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let digits = hex
+        .chars()
+        .map(|c| {
+            c.to_digit(16)
+                .map(|digit| digit as u8)
+                .ok_or(format!("Invalid hex digit in input: {:?}", c))
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("Hex input has an odd number of digits: {}", hex));
+    }
+
+    Ok(digits
+        .chunks(2)
+        .map(|pair| pair[0] << 4 | pair[1])
+        .collect())
+}
+
+/// A program as read from the command line.
+struct Loaded {
+    /// The BrainFuck source, after translating from the dialect.
+    source: String,
+    program: AST,
+    /// Settings from the program's first line.
+    directives: Directives,
+    warnings: Vec<Warning>,
+    /// Input embedded after --program-input-sep.
+    input: Option<Vec<u8>>,
+}
+
+/// Read, translate and parse the program named on the command line.
+fn load_program(args: &Args, dialect: Dialect) -> Result<Loaded, BfError> {
+    let extract = args.flag_extract.as_ref().map(|start| {
+        let end = args.flag_extract_end.as_ref().unwrap_or(start);
+        (start.as_str(), end.as_str())
+    });
+    let file = read_program(&args.arg_program, extract)?;
+    let (code, input) = match args.flag_program_input_sep {
+        Some(ref marker) => split_program_input(&file, marker),
+        None => (&file[..], None),
+    };
+    // Bytes that are not valid UTF-8 can only be comments, so replacing them
+    // changes nothing
+    let code = String::from_utf8_lossy(code);
+    let (directives, source, mut warnings) = Directives::extract(&code);
+    let source = dialect.to_brainfuck(&source)?;
+    let (program, parse_warnings) = AST::parse_with_options(&source, parse_options(args))?;
+    warnings.extend(parse_warnings);
+
+    Ok(Loaded {
+        source,
+        program,
+        directives,
+        warnings,
+        input: input.map(|input| input.to_vec()),
+    })
+}
+
+/// How the command line asks for the program to be parsed.
+fn parse_options(args: &Args) -> ParseOptions {
+    ParseOptions {
+        max_depth: args.flag_max_depth.unwrap_or(usize::MAX),
+        debug_ext: args.flag_debug_ext,
+        strict: args.flag_strict,
+        arithmetic: cell_arithmetic(args),
+        // Nothing on the command line stops at them
+        breakpoints: false,
+        peephole: true,
+        snippet: false,
+    }
+}
+
+/// Runs optimization passes, checking each one with the verifier if there
+/// is one, until the budget runs out.
+///
+/// Only the passes after parsing are counted against the budget. The ones
+/// the parser runs on each loop as it closes take time in proportion to the
+/// source, and the program would be far slower without them.
+struct Passes<'a> {
+    verifier: Option<&'a Verifier>,
+    /// When passes stop being run, if ever.
+    deadline: Option<Instant>,
+    /// Whether a pass was skipped yet, so the warning is only given once.
+    skipped: bool,
+}
+
+impl<'a> Passes<'a> {
+    /// Passes as the command line sets them up, for a run that started at
+    /// `start`.
+    fn new(args: &Args, verifier: Option<&'a Verifier>, start: Instant) -> Self {
+        Passes {
+            verifier,
+            deadline: args
+                .flag_opt_budget
+                .map(|budget| start + Duration::from_millis(budget)),
+            skipped: false,
+        }
+    }
+
+    /// Apply `pass` to `program`, first making sure it keeps the output the
+    /// same if there is a verifier. Returns `None` if the budget ran out, in
+    /// which case the pass is skipped.
+    fn run<R, F: FnOnce(&mut AST) -> R>(
+        &mut self,
+        name: &str,
+        program: &mut AST,
+        pass: F,
+    ) -> Option<R> {
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            if !self.skipped {
+                let warning = Warning::BudgetExceeded {
+                    pass: name.to_owned(),
+                };
+                eprintln!("Warning: {}", warning);
+                self.skipped = true;
+            }
+
+            return None;
+        }
+
+        Some(match self.verifier {
+            Some(verifier) => verifier.run_pass(name, program, pass).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(1)
+            }),
+            None => pass(program),
+        })
+    }
+}
+
+/// Run the optimization passes the command line allows on `program`, where
+/// `inspected` says the run will be looked at step by step and `tape_size`
+/// is the size of the tape it runs on, if fixed.
+///
+/// Folding discards the final tape and the steps taken, which --dump-tape,
+/// --trace, --watch, --profile-cells, --max-steps and --diff inspect, and
+/// leaves --check and --emit-callgraph nothing to compile. It also runs the
+/// program from cell 0 of a tape that grows to the right, so the pointer
+/// never faults. Both literal passes assume cells wrap, that . prints one
+/// cell, and that only going left can run off the tape. They would also
+/// change how many steps --max-steps counts, so only the parser's own passes
+/// run under it.
+fn optimize(
+    program: &mut AST,
+    args: &Args,
+    passes: &mut Passes,
+    inspected: bool,
+    tape_size: Option<usize>,
+) {
+    let foldable = !inspected
+        && unbounded_tape(args, tape_size)
+        && args.flag_start_cell == 0
+        && args.flag_core_on_fault.is_none();
+
+    if cell_arithmetic(args) == CellArithmetic::Wrap
+        && args.flag_print_width.is_none()
+        && unbounded_tape(args, tape_size)
+        && args.flag_max_steps.is_none()
+        && (!foldable
+            || passes.run("fold_static_output", program, |program| {
+                program.fold_static_output(STEP_LIMIT)
+            }) == Some(false))
+    {
+        passes.run("fuse_print_literals", program, AST::fuse_print_literals);
+    }
+    // Ranges are set and known multiplies done in one step, which the same
+    // options would see through
+    if !inspected {
+        passes.run("fold_known_multiplies", program, AST::fold_known_multiplies);
+        // Folding can leave arithmetic for the parser's passes to merge
+        passes.run("optimize", program, |program| {
+            program.optimize(cell_arithmetic(args))
+        });
+        passes.run("fuse_set_ranges", program, AST::fuse_set_ranges);
+    }
+}
+
+/// Split `file` at the first line equal to `marker` into the code before it
+/// and the input after it. Without the marker the whole file is code.
+fn split_program_input<'a>(file: &'a [u8], marker: &str) -> (&'a [u8], Option<&'a [u8]>) {
+    let mut start = 0;
+
+    for line in file.split_inclusive(|&byte| byte == b'\n') {
+        let end = line
+            .iter()
+            .rposition(|&byte| byte != b'\r' && byte != b'\n')
+            .map_or(0, |last| last + 1);
+        if &line[..end] == marker.as_bytes() {
+            return (&file[..start], Some(&file[start + line.len()..]));
+        }
+        start += line.len();
+    }
+
+    (file, None)
+}
+
+/// JIT compile all of `program`, deferred loops included, without running it.
+fn check(program: &AST, options: JITOptions) -> Result<(), BfError> {
+    JITTarget::new(&program.data, options, None)?.precompile()
+}
+
+/// The define runtime/bf_runtime.c needs to handle end of input as `eof`
+/// does, if any.
+fn runtime_eof(eof: EofMode) -> Option<String> {
+    match eof {
+        EofMode::NegativeOne => None,
+        EofMode::Zero => Some("-DBF_EOF=0".to_owned()),
+        EofMode::Unchanged => Some("-DBF_EOF_UNCHANGED".to_owned()),
+        EofMode::Value(value) => Some(format!("-DBF_EOF={}", value)),
+    }
+}
+/// The config file to read, if any, and whether it has to exist. The
+/// implicit .fuckerrc is not read under --deterministic, as it is neither
+/// the source nor the input.
+fn config_file(args: &Args) -> Option<(String, bool)> {
+    match args.flag_config {
+        Some(ref path) => Some((path.clone(), true)),
+        None if args.flag_deterministic => None,
+        None => Some((CONFIG_FILE.to_owned(), false)),
+    }
+}
+
+/// Fill in the options the command line left out from a config file of
+/// `key = value` lines, keyed by long option name such as `tape-size`.
+/// Blank lines and lines starting with `#` are skipped, and values may be
+/// quoted.
+///
+/// Only options without a `[default: ...]` can be told apart from ones left
+/// out, so only those are read. `cache-cell` and `interruptible` take true
+/// or false, and `backend = interp` acts as --int does. Returns a warning for
+/// each line that was not understood.
+fn apply_config(args: &mut Args, config: &str) -> Vec<String> {
+    /// Use `value` for `option` unless the command line gave one.
+    fn fill<T: FromStr>(option: &mut Option<T>, value: &str) -> bool {
+        match value.parse() {
+            Ok(value) => {
+                option.get_or_insert(value);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Turn `flag` on if `value` is true.
+    fn enable(flag: &mut bool, value: &str) -> bool {
+        match value.parse::<bool>() {
+            Ok(value) => {
+                *flag |= value;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    let mut warnings = Vec::new();
+
+    for (index, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => {
+                warnings.push(format!("line {}: expected key = value", index + 1));
+                continue;
+            }
+        };
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+
+        let valid = match key {
+            "tape-size" => fill(&mut args.flag_tape_size, value),
+            "eof" => fill(&mut args.flag_eof, value),
+            "eof-value" => fill(&mut args.flag_eof_value, value),
+            "pointer-mode" => fill(&mut args.flag_pointer_mode, value),
+            "max-output" => fill(&mut args.flag_max_output, value),
+            "max-steps" => fill(&mut args.flag_max_steps, value),
+            "max-depth" => fill(&mut args.flag_max_depth, value),
+            "flush" => fill(&mut args.flag_flush, value),
+            "cache-cell" => enable(&mut args.flag_cache_cell, value),
+            "interruptible" => enable(&mut args.flag_interruptible, value),
+            "backend" => match value {
+                "interp" => {
+                    args.flag_int = true;
+                    true
+                }
+                "jit" => true,
+                _ => false,
+            },
+            _ => {
+                warnings.push(format!("line {}: unknown key {}", index + 1, key));
+                continue;
+            }
+        };
+
+        if !valid {
+            warnings.push(format!(
+                "line {}: invalid value {} for {}",
+                index + 1,
+                value,
+                key
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// The tape size asked for, if any. Command line flags win over the
+/// program's own directives.
+fn tape_size(args: &Args, directives: &Directives) -> Option<usize> {
+    args.flag_tape_size.or(directives.tape_size)
+}
+
+/// Whether the tape grows to the right as far as the program goes, so that
+/// no pointer move can end the run.
+fn unbounded_tape(args: &Args, tape_size: Option<usize>) -> bool {
+    tape_size.is_none()
+        && args
+            .flag_pointer_mode
+            .as_deref()
+            .is_none_or(|mode| mode == "grow")
+}
+
+/// Whether the program has to run in the interpreter, because of the flags,
+/// FUCKER_BACKEND or a tape too small for compiled code to run on safely.
+fn use_interpreter(args: &Args, tape_size: Option<usize>) -> Result<bool, String> {
+    Ok(args.flag_int
+        || args.flag_trace
+        || !args.flag_watch.is_empty()
+        || args.flag_profile_cells
+        || args.flag_max_steps.is_some()
+        || args.flag_print_width.is_some()
+        || cell_arithmetic(args) == CellArithmetic::Error
+        || args.flag_pointer_mode.as_deref() == Some("two-sided")
+        || tape_size.is_some_and(|size| size < TAPE_SIZE)
+        || interpreter_by_default(env::var("FUCKER_BACKEND").ok())?)
+}
+
+/// Refuse flags the chosen backend would otherwise silently ignore.
+fn check_backend(args: &Args, interpreted: bool) -> Result<(), String> {
+    if args.flag_jit_step && interpreted {
+        Err("--jit-step needs the JIT".to_owned())
+    } else if args.flag_core_on_fault.is_some() && !interpreted {
+        Err("--core-on-fault needs the interpreter (--int)".to_owned())
+    } else if args.flag_pointer_mode.is_some() && !interpreted {
+        Err("--pointer-mode needs the interpreter (--int)".to_owned())
+    } else if args.flag_dump_tape && !interpreted {
+        Err("--dump-tape needs the interpreter (--int)".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether `backend`, the value of FUCKER_BACKEND, asks for the interpreter
+/// when no flag does.
+fn interpreter_by_default(backend: Option<String>) -> Result<bool, String> {
+    match backend {
+        Some(ref backend) if backend == "interp" => Ok(true),
+        Some(ref backend) if backend == "jit" => Ok(false),
+        Some(backend) => Err(format!("Unknown FUCKER_BACKEND: {}", backend)),
+        None => Ok(false),
+    }
+}
+
+/// Choose the EOF mode from --eof-value, --eof or the program's directives,
+/// in that order, falling back to the default unless under --deterministic.
+fn eof_mode(args: &Args, directives: &Directives) -> Result<EofMode, String> {
+    match (&args.flag_eof_value, &args.flag_eof) {
+        (Some(value), _) => value
+            .parse()
+            .map(EofMode::Value)
+            .map_err(|_| format!("EOF value must be between 0 and 255: {}", value)),
+        (None, Some(eof)) => eof.parse(),
+        (None, None) => match directives.eof {
+            Some(eof) => Ok(eof),
+            // Defaults can change between versions
+            None if args.flag_deterministic => Err(
+                "--deterministic needs the EOF mode from --eof, --eof-value or an eof directive"
+                    .to_owned(),
+            ),
+            None => Ok(EofMode::default()),
+        },
+    }
+}
+
+/// The cell arithmetic asked for on the command line.
+fn cell_arithmetic(args: &Args) -> CellArithmetic {
+    args.flag_cell_arithmetic.parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    })
+}
+
+/// Flush on newlines when a person may be watching the output as it comes.
+fn flush_policy(args: &Args) -> FlushPolicy {
+    match args.flag_flush {
+        Some(ref policy) => policy.parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1)
+        }),
+        None if args.flag_output.is_none() && unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 => {
+            FlushPolicy::Line
+        }
+        None => FlushPolicy::Full,
+    }
+}
+
+/// Set up the interpreter according to the command line.
+fn interpreter(program: &AST, args: &Args, eof: EofMode, tape_size: Option<usize>) -> Fucker {
+    configure_interpreter(Fucker::new(&program.data), args, eof, tape_size)
+}
+
+/// Apply the command line to an interpreter with any kind of tape.
+fn configure_interpreter<T: Tape>(
+    mut fucker: Fucker<T>,
+    args: &Args,
+    eof: EofMode,
+    tape_size: Option<usize>,
+) -> Fucker<T> {
+    if let Some(size) = tape_size {
+        fucker.set_tape_size(size).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1)
+        });
+        fucker.set_pointer_mode(PointerMode::Halt);
+    }
+    if let Some(ref mode) = args.flag_pointer_mode {
+        fucker.set_pointer_mode(mode.parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1)
+        }));
+    }
+    // Ctrl-C stops the interpreter cleanly, the JIT keeps the default
+    // behavior of killing the process unless built with --interruptible.
+    fucker.set_cancel_token(&install_sigint_handler());
+    fucker.set_dump_tape(args.flag_dump_tape);
+    if let Some(ref path) = args.flag_core_on_fault {
+        fucker.set_core_on_fault(PathBuf::from(path));
+    }
+    if args.flag_trace {
+        fucker.set_trace(Box::new(stderr()));
+    }
+    for &cell in &args.flag_watch {
+        fucker.add_watch(cell);
+    }
+    fucker.set_profile_cells(args.flag_profile_cells);
+    fucker.set_max_steps(args.flag_max_steps);
+    fucker
+        .set_start_cell(args.flag_start_cell)
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1)
+        });
+    fucker.set_eof_mode(eof);
+    fucker.set_cell_arithmetic(cell_arithmetic(args));
+    fucker.set_print_width(args.flag_print_width.unwrap_or(1));
+    fucker.fuse_moves();
+
+    fucker
 }
 
 /// Read a BrainFuck program's source code.
 ///
 /// When path is "-" this will read from stdin.
-fn read_program(path: &str) -> Result<String, String> {
-    let mut buffer: String = String::new();
+///
+/// The source is read as raw bytes, since files may contain comments in any
+/// encoding. With `extract`, only what lies between each pair of delimiters
+/// is kept.
+fn read_program(path: &str, extract: Option<(&str, &str)>) -> Result<Vec<u8>, BfError> {
+    let tty = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    if let Some(hint) = program_hint(path, tty) {
+        eprintln!("{}", hint);
+    }
+
+    let mut buffer = Vec::new();
     let mut source: Box<dyn Read> = {
         if path == "-" {
             Box::new(stdin())
         } else {
-            Box::new(File::open(path).map_err(|e| format!("Could not open file: {:?}", e))?)
+            Box::new(File::open(path).map_err(BfError::Open)?)
         }
     };
 
-    source
-        .read_to_string(&mut buffer)
-        .map_err(|e| format!("Could not read file: {:?}", e))?;
+    source.read_to_end(&mut buffer)?;
 
-    Ok(buffer)
+    match extract {
+        Some((start, end)) => extract_between(&buffer, start, end),
+        None => Ok(buffer),
+    }
+}
+
+/// Join every region of `file` that lies between `start` and the next `end`,
+/// one per line.
+fn extract_between(file: &[u8], start: &str, end: &str) -> Result<Vec<u8>, BfError> {
+    let find = |from: usize, needle: &str| {
+        file[from..]
+            .windows(needle.len().max(1))
+            .position(|window| window == needle.as_bytes())
+            .map(|pos| from + pos)
+    };
+
+    let mut code = Vec::new();
+    let mut found = false;
+    let mut pos = 0;
+    while let Some(open) = find(pos, start) {
+        let begin = open + start.len();
+        let close = match find(begin, end) {
+            Some(close) => close,
+            None => break,
+        };
+        code.extend_from_slice(&file[begin..close]);
+        code.push(b'\n');
+        found = true;
+        pos = close + end.len();
+    }
+
+    if found {
+        Ok(code)
+    } else {
+        Err(BfError::NothingExtracted {
+            start: start.to_owned(),
+            end: end.to_owned(),
+        })
+    }
+}
+
+/// What to tell someone typing the program in by hand, who may not realize
+/// it is being read from stdin.
+fn program_hint(path: &str, stdin_is_tty: bool) -> Option<&'static str> {
+    if path == "-" && stdin_is_tty {
+        Some("Reading the program from stdin, press Ctrl-D when done.")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+
+    use fucker::parser::ASTNode;
+    use fucker::runnable::Io;
+
+    use super::*;
+
+    #[test]
+    fn backend_from_env() {
+        let backend = |value: &str| interpreter_by_default(Some(value.to_owned()));
+
+        assert_eq!(backend("interp"), Ok(true));
+        assert_eq!(backend("jit"), Ok(false));
+        assert_eq!(
+            backend("wasm"),
+            Err("Unknown FUCKER_BACKEND: wasm".to_owned())
+        );
+        assert_eq!(interpreter_by_default(None), Ok(false));
+    }
+
+    /// Parse a command line, with the program written to a temporary file.
+    fn args_for<S: AsRef<[u8]>>(name: &str, source: S, flags: &[&str]) -> Args {
+        let path = env::temp_dir().join(name);
+        fs::write(&path, source).unwrap();
+
+        let mut argv = vec!["fucker".to_owned()];
+        argv.extend(flags.iter().map(|flag| flag.to_string()));
+        argv.push(path.to_string_lossy().into_owned());
+
+        Docopt::new(USAGE)
+            .and_then(|d| d.argv(argv).deserialize())
+            .unwrap()
+    }
+
+    #[test]
+    fn config_defaults() {
+        let config =
+            "# defaults\ntape-size = 100\neof = \"zero\"\n\ncolour = on\nmax-depth = deep\n";
+
+        let mut args = args_for("fucker-config.bf", "+.", &[]);
+        let warnings = apply_config(&mut args, config);
+        assert_eq!(args.flag_tape_size, Some(100));
+        assert_eq!(args.flag_eof.as_deref(), Some("zero"));
+        assert_eq!(
+            warnings,
+            vec![
+                "line 5: unknown key colour".to_owned(),
+                "line 6: invalid value deep for max-depth".to_owned(),
+            ]
+        );
+
+        // The command line wins
+        let mut args = args_for("fucker-config.bf", "+.", &["--tape-size", "5"]);
+        apply_config(&mut args, "tape-size = 100\nbackend = interp");
+        assert_eq!(args.flag_tape_size, Some(5));
+        assert!(args.flag_int);
+
+        // A small tape from the config is kept away from compiled code too
+        let mut args = args_for("fucker-config.bf", "+.", &[]);
+        apply_config(&mut args, "tape-size = 100");
+        let tape_size = tape_size(&args, &Directives::default());
+        assert_eq!(use_interpreter(&args, tape_size), Ok(true));
+    }
+
+    #[test]
+    fn config_file_deterministic() {
+        let args = args_for("fucker-config.bf", "+.", &[]);
+        assert_eq!(config_file(&args), Some((CONFIG_FILE.to_owned(), false)));
+
+        // Only a config given on the command line is read
+        let args = args_for("fucker-config.bf", "+.", &["--deterministic", "-o", "out"]);
+        assert_eq!(config_file(&args), None);
+        let args = args_for(
+            "fucker-config.bf",
+            "+.",
+            &["--deterministic", "-o", "out", "--config", "my.rc"],
+        );
+        assert_eq!(config_file(&args), Some(("my.rc".to_owned(), true)));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn check_program() {
+        let args = args_for("fucker-check-ok.bf", "+[>+<-]>.", &["--check"]);
+        assert!(args.flag_check);
+        let program = load_program(&args, Dialect::BrainFuck).unwrap().program;
+        assert!(check(&program, JITOptions::default()).is_ok());
+
+        let args = args_for("fucker-check-bad.bf", "+[>+<-", &["--check"]);
+        assert!(matches!(
+            load_program(&args, Dialect::BrainFuck),
+            Err(BfError::UnmatchedOpen { line: 1, col: 2 })
+        ));
+    }
+
+    #[test]
+    fn extract_markdown_fences() {
+        let source = "# Demo\n\nPrints A:\n\n```bf\n++++++++[>++++++++<-]>+.\n```\n\nThe end.\n";
+        let args = args_for("fucker-extract.md", source, &["--int", "--extract", "```"]);
+        let loaded = load_program(&args, Dialect::BrainFuck).unwrap();
+        let eof = eof_mode(&args, &loaded.directives).unwrap();
+        let mut fucker = interpreter(&loaded.program, &args, eof, None);
+        let mut output = Vec::new();
+        fucker.run_with_io(&mut io::empty(), &mut output);
+        assert_eq!(output, b"A");
+
+        let args = args_for("fucker-extract.md", source, &["--extract", "<bf>"]);
+        assert!(matches!(
+            load_program(&args, Dialect::BrainFuck),
+            Err(BfError::NothingExtracted { .. })
+        ));
+    }
+
+    #[test]
+    fn print_width() {
+        // Cells 1 2 3 4, printed from cell 0 and then from cell 2
+        let source = "+>++>+++>++++<<<.>>.";
+        let args = args_for("fucker-print-width.bf", source, &["--print-width", "2"]);
+        let loaded = load_program(&args, Dialect::BrainFuck).unwrap();
+        let mut fucker = interpreter(&loaded.program, &args, EofMode::Zero, None);
+        let mut output = Vec::new();
+        fucker.run_with_io(&mut io::empty(), &mut output);
+
+        assert_eq!(output, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn repeat() {
+        // Prints the digits 1 to 3, then counts up the cell after the input
+        let source = "++++++++[>++++++<-]>+.+.+.,[>+<-]>.";
+        let args = args_for("fucker-repeat.bf", source, &["--int", "--repeat", "3"]);
+        let program = load_program(&args, Dialect::BrainFuck).unwrap().program;
+        let mut fucker = interpreter(&program, &args, EofMode::Zero, None);
+
+        let mut output = Vec::new();
+        let times = run_repeated(
+            &mut fucker,
+            &mut &b"A"[..],
+            &mut output,
+            args.flag_repeat.unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(times.len(), 3);
+        assert_eq!(output, b"123A123A123A");
+        assert!(timing_report(&times).starts_with("3 runs in "));
+    }
+
+    #[test]
+    fn bounded_tape_passes() {
+        let run = |source: &str, flags: &[&str]| {
+            let args = args_for("fucker-bounded.bf", source, flags);
+            let tape_size = args.flag_tape_size;
+            let mut program = load_program(&args, Dialect::BrainFuck).unwrap().program;
+            let mut passes = Passes::new(&args, None, Instant::now());
+            optimize(&mut program, &args, &mut passes, false, tape_size);
+
+            let mut fucker = interpreter(&program, &args, EofMode::Zero, tape_size);
+            let (mut output, mut diagnostics) = (Vec::new(), Vec::new());
+            fucker.run_with_diagnostics(&mut io::empty(), &mut output, &mut diagnostics);
+            (output, diagnostics.is_empty())
+        };
+
+        let wrap = ["--int", "--tape-size", "3", "--pointer-mode", "wrap"];
+        assert_eq!(run("+>>>.", &wrap), (vec![1], true));
+        assert_eq!(run("+>>>.", &["--tape-size", "3"]), (vec![], false));
+        assert_eq!(run("+>>>.", &[]), (vec![0], true));
+
+        // Literals are not fused past moves that could leave the tape
+        assert_eq!(run(",>+.>>>>>>+.", &["--tape-size", "3"]), (vec![1], false));
+    }
+
+    #[test]
+    fn opt_budget() {
+        let run = |budget: &str| {
+            let source = "++++++++[>++++++++<-]>+.>[-]>[-]>[-]>[-]<<<<.";
+            let args = args_for("fucker-opt-budget.bf", source, &["--opt-budget", budget]);
+            let mut program = load_program(&args, Dialect::BrainFuck).unwrap().program;
+            let mut passes = Passes::new(&args, None, Instant::now());
+            optimize(&mut program, &args, &mut passes, false, None);
+
+            let mut fucker = interpreter(&program, &args, EofMode::Zero, None);
+            let mut output = Vec::new();
+            fucker.run_with_io(&mut io::empty(), &mut output);
+            (program, passes.skipped, output)
+        };
+
+        let (optimized, skipped, expected) = run("60000");
+        assert!(!skipped);
+        assert!(matches!(optimized.data[0], ASTNode::PrintLiteral(_)));
+
+        // Every pass after parsing skipped. The parser's own passes still
+        // turned the loops into MulAdd and Set, but nothing was folded.
+        let (unoptimized, skipped, output) = run("0");
+        assert!(skipped);
+        assert!(unoptimized.data.len() > optimized.data.len());
+        assert!(unoptimized.data.contains(&ASTNode::MulAdd(1, 8)));
+        assert!(!unoptimized
+            .data
+            .iter()
+            .any(|node| matches!(node, ASTNode::PrintLiteral(_) | ASTNode::Loop(_))));
+        assert_eq!(output, expected);
+        assert_eq!(output, b"AA");
+    }
+
+    #[test]
+    fn non_utf8_comments() {
+        let args = args_for("fucker-latin1.bf", b"+++ caf\xe9 \xff\xfe\n.", &[]);
+        let loaded = load_program(&args, Dialect::BrainFuck).unwrap();
+
+        assert_eq!(loaded.program.data, AST::parse("+++.").unwrap().data);
+    }
+
+    #[test]
+    fn tape_directive() {
+        let source = ";tape=64;eof=zero\n+[>+]";
+        let args = args_for("fucker-directive.bf", source, &[]);
+        let loaded = load_program(&args, Dialect::BrainFuck).unwrap();
+        let (program, directives) = (loaded.program, loaded.directives);
+        assert!(loaded.warnings.is_empty());
+        assert_eq!(eof_mode(&args, &directives), Ok(EofMode::Zero));
+
+        // Too small a tape for compiled code, so the interpreter halts
+        // rather than the program writing past it
+        let tape_size = tape_size(&args, &directives);
+        assert_eq!(tape_size, Some(64));
+        assert_eq!(use_interpreter(&args, tape_size), Ok(true));
+        let mut fucker = interpreter(&program, &args, EofMode::Zero, tape_size);
+        let (mut input, mut output) = (io::empty(), io::sink());
+        while fucker.step(&mut Io::new(&mut input, &mut output)) {}
+        assert_eq!(fucker.tape(), &[1u8; 64][..]);
+
+        // Flags win over directives
+        let args = args_for(
+            "fucker-directive.bf",
+            source,
+            &["--tape-size", "128", "--eof", "unchanged"],
+        );
+        assert_eq!(super::tape_size(&args, &directives), Some(128));
+        assert_eq!(eof_mode(&args, &directives), Ok(EofMode::Unchanged));
+    }
+
+    #[test]
+    fn emit_bf_round_trip() {
+        let run = |args: &Args| {
+            let loaded = load_program(args, Dialect::BrainFuck).unwrap();
+            let eof = eof_mode(args, &loaded.directives).unwrap();
+            let mut fucker = interpreter(&loaded.program, args, eof, loaded.directives.tape_size);
+            let mut output = Vec::new();
+            fucker.run_with_io(&mut &b"hi"[..], &mut output);
+            output
+        };
+
+        let source = ";eof=zero\n+-><++[->+++<]>[-]<,[>++<-+.,]>.";
+        let args = args_for("fucker-emit-bf.bf", source, &["--emit-bf"]);
+        let loaded = load_program(&args, Dialect::BrainFuck).unwrap();
+        let emitted = format!("{}{}", loaded.directives, loaded.program);
+        assert!(emitted.len() < source.len());
+
+        let emitted_args = args_for("fucker-emitted.bf", &emitted, &[]);
+        assert_eq!(run(&emitted_args), run(&args));
+        assert_eq!(run(&args), b"hi\x04");
+    }
+
+    #[test]
+    fn input_hex() {
+        let args: Args = Docopt::new(USAGE)
+            .and_then(|d| {
+                d.argv(vec!["fucker", "--input-hex", "41420A", "-"])
+                    .deserialize()
+            })
+            .unwrap();
+        let program = AST::parse(",.,.,.").unwrap();
+        let mut output = Vec::new();
+
+        Fucker::new(&program.data)
+            .run_with_io(&mut program_input(&args, None).unwrap(), &mut output);
+
+        assert_eq!(output, b"AB\n");
+    }
+
+    #[test]
+    fn max_steps() {
+        let args = args_for("fucker-max-steps.bf", "+.+.+.", &["--max-steps", "3"]);
+        assert_eq!(use_interpreter(&args, None), Ok(true));
+
+        let loaded = load_program(&args, Dialect::BrainFuck).unwrap();
+        let mut fucker = interpreter(&loaded.program, &args, EofMode::default(), None);
+        let (mut output, mut diagnostics) = (Vec::new(), Vec::new());
+        fucker.run_with_diagnostics(&mut io::empty(), &mut output, &mut diagnostics);
+
+        assert_eq!(output, [1]);
+        assert_eq!(fucker.exit_reason(), ExitReason::Limit);
+    }
+
+    #[test]
+    fn backend_only_flags() {
+        let args = args_for("fucker-core.bf", "+[>+]", &["--core-on-fault", "core"]);
+        assert!(check_backend(&args, false).is_err());
+        assert!(check_backend(&args, true).is_ok());
+
+        let args = args_for("fucker-core.bf", "+[>+]", &["--jit-step"]);
+        assert!(check_backend(&args, true).is_err());
+        assert!(check_backend(&args, false).is_ok());
+
+        for flags in [&["--pointer-mode", "wrap"][..], &["--dump-tape"]] {
+            let args = args_for("fucker-core.bf", "+[>+]", flags);
+            assert!(check_backend(&args, false).is_err());
+            assert!(check_backend(&args, true).is_ok());
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let flags = ["--deterministic", "-o", "/dev/null"];
+        let args = args_for("fucker-deterministic.bf", ",.", &flags);
+        assert!(check_deterministic(&args).is_ok());
+        assert_eq!(
+            program_input(&args, None).err(),
+            Some(
+                "--deterministic needs input from --input, --input-hex or --program-input-sep"
+                    .to_owned()
+            )
+        );
+        assert!(program_input(&args, Some(b"a".to_vec())).is_ok());
+
+        // The EOF mode is stated rather than left to the default
+        let directives = Directives::default();
+        assert!(eof_mode(&args, &directives).is_err());
+        let (pinned, _, _) = Directives::extract(";eof=zero\n,.");
+        assert_eq!(eof_mode(&args, &pinned), Ok(EofMode::Zero));
+        let flags = ["--deterministic", "-o", "/dev/null", "--eof", "unchanged"];
+        let args = args_for("fucker-deterministic.bf", ",.", &flags);
+        assert_eq!(eof_mode(&args, &directives), Ok(EofMode::Unchanged));
+
+        let args = args_for("fucker-deterministic.bf", ",.", &["--deterministic"]);
+        assert_eq!(
+            check_deterministic(&args),
+            Err("--deterministic needs an output file from -o".to_owned())
+        );
+    }
+
+    #[test]
+    fn deterministic_terminal_flags() {
+        for flag in &[
+            "--emit-bf",
+            "--emit-min",
+            "--debug",
+            "--stats",
+            "--emit-callgraph",
+        ] {
+            let flags = ["--deterministic", "-o", "/dev/null", flag];
+            let args = args_for("fucker-deterministic.bf", ",.", &flags);
+            assert_eq!(
+                check_deterministic(&args),
+                Err(format!(
+                    "--deterministic cannot use {}, which prints to stdout",
+                    flag
+                ))
+            );
+        }
+
+        let flags = ["advise", "--deterministic", "-o", "/dev/null"];
+        let args = args_for("fucker-deterministic.bf", ",.", &flags);
+        assert_eq!(
+            check_deterministic(&args),
+            Err("--deterministic cannot use advise, which prints to stdout".to_owned())
+        );
+
+        let flags = ["--deterministic", "-o", "/dev/null", "--jit-step"];
+        let args = args_for("fucker-deterministic.bf", ",.", &flags);
+        assert_eq!(
+            check_deterministic(&args),
+            Err("--deterministic cannot use --jit-step, which reads the terminal".to_owned())
+        );
+    }
+
+    #[test]
+    fn program_input_sep() {
+        let flags = ["--program-input-sep", "---"];
+        let args = args_for("fucker-input-sep.bf", ",.,.,.\n---\nab\n---\n", &flags);
+        let loaded = load_program(&args, Dialect::BrainFuck).unwrap();
+        assert_eq!(loaded.input, Some(b"ab\n---\n".to_vec()));
+
+        let mut output = Vec::new();
+        Fucker::new(&loaded.program.data).run_with_io(
+            &mut program_input(&args, loaded.input).unwrap(),
+            &mut output,
+        );
+        assert_eq!(output, b"ab\n");
+
+        // Only a whole line counts as the marker
+        assert_eq!(
+            split_program_input(b"+.---\n", "---"),
+            (&b"+.---\n"[..], None)
+        );
+        assert_eq!(
+            split_program_input(b"+.\r\n---\r\n", "---"),
+            (&b"+.\r\n"[..], Some(&b""[..]))
+        );
+    }
+
+    #[test]
+    fn stdin_hint() {
+        assert!(program_hint("-", true).is_some());
+        assert_eq!(program_hint("-", false), None);
+        assert_eq!(program_hint("hello.bf", true), None);
+    }
+
+    #[test]
+    fn input_hex_errors() {
+        assert_eq!(decode_hex("00ff7F"), Ok(vec![0x00, 0xff, 0x7f]));
+        assert!(decode_hex("414").is_err());
+        assert!(decode_hex("4G").is_err());
+    }
 }
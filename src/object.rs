@@ -0,0 +1,523 @@
+//! Writing a compiled program out as an object file, to be linked into a
+//! native executable.
+//!
+//! The machine code is what the JIT generates, except that every loop is
+//! compiled inline, so nothing is left to compile while running. It is
+//! called through one entry point:
+//!
+//! ```text
+//! unsigned char *bf_main(unsigned char *tape, void *context);
+//! ```
+//!
+//! which returns the data pointer once the program ends. Output and input go
+//! through `bf_print`, `bf_print_repeat`, `bf_print_literal`, `bf_read` and
+//! `bf_debug_print`, each taking `context` first, which the object leaves for
+//! the linker to find. `runtime/bf_runtime.c` defines them on top of stdio,
+//! along with a `main` that gives the program a tape of 30000 cells:
+//!
+//! ```text
+//! fucker --emit-object hello.o hello.bf
+//! cc -no-pie -o hello hello.o runtime/bf_runtime.c
+//! ```
+//!
+//! The code loads every address whole, so it cannot be position independent
+//! and has to be linked with `-no-pie`. Only x86-64 ELF objects are written.
+
+use std::collections::VecDeque;
+
+use code_gen;
+use error::BfError;
+use parser::{ASTNode, AST};
+use runnable::{CellArithmetic, JITContext, JITOptions};
+
+/// Name of the function that runs the program.
+pub const ENTRY_SYMBOL: &str = "bf_main";
+
+/// Opcodes of `movabs rax,imm64` and `movabs rsi,imm64`, which load every
+/// address the generated code uses.
+const MOV_RAX: [u8; 2] = [0x48, 0xb8];
+const MOV_RSI: [u8; 2] = [0x48, 0xbe];
+
+/// Length of the `cmp` and `je` that `code_gen::aot_loop` puts before a
+/// loop's body.
+const LOOP_TEST: usize = 10;
+
+// Stand-ins for the runtime's functions, so that code generation has an
+// address to embed. Every embedded address gets a relocation instead.
+extern "C" fn unlinked_print(_: &mut JITContext, _: u8) {}
+extern "C" fn unlinked_print_repeat(_: &mut JITContext, _: u8, _: usize) {}
+extern "C" fn unlinked_print_literal(_: &mut JITContext, _: *const u8, _: usize) {}
+extern "C" fn unlinked_read(_: &mut JITContext, current: u8) -> u8 {
+    current
+}
+
+/// What an address in the code has to be filled in with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    /// A function of the runtime, by name.
+    Runtime(&'static str),
+    /// A literal, at this offset into .rodata.
+    Literal(usize),
+}
+
+/// An 8 byte address in the code to be filled in by the linker.
+#[derive(Debug, Clone, Copy)]
+struct Relocation {
+    offset: usize,
+    target: Target,
+}
+
+/// Machine code along with the addresses in it still to be filled in.
+#[derive(Default)]
+struct Code {
+    bytes: Vec<u8>,
+    relocations: Vec<Relocation>,
+}
+
+impl Code {
+    /// Replace the address `address` loaded by the `movabs` with opcode
+    /// `opcode` at or after `start` by a relocation to `target`.
+    fn relocate(&mut self, start: usize, opcode: [u8; 2], address: *const (), target: Target) {
+        let mut pattern = opcode.to_vec();
+        pattern.extend(&(address as usize).to_ne_bytes());
+
+        let found = self.bytes[start..]
+            .windows(pattern.len())
+            .position(|window| window == &pattern[..])
+            .expect("code generation embeds the address it is given");
+        let offset = start + found + opcode.len();
+
+        self.bytes[offset..offset + 8].copy_from_slice(&[0; 8]);
+        self.relocations.push(Relocation { offset, target });
+    }
+
+    /// Append `other`, keeping its relocations.
+    fn extend(&mut self, other: Code) {
+        let base = self.bytes.len();
+        self.bytes.extend(other.bytes);
+        self.relocations
+            .extend(other.relocations.into_iter().map(|relocation| Relocation {
+                offset: base + relocation.offset,
+                ..relocation
+            }));
+    }
+}
+
+/// Compile `program` to an ELF relocatable object defining `ENTRY_SYMBOL`.
+///
+/// Code is generated with `options.arithmetic`. The end of input is up to
+/// the runtime, and the code is never interruptible, so the other options
+/// are ignored.
+pub fn emit_object(program: &AST, options: JITOptions) -> Result<Vec<u8>, BfError> {
+    match options.arithmetic {
+        CellArithmetic::Error => return Err(BfError::ArithmeticUnsupported(options.arithmetic)),
+        CellArithmetic::Wrap | CellArithmetic::Saturate => {}
+    }
+    program.validate()?;
+
+    let mut rodata = Vec::new();
+    let body = compile(&program.data, options.arithmetic, &mut rodata);
+
+    // bf_main takes the context second, where the wrapper expects it fourth
+    // mov    rcx,rsi
+    let mut text = Code {
+        bytes: vec![0x48, 0x89, 0xf1],
+        relocations: Vec::new(),
+    };
+    code_gen::wrapper_prologue(&mut text.bytes, false);
+    text.extend(body);
+    code_gen::wrapper_return(&mut text.bytes, false);
+
+    elf_object(&text, &rodata)
+}
+
+/// Compile `nodes` as `JITTarget::shallow_compile` does, but inlining every
+/// loop and leaving the cell in memory. Literals are added to `rodata`.
+fn compile(nodes: &VecDeque<ASTNode>, arithmetic: CellArithmetic, rodata: &mut Vec<u8>) -> Code {
+    let mut code = Code::default();
+
+    for node in nodes {
+        let start = code.bytes.len();
+        let bytes = &mut code.bytes;
+
+        match node {
+            ASTNode::Incr(n) => code_gen::incr(bytes, *n),
+            ASTNode::Decr(n) => code_gen::decr(bytes, *n),
+            ASTNode::Set(n) => code_gen::set(bytes, *n),
+            ASTNode::SetRange(len, n) => code_gen::set_range(bytes, *len, *n),
+            ASTNode::Next(n) => code_gen::next(bytes, *n),
+            ASTNode::Prev(n) => code_gen::prev(bytes, *n),
+            ASTNode::MulAdd(offset, factor) => code_gen::mul_add(bytes, *offset, *factor),
+            ASTNode::AddTo(offset, n) => code_gen::add_to(bytes, *offset, *n),
+            ASTNode::Print => {
+                code_gen::print(bytes, unlinked_print);
+                code.relocate(
+                    start,
+                    MOV_RAX,
+                    unlinked_print as *const (),
+                    Target::Runtime("bf_print"),
+                );
+            }
+            ASTNode::PrintRepeat(n) => {
+                code_gen::print_repeat(bytes, unlinked_print_repeat, *n);
+                let address = unlinked_print_repeat as *const ();
+                code.relocate(start, MOV_RAX, address, Target::Runtime("bf_print_repeat"));
+            }
+            ASTNode::PrintLiteral(literal) => {
+                code_gen::print_literal(bytes, unlinked_print_literal, literal);
+                let address = unlinked_print_literal as *const ();
+                code.relocate(start, MOV_RAX, address, Target::Runtime("bf_print_literal"));
+                let address = literal.as_ptr() as *const ();
+                code.relocate(start, MOV_RSI, address, Target::Literal(rodata.len()));
+                rodata.extend(literal);
+            }
+            ASTNode::Read => {
+                code_gen::read(bytes, unlinked_read);
+                code.relocate(
+                    start,
+                    MOV_RAX,
+                    unlinked_read as *const (),
+                    Target::Runtime("bf_read"),
+                );
+            }
+            ASTNode::DebugPrintValue => {
+                code_gen::print(bytes, unlinked_print);
+                let address = unlinked_print as *const ();
+                code.relocate(start, MOV_RAX, address, Target::Runtime("bf_debug_print"));
+            }
+            ASTNode::Breakpoint => {}
+            ASTNode::Loop(nodes) => {
+                let body = compile(nodes, arithmetic, rodata);
+                code_gen::aot_loop(bytes, body.bytes);
+                code.relocations
+                    .extend(body.relocations.into_iter().map(|relocation| Relocation {
+                        offset: start + LOOP_TEST + relocation.offset,
+                        ..relocation
+                    }));
+            }
+        }
+
+        if arithmetic == CellArithmetic::Saturate {
+            match node {
+                ASTNode::Incr(_) => code_gen::saturate(&mut code.bytes, 0xff),
+                ASTNode::Decr(_) => code_gen::saturate(&mut code.bytes, 0),
+                _ => {}
+            }
+        }
+    }
+
+    code
+}
+
+// The parts of the ELF format used below
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+const STB_GLOBAL: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const R_X86_64_64: u64 = 1;
+const HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const RELOCATION_SIZE: usize = 24;
+
+/// Section indices, in the order the sections are written.
+const TEXT: u16 = 1;
+const RODATA: u16 = 2;
+const SYMTAB: u32 = 4;
+const STRTAB: u32 = 5;
+
+/// Symbol indices. The runtime's functions come after these.
+const RODATA_SYMBOL: u64 = 2;
+const FIRST_GLOBAL: u32 = 3;
+
+/// A section and the header fields that differ between sections.
+struct Section {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    link: u32,
+    info: u32,
+    align: usize,
+    entry_size: u64,
+    data: Vec<u8>,
+}
+
+/// Add `name` to a string table, returning its offset.
+fn add_string(strings: &mut Vec<u8>, name: &str) -> u32 {
+    let offset = strings.len() as u32;
+    strings.extend(name.as_bytes());
+    strings.push(0);
+    offset
+}
+
+/// Add a symbol to a symbol table.
+fn add_symbol(symbols: &mut Vec<u8>, name: u32, info: u8, section: u16, size: u64) {
+    symbols.extend(&name.to_le_bytes());
+    symbols.push(info);
+    symbols.push(0);
+    symbols.extend(&section.to_le_bytes());
+    symbols.extend(&0u64.to_le_bytes());
+    symbols.extend(&size.to_le_bytes());
+}
+
+/// Pad `bytes` with zeros to a multiple of `align`.
+fn align(bytes: &mut Vec<u8>, align: usize) {
+    let len = bytes.len().div_ceil(align) * align;
+    bytes.resize(len, 0);
+}
+
+/// Lay out an x86-64 ELF relocatable object holding `text` as a global
+/// function at the start of .text, and `rodata` for the literals.
+fn elf_object(text: &Code, rodata: &[u8]) -> Result<Vec<u8>, BfError> {
+    // Only the runtime functions the code calls are left undefined
+    let mut runtime: Vec<&'static str> = Vec::new();
+    for relocation in &text.relocations {
+        if let Target::Runtime(name) = relocation.target {
+            if !runtime.contains(&name) {
+                runtime.push(name);
+            }
+        }
+    }
+
+    let mut strings = vec![0];
+    let mut symbols = vec![0; SYMBOL_SIZE];
+    add_symbol(&mut symbols, 0, STT_SECTION, TEXT, 0);
+    add_symbol(&mut symbols, 0, STT_SECTION, RODATA, 0);
+    let entry = add_string(&mut strings, ENTRY_SYMBOL);
+    let info = STB_GLOBAL << 4 | STT_FUNC;
+    add_symbol(&mut symbols, entry, info, TEXT, text.bytes.len() as u64);
+    for name in &runtime {
+        let name = add_string(&mut strings, name);
+        add_symbol(&mut symbols, name, STB_GLOBAL << 4, 0, 0);
+    }
+
+    let relocations = relocation_entries(&text.relocations, &runtime)?;
+
+    let section = |name, kind, flags, align, data| Section {
+        name,
+        kind,
+        flags,
+        link: 0,
+        info: 0,
+        align,
+        entry_size: 0,
+        data,
+    };
+    let mut sections = vec![
+        section(
+            ".text",
+            SHT_PROGBITS,
+            SHF_ALLOC | SHF_EXECINSTR,
+            16,
+            text.bytes.clone(),
+        ),
+        section(".rodata", SHT_PROGBITS, SHF_ALLOC, 1, rodata.to_vec()),
+        Section {
+            link: SYMTAB,
+            info: u32::from(TEXT),
+            entry_size: RELOCATION_SIZE as u64,
+            ..section(".rela.text", SHT_RELA, SHF_INFO_LINK, 8, relocations)
+        },
+        Section {
+            link: STRTAB,
+            info: FIRST_GLOBAL,
+            entry_size: SYMBOL_SIZE as u64,
+            ..section(".symtab", SHT_SYMTAB, 0, 8, symbols)
+        },
+        section(".strtab", SHT_STRTAB, 0, 1, strings),
+        // Without this linkers assume the program needs an executable stack
+        section(".note.GNU-stack", SHT_PROGBITS, 0, 1, Vec::new()),
+    ];
+
+    let mut section_names = vec![0];
+    let mut name_offsets: Vec<u32> = sections
+        .iter()
+        .map(|section| add_string(&mut section_names, section.name))
+        .collect();
+    name_offsets.push(add_string(&mut section_names, ".shstrtab"));
+    sections.push(section(".shstrtab", SHT_STRTAB, 0, 1, section_names));
+
+    let mut object = vec![0; HEADER_SIZE];
+    let mut offsets = Vec::new();
+    for section in &sections {
+        align(&mut object, section.align);
+        offsets.push(object.len() as u64);
+        object.extend(&section.data);
+    }
+
+    align(&mut object, 8);
+    let section_headers = object.len() as u64;
+    object.extend(&[0; SECTION_HEADER_SIZE]);
+    for ((section, offset), name) in sections.iter().zip(offsets).zip(name_offsets) {
+        object.extend(&name.to_le_bytes());
+        object.extend(&section.kind.to_le_bytes());
+        object.extend(&section.flags.to_le_bytes());
+        object.extend(&0u64.to_le_bytes());
+        object.extend(&offset.to_le_bytes());
+        object.extend(&(section.data.len() as u64).to_le_bytes());
+        object.extend(&section.link.to_le_bytes());
+        object.extend(&section.info.to_le_bytes());
+        object.extend(&(section.align as u64).to_le_bytes());
+        object.extend(&section.entry_size.to_le_bytes());
+    }
+
+    // 64-bit, little endian, version 1, System V ABI
+    let mut header = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0];
+    header.resize(16, 0);
+    // A relocatable file for x86-64
+    header.extend(&1u16.to_le_bytes());
+    header.extend(&62u16.to_le_bytes());
+    header.extend(&1u32.to_le_bytes());
+    // No entry point or program headers
+    header.extend(&0u64.to_le_bytes());
+    header.extend(&0u64.to_le_bytes());
+    header.extend(&section_headers.to_le_bytes());
+    header.extend(&0u32.to_le_bytes());
+    header.extend(&(HEADER_SIZE as u16).to_le_bytes());
+    header.extend(&0u16.to_le_bytes());
+    header.extend(&0u16.to_le_bytes());
+    header.extend(&(SECTION_HEADER_SIZE as u16).to_le_bytes());
+    header.extend(&(sections.len() as u16 + 1).to_le_bytes());
+    header.extend(&(sections.len() as u16).to_le_bytes());
+    object[..HEADER_SIZE].copy_from_slice(&header);
+
+    Ok(object)
+}
+
+/// Lay out the .rela.text entries for `relocations`, where `runtime` holds
+/// the runtime functions in the order of their symbols after the entry.
+fn relocation_entries(
+    relocations: &[Relocation],
+    runtime: &[&'static str],
+) -> Result<Vec<u8>, BfError> {
+    let mut entries = Vec::new();
+    for relocation in relocations {
+        let (symbol, addend) = match relocation.target {
+            Target::Runtime(name) => {
+                let index = runtime
+                    .iter()
+                    .position(|&used| used == name)
+                    .ok_or(BfError::UndefinedSymbol(name))?;
+                (u64::from(FIRST_GLOBAL) + 1 + index as u64, 0)
+            }
+            Target::Literal(offset) => (RODATA_SYMBOL, offset as i64),
+        };
+
+        entries.extend(&(relocation.offset as u64).to_le_bytes());
+        entries.extend(&(symbol << 32 | R_X86_64_64).to_le_bytes());
+        entries.extend(&addend.to_le_bytes());
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u16(bytes: &[u8], at: usize) -> usize {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]]) as usize
+    }
+
+    fn read_u32(bytes: &[u8], at: usize) -> usize {
+        let mut word = [0; 4];
+        word.copy_from_slice(&bytes[at..at + 4]);
+        u32::from_le_bytes(word) as usize
+    }
+
+    fn read_u64(bytes: &[u8], at: usize) -> usize {
+        let mut word = [0; 8];
+        word.copy_from_slice(&bytes[at..at + 8]);
+        u64::from_le_bytes(word) as usize
+    }
+
+    /// The name, info byte and section index of every symbol in `object`.
+    fn symbols(object: &[u8]) -> Vec<(String, u8, usize)> {
+        let headers = read_u64(object, 0x28);
+        let header = |index: usize| headers + index * SECTION_HEADER_SIZE;
+        let symtab = (0..read_u16(object, 0x3c))
+            .map(header)
+            .find(|&at| read_u32(object, at + 4) == SHT_SYMTAB as usize)
+            .unwrap();
+        let strtab = read_u64(object, header(read_u32(object, symtab + 0x28)) + 0x18);
+
+        let start = read_u64(object, symtab + 0x18);
+        let end = start + read_u64(object, symtab + 0x20);
+        (start..end)
+            .step_by(SYMBOL_SIZE)
+            .map(|at| {
+                let name = &object[strtab + read_u32(object, at)..];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap()];
+                let name = String::from_utf8(name.to_vec()).unwrap();
+                (name, object[at + 4], read_u16(object, at + 6))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn entry_symbol() {
+        let mut program = AST::parse("++++++++[>++++++++<-]>+.,[.,]").unwrap();
+        program.fuse_print_literals();
+        let object = emit_object(&program, JITOptions::default()).unwrap();
+
+        assert_eq!(&object[..4], b"\x7fELF");
+        let symbols = symbols(&object);
+        let entry = (ENTRY_SYMBOL.to_owned(), 0x12, usize::from(TEXT));
+        assert!(symbols.contains(&entry));
+        // Both calls to print are left to the runtime
+        assert!(symbols.contains(&("bf_print".to_owned(), 0x10, 0)));
+        assert!(symbols.contains(&("bf_read".to_owned(), 0x10, 0)));
+        assert!(!symbols.iter().any(|(name, _, _)| name == "bf_print_repeat"));
+    }
+
+    #[test]
+    fn literals_relocated() {
+        let mut program = AST::parse("++++++++[>++++++++<-]>+.+.").unwrap();
+        program.fold_static_output(1000);
+        let object = emit_object(&program, JITOptions::default()).unwrap();
+
+        // The literal is in .rodata, and its address is not in the code
+        let literal = program.data.iter().find_map(|node| match node {
+            ASTNode::PrintLiteral(literal) => Some(literal.clone()),
+            _ => None,
+        });
+        let literal = literal.unwrap();
+        assert!(object
+            .windows(literal.len())
+            .any(|window| window == &literal[..]));
+        assert!(!object
+            .windows(8)
+            .any(|window| window == &(literal.as_ptr() as usize).to_ne_bytes()[..]));
+
+        let options = JITOptions {
+            arithmetic: CellArithmetic::Error,
+            ..JITOptions::default()
+        };
+        assert!(matches!(
+            emit_object(&program, options),
+            Err(BfError::ArithmeticUnsupported(CellArithmetic::Error))
+        ));
+    }
+
+    #[test]
+    fn undefined_symbol() {
+        let relocations = [Relocation {
+            offset: 2,
+            target: Target::Runtime("bf_read"),
+        }];
+
+        let entries = relocation_entries(&relocations, &["bf_print", "bf_read"]).unwrap();
+        assert_eq!(read_u64(&entries, 0), 2);
+        assert_eq!(read_u64(&entries, 8) >> 32, FIRST_GLOBAL as usize + 2);
+        assert!(matches!(
+            relocation_entries(&relocations, &["bf_print"]),
+            Err(BfError::UndefinedSymbol("bf_read"))
+        ));
+    }
+}
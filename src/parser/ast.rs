@@ -1,23 +1,53 @@
-use std::collections::VecDeque;
-
-/// BrainFuck AST node
-#[derive(Debug, Clone, PartialEq)]
-pub enum ASTNode {
-    /// Add to the current memory cell.
-    Incr(u8),
-    /// Remove from the current memory cell.
-    Decr(u8),
-    /// Shift the data pointer to the right.
-    Next(usize),
-    /// Shift the data pointer to the left.
-    Prev(usize),
-    /// Display the current memory cell as an ASCII character.
-    Print,
-    /// Read one character from stdin.
-    Read,
-    /// Loop over the contained instructions while the current memory cell is
-    /// not zero.
-    Loop(VecDeque<ASTNode>),
+use std::collections::vec_deque;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::iter;
+use std::mem;
+
+pub use super::super::bare::ASTNode;
+use super::super::error::BfError;
+use super::super::runnable::{CellArithmetic, EofMode};
+use super::span::{self, NodeSpan, Origins, SourceMap, Span};
+use super::{ASTBuilder, Warning};
+
+/// Loop nesting depth beyond which a warning is emitted.
+const DEEP_NESTING: usize = 0x100;
+
+/// Settings for `AST::parse_with_options`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Fail if loops nest deeper than this.
+    pub max_depth: usize,
+    /// Parse `$` as `DebugPrintValue` rather than as a comment.
+    pub debug_ext: bool,
+    /// Parse `#` as `Breakpoint` rather than as a comment.
+    pub breakpoints: bool,
+    /// Fail with `BfError::DeadLoop` on a loop at the start of the program
+    /// rather than removing it with a warning.
+    pub strict: bool,
+    /// How the program will run its arithmetic. Anything but wrapping rules
+    /// out merging `+` with `-` and runs that cross a boundary.
+    pub arithmetic: CellArithmetic,
+    /// Run the peephole passes while parsing. Without them only runs of the
+    /// same command are merged.
+    pub peephole: bool,
+    /// Keep loops at the start of the input, for snippets run on a tape
+    /// that is already in use rather than on a fresh one.
+    pub snippet: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            max_depth: usize::MAX,
+            debug_ext: false,
+            breakpoints: false,
+            strict: false,
+            arithmetic: CellArithmetic::Wrap,
+            peephole: true,
+            snippet: false,
+        }
+    }
 }
 
 /// Container for a vector of ASTNodes.
@@ -26,29 +56,296 @@ pub struct AST {
     pub data: VecDeque<ASTNode>,
 }
 
+/// Pre-order iterator over every node in an AST, see `AST::iter_flat`.
+pub struct FlatIter<'a> {
+    /// Iterators over each loop body currently being traversed, innermost
+    /// last.
+    stack: Vec<vec_deque::Iter<'a, ASTNode>>,
+}
+
+impl<'a> Iterator for FlatIter<'a> {
+    /// Nesting depth (0 for top level nodes) and the node itself.
+    type Item = (usize, &'a ASTNode);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let depth = self.stack.len().checked_sub(1)?;
+
+            match self.stack[depth].next() {
+                Some(node) => {
+                    if let ASTNode::Loop(body) = node {
+                        self.stack.push(body.iter());
+                    }
+
+                    return Some((depth, node));
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+/// `AST::validate` for a list of nodes.
+pub(crate) fn validate_nodes(nodes: &VecDeque<ASTNode>) -> Result<(), BfError> {
+    let mut path = Vec::new();
+    validate_level(nodes, &mut path)
+}
+
+/// Check `nodes`, the body found at `path`.
+fn validate_level(nodes: &VecDeque<ASTNode>, path: &mut Vec<usize>) -> Result<(), BfError> {
+    for (index, node) in nodes.iter().enumerate() {
+        path.push(index);
+
+        let reason = match node {
+            ASTNode::Next(n) | ASTNode::Prev(n) if i32::try_from(*n).is_err() => {
+                Some("the pointer moves too far at once")
+            }
+            ASTNode::MulAdd(offset, _) if i32::try_from(*offset).is_err() => {
+                Some("the target cell is too far away")
+            }
+            ASTNode::MulAdd(0, _) => Some("the target cell is the counter"),
+            ASTNode::AddTo(offset, _) if i32::try_from(*offset).is_err() => {
+                Some("the target cell is too far away")
+            }
+            ASTNode::Loop(body) => {
+                validate_level(body, path)?;
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(reason) = reason {
+            return Err(BfError::InvalidNode {
+                path: path.clone(),
+                reason,
+            });
+        }
+
+        path.pop();
+    }
+
+    Ok(())
+}
+
 impl AST {
+    /// Start building a program node by node.
+    pub fn builder() -> ASTBuilder {
+        ASTBuilder::new()
+    }
+
+    /// Iterate over all nodes in execution order, descending into loops
+    /// directly after yielding the loop itself.
+    pub fn iter_flat(&self) -> FlatIter<'_> {
+        FlatIter {
+            stack: vec![self.data.iter()],
+        }
+    }
+
+    /// Whether any `,` is left in the program, in loops included.
+    pub fn reads_input(&self) -> bool {
+        self.iter_flat().any(|(_, node)| *node == ASTNode::Read)
+    }
+
+    /// Whether any print is left in the program, in loops included. Debug
+    /// prints only go to stderr and don't count.
+    pub fn writes_output(&self) -> bool {
+        self.iter_flat().any(|(_, node)| {
+            matches!(
+                node,
+                ASTNode::Print | ASTNode::PrintRepeat(_) | ASTNode::PrintLiteral(_)
+            )
+        })
+    }
+
+    /// Check that the program holds nothing the backends cannot run, for
+    /// trees that were built or imported rather than parsed.
+    ///
+    /// Compiled code moves the pointer by at most `i32::MAX` cells at once,
+    /// and a multiply always targets a cell other than its counter.
+    pub fn validate(&self) -> Result<(), BfError> {
+        validate_nodes(&self.data)
+    }
+
+    /// Warn about loops that rely on reading a 0 at end of input when the
+    /// EOF mode will never produce one.
+    ///
+    /// Only loops whose body ends with `,` (optionally followed by `.`) are
+    /// considered, since their continuation clearly depends on the byte read.
+    pub fn eof_warnings(&self, eof: EofMode) -> Vec<Warning> {
+        if eof.on_eof(1) == 0 {
+            return Vec::new();
+        }
+
+        self.iter_flat()
+            .filter(|(_depth, node)| match node {
+                ASTNode::Loop(body) => {
+                    let last_effect = body.iter().rev().find(|node| {
+                        !matches!(
+                            node,
+                            ASTNode::Print
+                                | ASTNode::PrintRepeat(_)
+                                | ASTNode::PrintLiteral(_)
+                                | ASTNode::DebugPrintValue
+                        )
+                    });
+                    last_effect == Some(&ASTNode::Read)
+                }
+                _ => false,
+            })
+            .map(|_| Warning::EofLoop { eof })
+            .collect()
+    }
+
     /// Convert raw input into an AST.
-    pub fn parse(input: &str) -> Result<Self, String> {
+    ///
+    /// The input may be a `&str` or raw bytes. Only the eight command
+    /// characters matter, so comments need not be valid UTF-8.
+    pub fn parse<S: AsRef<[u8]> + ?Sized>(input: &S) -> Result<Self, BfError> {
+        Self::parse_with_warnings(input).map(|(ast, _warnings)| ast)
+    }
+
+    /// Convert raw input into an AST, also returning any non-fatal
+    /// diagnostics about how the program was interpreted.
+    pub fn parse_with_warnings<S: AsRef<[u8]> + ?Sized>(
+        input: &S,
+    ) -> Result<(Self, Vec<Warning>), BfError> {
+        Self::parse_with_max_depth(input, usize::MAX)
+    }
+
+    /// Like `parse_with_warnings`, but fail if loops nest more than
+    /// `max_depth` deep.
+    pub fn parse_with_max_depth<S: AsRef<[u8]> + ?Sized>(
+        input: &S,
+        max_depth: usize,
+    ) -> Result<(Self, Vec<Warning>), BfError> {
+        Self::parse_with_options(
+            input,
+            ParseOptions {
+                max_depth,
+                ..ParseOptions::default()
+            },
+        )
+    }
+
+    /// Like `parse_with_warnings`, with every parser setting available.
+    pub fn parse_with_options<S: AsRef<[u8]> + ?Sized>(
+        input: &S,
+        options: ParseOptions,
+    ) -> Result<(Self, Vec<Warning>), BfError> {
+        Self::parse_mapped(input.as_ref(), options, false).map(|(ast, warnings, _)| (ast, warnings))
+    }
+
+    /// Convert a snippet, such as one line typed into a REPL, into an AST.
+    ///
+    /// Unlike with `parse`, a loop at the start is kept, since the snippet
+    /// may run on a cell that is not 0.
+    pub fn parse_snippet<S: AsRef<[u8]> + ?Sized>(input: &S) -> Result<Self, BfError> {
+        let options = ParseOptions {
+            snippet: true,
+            ..ParseOptions::default()
+        };
+        Self::parse_with_options(input, options).map(|(ast, _warnings)| ast)
+    }
+
+    /// Like `parse_with_options`, also returning where each node came from.
+    ///
+    /// A node made from several commands, such as a run or a loop rewritten
+    /// into other nodes, spans all of them. The spans are kept apart from the
+    /// nodes so that the usual parse does not pay for them, and they only
+    /// match the nodes as parsing left them, not after later passes.
+    pub fn parse_with_spans<S: AsRef<[u8]> + ?Sized>(
+        input: &S,
+        options: ParseOptions,
+    ) -> Result<(Self, SourceMap), BfError> {
+        Self::parse_mapped(input.as_ref(), options, true)
+            .map(|(ast, _, spans)| (ast, spans.unwrap_or_default()))
+    }
+
+    /// Parse `input`, following the span of every node when `mapped`.
+    fn parse_mapped(
+        input: &[u8],
+        options: ParseOptions,
+        mapped: bool,
+    ) -> Result<(Self, Vec<Warning>, Option<SourceMap>), BfError> {
+        let max_depth = options.max_depth;
         let mut output = VecDeque::new();
         let mut loops: VecDeque<VecDeque<ASTNode>> = VecDeque::new();
+        let mut warnings = Vec::new();
+        let mut warned_nesting = false;
+        // Position of the current character, and of each open loop's [
+        let (mut line, mut col) = (1, 0);
+        let mut loop_starts: Vec<Span> = Vec::new();
+        // Spans of the nodes in output and in each open loop, when mapped
+        let mut output_spans = if mapped { Some(Vec::new()) } else { None };
+        let mut loop_spans: Vec<Vec<NodeSpan>> = Vec::new();
+
+        for (offset, &byte) in input.iter().enumerate() {
+            // Columns count characters, so UTF-8 continuation bytes are
+            // skipped
+            if byte & 0xc0 != 0x80 {
+                col += 1;
+            }
 
-        for character in input.chars() {
-            let next_node = match character {
-                '+' => ASTNode::Incr(1),
-                '-' => ASTNode::Decr(1),
-                '>' => ASTNode::Next(1),
-                '<' => ASTNode::Prev(1),
-                '.' => ASTNode::Print,
-                ',' => ASTNode::Read,
-                '[' => {
+            if byte == b'\n' {
+                line += 1;
+                col = 0;
+            }
+
+            let mut span = Span {
+                start: offset,
+                end: offset + 1,
+                line,
+                col,
+            };
+            let mut body_spans = SourceMap::default();
+
+            let next_node = match byte {
+                b'+' => ASTNode::Incr(1),
+                b'-' => ASTNode::Decr(1),
+                b'>' => ASTNode::Next(1),
+                b'<' => ASTNode::Prev(1),
+                b'.' => ASTNode::Print,
+                b',' => ASTNode::Read,
+                b'$' if options.debug_ext => ASTNode::DebugPrintValue,
+                b'#' if options.breakpoints => ASTNode::Breakpoint,
+                b'[' => {
                     loops.push_back(VecDeque::new());
+                    loop_starts.push(span);
+                    if mapped {
+                        loop_spans.push(Vec::new());
+                    }
+
+                    if loops.len() > max_depth {
+                        return Err(BfError::TooDeep {
+                            limit: max_depth,
+                            line,
+                            col,
+                        });
+                    }
+
+                    if loops.len() > DEEP_NESTING && !warned_nesting {
+                        warnings.push(Warning::DeepNesting {
+                            depth: loops.len(),
+                            line,
+                            col,
+                        });
+                        warned_nesting = true;
+                    }
+
                     continue;
                 }
-                ']' => {
+                b']' => {
                     // Example program that will cause this error:
                     //
                     // []]
-                    let mut current_loop = loops.pop_back().ok_or("More ] than [")?;
+                    let mut current_loop = loops
+                        .pop_back()
+                        .ok_or(BfError::UnmatchedClose { line, col })?;
+                    let start = loop_starts.pop().unwrap_or(span);
+                    let (loop_line, loop_col) = (start.line, start.col);
+                    let mut spans = if mapped { loop_spans.pop() } else { None };
 
                     // Do not add loop if it will be the first element in the
                     // output vector. This is because:
@@ -57,50 +354,247 @@ impl AST {
                     // 2. Loops are skipped when the current cell is 0
                     //
                     // So if no non-loops have executed there is no use in
-                    // emitting a Loop ASTNode.
-                    if output.is_empty() {
+                    // emitting a Loop ASTNode. A snippet runs on cells left
+                    // by earlier code, so there it may run.
+                    if output.is_empty() && !options.snippet {
+                        if loops.is_empty() && options.strict {
+                            return Err(BfError::DeadLoop {
+                                line: loop_line,
+                                col: loop_col,
+                            });
+                        }
+                        if loops.is_empty() {
+                            warnings.push(Warning::DeadLoopRemoved {
+                                line: loop_line,
+                                col: loop_col,
+                            });
+                        }
+
                         continue;
                     }
 
-                    ASTNode::Loop(Self::shallow_run_length_optimize(&mut current_loop))
+                    let body = Self::parse_level(
+                        &mut current_loop,
+                        &mut warnings,
+                        options,
+                        spans.as_mut(),
+                    );
+
+                    // After a loop or a clear the cell is 0 and the loop is
+                    // dead rather than infinite
+                    let after_zero = matches!(
+                        loops.back().unwrap_or(&output).back(),
+                        Some(ASTNode::Loop(_)) | Some(ASTNode::Set(0))
+                    );
+                    if !after_zero && Self::changes_nothing(&body, options.arithmetic) {
+                        warnings.push(Warning::InfiniteLoop {
+                            line: loop_line,
+                            col: loop_col,
+                        });
+                    }
+
+                    span = Span {
+                        end: offset + 1,
+                        ..start
+                    };
+                    let body = if options.peephole {
+                        Self::canonical_loop(body, options.arithmetic, spans.as_mut())
+                    } else {
+                        body
+                    };
+                    body_spans.spans = spans.unwrap_or_default();
+
+                    ASTNode::Loop(body)
                 }
                 // All other characters are comments and will be ignored
                 _ => continue,
             };
 
             loops.back_mut().unwrap_or(&mut output).push_back(next_node);
+            if let Some(top) = output_spans.as_mut() {
+                loop_spans.last_mut().unwrap_or(top).push(NodeSpan {
+                    span,
+                    body: body_spans,
+                });
+            }
         }
 
-        if !loops.is_empty() {
+        if let Some(start) = loop_starts.first() {
             // Example program that will cause this error:
             //
             // [[]
-            return Err("More [ than ]".to_string());
+            return Err(BfError::UnmatchedOpen {
+                line: start.line,
+                col: start.col,
+            });
         }
 
-        Ok(AST {
-            data: Self::shallow_run_length_optimize(&mut output),
-        })
+        let data = Self::parse_level(&mut output, &mut warnings, options, output_spans.as_mut());
+        let spans = output_spans.map(|spans| SourceMap { spans });
+
+        Ok((AST { data }, warnings, spans))
+    }
+
+    /// Optimize one list of parsed nodes as `options` say.
+    fn parse_level(
+        input: &mut VecDeque<ASTNode>,
+        warnings: &mut Vec<Warning>,
+        options: ParseOptions,
+        mut spans: Option<&mut Vec<NodeSpan>>,
+    ) -> VecDeque<ASTNode> {
+        if options.peephole {
+            return Self::shallow_optimize_mapped(input, warnings, options.arithmetic, spans);
+        }
+
+        let mut origins = span::origins(&spans);
+        let output =
+            Self::shallow_run_length_optimize(input, warnings, options.arithmetic, &mut origins);
+        span::remap(&mut spans, origins, &output);
+
+        output
     }
 
-    /// Convert runs of +, -, < and > into bulk operations.
-    fn shallow_run_length_optimize(input: &mut VecDeque<ASTNode>) -> VecDeque<ASTNode> {
+    /// Run every peephole pass over one list of nodes. Loop bodies are
+    /// expected to have been optimized already.
+    pub(super) fn shallow_optimize(
+        input: &mut VecDeque<ASTNode>,
+        warnings: &mut Vec<Warning>,
+        arithmetic: CellArithmetic,
+    ) -> VecDeque<ASTNode> {
+        Self::shallow_optimize_mapped(input, warnings, arithmetic, None)
+    }
+
+    /// Like `shallow_optimize`, also updating `spans` to match.
+    fn shallow_optimize_mapped(
+        input: &mut VecDeque<ASTNode>,
+        warnings: &mut Vec<Warning>,
+        arithmetic: CellArithmetic,
+        mut spans: Option<&mut Vec<NodeSpan>>,
+    ) -> VecDeque<ASTNode> {
+        let mut origins = span::origins(&spans);
+        let output = Self::shallow_run_length_optimize(input, warnings, arithmetic, &mut origins);
+        span::remap(&mut spans, origins, &output);
+
+        let mut origins = span::origins(&spans);
+        let output = Self::shallow_clear_loop_optimize(output, arithmetic, &mut origins);
+        span::remap(&mut spans, origins, &output);
+
+        let mut origins = span::origins(&spans);
+        let output = Self::shallow_multiply_loop_optimize(output, arithmetic, &mut origins);
+        span::remap(&mut spans, origins, &output);
+
+        let mut origins = span::origins(&spans);
+        let output = Self::shallow_dead_loop_optimize(output, &mut origins);
+        span::remap(&mut spans, origins, &output);
+
+        output
+    }
+
+    /// Put an optimized loop body in the standard form loops are kept in,
+    /// also updating `spans` to match.
+    fn canonical_loop(
+        body: VecDeque<ASTNode>,
+        arithmetic: CellArithmetic,
+        mut spans: Option<&mut Vec<NodeSpan>>,
+    ) -> VecDeque<ASTNode> {
+        if arithmetic != CellArithmetic::Wrap {
+            return body;
+        }
+
+        let mut origins = span::origins(&spans);
+        let body = Self::shallow_canonicalize_mapped(body, &mut origins);
+        span::remap(&mut spans, origins, &body);
+
+        body
+    }
+
+    /// Run the parser's passes again over every loop body, innermost first,
+    /// and then over the top level, until nothing changes.
+    ///
+    /// Parsing already does this once, so this is for programs whose nodes
+    /// were built or rewritten by hand, or by passes that leave new chances
+    /// behind. Warnings were given when parsing and are not repeated.
+    pub fn optimize(&mut self, arithmetic: CellArithmetic) {
+        loop {
+            let data = Self::deep_optimize(self.data.clone(), arithmetic);
+            if data == self.data {
+                break;
+            }
+            self.data = data;
+        }
+    }
+
+    /// One round of `AST::optimize` over `input` and its loop bodies.
+    fn deep_optimize(input: VecDeque<ASTNode>, arithmetic: CellArithmetic) -> VecDeque<ASTNode> {
+        let mut nodes = input
+            .into_iter()
+            .map(|node| match node {
+                ASTNode::Loop(body) => {
+                    let body = Self::deep_optimize(body, arithmetic);
+                    ASTNode::Loop(Self::canonical_loop(body, arithmetic, None))
+                }
+                node => node,
+            })
+            .collect();
+
+        Self::shallow_optimize(&mut nodes, &mut Vec::new(), arithmetic)
+    }
+
+    /// Convert runs of +, -, <, > and . into bulk operations.
+    ///
+    /// Unless arithmetic wraps, a run too long for one node is split so each
+    /// node still crosses a boundary exactly when the run would.
+    fn shallow_run_length_optimize(
+        input: &mut VecDeque<ASTNode>,
+        warnings: &mut Vec<Warning>,
+        arithmetic: CellArithmetic,
+        origins: &mut Origins,
+    ) -> VecDeque<ASTNode> {
         let mut output = VecDeque::new();
+        let wrap = arithmetic == CellArithmetic::Wrap;
 
-        while let Some(next_node) = input.pop_front() {
+        for (index, next_node) in input.drain(..).enumerate() {
             let prev_node = output.back();
 
             // For each operator +, -, < and >, if the last instruction in the
             // output Vec is the same, then increment that instruction instead
             // of adding another identical instruction.
             let combined = match (prev_node, &next_node) {
-                (Some(ASTNode::Incr(b)), ASTNode::Incr(a)) => ASTNode::Incr(a.wrapping_add(*b)),
-                (Some(ASTNode::Decr(b)), ASTNode::Decr(a)) => ASTNode::Decr(a.wrapping_add(*b)),
-                (Some(ASTNode::Next(b)), ASTNode::Next(a)) => ASTNode::Next(a.wrapping_add(*b)),
-                (Some(ASTNode::Prev(b)), ASTNode::Prev(a)) => ASTNode::Prev(a.wrapping_add(*b)),
+                (Some(ASTNode::Incr(b)), ASTNode::Incr(a))
+                    if wrap || a.checked_add(*b).is_some() =>
+                {
+                    if a.checked_add(*b).is_none() {
+                        warnings.push(Warning::RunLengthWrapped { command: '+' });
+                    }
+
+                    ASTNode::Incr(a.wrapping_add(*b))
+                }
+                (Some(ASTNode::Decr(b)), ASTNode::Decr(a))
+                    if wrap || a.checked_add(*b).is_some() =>
+                {
+                    if a.checked_add(*b).is_none() {
+                        warnings.push(Warning::RunLengthWrapped { command: '-' });
+                    }
+
+                    ASTNode::Decr(a.wrapping_add(*b))
+                }
+                // Unlike cell arithmetic a wrapped pointer move is never
+                // intended. Runs too long for one node are split instead.
+                (Some(ASTNode::Next(b)), ASTNode::Next(a)) if a.checked_add(*b).is_some() => {
+                    ASTNode::Next(a + b)
+                }
+                (Some(ASTNode::Prev(b)), ASTNode::Prev(a)) if a.checked_add(*b).is_some() => {
+                    ASTNode::Prev(a + b)
+                }
+                // Nothing between the prints can change the cell
+                (Some(ASTNode::Print), ASTNode::Print) => ASTNode::PrintRepeat(2),
+                (Some(ASTNode::PrintRepeat(n)), ASTNode::Print) if *n < usize::MAX => {
+                    ASTNode::PrintRepeat(n + 1)
+                }
                 _ => {
                     // Node is not combineable, just move into the output vector
                     output.push_back(next_node);
+                    span::track(origins, index..index + 1);
                     continue;
                 }
             };
@@ -108,6 +602,530 @@ impl AST {
             // Replace last node with the combined one
             output.pop_back();
             output.push_back(combined);
+            span::extend(origins, index + 1);
+        }
+
+        output
+    }
+
+    /// Whether a loop body with only arithmetic and pointer moves leaves
+    /// the pointer and every cell as they were, so that the loop runs forever
+    /// once entered. Without wrapping only pointer moves are considered,
+    /// since arithmetic may stop at a cell's limits.
+    fn changes_nothing(body: &VecDeque<ASTNode>, arithmetic: CellArithmetic) -> bool {
+        let wrap = arithmetic == CellArithmetic::Wrap;
+        let mut effects: BTreeMap<i128, u8> = BTreeMap::new();
+        let mut offset: i128 = 0;
+
+        for node in body {
+            match node {
+                ASTNode::Incr(n) if wrap => {
+                    let effect = effects.entry(offset).or_insert(0);
+                    *effect = effect.wrapping_add(*n);
+                }
+                ASTNode::Decr(n) if wrap => {
+                    let effect = effects.entry(offset).or_insert(0);
+                    *effect = effect.wrapping_sub(*n);
+                }
+                ASTNode::Next(n) => offset += *n as i128,
+                ASTNode::Prev(n) => offset -= *n as i128,
+                _ => return false,
+            }
+        }
+
+        offset == 0 && effects.values().all(|&effect| effect == 0)
+    }
+
+    /// Convert `[-]` and `[+]` into setting the cell to 0. Without wrapping
+    /// `[+]` never reaches 0, so it is left alone.
+    ///
+    /// With wrapping, a body that only adds or takes an odd amount, such as
+    /// `[---]` or `[->+<-->-<]` once its cancelling moves are gone, also
+    /// always stops at 0.
+    fn shallow_clear_loop_optimize(
+        input: VecDeque<ASTNode>,
+        arithmetic: CellArithmetic,
+        origins: &mut Origins,
+    ) -> VecDeque<ASTNode> {
+        let wrap = arithmetic == CellArithmetic::Wrap;
+
+        input
+            .into_iter()
+            .enumerate()
+            .map(|(index, node)| {
+                span::track(origins, index..index + 1);
+                node
+            })
+            .map(|node| match node {
+                ASTNode::Loop(ref body)
+                    if body.len() == 1
+                        && match body[0] {
+                            ASTNode::Decr(1) => true,
+                            ASTNode::Incr(n) | ASTNode::Decr(n) => wrap && n % 2 == 1,
+                            _ => false,
+                        } =>
+                {
+                    ASTNode::Set(0)
+                }
+                node => node,
+            })
+            .collect()
+    }
+
+    /// Convert loops such as `[->+>+++<<]`, which add multiples of the
+    /// current cell to other cells while counting it down to 0, into one
+    /// `MulAdd` per target followed by `Set(0)`.
+    ///
+    /// The body may only do arithmetic and pointer moves, must end where it
+    /// started and must take exactly 1 from the current cell. That count only
+    /// works out when arithmetic wraps.
+    fn shallow_multiply_loop_optimize(
+        input: VecDeque<ASTNode>,
+        arithmetic: CellArithmetic,
+        origins: &mut Origins,
+    ) -> VecDeque<ASTNode> {
+        let wrap = arithmetic == CellArithmetic::Wrap;
+        let mut output = VecDeque::new();
+
+        for (index, node) in input.into_iter().enumerate() {
+            let before = output.len();
+
+            match node {
+                ASTNode::Loop(ref body) if wrap => match Self::multiply_targets(body) {
+                    Some(targets) => {
+                        output.extend(
+                            targets
+                                .into_iter()
+                                .map(|(offset, factor)| ASTNode::MulAdd(offset, factor)),
+                        );
+                        output.push_back(ASTNode::Set(0));
+                    }
+                    None => output.push_back(node),
+                },
+                node => output.push_back(node),
+            }
+
+            for _ in before..output.len() {
+                span::track(origins, index..index + 1);
+            }
+        }
+
+        output
+    }
+
+    /// The offset and factor of every cell a multiply loop with `body` adds
+    /// to, lowest offset first, or None if it is not a multiply loop.
+    fn multiply_targets(body: &VecDeque<ASTNode>) -> Option<Vec<(isize, u8)>> {
+        let mut effects: BTreeMap<i128, u8> = BTreeMap::new();
+        let mut offset: i128 = 0;
+
+        for node in body {
+            match node {
+                ASTNode::Incr(n) => {
+                    let effect = effects.entry(offset).or_insert(0);
+                    *effect = effect.wrapping_add(*n);
+                }
+                ASTNode::Decr(n) => {
+                    let effect = effects.entry(offset).or_insert(0);
+                    *effect = effect.wrapping_sub(*n);
+                }
+                ASTNode::Next(n) => offset += *n as i128,
+                ASTNode::Prev(n) => offset -= *n as i128,
+                _ => return None,
+            }
+        }
+
+        if offset != 0 || effects.remove(&0) != Some(0xff) {
+            return None;
+        }
+
+        effects
+            .into_iter()
+            .filter(|&(_, factor)| factor != 0)
+            // Compiled code addresses targets with a 32 bit displacement
+            .map(|(offset, factor)| Some((i32::try_from(offset).ok()? as isize, factor)))
+            .collect()
+    }
+
+    /// Rewrite each run of arithmetic and pointer moves into a standard form,
+    /// so that loop bodies with the same effect compare equal and share
+    /// compiled fragments.
+    ///
+    /// Cells are visited from the lowest offset to the highest, each getting
+    /// at most one node, before moving to where the run left the pointer.
+    /// Only cells the run already touched are visited.
+    pub(super) fn shallow_canonicalize(input: VecDeque<ASTNode>) -> VecDeque<ASTNode> {
+        Self::shallow_canonicalize_mapped(input, &mut None)
+    }
+
+    /// Like `shallow_canonicalize`, recording where each node came from. The
+    /// nodes written for a run all come from the whole run.
+    fn shallow_canonicalize_mapped(
+        input: VecDeque<ASTNode>,
+        origins: &mut Origins,
+    ) -> VecDeque<ASTNode> {
+        let mut output = VecDeque::new();
+        // Value each touched cell is set to, if any, and what is added after
+        let mut effects: BTreeMap<i128, (Option<u8>, u8)> = BTreeMap::new();
+        let mut offset: i128 = 0;
+        // Index of the first node of the current run
+        let mut run_start = 0;
+        let len = input.len();
+
+        for (index, node) in input.into_iter().enumerate() {
+            match node {
+                ASTNode::Incr(n) => {
+                    let effect = effects.entry(offset).or_insert((None, 0));
+                    effect.1 = effect.1.wrapping_add(n);
+                }
+                ASTNode::Decr(n) => {
+                    let effect = effects.entry(offset).or_insert((None, 0));
+                    effect.1 = effect.1.wrapping_sub(n);
+                }
+                ASTNode::Set(n) => {
+                    effects.insert(offset, (Some(n), 0));
+                }
+                ASTNode::Next(n) => offset += n as i128,
+                ASTNode::Prev(n) => offset -= n as i128,
+                node => {
+                    let before = output.len();
+                    Self::emit_effects(&mut output, &effects, offset);
+                    for _ in before..output.len() {
+                        span::track(origins, run_start..index);
+                    }
+                    effects.clear();
+                    offset = 0;
+                    output.push_back(node);
+                    span::track(origins, index..index + 1);
+                    run_start = index + 1;
+                }
+            }
+        }
+
+        let before = output.len();
+        Self::emit_effects(&mut output, &effects, offset);
+        for _ in before..output.len() {
+            span::track(origins, run_start..len);
+        }
+
+        output
+    }
+
+    /// Emit the canonical nodes for one run, see `shallow_canonicalize`.
+    fn emit_effects(
+        output: &mut VecDeque<ASTNode>,
+        effects: &BTreeMap<i128, (Option<u8>, u8)>,
+        end: i128,
+    ) {
+        let mut current = 0;
+
+        for (&offset, &effect) in effects {
+            let node = match effect {
+                (Some(value), add) => ASTNode::Set(value.wrapping_add(add)),
+                (None, 0) => continue,
+                (None, add) if add <= 0x80 => ASTNode::Incr(add),
+                (None, add) => ASTNode::Decr(add.wrapping_neg()),
+            };
+
+            Self::emit_move(output, offset - current);
+            output.push_back(node);
+            current = offset;
+        }
+
+        Self::emit_move(output, end - current);
+    }
+
+    /// Emit pointer moves covering `distance`, split into nodes that fit.
+    fn emit_move(output: &mut VecDeque<ASTNode>, mut distance: i128) {
+        while distance != 0 {
+            let step = distance.unsigned_abs().min(usize::MAX as u128) as usize;
+
+            if distance > 0 {
+                output.push_back(ASTNode::Next(step));
+                distance -= step as i128;
+            } else {
+                output.push_back(ASTNode::Prev(step));
+                distance += step as i128;
+            }
+        }
+    }
+
+    /// Fuse prints of cells whose value is known at compile time into
+    /// `PrintLiteral` nodes, throughout the program.
+    ///
+    /// Values are only tracked through straight-line arithmetic, starting
+    /// over at every loop or read, and assume arithmetic wraps. The arithmetic
+    /// itself is kept so the tape ends up the same.
+    ///
+    /// The tape is taken to grow to the right as needed, so only going left
+    /// of every cell seen so far can end the run. A literal ends before that,
+    /// so the bytes printed up to there still come out. Fixed-size tapes need
+    /// the program left as it is.
+    pub fn fuse_print_literals(&mut self) {
+        self.data = Self::print_literal_optimize(mem::take(&mut self.data), true);
+    }
+
+    /// Fuse the prints in `input` and in its loop bodies. `zeroed` says every
+    /// cell is 0 at the start of `input`, which holds for the top level of a
+    /// program.
+    fn print_literal_optimize(input: VecDeque<ASTNode>, zeroed: bool) -> VecDeque<ASTNode> {
+        let mut output = VecDeque::new();
+        // Value of each cell the current run touched, None if it depends on
+        // input, by offset from the start of the run
+        let mut known: HashMap<i128, Option<u8>> = HashMap::new();
+        let mut zeroed = zeroed;
+        let mut offset: i128 = 0;
+        // The leftmost cell the current run has been on
+        let mut lowest: i128 = 0;
+        // Where the literal being built is in output, and its bytes
+        let mut literal: Option<(usize, Vec<u8>)> = None;
+
+        for node in input {
+            let value = match known.get(&offset) {
+                Some(&value) => value,
+                None if zeroed => Some(0),
+                None => None,
+            };
+
+            match node {
+                ASTNode::Incr(n) => {
+                    known.insert(offset, value.map(|value| value.wrapping_add(n)));
+                }
+                ASTNode::Decr(n) => {
+                    known.insert(offset, value.map(|value| value.wrapping_sub(n)));
+                }
+                ASTNode::Set(n) => {
+                    known.insert(offset, Some(n));
+                }
+                ASTNode::SetRange(len, n) => {
+                    for cell in 0..len as i128 {
+                        known.insert(offset + cell, Some(n));
+                    }
+                    offset += len as i128;
+                }
+                ASTNode::MulAdd(target, factor) => {
+                    let target = offset + target as i128;
+                    if target < lowest {
+                        lowest = target;
+                        Self::finish_literal(&mut output, literal.take());
+                    }
+                    let before = match known.get(&target) {
+                        Some(&before) => before,
+                        None if zeroed => Some(0),
+                        None => None,
+                    };
+                    let after = match (value, before) {
+                        (Some(0), before) => before,
+                        (Some(value), Some(before)) => {
+                            Some(before.wrapping_add(value.wrapping_mul(factor)))
+                        }
+                        _ => None,
+                    };
+                    known.insert(target, after);
+                }
+                ASTNode::AddTo(target, n) => {
+                    let target = offset + target as i128;
+                    if target < lowest {
+                        lowest = target;
+                        Self::finish_literal(&mut output, literal.take());
+                    }
+                    let before = match known.get(&target) {
+                        Some(&before) => before,
+                        None if zeroed => Some(0),
+                        None => None,
+                    };
+                    known.insert(target, before.map(|before| before.wrapping_add(n)));
+                }
+                ASTNode::Next(n) => offset += n as i128,
+                ASTNode::Prev(n) => {
+                    offset -= n as i128;
+                    if offset < lowest {
+                        lowest = offset;
+                        Self::finish_literal(&mut output, literal.take());
+                    }
+                }
+                ASTNode::Print | ASTNode::PrintRepeat(_) if value.is_some() => {
+                    let count = match node {
+                        ASTNode::PrintRepeat(n) => n,
+                        _ => 1,
+                    };
+                    let byte = value.unwrap();
+                    match literal {
+                        Some((_, ref mut bytes)) => bytes.extend(iter::repeat_n(byte, count)),
+                        None => {
+                            literal = Some((output.len(), vec![byte; count]));
+                            output.push_back(node);
+                        }
+                    }
+
+                    continue;
+                }
+                ASTNode::PrintLiteral(ref extra) => {
+                    if let Some((_, ref mut bytes)) = literal {
+                        bytes.extend(extra);
+
+                        continue;
+                    }
+                }
+                ASTNode::Read => {
+                    Self::finish_literal(&mut output, literal.take());
+                    known.insert(offset, None);
+                }
+                ASTNode::Loop(body) => {
+                    Self::finish_literal(&mut output, literal.take());
+                    // Nothing is known afterwards except that the loop
+                    // ended on a 0 cell
+                    known.clear();
+                    zeroed = false;
+                    offset = 0;
+                    lowest = 0;
+                    known.insert(0, Some(0));
+
+                    output.push_back(ASTNode::Loop(Self::print_literal_optimize(body, false)));
+                    continue;
+                }
+                _ => Self::finish_literal(&mut output, literal.take()),
+            }
+
+            output.push_back(node);
+        }
+
+        Self::finish_literal(&mut output, literal);
+
+        // Dropping the prints can leave arithmetic of the same kind next to
+        // each other, merge it again
+        Self::shallow_run_length_optimize(
+            &mut output,
+            &mut Vec::new(),
+            CellArithmetic::Wrap,
+            &mut None,
+        )
+    }
+
+    /// Put a literal built by `print_literal_optimize` in place of
+    /// the print that started it. A single byte is left as that `Print`.
+    fn finish_literal(output: &mut VecDeque<ASTNode>, literal: Option<(usize, Vec<u8>)>) {
+        if let Some((index, bytes)) = literal {
+            if bytes.len() > 1 {
+                output[index] = ASTNode::PrintLiteral(bytes);
+            }
+        }
+    }
+
+    /// Fold multiplies of a cell whose value is known at compile time, such
+    /// as `[-]++++[->+++<]`, into `AddTo` nodes adding the products,
+    /// throughout the program.
+    ///
+    /// Only arithmetic on the current cell since the last other node is
+    /// tracked, along with the 0 every cell starts at. Nodes that only built
+    /// the value are dropped when a `Set` overwrites it right after, as the
+    /// one ending a multiply loop does.
+    pub fn fold_known_multiplies(&mut self) {
+        self.data = Self::known_multiply_optimize(mem::take(&mut self.data), true);
+    }
+
+    /// Fold the multiplies in `input` and in its loop bodies. `zeroed` says
+    /// the current cell is 0 at the start of `input`.
+    fn known_multiply_optimize(input: VecDeque<ASTNode>, zeroed: bool) -> VecDeque<ASTNode> {
+        let mut output = VecDeque::new();
+        let mut value = if zeroed { Some(0u8) } else { None };
+        // Arithmetic on the current cell that nothing has read yet. The
+        // `AddTo` nodes go straight to output, which they can swap with.
+        let mut setup = Vec::new();
+
+        for node in input {
+            match node {
+                ASTNode::Incr(n) => value = value.map(|value| value.wrapping_add(n)),
+                ASTNode::Decr(n) => value = value.map(|value| value.wrapping_sub(n)),
+                ASTNode::Set(n) => {
+                    setup.clear();
+                    value = Some(n);
+                }
+                ASTNode::MulAdd(offset, factor) if offset != 0 && value.is_some() => {
+                    let n = value.unwrap().wrapping_mul(factor);
+                    if n != 0 {
+                        output.push_back(ASTNode::AddTo(offset, n));
+                    }
+                    continue;
+                }
+                // A multiply leaves the current cell alone
+                ASTNode::MulAdd(..) => {}
+                ASTNode::Loop(body) => {
+                    output.extend(setup.drain(..));
+                    output.push_back(ASTNode::Loop(Self::known_multiply_optimize(body, false)));
+                    value = Some(0);
+                    continue;
+                }
+                _ => value = None,
+            }
+
+            if matches!(node, ASTNode::Incr(_) | ASTNode::Decr(_) | ASTNode::Set(_)) {
+                setup.push(node);
+            } else {
+                output.extend(setup.drain(..));
+                output.push_back(node);
+            }
+        }
+
+        output.extend(setup);
+
+        output
+    }
+
+    /// Fuse chains of clears such as `[-]>[-]>[-]>` into `SetRange` nodes,
+    /// throughout the program.
+    pub fn fuse_set_ranges(&mut self) {
+        self.data = Self::set_range_optimize(mem::take(&mut self.data));
+    }
+
+    /// Fuse every run of the same `Set` followed by `Next(1)` in `input` and
+    /// in its loop bodies.
+    fn set_range_optimize(input: VecDeque<ASTNode>) -> VecDeque<ASTNode> {
+        let mut output = VecDeque::new();
+        let mut nodes = input.into_iter().peekable();
+
+        while let Some(node) = nodes.next() {
+            match node {
+                ASTNode::Set(value) if nodes.peek() == Some(&ASTNode::Next(1)) => {
+                    nodes.next();
+
+                    match output.back_mut() {
+                        Some(ASTNode::SetRange(len, last)) if *last == value => *len += 1,
+                        _ => output.push_back(ASTNode::SetRange(1, value)),
+                    }
+                }
+                ASTNode::Loop(body) => {
+                    output.push_back(ASTNode::Loop(Self::set_range_optimize(body)))
+                }
+                node => output.push_back(node),
+            }
+        }
+
+        // A single clear is no faster as a range
+        output
+            .into_iter()
+            .flat_map(|node| match node {
+                ASTNode::SetRange(1, value) => vec![ASTNode::Set(value), ASTNode::Next(1)],
+                node => vec![node],
+            })
+            .collect()
+    }
+
+    /// Remove loops directly after a `Set(0)`, since their cell is known to be
+    /// 0 on entry and they never run.
+    fn shallow_dead_loop_optimize(
+        input: VecDeque<ASTNode>,
+        origins: &mut Origins,
+    ) -> VecDeque<ASTNode> {
+        let mut output = VecDeque::new();
+
+        for (index, node) in input.into_iter().enumerate() {
+            if let (Some(ASTNode::Set(0)), ASTNode::Loop(_)) = (output.back(), &node) {
+                continue;
+            }
+
+            output.push_back(node);
+            span::track(origins, index..index + 1);
         }
 
         output
@@ -120,14 +1138,66 @@ mod tests {
 
     #[test]
     fn too_many_loop_begins() {
-        let ast = AST::parse("[[]");
-        assert!(ast.is_err());
+        let ast = AST::parse("+\n[[]");
+        assert!(matches!(
+            ast,
+            Err(BfError::UnmatchedOpen { line: 2, col: 1 })
+        ));
     }
 
     #[test]
     fn too_many_loop_ends() {
         let ast = AST::parse("[]]");
-        assert!(ast.is_err());
+        assert!(matches!(
+            ast,
+            Err(BfError::UnmatchedClose { line: 1, col: 3 })
+        ));
+    }
+
+    #[test]
+    fn parse_bytes() {
+        let ast = AST::parse(&b"+\xff\xfe[\x80-]+."[..]).unwrap();
+        assert_eq!(ast.data, AST::parse("+[-]+.").unwrap().data);
+
+        // Columns count characters, whatever their encoding
+        assert!(matches!(
+            AST::parse("\u{e9}\u{e9}[").unwrap_err(),
+            BfError::UnmatchedOpen { line: 1, col: 3 }
+        ));
+    }
+
+    #[test]
+    fn max_depth() {
+        assert!(AST::parse_with_max_depth("+[>[-]<-]", 2).is_ok());
+
+        let err = AST::parse_with_max_depth("+[>[-[+]]<-]", 2).unwrap_err();
+        assert!(matches!(
+            err,
+            BfError::TooDeep {
+                limit: 2,
+                line: 1,
+                col: 6
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Loops nested deeper than 2 at line 1, col 6"
+        );
+    }
+
+    #[test]
+    fn debug_ext() {
+        assert_eq!(AST::parse("+$").unwrap().data.len(), 1);
+
+        let options = ParseOptions {
+            debug_ext: true,
+            ..ParseOptions::default()
+        };
+        let (ast, _) = AST::parse_with_options("+$", options).unwrap();
+        assert_eq!(
+            ast.data,
+            VecDeque::from(vec![ASTNode::Incr(1), ASTNode::DebugPrintValue])
+        );
     }
 
     #[test]
@@ -136,4 +1206,580 @@ mod tests {
         assert_eq!(ast.data.len(), 1);
         assert_eq!(ast.data[0], ASTNode::Incr(5));
     }
+
+    #[test]
+    fn print_repeat() {
+        let ast = AST::parse(".....").unwrap();
+        assert_eq!(ast.data, VecDeque::from(vec![ASTNode::PrintRepeat(5)]));
+
+        // Runs stop at anything that moves the pointer or changes the cell
+        let ast = AST::parse(",.>..+.. .").unwrap();
+        assert_eq!(
+            ast.data,
+            VecDeque::from(vec![
+                ASTNode::Read,
+                ASTNode::Print,
+                ASTNode::Next(1),
+                ASTNode::PrintRepeat(2),
+                ASTNode::Incr(1),
+                ASTNode::PrintRepeat(3),
+            ])
+        );
+    }
+
+    #[test]
+    fn run_length_no_pointer_wrap() {
+        let mut nodes: VecDeque<ASTNode> = vec![
+            ASTNode::Next(usize::MAX - 1),
+            ASTNode::Next(1),
+            ASTNode::Next(1),
+            ASTNode::Prev(usize::MAX),
+            ASTNode::Prev(2),
+        ]
+        .into();
+        let optimized = AST::shallow_run_length_optimize(
+            &mut nodes,
+            &mut Vec::new(),
+            CellArithmetic::Wrap,
+            &mut None,
+        );
+
+        assert_eq!(
+            optimized,
+            VecDeque::from(vec![
+                ASTNode::Next(usize::MAX),
+                ASTNode::Next(1),
+                ASTNode::Prev(usize::MAX),
+                ASTNode::Prev(2),
+            ])
+        );
+    }
+
+    #[test]
+    fn nested_clear_loop() {
+        let ast = AST::parse("+[>+[>+[-].<-]<-]").unwrap();
+        let inner = match ast.data.get(1) {
+            Some(ASTNode::Loop(outer)) => match outer.get(2) {
+                Some(ASTNode::Loop(inner)) => inner.clone(),
+                other => panic!("expected a loop, got {:?}", other),
+            },
+            other => panic!("expected a loop, got {:?}", other),
+        };
+
+        assert!(inner.contains(&ASTNode::Set(0)), "{:?}", inner);
+        assert!(!inner.iter().any(|node| matches!(node, ASTNode::Loop(_))));
+    }
+
+    #[test]
+    fn optimize_nested() {
+        use self::ASTNode::*;
+
+        let source = "+[>++[>+[-]>[-<+>]<.<-]<-]";
+        // The same program as the parser sees it, before any pass
+        let mut ast = AST {
+            data: VecDeque::from(vec![
+                Incr(1),
+                Loop(VecDeque::from(vec![
+                    Next(1),
+                    Incr(1),
+                    Incr(1),
+                    Loop(VecDeque::from(vec![
+                        Next(1),
+                        Incr(1),
+                        Loop(VecDeque::from(vec![Decr(1)])),
+                        Next(1),
+                        Loop(VecDeque::from(vec![Decr(1), Prev(1), Incr(1), Next(1)])),
+                        Prev(1),
+                        Print,
+                        Prev(1),
+                        Decr(1),
+                    ])),
+                    Prev(1),
+                    Decr(1),
+                ])),
+            ]),
+        };
+        let before = ast.iter_flat().count();
+
+        ast.optimize(CellArithmetic::Wrap);
+        let parsed = AST::parse(source).unwrap();
+
+        assert_eq!(ast.data, parsed.data);
+        assert!(ast.iter_flat().count() < before);
+        // Both innermost loops became a clear and a multiply
+        let inner: Vec<_> = ast
+            .iter_flat()
+            .filter(|&(depth, _)| depth == 2)
+            .map(|(_, node)| node)
+            .collect();
+        assert!(inner.contains(&&Set(0)));
+        assert!(inner.contains(&&MulAdd(-1, 1)));
+        assert!(!inner.iter().any(|node| matches!(node, Loop(_))));
+
+        let mut optimized = parsed.clone();
+        optimized.optimize(CellArithmetic::Wrap);
+        assert_eq!(optimized.data, parsed.data);
+    }
+
+    #[test]
+    fn clear_loop() {
+        let ast = AST::parse("+[-]>+[+]").unwrap();
+        assert_eq!(
+            ast.data,
+            VecDeque::from(vec![
+                ASTNode::Incr(1),
+                ASTNode::Set(0),
+                ASTNode::Next(1),
+                ASTNode::Incr(1),
+                ASTNode::Set(0),
+            ])
+        );
+    }
+
+    #[test]
+    fn multiply_loop() {
+        assert_eq!(
+            AST::parse("+[->+>+<<]").unwrap().data,
+            VecDeque::from(vec![
+                ASTNode::Incr(1),
+                ASTNode::MulAdd(1, 1),
+                ASTNode::MulAdd(2, 1),
+                ASTNode::Set(0),
+            ])
+        );
+        assert_eq!(
+            AST::parse("+[>>---<+++<-]").unwrap().data,
+            VecDeque::from(vec![
+                ASTNode::Incr(1),
+                ASTNode::MulAdd(1, 3),
+                ASTNode::MulAdd(2, 0xfd),
+                ASTNode::Set(0),
+            ])
+        );
+
+        // The pointer drifts, or the control cell does not count down by one
+        for source in &["+[->+<<]", "+[-->+<]", "+[>+<]"] {
+            let ast = AST::parse(source).unwrap();
+            assert!(matches!(ast.data[1], ASTNode::Loop(_)), "{}", source);
+        }
+    }
+
+    #[test]
+    fn multiply_offsets() {
+        for &distance in &[1, 2, 3, 7, 300] {
+            let right = format!("+[{}+{}-]", ">".repeat(distance), "<".repeat(distance));
+            let left = format!("+[-{}++{}]", "<".repeat(distance), ">".repeat(distance));
+
+            let ast = AST::parse(&right).unwrap();
+            assert_eq!(ast.data[1], ASTNode::MulAdd(distance as isize, 1));
+            let ast = AST::parse(&left).unwrap();
+            assert_eq!(ast.data[1], ASTNode::MulAdd(-(distance as isize), 2));
+        }
+
+        // Moves out and back in unequal steps, passing the start on the way
+        let body = VecDeque::from(vec![
+            ASTNode::Decr(1),
+            ASTNode::Next(7),
+            ASTNode::Incr(1),
+            ASTNode::Prev(4),
+            ASTNode::Prev(6),
+            ASTNode::Incr(2),
+            ASTNode::Next(1),
+            ASTNode::Next(2),
+        ]);
+        assert_eq!(AST::multiply_targets(&body), Some(vec![(-3, 2), (7, 1)]));
+    }
+
+    #[test]
+    fn odd_step_loop_cleared() {
+        assert_eq!(
+            AST::parse("+[-->+<->-<]>+[+++]").unwrap().data,
+            VecDeque::from(vec![
+                ASTNode::Incr(1),
+                ASTNode::Set(0),
+                ASTNode::Next(1),
+                ASTNode::Incr(1),
+                ASTNode::Set(0),
+            ])
+        );
+        // Even steps may skip over 0
+        assert!(matches!(
+            AST::parse("+[--]").unwrap().data[1],
+            ASTNode::Loop(_)
+        ));
+    }
+
+    #[test]
+    fn infinite_loop_warning() {
+        let (_, warnings) = AST::parse_with_warnings("+\n+[<>]>[+-][-][]").unwrap();
+        assert_eq!(
+            warnings,
+            vec![
+                Warning::InfiniteLoop { line: 2, col: 2 },
+                Warning::InfiniteLoop { line: 2, col: 7 },
+            ]
+        );
+    }
+
+    #[test]
+    fn loop_after_clear_removed() {
+        let mut nodes: VecDeque<ASTNode> = vec![
+            ASTNode::Loop(vec![ASTNode::Decr(1)].into()),
+            ASTNode::Loop(vec![ASTNode::Incr(2)].into()),
+        ]
+        .into();
+
+        assert_eq!(
+            AST::shallow_optimize(&mut nodes, &mut Vec::new(), CellArithmetic::Wrap),
+            VecDeque::from(vec![ASTNode::Set(0)])
+        );
+        assert_eq!(
+            AST::parse("+[-][++]").unwrap().data,
+            VecDeque::from(vec![ASTNode::Incr(1), ASTNode::Set(0)])
+        );
+    }
+
+    #[test]
+    fn canonical_loops() {
+        let ast = AST::parse("+[->+<]>[>+<-]>[>>-<+<-]").unwrap();
+
+        assert!(ast.data.range(1..3).eq(ast.data.range(4..6)));
+        assert_eq!(
+            ast.data.range(7..).cloned().collect::<Vec<_>>(),
+            vec![
+                ASTNode::MulAdd(1, 1),
+                ASTNode::MulAdd(2, 0xff),
+                ASTNode::Set(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn canonical_kept_loops() {
+        // Stepping by 2 keeps these from becoming multiply-adds
+        let ast = AST::parse("+[-->+<]>[>+<--]>[>>-<+<--]").unwrap();
+
+        assert_eq!(ast.data[1], ast.data[3]);
+        assert_eq!(
+            ast.data[5],
+            ASTNode::Loop(
+                vec![
+                    ASTNode::Decr(2),
+                    ASTNode::Next(1),
+                    ASTNode::Incr(1),
+                    ASTNode::Next(1),
+                    ASTNode::Decr(1),
+                    ASTNode::Prev(2),
+                ]
+                .into()
+            )
+        );
+    }
+
+    #[test]
+    fn canonical_sets() {
+        let body: VecDeque<ASTNode> = vec![
+            ASTNode::Incr(3),
+            ASTNode::Set(0),
+            ASTNode::Incr(2),
+            ASTNode::Prev(1),
+            ASTNode::Incr(1),
+            ASTNode::Decr(1),
+            ASTNode::Next(2),
+            ASTNode::Print,
+        ]
+        .into();
+
+        assert_eq!(
+            AST::shallow_canonicalize(body),
+            VecDeque::from(vec![ASTNode::Set(2), ASTNode::Next(1), ASTNode::Print,])
+        );
+    }
+
+    #[test]
+    fn print_literal() {
+        let fused = |source: &str| {
+            let mut ast = AST::parse(source).unwrap();
+            ast.fuse_print_literals();
+            ast.data
+        };
+
+        let source = "+".repeat(b'A' as usize) + ".+.+.+.+.";
+        assert_eq!(
+            fused(&source),
+            VecDeque::from(vec![
+                ASTNode::Incr(b'A'),
+                ASTNode::PrintLiteral(b"ABCDE".to_vec()),
+                ASTNode::Incr(4),
+            ])
+        );
+
+        // Reading input ends the literal, and the read cell is not known
+        assert_eq!(
+            fused("+.>++.<,.>."),
+            VecDeque::from(vec![
+                ASTNode::Incr(1),
+                ASTNode::PrintLiteral(vec![1, 2]),
+                ASTNode::Next(1),
+                ASTNode::Incr(2),
+                ASTNode::Prev(1),
+                ASTNode::Read,
+                ASTNode::Print,
+                ASTNode::Next(1),
+                ASTNode::Print,
+            ])
+        );
+    }
+
+    #[test]
+    fn print_literal_after_loop() {
+        // Only the cell a loop ends on is known afterwards
+        let mut ast = AST::parse(",[>+<-]+.+.>.").unwrap();
+        ast.fuse_print_literals();
+        assert_eq!(
+            ast.data,
+            VecDeque::from(vec![
+                ASTNode::Read,
+                ASTNode::MulAdd(1, 1),
+                ASTNode::Set(0),
+                ASTNode::Incr(1),
+                ASTNode::PrintLiteral(vec![1, 2]),
+                ASTNode::Incr(1),
+                ASTNode::Next(1),
+                ASTNode::Print,
+            ])
+        );
+    }
+
+    #[test]
+    fn print_literal_before_left_move() {
+        // Going back over cells already seen cannot leave the tape
+        let mut ast = AST::parse("+.>+.<.").unwrap();
+        ast.fuse_print_literals();
+        assert_eq!(ast.data[1], ASTNode::PrintLiteral(vec![1, 1, 1]));
+
+        // Going further left might, so the literal ends before that
+        let mut ast = AST::parse(",>+.+.<<.").unwrap();
+        ast.fuse_print_literals();
+        assert_eq!(
+            ast.data,
+            VecDeque::from(vec![
+                ASTNode::Read,
+                ASTNode::Next(1),
+                ASTNode::Incr(1),
+                ASTNode::PrintLiteral(vec![1, 2]),
+                ASTNode::Incr(1),
+                ASTNode::Prev(2),
+                ASTNode::Print,
+            ])
+        );
+    }
+
+    #[test]
+    fn print_literal_after_kept_loop() {
+        // Stepping by 2 keeps this from becoming a multiply-add
+        let mut ast = AST::parse(",[>+<--]+.+.>.").unwrap();
+        ast.fuse_print_literals();
+        assert_eq!(
+            ast.data,
+            VecDeque::from(vec![
+                ASTNode::Read,
+                ASTNode::Loop(
+                    vec![
+                        ASTNode::Decr(2),
+                        ASTNode::Next(1),
+                        ASTNode::Incr(1),
+                        ASTNode::Prev(1),
+                    ]
+                    .into()
+                ),
+                ASTNode::Incr(1),
+                ASTNode::PrintLiteral(vec![1, 2]),
+                ASTNode::Incr(1),
+                ASTNode::Next(1),
+                ASTNode::Print,
+            ])
+        );
+    }
+
+    #[test]
+    fn set_range() {
+        let mut ast = AST::parse("+>+>+>+>+>+<<<<<[-]>[-]>[-]>[-]>[-]>+[>[-]>[-]>-]").unwrap();
+        ast.fuse_set_ranges();
+
+        assert_eq!(
+            ast.data.iter().skip(12).collect::<Vec<_>>(),
+            vec![
+                &ASTNode::SetRange(5, 0),
+                &ASTNode::Incr(1),
+                &ASTNode::Loop(VecDeque::from(vec![
+                    ASTNode::Next(1),
+                    ASTNode::SetRange(2, 0),
+                    ASTNode::Decr(1),
+                ])),
+            ]
+        );
+
+        let mut single = AST::parse("+[-]>+").unwrap();
+        let before = single.clone();
+        single.fuse_set_ranges();
+        assert_eq!(single.data, before.data);
+    }
+
+    #[test]
+    fn validate() {
+        let mut program = AST::parse("+>++[-<+++>]<,[.,]").unwrap();
+        assert!(program.validate().is_ok());
+
+        let last = program.data.len() - 1;
+        match &mut program.data[last] {
+            ASTNode::Loop(body) => body.push_back(ASTNode::Next(usize::MAX)),
+            node => panic!("Expected a loop, got {:?}", node),
+        }
+        assert!(matches!(
+            program.validate(),
+            Err(BfError::InvalidNode { ref path, .. }) if path == &[last, 2]
+        ));
+
+        let program = AST {
+            data: VecDeque::from(vec![ASTNode::Incr(1), ASTNode::MulAdd(0, 2)]),
+        };
+        assert_eq!(
+            program.validate().unwrap_err().to_string(),
+            "Invalid node at 1: the target cell is the counter"
+        );
+    }
+
+    #[test]
+    fn known_multiply() {
+        let mut program = AST {
+            data: VecDeque::from(vec![
+                ASTNode::Set(4),
+                ASTNode::MulAdd(1, 3),
+                ASTNode::Set(0),
+            ]),
+        };
+        program.fold_known_multiplies();
+        assert_eq!(
+            program.data,
+            VecDeque::from(vec![ASTNode::AddTo(1, 12), ASTNode::Set(0)])
+        );
+
+        // The value is built by arithmetic after a read, and the products wrap
+        let source = format!(",[-]++++[-<{}>>+++<]>.,[->++<]", "+".repeat(100));
+        let mut program = AST::parse(&source).unwrap();
+        program.fold_known_multiplies();
+        assert_eq!(
+            program.data.range(..4).cloned().collect::<Vec<_>>(),
+            vec![
+                ASTNode::Read,
+                ASTNode::AddTo(-1, 0x90),
+                ASTNode::AddTo(1, 12),
+                ASTNode::Set(0),
+            ]
+        );
+        // Unknown after a read
+        assert!(program.data.contains(&ASTNode::MulAdd(1, 2)));
+    }
+
+    #[test]
+    fn iter_flat() {
+        let ast = AST::parse("+[>+]").unwrap();
+        let nodes: Vec<(usize, &ASTNode)> = ast.iter_flat().collect();
+        let body: VecDeque<ASTNode> = vec![ASTNode::Next(1), ASTNode::Incr(1)].into();
+
+        assert_eq!(
+            nodes,
+            vec![
+                (0, &ASTNode::Incr(1)),
+                (0, &ASTNode::Loop(body)),
+                (1, &ASTNode::Next(1)),
+                (1, &ASTNode::Incr(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_and_writes() {
+        let io = |source: &str| {
+            let ast = AST::parse(source).unwrap();
+            (ast.reads_input(), ast.writes_output())
+        };
+
+        assert_eq!(io("+++>++<-"), (false, false));
+        assert_eq!(io("+[>,<-]"), (true, false));
+        assert_eq!(io("+[>[.]<-]"), (false, true));
+        assert_eq!(io(",."), (true, true));
+        // Only reachable through a dead loop, which is removed
+        assert_eq!(io("[,.]+"), (false, false));
+
+        let (ast, _) = AST::parse_with_options(
+            "+$",
+            ParseOptions {
+                debug_ext: true,
+                ..ParseOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(!ast.writes_output());
+    }
+
+    #[test]
+    fn dead_loop_warning() {
+        let (ast, warnings) = AST::parse_with_warnings("[-]+++++").unwrap();
+        assert_eq!(ast.data.len(), 1);
+        assert_eq!(warnings, vec![Warning::DeadLoopRemoved { line: 1, col: 1 }]);
+    }
+
+    #[test]
+    fn strict_dead_loop() {
+        let strict = ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        };
+
+        assert!(matches!(
+            AST::parse_with_options("\n  [-]+", strict),
+            Err(BfError::DeadLoop { line: 2, col: 3 })
+        ));
+        assert!(matches!(
+            AST::parse_with_spans("[[-]]+", strict),
+            Err(BfError::DeadLoop { line: 1, col: 1 })
+        ));
+        // Loops after the first command may run
+        assert!(AST::parse_with_options("+[-][-]", strict).is_ok());
+        assert!(AST::parse_with_spans("+[[-]]", strict).is_ok());
+    }
+
+    #[test]
+    fn snippet_leading_loop() {
+        let ast = AST::parse_snippet("[->+<]>").unwrap();
+        assert_eq!(
+            ast.data,
+            [ASTNode::MulAdd(1, 1), ASTNode::Set(0), ASTNode::Next(1)]
+        );
+
+        let ast = AST::parse_snippet("[-->++<]").unwrap();
+        assert_eq!(ast.data.len(), 1);
+        assert!(matches!(ast.data[0], ASTNode::Loop(_)));
+    }
+
+    #[test]
+    fn eof_loop_warning() {
+        let ast = AST::parse(",[.,]").unwrap();
+        assert_eq!(
+            ast.eof_warnings(EofMode::Unchanged),
+            vec![Warning::EofLoop {
+                eof: EofMode::Unchanged
+            }]
+        );
+        assert!(ast.eof_warnings(EofMode::Zero).is_empty());
+    }
+
+    #[test]
+    fn run_length_wrap_warning() {
+        let (ast, warnings) = AST::parse_with_warnings(&"+".repeat(257)).unwrap();
+        assert_eq!(ast.data[0], ASTNode::Incr(1));
+        assert_eq!(warnings, vec![Warning::RunLengthWrapped { command: '+' }]);
+    }
 }
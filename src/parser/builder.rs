@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+use std::mem;
+
+use super::super::runnable::CellArithmetic;
+use super::{ASTNode, AST};
+
+/// Construct an AST directly, without going through BrainFuck source.
+///
+/// Consecutive arithmetic and pointer moves are merged as they are added, and
+/// loops go through the same peephole passes as in the parser, so the result
+/// matches what `AST::parse` produces for the equivalent source.
+#[derive(Debug, Default)]
+pub struct ASTBuilder {
+    nodes: VecDeque<ASTNode>,
+    /// Whether this builds a whole program rather than a loop body.
+    top_level: bool,
+}
+
+impl ASTBuilder {
+    pub fn new() -> Self {
+        ASTBuilder {
+            nodes: VecDeque::new(),
+            top_level: true,
+        }
+    }
+
+    /// Add `n` to the current cell.
+    pub fn incr(&mut self, n: u8) -> &mut Self {
+        if n == 0 {
+            return self;
+        }
+
+        match self.nodes.back_mut() {
+            Some(ASTNode::Incr(m)) => *m = m.wrapping_add(n),
+            _ => self.nodes.push_back(ASTNode::Incr(n)),
+        }
+
+        self
+    }
+
+    /// Subtract `n` from the current cell.
+    pub fn decr(&mut self, n: u8) -> &mut Self {
+        if n == 0 {
+            return self;
+        }
+
+        match self.nodes.back_mut() {
+            Some(ASTNode::Decr(m)) => *m = m.wrapping_add(n),
+            _ => self.nodes.push_back(ASTNode::Decr(n)),
+        }
+
+        self
+    }
+
+    /// Move the data pointer `n` cells to the right.
+    pub fn next(&mut self, n: usize) -> &mut Self {
+        if n == 0 {
+            return self;
+        }
+
+        match self.nodes.back_mut() {
+            Some(ASTNode::Next(m)) if m.checked_add(n).is_some() => *m += n,
+            _ => self.nodes.push_back(ASTNode::Next(n)),
+        }
+
+        self
+    }
+
+    /// Move the data pointer `n` cells to the left.
+    pub fn prev(&mut self, n: usize) -> &mut Self {
+        if n == 0 {
+            return self;
+        }
+
+        match self.nodes.back_mut() {
+            Some(ASTNode::Prev(m)) if m.checked_add(n).is_some() => *m += n,
+            _ => self.nodes.push_back(ASTNode::Prev(n)),
+        }
+
+        self
+    }
+
+    /// Output the current cell.
+    pub fn print(&mut self) -> &mut Self {
+        self.nodes.push_back(ASTNode::Print);
+
+        self
+    }
+
+    /// Read a byte into the current cell.
+    pub fn read(&mut self) -> &mut Self {
+        self.nodes.push_back(ASTNode::Read);
+
+        self
+    }
+
+    /// Add a loop whose body is built by `body`.
+    ///
+    /// Like the parser, a loop at the very start of the program is dropped
+    /// since every cell starts at 0.
+    pub fn loop_<F: FnOnce(&mut ASTBuilder)>(&mut self, body: F) -> &mut Self {
+        let mut inner = ASTBuilder::default();
+        body(&mut inner);
+
+        if !(self.top_level && self.nodes.is_empty()) {
+            let body =
+                AST::shallow_optimize(&mut inner.nodes, &mut Vec::new(), CellArithmetic::Wrap);
+            self.nodes
+                .push_back(ASTNode::Loop(AST::shallow_canonicalize(body)));
+        }
+
+        self
+    }
+
+    /// Take the nodes added so far, leaving the builder empty.
+    pub fn build(&mut self) -> AST {
+        AST {
+            data: AST::shallow_optimize(
+                &mut mem::take(&mut self.nodes),
+                &mut Vec::new(),
+                CellArithmetic::Wrap,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_parser() {
+        let ast = AST::builder()
+            .incr(1)
+            .incr(1)
+            .loop_(|body| {
+                body.next(1).incr(1).prev(1).decr(1);
+            })
+            .build();
+
+        assert_eq!(ast.data, AST::parse("++[>+<-]").unwrap().data);
+    }
+
+    #[test]
+    fn leading_loop_dropped() {
+        let ast = AST::builder()
+            .loop_(|body| {
+                body.print();
+            })
+            .read()
+            .loop_(|body| {
+                body.loop_(|inner| {
+                    inner.decr(1);
+                });
+            })
+            .build();
+
+        assert_eq!(ast.data, AST::parse("[.],[[-]]").unwrap().data);
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::super::error::BfError;
+
+/// A language whose tokens map one-to-one onto BrainFuck commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dialect {
+    /// Plain BrainFuck, passed through untouched.
+    #[default]
+    BrainFuck,
+    /// Ook!, where each command is a pair of `Ook.`, `Ook?` or `Ook!`.
+    Ook,
+}
+
+/// Ook! token pairs and the command each stands for.
+const OOK_COMMANDS: [((char, char), char); 8] = [
+    (('.', '?'), '>'),
+    (('?', '.'), '<'),
+    (('.', '.'), '+'),
+    (('!', '!'), '-'),
+    (('!', '.'), '.'),
+    (('.', '!'), ','),
+    (('!', '?'), '['),
+    (('?', '!'), ']'),
+];
+
+impl Dialect {
+    /// Rewrite a program written in this dialect as BrainFuck source.
+    pub fn to_brainfuck(self, source: &str) -> Result<String, BfError> {
+        match self {
+            Dialect::BrainFuck => Ok(source.to_owned()),
+            Dialect::Ook => translate_pairs(source, "Ook", &OOK_COMMANDS),
+        }
+    }
+}
+
+/// Translate a dialect where every command is two `word` tokens, each
+/// suffixed by a punctuation mark. Text that is not a token is ignored.
+fn translate_pairs(
+    source: &str,
+    word: &str,
+    commands: &[((char, char), char)],
+) -> Result<String, BfError> {
+    let marks: Vec<char> = source
+        .match_indices(word)
+        .filter_map(|(index, _)| source[index + word.len()..].chars().next())
+        .filter(|mark| ".?!".contains(*mark))
+        .collect();
+
+    if !marks.len().is_multiple_of(2) {
+        return Err(BfError::Dialect(format!("Unpaired {} token", word)));
+    }
+
+    marks
+        .chunks(2)
+        .map(|pair| {
+            commands
+                .iter()
+                .find(|(tokens, _)| *tokens == (pair[0], pair[1]))
+                .map(|(_, command)| *command)
+                .ok_or_else(|| {
+                    BfError::Dialect(format!(
+                        "Unknown command: {0}{1} {0}{2}",
+                        word, pair[0], pair[1]
+                    ))
+                })
+        })
+        .collect()
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bf" | "brainfuck" => Ok(Dialect::BrainFuck),
+            "ook" => Ok(Dialect::Ook),
+            _ => Err(format!("Unknown dialect: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Dialect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Dialect::BrainFuck => write!(f, "brainfuck"),
+            Dialect::Ook => write!(f, "ook"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::super::super::runnable::{Fucker, Runnable};
+    use super::super::AST;
+    use super::*;
+
+    /// Interpret a BrainFuck program and collect its output.
+    fn run(source: &str) -> Vec<u8> {
+        let ast = AST::parse(source).unwrap();
+        let mut output = Vec::new();
+        Fucker::new(&ast.data).run_with_io(&mut io::empty(), &mut output);
+
+        output
+    }
+
+    #[test]
+    fn ook_hello_world() {
+        let ook = include_str!("../../test/programs/hello_world.ook");
+        let translated = Dialect::Ook.to_brainfuck(ook).unwrap();
+
+        assert_eq!(run(&translated), b"Hello World!\n");
+    }
+
+    #[test]
+    fn ook_errors() {
+        let unpaired = Dialect::Ook.to_brainfuck("Ook. Ook? Ook.").unwrap_err();
+        assert_eq!(unpaired.to_string(), "Unpaired Ook token");
+
+        let unknown = Dialect::Ook.to_brainfuck("Ook? Ook?").unwrap_err();
+        assert_eq!(unknown.to_string(), "Unknown command: Ook? Ook?");
+    }
+}
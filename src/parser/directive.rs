@@ -0,0 +1,156 @@
+use std::fmt;
+
+use super::super::runnable::EofMode;
+use super::Warning;
+
+/// Runtime settings a program asks for on its first line, written as a
+/// comment such as `;tape=65536;eof=zero`.
+///
+/// The line only counts as directives if it starts with `;` and every item
+/// is a `key=value` pair, so ordinary comments are left alone.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Directives {
+    /// Number of cells on the tape.
+    pub tape_size: Option<usize>,
+    pub eof: Option<EofMode>,
+}
+
+impl Directives {
+    /// Read the directives from the first line of `source`.
+    ///
+    /// Also returns the source with that line emptied, since values like
+    /// `negative-one` would otherwise be run as commands. Unknown keys and
+    /// unusable values are skipped with a warning.
+    pub fn extract(source: &str) -> (Self, String, Vec<Warning>) {
+        let mut directives = Directives::default();
+        let mut warnings = Vec::new();
+
+        let (first_line, rest) = match source.find('\n') {
+            Some(end) => source.split_at(end),
+            None => (source, ""),
+        };
+
+        let pairs: Option<Vec<(&str, &str)>> = match first_line.trim_end().strip_prefix(';') {
+            Some(line) => line
+                .split(';')
+                .filter(|item| !item.trim().is_empty())
+                .map(|item| {
+                    let mut parts = item.splitn(2, '=');
+                    Some((parts.next()?.trim(), parts.next()?.trim()))
+                })
+                .collect(),
+            None => None,
+        };
+
+        let pairs = match pairs {
+            Some(pairs) => pairs,
+            None => return (directives, source.to_owned(), warnings),
+        };
+
+        for (key, value) in pairs {
+            let valid = match key {
+                "tape" => match value.parse() {
+                    Ok(size) if size > 0 => {
+                        directives.tape_size = Some(size);
+                        true
+                    }
+                    _ => false,
+                },
+                "eof" => match value.parse() {
+                    Ok(eof) => {
+                        directives.eof = Some(eof);
+                        true
+                    }
+                    Err(_) => false,
+                },
+                _ => {
+                    warnings.push(Warning::UnknownDirective {
+                        key: key.to_owned(),
+                    });
+                    continue;
+                }
+            };
+
+            if !valid {
+                warnings.push(Warning::InvalidDirective {
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                });
+            }
+        }
+
+        (directives, rest.to_owned(), warnings)
+    }
+}
+
+/// Write the directives back out as a first line, or nothing if there are
+/// none.
+impl fmt::Display for Directives {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(size) = self.tape_size {
+            write!(f, ";tape={}", size)?;
+        }
+        if let Some(eof) = self.eof {
+            write!(f, ";eof={}", eof)?;
+        }
+        if *self != Directives::default() {
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract() {
+        let (directives, source, warnings) =
+            Directives::extract(";tape=65536;eof=negative-one\n+.");
+
+        assert_eq!(
+            directives,
+            Directives {
+                tape_size: Some(65536),
+                eof: Some(EofMode::NegativeOne),
+            }
+        );
+        assert_eq!(source, "\n+.");
+        assert!(warnings.is_empty());
+
+        assert_eq!(directives.to_string(), ";tape=65536;eof=negative-one\n");
+        assert_eq!(Directives::default().to_string(), "");
+    }
+
+    #[test]
+    fn not_directives() {
+        let source = "; prints a + b\n,>,[<+>-]<.";
+        let (directives, unchanged, warnings) = Directives::extract(source);
+
+        assert_eq!(directives, Directives::default());
+        assert_eq!(unchanged, source);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn bad_directives() {
+        let (directives, _, warnings) = Directives::extract(";cell=16;tape=0;eof=zero");
+
+        assert_eq!(directives.eof, Some(EofMode::Zero));
+        assert_eq!(directives.tape_size, None);
+        assert_eq!(
+            warnings,
+            vec![
+                Warning::UnknownDirective {
+                    key: "cell".to_owned()
+                },
+                Warning::InvalidDirective {
+                    key: "tape".to_owned(),
+                    value: "0".to_owned()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::iter;
+
+use super::{ASTNode, AST};
+
+/// Default number of nodes `AST::fold_static_output` will execute before
+/// deciding the program is too expensive to evaluate at compile time.
+pub const STEP_LIMIT: usize = 1_000_000;
+
+/// Runs a program at compile time, see `AST::static_output`.
+struct Evaluator {
+    tape: Vec<u8>,
+    dp: usize,
+    output: Vec<u8>,
+    steps_left: usize,
+}
+
+impl Evaluator {
+    /// Execute `nodes`, returning None if the program can't be evaluated.
+    fn run(&mut self, nodes: &VecDeque<ASTNode>) -> Option<()> {
+        for node in nodes {
+            self.steps_left = self.steps_left.checked_sub(1)?;
+
+            if self.dp >= self.tape.len() {
+                self.tape.resize(self.dp + 1, 0);
+            }
+
+            match node {
+                ASTNode::Incr(n) => self.tape[self.dp] = self.tape[self.dp].wrapping_add(*n),
+                ASTNode::Decr(n) => self.tape[self.dp] = self.tape[self.dp].wrapping_sub(*n),
+                ASTNode::Set(n) => self.tape[self.dp] = *n,
+                ASTNode::SetRange(len, n) => {
+                    let end = self.dp.checked_add(*len)?;
+                    if end > self.tape.len() {
+                        self.tape.resize(end, 0);
+                    }
+                    self.tape[self.dp..end].fill(*n);
+                    self.dp = end;
+                }
+                ASTNode::MulAdd(offset, factor) => {
+                    let value = self.tape[self.dp];
+                    if value != 0 {
+                        self.add_to(*offset, value.wrapping_mul(*factor))?;
+                    }
+                }
+                ASTNode::AddTo(offset, n) => self.add_to(*offset, *n)?,
+                ASTNode::Next(n) => self.dp = self.dp.checked_add(*n)?,
+                ASTNode::Prev(n) => self.dp = self.dp.checked_sub(*n)?,
+                ASTNode::Print => self.output.push(self.tape[self.dp]),
+                ASTNode::PrintRepeat(n) => {
+                    let byte = self.tape[self.dp];
+                    self.output.extend(iter::repeat_n(byte, *n));
+                }
+                ASTNode::PrintLiteral(bytes) => self.output.extend(bytes),
+                // These would be lost when folding into a literal print
+                ASTNode::Read | ASTNode::DebugPrintValue | ASTNode::Breakpoint => return None,
+                ASTNode::Loop(body) => {
+                    while self.tape.get(self.dp).cloned().unwrap_or(0) != 0 {
+                        self.steps_left = self.steps_left.checked_sub(1)?;
+                        self.run(body)?;
+                    }
+                }
+            }
+        }
+
+        Some(())
+    }
+
+    /// Add `n` to the cell `offset` cells from the data pointer, returning
+    /// None left of cell 0.
+    fn add_to(&mut self, offset: isize, n: u8) -> Option<()> {
+        let target = (self.dp as isize).checked_add(offset)?;
+        let target = usize::try_from(target).ok()?;
+        if target >= self.tape.len() {
+            self.tape.resize(target + 1, 0);
+        }
+        self.tape[target] = self.tape[target].wrapping_add(n);
+
+        Some(())
+    }
+}
+
+impl AST {
+    /// Compute the output of a program that takes no input by running it.
+    ///
+    /// Returns None if the program reads input, moves left of cell 0, or has
+    /// not finished after `step_limit` nodes.
+    pub fn static_output(&self, step_limit: usize) -> Option<Vec<u8>> {
+        // Spares running a program that is bound to stop at its first read
+        if self.reads_input() {
+            return None;
+        }
+
+        let mut evaluator = Evaluator {
+            tape: Vec::new(),
+            dp: 0,
+            output: Vec::new(),
+            steps_left: step_limit,
+        };
+
+        evaluator.run(&self.data)?;
+
+        Some(evaluator.output)
+    }
+
+    /// Replace the whole program with a literal print of its output when
+    /// `static_output` can compute it. Returns whether the program changed.
+    ///
+    /// This discards the final state of the tape.
+    pub fn fold_static_output(&mut self, step_limit: usize) -> bool {
+        match self.static_output(step_limit) {
+            Some(output) => {
+                self.data.clear();
+
+                if !output.is_empty() {
+                    self.data.push_back(ASTNode::PrintLiteral(output));
+                }
+
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fold_hello_world() {
+        let mut ast = AST::parse(include_str!("../../test/programs/hello_world.bf")).unwrap();
+
+        assert!(ast.fold_static_output(STEP_LIMIT));
+        assert_eq!(
+            ast.data,
+            VecDeque::from(vec![ASTNode::PrintLiteral(b"Hello World!\n".to_vec())])
+        );
+    }
+
+    #[test]
+    fn not_static() {
+        // Reads input
+        assert_eq!(AST::parse("+.,.").unwrap().static_output(STEP_LIMIT), None);
+        // Never terminates
+        assert_eq!(AST::parse("+[]").unwrap().static_output(STEP_LIMIT), None);
+        // Moves left of the first cell
+        assert_eq!(AST::parse("+<").unwrap().static_output(STEP_LIMIT), None);
+    }
+}
@@ -1,26 +1,91 @@
 use std::fmt;
 
 /// BrainFuck instruction
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub enum Instr {
     /// Add to the current memory cell.
     Incr(u8),
     /// Remove from the current memory cell.
     Decr(u8),
+    /// Overwrite the current memory cell.
+    Set(u8),
+    /// Overwrite the contained number of cells from the current one on and
+    /// move the data pointer past them.
+    SetRange(usize, u8),
+    /// Add the current memory cell times the factor to the cell at the
+    /// offset.
+    MulAdd(isize, u8),
+    /// Add to the cell at the offset, leaving the data pointer alone.
+    AddTo(isize, u8),
+    /// Move the data pointer by the offset, then add to the cell it lands on.
+    IncrAt(isize, u8),
+    /// Move the data pointer by the offset, then remove from the cell.
+    DecrAt(isize, u8),
+    /// Move the data pointer by the offset, then overwrite the cell.
+    SetAt(isize, u8),
+    /// Move the data pointer by the offset, then display the cell.
+    PrintAt(isize),
+    /// Move the data pointer by the offset, then read into the cell.
+    ReadAt(isize),
     /// Shift the data pointer to the right.
     Next(usize),
     /// Shift the data pointer to the left.
     Prev(usize),
     /// Display the current memory cell as an ASCII character.
     Print,
+    /// Display the current memory cell the contained number of times.
+    PrintRepeat(usize),
+    /// Output the literal with the contained index.
+    PrintLiteral(usize),
     /// Read one character from stdin.
     Read,
+    /// Write the current memory cell as a decimal number to stderr.
+    DebugPrintValue,
+    /// Do nothing, but stop `Fucker::run_to_breakpoint`.
+    Breakpoint,
     /// If the current memory cell is 0, jump forward by the contained offset.
     BeginLoop(usize),
     /// If the current memory cell is not 0, jump backward by the contained offset.
     EndLoop(usize),
 }
 
+impl Instr {
+    /// The same instruction with a move of the data pointer by `offset` in
+    /// front, if there is a form for that.
+    pub fn after_move(self, offset: isize) -> Option<Instr> {
+        match self {
+            Instr::Incr(n) => Some(Instr::IncrAt(offset, n)),
+            Instr::Decr(n) => Some(Instr::DecrAt(offset, n)),
+            Instr::Set(n) => Some(Instr::SetAt(offset, n)),
+            Instr::Print => Some(Instr::PrintAt(offset)),
+            Instr::Read => Some(Instr::ReadAt(offset)),
+            _ => None,
+        }
+    }
+
+    /// The move and the plain instruction an instruction from `after_move`
+    /// consists of.
+    pub fn split_move(self) -> Option<(isize, Instr)> {
+        match self {
+            Instr::IncrAt(offset, n) => Some((offset, Instr::Incr(n))),
+            Instr::DecrAt(offset, n) => Some((offset, Instr::Decr(n))),
+            Instr::SetAt(offset, n) => Some((offset, Instr::Set(n))),
+            Instr::PrintAt(offset) => Some((offset, Instr::Print)),
+            Instr::ReadAt(offset) => Some((offset, Instr::Read)),
+            _ => None,
+        }
+    }
+
+    /// Steps running this instruction counts as: two for one from
+    /// `after_move`, so that fusing moves leaves step counts alone.
+    pub fn steps(self) -> u64 {
+        match self.split_move() {
+            Some(_) => 2,
+            None => 1,
+        }
+    }
+}
+
 /// Display Instr similar to assembly.
 impl fmt::Debug for Instr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -29,12 +94,25 @@ impl fmt::Debug for Instr {
             Instr::Incr(n) => write!(f, "ADD\t0x{:04X}", n),
             Instr::Decr(1) => write!(f, "DEC"),
             Instr::Decr(n) => write!(f, "SUB\t0x{:04X}", n),
+            Instr::Set(n) => write!(f, "SET\t0x{:04X}", n),
+            Instr::SetRange(len, n) => write!(f, "SETRANGE\t0x{:04X}, 0x{:04X}", len, n),
+            Instr::MulAdd(offset, factor) => write!(f, "MULADD\t{:+}, 0x{:04X}", offset, factor),
+            Instr::AddTo(offset, n) => write!(f, "ADDTO\t{:+}, 0x{:04X}", offset, n),
+            Instr::IncrAt(offset, n) => write!(f, "ADDAT\t{:+}, 0x{:04X}", offset, n),
+            Instr::DecrAt(offset, n) => write!(f, "SUBAT\t{:+}, 0x{:04X}", offset, n),
+            Instr::SetAt(offset, n) => write!(f, "SETAT\t{:+}, 0x{:04X}", offset, n),
+            Instr::PrintAt(offset) => write!(f, "PRINTAT\t{:+}", offset),
+            Instr::ReadAt(offset) => write!(f, "READAT\t{:+}", offset),
             Instr::Next(1) => write!(f, "NEXT"),
             Instr::Next(n) => write!(f, "NEXT\t0x{:04X}", n),
             Instr::Prev(1) => write!(f, "PREV"),
             Instr::Prev(n) => write!(f, "PREV\t0x{:04X}", n),
             Instr::Print => write!(f, "PRINT"),
+            Instr::PrintRepeat(n) => write!(f, "PRINTN\t0x{:04X}", n),
+            Instr::PrintLiteral(index) => write!(f, "PRINTS\t0x{:04X}", index),
             Instr::Read => write!(f, "READ"),
+            Instr::DebugPrintValue => write!(f, "DEBUG"),
+            Instr::Breakpoint => write!(f, "BREAK"),
             Instr::BeginLoop(end_pos) => write!(f, "BEGIN\t0x{:04X}", end_pos),
             Instr::EndLoop(ret_pos) => write!(f, "END\t0x{:04X}", ret_pos),
         }
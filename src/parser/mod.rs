@@ -1,5 +1,22 @@
 mod ast;
+mod builder;
+mod dialect;
+mod directive;
+mod evaluate;
 mod instr;
+mod source;
+mod span;
+mod stats;
+mod tree;
+mod warning;
 
-pub use self::ast::{ASTNode, AST};
+pub(crate) use self::ast::validate_nodes;
+pub use self::ast::{ASTNode, FlatIter, ParseOptions, AST};
+pub use self::builder::ASTBuilder;
+pub use self::dialect::Dialect;
+pub use self::directive::Directives;
+pub use self::evaluate::STEP_LIMIT;
 pub use self::instr::Instr;
+pub use self::span::{NodeSpan, SourceMap, Span};
+pub use self::stats::Stats;
+pub use self::warning::Warning;
@@ -0,0 +1,309 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use super::{ASTNode, AST};
+
+/// Write the program back out as BrainFuck, using as few commands as the
+/// optimized nodes allow.
+///
+/// Runs of arithmetic take the shorter way around, so adding 255 becomes a
+/// single `-`, and `[-]` is left out where the cell is already known to be 0.
+/// `PrintLiteral` is spelled out on the current cell, starting from the value
+/// the cell is known to have there and putting that value back afterwards,
+/// so the tape is left as it was. Every literal the passes build prints
+/// known values, only a hand built literal on a cell of unknown value has
+/// to clear the cell first.
+impl fmt::Display for AST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_nodes(f, &AST::shallow_canonicalize(self.data.clone()), true)
+    }
+}
+
+/// Write `nodes`, where `fresh` says every cell starts out 0.
+fn write_nodes(f: &mut fmt::Formatter, nodes: &VecDeque<ASTNode>, fresh: bool) -> fmt::Result {
+    let mut known = Known::new(fresh);
+    let mut fresh = fresh;
+    // Whether the current cell is 0 after the node just written
+    let mut zero = false;
+
+    let mut nodes = nodes.iter().peekable();
+
+    while let Some(node) = nodes.next() {
+        let known_zero = fresh || zero;
+        zero = false;
+        let value = known.value();
+        known.apply(node);
+
+        match node {
+            ASTNode::Incr(n) => write_add(f, *n)?,
+            ASTNode::Decr(n) => write_add(f, n.wrapping_neg())?,
+            ASTNode::Set(n) => {
+                if !known_zero {
+                    write!(f, "[-]")?;
+                }
+                write_add(f, *n)?;
+            }
+            ASTNode::SetRange(len, n) => {
+                for cell in 0..*len {
+                    if !(fresh || (cell == 0 && known_zero)) {
+                        write!(f, "[-]")?;
+                    }
+                    write_add(f, *n)?;
+                    write!(f, ">")?;
+                }
+            }
+            ASTNode::MulAdd(..) => {
+                // Written back as the multiply loop it came from, whose
+                // Set(0) follows the last target
+                write!(f, "[-")?;
+                let mut position = 0;
+                let mut target = Some(node);
+
+                while let Some(&ASTNode::MulAdd(offset, factor)) = target {
+                    write_move(f, offset - position)?;
+                    write_add(f, factor)?;
+                    position = offset;
+
+                    target = match nodes.peek() {
+                        Some(ASTNode::MulAdd(..)) => nodes.next(),
+                        _ => None,
+                    };
+                    if let Some(next) = target {
+                        known.apply(next);
+                    }
+                }
+
+                write_move(f, -position)?;
+                write!(f, "]")?;
+                zero = true;
+            }
+            ASTNode::AddTo(offset, n) => {
+                write_move(f, *offset)?;
+                write_add(f, *n)?;
+                write_move(f, -offset)?;
+                zero = known_zero;
+            }
+            ASTNode::Next(n) => {
+                write_repeated(f, '>', *n)?;
+                continue;
+            }
+            ASTNode::Prev(n) => {
+                write_repeated(f, '<', *n)?;
+                continue;
+            }
+            ASTNode::Print => write!(f, ".")?,
+            ASTNode::PrintRepeat(n) => write_repeated(f, '.', *n)?,
+            ASTNode::PrintLiteral(bytes) => {
+                let start = match value {
+                    Some(value) => value,
+                    None => {
+                        write!(f, "[-]")?;
+                        0
+                    }
+                };
+
+                let mut current = start;
+                for &byte in bytes {
+                    write_add(f, byte.wrapping_sub(current))?;
+                    write!(f, ".")?;
+                    current = byte;
+                }
+                write_add(f, start.wrapping_sub(current))?;
+            }
+            ASTNode::Read => write!(f, ",")?,
+            ASTNode::DebugPrintValue => write!(f, "$")?,
+            ASTNode::Breakpoint => write!(f, "#")?,
+            ASTNode::Loop(body) => {
+                write!(f, "[")?;
+                write_nodes(f, body, false)?;
+                write!(f, "]")?;
+                zero = true;
+            }
+        }
+
+        fresh = false;
+    }
+
+    Ok(())
+}
+
+/// Values of the cells around the pointer while writing straight-line code,
+/// tracked the same way as by `AST::fuse_print_literals`.
+struct Known {
+    /// Value of each cell written to, None if it depends on input, by offset
+    /// from where tracking started.
+    values: HashMap<isize, Option<u8>>,
+    /// Whether cells not in `values` are 0.
+    zeroed: bool,
+    offset: isize,
+}
+
+impl Known {
+    fn new(zeroed: bool) -> Self {
+        Known {
+            values: HashMap::new(),
+            zeroed,
+            offset: 0,
+        }
+    }
+
+    fn value_at(&self, offset: isize) -> Option<u8> {
+        match self.values.get(&offset) {
+            Some(&value) => value,
+            None if self.zeroed => Some(0),
+            None => None,
+        }
+    }
+
+    /// Value of the current cell, if known.
+    fn value(&self) -> Option<u8> {
+        self.value_at(self.offset)
+    }
+
+    /// Follow `node`'s effect on the tape.
+    fn apply(&mut self, node: &ASTNode) {
+        let value = self.value();
+
+        match *node {
+            ASTNode::Incr(n) => {
+                self.values
+                    .insert(self.offset, value.map(|v| v.wrapping_add(n)));
+            }
+            ASTNode::Decr(n) => {
+                self.values
+                    .insert(self.offset, value.map(|v| v.wrapping_sub(n)));
+            }
+            ASTNode::Set(n) => {
+                self.values.insert(self.offset, Some(n));
+            }
+            ASTNode::SetRange(len, n) => {
+                for cell in 0..len as isize {
+                    self.values.insert(self.offset + cell, Some(n));
+                }
+                self.offset += len as isize;
+            }
+            ASTNode::MulAdd(target, factor) => {
+                let target = self.offset + target;
+                let after = match (value, self.value_at(target)) {
+                    (Some(0), before) => before,
+                    (Some(value), Some(before)) => {
+                        Some(before.wrapping_add(value.wrapping_mul(factor)))
+                    }
+                    _ => None,
+                };
+                self.values.insert(target, after);
+            }
+            ASTNode::AddTo(target, n) => {
+                let target = self.offset + target;
+                let after = self.value_at(target).map(|before| before.wrapping_add(n));
+                self.values.insert(target, after);
+            }
+            ASTNode::Next(n) => self.offset += n as isize,
+            ASTNode::Prev(n) => self.offset -= n as isize,
+            ASTNode::Read => {
+                self.values.insert(self.offset, None);
+            }
+            ASTNode::Loop(_) => {
+                // Only the 0 the loop ended on is known
+                *self = Known::new(false);
+                self.values.insert(0, Some(0));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Write the shortest run of `+` or `-` that adds `n` to a cell.
+fn write_add(f: &mut fmt::Formatter, n: u8) -> fmt::Result {
+    if n <= 0x80 {
+        write_repeated(f, '+', n as usize)
+    } else {
+        write_repeated(f, '-', n.wrapping_neg() as usize)
+    }
+}
+
+/// Write the pointer moves covering `distance`.
+fn write_move(f: &mut fmt::Formatter, distance: isize) -> fmt::Result {
+    if distance > 0 {
+        write_repeated(f, '>', distance as usize)
+    } else {
+        write_repeated(f, '<', distance.unsigned_abs())
+    }
+}
+
+fn write_repeated(f: &mut fmt::Formatter, command: char, count: usize) -> fmt::Result {
+    for _ in 0..count {
+        write!(f, "{}", command)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::super::super::runnable::{Fucker, Io};
+    use super::super::STEP_LIMIT;
+    use super::*;
+
+    /// Interpret `ast`, returning its output and the start of the tape.
+    fn run(ast: &AST) -> (Vec<u8>, Vec<u8>) {
+        let mut fucker = Fucker::new(&ast.data);
+        let mut output = Vec::new();
+        {
+            let mut input = io::empty();
+            let mut io = Io::new(&mut input, &mut output);
+            while fucker.step(&mut io) {}
+        }
+
+        (output, fucker.tape()[..8].to_vec())
+    }
+
+    #[test]
+    fn redundant_commands_removed() {
+        let source = "+-++--+>><<>+-<.,[-]+++.[->+<]>[-]--.";
+        let emitted = AST::parse(source).unwrap().to_string();
+
+        assert_eq!(emitted, "+.,[-]+++.[->+<]>[-]--.");
+        assert!(emitted.len() < source.len());
+        assert_eq!(AST::parse(&emitted).unwrap().to_string(), emitted);
+    }
+
+    #[test]
+    fn literal_spelled_out() {
+        let mut ast = AST::parse(include_str!("../../test/programs/hello_world.bf")).unwrap();
+        ast.fold_static_output(STEP_LIMIT);
+
+        let emitted = AST::parse(&ast.to_string()).unwrap();
+        assert_eq!(
+            emitted.static_output(STEP_LIMIT),
+            Some(b"Hello World!\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn literal_keeps_tape() {
+        for source in &[
+            "+++.++.>",
+            "++++++++[>++++++++<-]>+.+.>++.<-.",
+            "+>++>+++<<.>.>.<-.",
+            "[-]>+++.<.",
+        ] {
+            let mut fused = AST::parse(source).unwrap();
+            fused.fuse_print_literals();
+            assert!(fused
+                .data
+                .iter()
+                .any(|node| matches!(node, ASTNode::PrintLiteral(_))));
+
+            let emitted = AST::parse(&fused.to_string()).unwrap();
+            assert_eq!(
+                run(&emitted),
+                run(&AST::parse(source).unwrap()),
+                "{}",
+                source
+            );
+        }
+    }
+}
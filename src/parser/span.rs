@@ -0,0 +1,162 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use super::ASTNode;
+
+/// Where in the source a node came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offset of the first command.
+    pub start: usize,
+    /// Byte offset just past the last command.
+    pub end: usize,
+    /// Line and column of the first command, counted as in parse errors.
+    pub line: usize,
+    pub col: usize,
+}
+
+/// Spans for a list of nodes, one for each node in the same order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    pub spans: Vec<NodeSpan>,
+}
+
+/// The span of one node, along with the spans of a loop's body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeSpan {
+    pub span: Span,
+    /// Empty unless the node is a loop.
+    pub body: SourceMap,
+}
+
+/// For each node a pass puts out, the range of the pass's input nodes it
+/// was made from. None when no spans are wanted, so passes skip the work.
+pub(super) type Origins = Option<Vec<Range<usize>>>;
+
+/// Start recording origins if there are spans to follow them.
+pub(super) fn origins(spans: &Option<&mut Vec<NodeSpan>>) -> Origins {
+    spans.as_ref().map(|_| Vec::new())
+}
+
+/// Record that the next node put out came from the input nodes in `range`.
+pub(super) fn track(origins: &mut Origins, range: Range<usize>) {
+    if let Some(origins) = origins {
+        origins.push(range);
+    }
+}
+
+/// Record that the last node put out also came from input nodes up to
+/// `end`.
+pub(super) fn extend(origins: &mut Origins, end: usize) {
+    if let Some(range) = origins.as_mut().and_then(|origins| origins.last_mut()) {
+        range.end = end;
+    }
+}
+
+/// Replace the spans of a pass's input with those of its output `nodes`.
+///
+/// A node covers the spans of every node it was made from. Only a loop made
+/// from a single loop keeps the spans of its body, since a loop rewritten
+/// into other nodes has no body left.
+pub(super) fn remap(
+    spans: &mut Option<&mut Vec<NodeSpan>>,
+    origins: Origins,
+    nodes: &VecDeque<ASTNode>,
+) {
+    if let (Some(spans), Some(origins)) = (spans.as_deref_mut(), origins) {
+        let mapped = origins
+            .into_iter()
+            .zip(nodes)
+            .map(|(range, node)| {
+                let first = &spans[range.start];
+                let body = match node {
+                    ASTNode::Loop(_) if range.len() == 1 => first.body.clone(),
+                    _ => SourceMap::default(),
+                };
+
+                NodeSpan {
+                    span: Span {
+                        end: spans[range.end - 1].span.end,
+                        ..first.span
+                    },
+                    body,
+                }
+            })
+            .collect();
+
+        *spans = mapped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::error::BfError;
+    use super::super::{ParseOptions, AST};
+    use super::*;
+
+    /// Byte range of each node's span, for short assertions.
+    fn ranges(map: &SourceMap) -> Vec<Range<usize>> {
+        map.spans
+            .iter()
+            .map(|span| span.span.start..span.span.end)
+            .collect()
+    }
+
+    #[test]
+    fn run_spans() {
+        let (ast, map) = AST::parse_with_spans("a+++\n[->+<]", ParseOptions::default()).unwrap();
+
+        assert_eq!(ast.data[0], ASTNode::Incr(3));
+        assert_eq!(
+            map.spans[0].span,
+            Span {
+                start: 1,
+                end: 4,
+                line: 1,
+                col: 2
+            }
+        );
+
+        // The multiply loop became MulAdd and Set(0), both from the loop
+        assert_eq!(ast.data.len(), 3);
+        assert_eq!(ranges(&map), [1..4, 5..11, 5..11]);
+        assert_eq!((map.spans[1].span.line, map.spans[1].span.col), (2, 1));
+        assert!(map.spans.iter().all(|span| span.body.spans.is_empty()));
+    }
+
+    #[test]
+    fn loop_spans() {
+        let (ast, map) = AST::parse_with_spans("+[>,.<-]", ParseOptions::default()).unwrap();
+
+        assert!(matches!(ast.data[1], ASTNode::Loop(ref body) if body.len() == 5));
+        assert_eq!(ranges(&map), [0..1, 1..8]);
+        // Canonical form rewrote `<-` into two nodes covering both commands
+        assert_eq!(ranges(&map.spans[1].body), [2..3, 3..4, 4..5, 5..7, 5..7]);
+    }
+
+    #[test]
+    fn unoptimized_spans() {
+        let options = ParseOptions {
+            peephole: false,
+            ..ParseOptions::default()
+        };
+        let (ast, map) = AST::parse_with_spans("+[--<]--", options).unwrap();
+
+        // Only the runs were merged
+        assert_eq!(ast.data.len(), 3);
+        assert_eq!(ranges(&map), [0..1, 1..6, 6..8]);
+        assert_eq!(ranges(&map.spans[1].body), [2..4, 4..5]);
+    }
+
+    #[test]
+    fn span_errors() {
+        assert!(matches!(
+            AST::parse_with_spans("+\n[[]", ParseOptions::default()),
+            Err(BfError::UnmatchedOpen { line: 2, col: 1 })
+        ));
+        assert!(matches!(
+            AST::parse_with_spans("[]]", ParseOptions::default()),
+            Err(BfError::UnmatchedClose { line: 1, col: 3 })
+        ));
+    }
+}
@@ -0,0 +1,183 @@
+use std::cmp;
+
+use super::{ASTNode, AST};
+
+/// Summary of the commands making up a program.
+///
+/// Amounts are totals, so `Incr(3)` counts as 3 towards `incr` but as a
+/// single node.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Number of commands in the source, or nodes in an AST.
+    pub nodes: usize,
+    pub incr: usize,
+    pub decr: usize,
+    pub next: usize,
+    pub prev: usize,
+    pub prints: usize,
+    pub reads: usize,
+    pub loops: usize,
+    /// Loops of the form `[-]` or `[+]`, which zero the current cell.
+    pub clears: usize,
+    pub max_depth: usize,
+}
+
+impl Stats {
+    /// Count the commands in raw, unoptimized source code.
+    pub fn from_source(input: &str) -> Self {
+        let mut stats = Stats::default();
+        let mut depth = 0;
+        // The last three commands, used to spot clear loops
+        let mut window = ['\0'; 3];
+
+        for character in input.chars() {
+            match character {
+                '+' => stats.incr += 1,
+                '-' => stats.decr += 1,
+                '>' => stats.next += 1,
+                '<' => stats.prev += 1,
+                '.' => stats.prints += 1,
+                ',' => stats.reads += 1,
+                '[' => {
+                    stats.loops += 1;
+                    depth += 1;
+                    stats.max_depth = cmp::max(stats.max_depth, depth);
+                }
+                ']' => depth -= 1,
+                _ => continue,
+            }
+
+            stats.nodes += 1;
+            window = [window[1], window[2], character];
+
+            if window == ['[', '-', ']'] || window == ['[', '+', ']'] {
+                stats.clears += 1;
+            }
+        }
+
+        stats
+    }
+
+    /// Count the nodes in a parsed (and therefore optimized) program.
+    pub fn from_ast(ast: &AST) -> Self {
+        let mut stats = Stats::default();
+
+        for (depth, node) in ast.iter_flat() {
+            stats.nodes += 1;
+
+            match node {
+                ASTNode::Incr(n) => stats.incr += *n as usize,
+                ASTNode::Decr(n) => stats.decr += *n as usize,
+                ASTNode::Set(0) => stats.clears += 1,
+                ASTNode::Set(_) => {}
+                ASTNode::SetRange(len, n) => {
+                    if *n == 0 {
+                        stats.clears += len;
+                    }
+                    stats.next += len;
+                }
+                ASTNode::Next(n) => stats.next += n,
+                ASTNode::Prev(n) => stats.prev += n,
+                ASTNode::Print => stats.prints += 1,
+                ASTNode::PrintRepeat(n) => stats.prints += n,
+                ASTNode::PrintLiteral(bytes) => stats.prints += bytes.len(),
+                ASTNode::Read => stats.reads += 1,
+                ASTNode::MulAdd(..)
+                | ASTNode::AddTo(..)
+                | ASTNode::DebugPrintValue
+                | ASTNode::Breakpoint => {}
+                ASTNode::Loop(_) => {
+                    stats.loops += 1;
+                    stats.max_depth = cmp::max(stats.max_depth, depth + 1);
+                }
+            }
+        }
+
+        stats
+    }
+
+    /// Label and value of each statistic, in display order.
+    pub fn rows(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("nodes", self.nodes),
+            ("incr", self.incr),
+            ("decr", self.decr),
+            ("next", self.next),
+            ("prev", self.prev),
+            ("print", self.prints),
+            ("read", self.reads),
+            ("loops", self.loops),
+            ("clears", self.clears),
+            ("max depth", self.max_depth),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "++[>+++<-]>.[-]";
+
+    #[test]
+    fn source_stats() {
+        let stats = Stats::from_source(PROGRAM);
+        assert_eq!(
+            stats,
+            Stats {
+                nodes: 15,
+                incr: 5,
+                decr: 2,
+                next: 2,
+                prev: 1,
+                prints: 1,
+                reads: 0,
+                loops: 2,
+                clears: 1,
+                max_depth: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn ast_stats() {
+        // The copy loop becomes a multiply-add and a clear
+        let stats = Stats::from_ast(&AST::parse(PROGRAM).unwrap());
+        assert_eq!(
+            stats,
+            Stats {
+                nodes: 6,
+                incr: 2,
+                decr: 0,
+                next: 1,
+                prev: 0,
+                prints: 1,
+                reads: 0,
+                loops: 0,
+                clears: 2,
+                max_depth: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn ast_stats_loops() {
+        // Stepping by 2 keeps the loop from becoming a multiply-add
+        let stats = Stats::from_ast(&AST::parse("++[>+++<--]>.[-]").unwrap());
+        assert_eq!(
+            stats,
+            Stats {
+                nodes: 9,
+                incr: 5,
+                decr: 2,
+                next: 2,
+                prev: 1,
+                prints: 1,
+                reads: 0,
+                loops: 1,
+                clears: 1,
+                max_depth: 1,
+            }
+        );
+    }
+}
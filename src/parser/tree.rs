@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use super::{ASTNode, AST};
+
+/// Spaces each level of loop nesting is indented by.
+const INDENT_WIDTH: usize = 2;
+
+impl AST {
+    /// Write the optimized nodes as a tree, one node per line, starting
+    /// `indent` levels in.
+    ///
+    /// The format is kept stable so it can be compared and parsed:
+    ///
+    /// - `incr N`, `decr N`, `next N`, `prev N` with their run-length counts
+    /// - `set N` for a cell set to a known value
+    /// - `set_range L N` for L cells from the current one set to N, leaving
+    ///   the data pointer past them
+    /// - `mul_add +O * F` for adding F times the current cell to the cell O
+    ///   away
+    /// - `add_to +O N` for adding N to the cell O away
+    /// - `print`, `read`, `debug_print` and `breakpoint`
+    /// - `print_repeat N` for N prints of the same cell
+    /// - `print_literal "..."` with the bytes escaped as in Rust strings
+    /// - `loop` with its body one level further in
+    pub fn write_tree(&self, w: &mut dyn Write, indent: usize) -> io::Result<()> {
+        write_nodes(w, &self.data, indent)
+    }
+}
+
+fn write_nodes(w: &mut dyn Write, nodes: &VecDeque<ASTNode>, indent: usize) -> io::Result<()> {
+    for node in nodes {
+        write!(w, "{:1$}", "", indent * INDENT_WIDTH)?;
+
+        match node {
+            ASTNode::Incr(n) => writeln!(w, "incr {}", n)?,
+            ASTNode::Decr(n) => writeln!(w, "decr {}", n)?,
+            ASTNode::Next(n) => writeln!(w, "next {}", n)?,
+            ASTNode::Prev(n) => writeln!(w, "prev {}", n)?,
+            ASTNode::Set(n) => writeln!(w, "set {}", n)?,
+            ASTNode::MulAdd(offset, factor) => writeln!(w, "mul_add {:+} * {}", offset, factor)?,
+            ASTNode::AddTo(offset, n) => writeln!(w, "add_to {:+} {}", offset, n)?,
+            ASTNode::Print => writeln!(w, "print")?,
+            ASTNode::PrintRepeat(n) => writeln!(w, "print_repeat {}", n)?,
+            ASTNode::PrintLiteral(bytes) => {
+                writeln!(w, "print_literal \"{}\"", bytes.escape_ascii())?
+            }
+            ASTNode::Read => writeln!(w, "read")?,
+            ASTNode::DebugPrintValue => writeln!(w, "debug_print")?,
+            ASTNode::SetRange(len, n) => writeln!(w, "set_range {} {}", len, n)?,
+            ASTNode::Breakpoint => writeln!(w, "breakpoint")?,
+            ASTNode::Loop(body) => {
+                writeln!(w, "loop")?;
+                write_nodes(w, body, indent + 1)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_tree() {
+        let mut ast = AST::parse(",[>++[>+<<.]<-]+++[->++<]>.").unwrap();
+        ast.data.push_back(ASTNode::PrintLiteral(b"Hi\n".to_vec()));
+        let mut tree = Vec::new();
+        ast.write_tree(&mut tree, 1).unwrap();
+
+        assert_eq!(
+            String::from_utf8(tree).unwrap(),
+            concat!(
+                "  read\n",
+                "  loop\n",
+                "    next 1\n",
+                "    incr 2\n",
+                "    loop\n",
+                "      next 1\n",
+                "      incr 1\n",
+                "      prev 2\n",
+                "      print\n",
+                "    prev 1\n",
+                "    decr 1\n",
+                "  incr 3\n",
+                "  mul_add +1 * 2\n",
+                "  set 0\n",
+                "  next 1\n",
+                "  print\n",
+                "  print_literal \"Hi\\n\"\n",
+            )
+        );
+    }
+
+    #[test]
+    fn every_line_form() {
+        let mut ast = AST::parse("").unwrap();
+        ast.data.extend(vec![
+            ASTNode::SetRange(3, 7),
+            ASTNode::AddTo(-2, 5),
+            ASTNode::DebugPrintValue,
+            ASTNode::Breakpoint,
+            ASTNode::PrintRepeat(4),
+        ]);
+        let mut tree = Vec::new();
+        ast.write_tree(&mut tree, 0).unwrap();
+
+        assert_eq!(
+            String::from_utf8(tree).unwrap(),
+            concat!(
+                "set_range 3 7\n",
+                "add_to -2 5\n",
+                "debug_print\n",
+                "breakpoint\n",
+                "print_repeat 4\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+use super::super::runnable::EofMode;
+
+/// Non-fatal diagnostic produced while parsing a program.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// A loop at the start of the program can never execute and was removed.
+    DeadLoopRemoved { line: usize, col: usize },
+    /// Loops are nested suspiciously deep.
+    DeepNesting {
+        depth: usize,
+        line: usize,
+        col: usize,
+    },
+    /// A loop changes nothing, so it never ends if the cell is not 0 when
+    /// it is reached.
+    InfiniteLoop { line: usize, col: usize },
+    /// A run of a command was long enough for its combined count to wrap
+    /// around.
+    RunLengthWrapped { command: char },
+    /// A loop is continued by the byte it just read, which under this EOF
+    /// mode can never be 0 once input runs out.
+    EofLoop { eof: EofMode },
+    /// A first-line directive used a key that isn't understood.
+    UnknownDirective { key: String },
+    /// A first-line directive had a value that can't be used for its key.
+    InvalidDirective { key: String, value: String },
+    /// The optimization budget ran out before the named pass, which was
+    /// skipped along with every pass after it.
+    BudgetExceeded { pass: String },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::DeadLoopRemoved { line, col } => write!(
+                f,
+                "Dead loop removed at line {}, column {} (the current cell is always 0)",
+                line, col
+            ),
+            Warning::DeepNesting { depth, line, col } => write!(
+                f,
+                "Loops nested {} deep at line {}, column {}",
+                depth, line, col
+            ),
+            Warning::InfiniteLoop { line, col } => write!(
+                f,
+                "Loop at line {}, column {} never ends unless the current cell is 0",
+                line, col
+            ),
+            Warning::RunLengthWrapped { command } => {
+                write!(f, "Run of '{}' commands wrapped around", command)
+            }
+            Warning::EofLoop { eof } => write!(
+                f,
+                "Loop ending in , will not stop at end of input with EOF mode {} (try zero)",
+                eof
+            ),
+            Warning::UnknownDirective { key } => write!(f, "Unknown directive '{}' ignored", key),
+            Warning::InvalidDirective { key, value } => {
+                write!(
+                    f,
+                    "Invalid value '{}' for directive '{}' ignored",
+                    value, key
+                )
+            }
+            Warning::BudgetExceeded { pass } => write!(
+                f,
+                "Optimization budget used up, skipped {} and the passes after it",
+                pass
+            ),
+        }
+    }
+}
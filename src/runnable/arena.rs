@@ -0,0 +1,428 @@
+#[cfg(test)]
+use std::cell::Cell;
+use std::cmp;
+use std::ops::Deref;
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use libc::{sysconf, _SC_PAGESIZE};
+
+use super::super::error::BfError;
+use super::jit_log::{self, JITEvent};
+
+/// Size of each block of executable memory requested from the OS.
+const CHUNK_SIZE: usize = 0x10000;
+
+/// Alignment of each piece of code carved out of a chunk.
+const CODE_ALIGN: usize = 0x10;
+
+/// Byte unused executable memory is filled with by default: `ret`, so a jump
+/// into it returns quietly.
+pub const RET_FILL: u8 = 0xc3;
+
+lazy_static! {
+    static ref PAGE_SIZE: usize = unsafe { sysconf(_SC_PAGESIZE) as usize };
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Makes every `mprotect` on this thread behave as if it were denied.
+    static DENY_EXEC: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Simulate a system that refuses to map executable memory, for tests on
+/// the current thread.
+#[cfg(test)]
+pub fn deny_exec(deny: bool) {
+    DENY_EXEC.with(|flag| flag.set(deny));
+}
+
+/// Round `numerator` up to a multiple of `denominator`.
+///
+/// * `numerator` - The upper component of a division
+/// * `denominator` - The lower component of a division
+fn int_ceil(numerator: usize, denominator: usize) -> usize {
+    numerator.div_ceil(denominator) * denominator
+}
+
+/// A block of executable memory, filled from the front and reusing the
+/// space of code that has been dropped.
+#[derive(Debug)]
+struct Chunk {
+    ptr: *mut u8,
+    size: usize,
+    used: usize,
+    /// Offset and size of the gaps before `used` left by dropped code,
+    /// sorted and never touching each other.
+    holes: Vec<(usize, usize)>,
+    /// Pieces of code handed out and not yet dropped.
+    live: usize,
+}
+
+// The chunk owns its allocation, and its bytes are never written after being
+// handed out.
+unsafe impl Send for Chunk {}
+
+/// Allocate `size` bytes starting at a multiple of `alignment`.
+fn aligned_alloc(size: usize, alignment: usize) -> Result<*mut libc::c_void, BfError> {
+    let mut ptr: *mut libc::c_void = ptr::null_mut();
+
+    // The error comes back as the return value, errno is left alone.
+    match unsafe { libc::posix_memalign(&mut ptr, alignment, size) } {
+        0 => Ok(ptr),
+        libc::ENOMEM => Err(BfError::OutOfExecMemory { size }),
+        libc::EINVAL => Err(BfError::BadExecAlignment { alignment }),
+        code => Err(BfError::ExecAllocFailed { code }),
+    }
+}
+
+impl Chunk {
+    fn new(size: usize, fill: u8) -> Result<Self, BfError> {
+        let ptr = aligned_alloc(size, *PAGE_SIZE)?;
+
+        // W^X policies (SELinux, PaX, hardened runtimes) refuse to make
+        // memory both writable and executable.
+        let protected = unsafe {
+            libc::mprotect(
+                ptr,
+                size,
+                libc::PROT_EXEC | libc::PROT_READ | libc::PROT_WRITE,
+            )
+        };
+        #[cfg(test)]
+        let protected = if DENY_EXEC.with(Cell::get) {
+            -1
+        } else {
+            protected
+        };
+
+        if protected != 0 {
+            unsafe { libc::free(ptr) };
+            return Err(BfError::ExecMemoryDenied);
+        }
+
+        unsafe {
+            libc::memset(ptr, fill as libc::c_int, size);
+        }
+        jit_log::log(JITEvent::ChunkAllocated { size });
+
+        Ok(Chunk {
+            ptr: ptr as *mut u8,
+            size,
+            used: 0,
+            holes: Vec::new(),
+            live: 0,
+        })
+    }
+
+    fn free(&self) -> usize {
+        self.size - self.used
+    }
+
+    /// Offset of `size` bytes taken from the first hole big enough.
+    fn take_hole(&mut self, size: usize) -> Option<usize> {
+        let index = self.holes.iter().position(|&(_, len)| len >= size)?;
+        let (offset, len) = self.holes[index];
+
+        if len == size {
+            self.holes.remove(index);
+        } else {
+            self.holes[index] = (offset + size, len - size);
+        }
+
+        Some(offset)
+    }
+
+    /// Give back the `size` bytes at `offset`, filling them with `fill`.
+    fn give_back(&mut self, offset: usize, size: usize, fill: u8) {
+        unsafe { ptr::write_bytes(self.ptr.add(offset), fill, size) };
+        self.live -= 1;
+
+        if self.live == 0 {
+            self.used = 0;
+            self.holes.clear();
+            return;
+        }
+
+        let index = self.holes.partition_point(|&(start, _)| start < offset);
+        self.holes.insert(index, (offset, size));
+
+        // Merge with the holes on either side
+        if index + 1 < self.holes.len() && offset + size == self.holes[index + 1].0 {
+            self.holes[index].1 += self.holes.remove(index + 1).1;
+        }
+        if index > 0 && self.holes[index - 1].0 + self.holes[index - 1].1 == offset {
+            self.holes[index - 1].1 += self.holes.remove(index).1;
+        }
+
+        // A hole at the end is just unused space
+        if let Some(&(start, len)) = self.holes.last() {
+            if start + len == self.used {
+                self.used = start;
+                self.holes.pop();
+            }
+        }
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        unsafe { libc::free(self.ptr as *mut libc::c_void) };
+    }
+}
+
+/// Allocator for executable code.
+///
+/// Code is copied into large executable chunks instead of each fragment
+/// getting pages of its own. The space of dropped code is reused by later
+/// allocations, a chunk with no code left in it is freed unless it is the
+/// newest, and the rest are freed once the arena and every piece of code
+/// carved from it are dropped.
+#[derive(Debug, Clone)]
+pub struct CodeArena {
+    chunks: Arc<Mutex<Vec<Chunk>>>,
+    /// Byte that memory not yet holding code is filled with.
+    fill: u8,
+}
+
+impl Default for CodeArena {
+    fn default() -> Self {
+        Self::with_fill(RET_FILL)
+    }
+}
+
+impl CodeArena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an arena whose unused memory is filled with `fill` instead of
+    /// `ret`. Filling with 0 (`add [rax],al`) makes a jump past the end of
+    /// generated code fault instead of silently returning.
+    pub fn with_fill(fill: u8) -> Self {
+        CodeArena {
+            chunks: Arc::new(Mutex::new(Vec::new())),
+            fill,
+        }
+    }
+
+    /// Copy `source` into executable memory.
+    pub fn alloc(&self, source: &[u8]) -> Result<Code, BfError> {
+        self.alloc_with(source, Vec::new())
+    }
+
+    /// Copy `source` into executable memory, keeping the code in `needs`
+    /// alive for as long as it is.
+    pub fn alloc_with(&self, source: &[u8], needs: Vec<Code>) -> Result<Code, BfError> {
+        let mut chunks = self.chunks.lock().unwrap();
+        let size = int_ceil(source.len().max(1), CODE_ALIGN);
+
+        let hole = chunks
+            .iter_mut()
+            .enumerate()
+            .find_map(|(index, chunk)| chunk.take_hole(size).map(|offset| (index, offset)));
+        let (index, offset) = match hole {
+            Some(hole) => hole,
+            None => {
+                if chunks.last().is_none_or(|chunk| chunk.free() < size) {
+                    let chunk_size = cmp::max(CHUNK_SIZE, int_ceil(size, *PAGE_SIZE));
+                    chunks.push(Chunk::new(chunk_size, self.fill)?);
+                }
+
+                let index = chunks.len() - 1;
+                let chunk = &mut chunks[index];
+                chunk.used += size;
+                (index, chunk.used - size)
+            }
+        };
+
+        let chunk = &mut chunks[index];
+        chunk.live += 1;
+        let ptr = unsafe { chunk.ptr.add(offset) };
+        unsafe { ptr::copy_nonoverlapping(source.as_ptr(), ptr, source.len()) };
+
+        Ok(Code {
+            ptr,
+            len: source.len(),
+            _space: Arc::new(Space {
+                chunks: self.chunks.clone(),
+                ptr,
+                size,
+                fill: self.fill,
+                _needs: needs,
+            }),
+        })
+    }
+
+    /// Number of chunks allocated so far.
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.lock().unwrap().len()
+    }
+}
+
+/// Executable bytes living in a `CodeArena`.
+#[derive(Debug, Clone)]
+pub struct Code {
+    ptr: *const u8,
+    len: usize,
+    /// Keeps the memory behind `ptr` alive.
+    _space: Arc<Space>,
+}
+
+/// Space given out by a `CodeArena`, handed back once every copy of the
+/// `Code` in it is dropped.
+#[derive(Debug)]
+struct Space {
+    chunks: Arc<Mutex<Vec<Chunk>>>,
+    ptr: *mut u8,
+    size: usize,
+    fill: u8,
+    /// Code this code refers to, such as the literals it prints.
+    _needs: Vec<Code>,
+}
+
+// The space is only written to under the chunks' lock.
+unsafe impl Send for Space {}
+unsafe impl Sync for Space {}
+
+impl Drop for Space {
+    fn drop(&mut self) {
+        let mut chunks = self.chunks.lock().unwrap();
+        let index = chunks
+            .iter()
+            .position(|chunk| {
+                chunk.ptr <= self.ptr && self.ptr < unsafe { chunk.ptr.add(chunk.size) }
+            })
+            .expect("code was allocated from one of its arena's chunks");
+
+        let chunk = &mut chunks[index];
+        let offset = self.ptr as usize - chunk.ptr as usize;
+        chunk.give_back(offset, self.size, self.fill);
+
+        if chunk.live == 0 && index + 1 < chunks.len() {
+            chunks.remove(index);
+        }
+    }
+}
+
+// Code is immutable once allocated, so it can be shared freely.
+unsafe impl Send for Code {}
+unsafe impl Sync for Code {}
+
+impl Deref for Code {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn int_ceil_rounds_up() {
+        assert_eq!(int_ceil(0x1000, 0x1000), 0x1000);
+        assert_eq!(int_ceil(0x2000, 0x1000), 0x2000);
+        assert_eq!(int_ceil(1, 0x1000), 0x1000);
+        assert_eq!(int_ceil(0x1001, 0x1000), 0x2000);
+        assert_eq!(int_ceil(0, 0x1000), 0);
+        assert_eq!(int_ceil(usize::MAX - 15, 16), usize::MAX - 15);
+    }
+
+    #[test]
+    fn alloc_errors() {
+        assert!(matches!(
+            Chunk::new(usize::MAX - *PAGE_SIZE, RET_FILL),
+            Err(BfError::OutOfExecMemory { .. })
+        ));
+        // Not a power of two
+        assert!(matches!(
+            aligned_alloc(0x1000, 3 * *PAGE_SIZE),
+            Err(BfError::BadExecAlignment { .. })
+        ));
+    }
+
+    #[test]
+    fn alloc_shares_chunks() {
+        let arena = CodeArena::new();
+        let first = arena.alloc(&[0x90, 0xc3]).unwrap();
+        let second = arena.alloc(&[0xc3]).unwrap();
+
+        assert_eq!(&first[..], &[0x90, 0xc3]);
+        assert_eq!(&second[..], &[0xc3]);
+        assert_eq!(
+            second.as_ptr() as usize - first.as_ptr() as usize,
+            CODE_ALIGN
+        );
+        assert_eq!(arena.chunk_count(), 1);
+    }
+
+    #[test]
+    fn fill_byte() {
+        for &fill in &[RET_FILL, 0] {
+            let arena = CodeArena::with_fill(fill);
+            let code = arena.alloc(&[0x90]).unwrap();
+            let after = unsafe { *code.as_ptr().add(1) };
+
+            assert_eq!(after, fill);
+        }
+    }
+
+    #[test]
+    fn reuse_dropped_code() {
+        let arena = CodeArena::new();
+        let first = arena.alloc(&[0x90; 0x20]).unwrap();
+        let second = arena.alloc(&[0x90]).unwrap();
+        let first_ptr = first.as_ptr();
+        drop(first);
+
+        // The gap is filled again, with `ret` where no code is
+        let third = arena.alloc(&[0x90, 0x90]).unwrap();
+        assert_eq!(third.as_ptr(), first_ptr);
+        assert_eq!(unsafe { *third.as_ptr().add(2) }, RET_FILL);
+
+        drop((second, third));
+        let fourth = arena.alloc(&[0x90]).unwrap();
+        assert_eq!(fourth.as_ptr(), first_ptr);
+    }
+
+    #[test]
+    fn free_empty_chunks() {
+        let arena = CodeArena::new();
+        let big = arena.alloc(&vec![0x90; CHUNK_SIZE]).unwrap();
+        let small = arena.alloc(&[0x90]).unwrap();
+        assert_eq!(arena.chunk_count(), 2);
+
+        // The older chunk is freed, the newest kept for what comes next
+        drop(big);
+        assert_eq!(arena.chunk_count(), 1);
+        drop(small);
+        assert_eq!(arena.chunk_count(), 1);
+    }
+
+    #[test]
+    fn needs_outlive_code() {
+        let arena = CodeArena::new();
+        let literal = arena.alloc(b"hi").unwrap();
+        let literal_ptr = literal.as_ptr();
+        let code = arena.alloc_with(&[0xc3], vec![literal]).unwrap();
+
+        // The literal's space is not handed out while the code is alive
+        let other = arena.alloc(b"x").unwrap();
+        assert_ne!(other.as_ptr(), literal_ptr);
+        drop(code);
+        assert_eq!(arena.alloc(b"y").unwrap().as_ptr(), literal_ptr);
+    }
+
+    #[test]
+    fn alloc_large() {
+        let arena = CodeArena::new();
+        let code = arena.alloc(&vec![0x90; CHUNK_SIZE + 1]).unwrap();
+
+        assert_eq!(code.len(), CHUNK_SIZE + 1);
+        assert_eq!(arena.chunk_count(), 1);
+    }
+}
@@ -0,0 +1,74 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// What `+` and `-` do when a cell would go past 255 or below 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum CellArithmetic {
+    /// Continue from the other end, as most BrainFuck implementations do.
+    #[default]
+    Wrap,
+    /// Stay at 255 or 0.
+    Saturate,
+    /// Stop the program with an error naming the cell and its value.
+    Error,
+}
+
+impl CellArithmetic {
+    /// Add `n` to `value`, returning None if this is an error.
+    pub fn add(self, value: u8, n: u8) -> Option<u8> {
+        match self {
+            CellArithmetic::Wrap => Some(value.wrapping_add(n)),
+            CellArithmetic::Saturate => Some(value.saturating_add(n)),
+            CellArithmetic::Error => value.checked_add(n),
+        }
+    }
+
+    /// Subtract `n` from `value`, returning None if this is an error.
+    pub fn sub(self, value: u8, n: u8) -> Option<u8> {
+        match self {
+            CellArithmetic::Wrap => Some(value.wrapping_sub(n)),
+            CellArithmetic::Saturate => Some(value.saturating_sub(n)),
+            CellArithmetic::Error => value.checked_sub(n),
+        }
+    }
+}
+
+impl FromStr for CellArithmetic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(CellArithmetic::Wrap),
+            "saturate" => Ok(CellArithmetic::Saturate),
+            "error" => Ok(CellArithmetic::Error),
+            _ => Err(format!("Unknown cell arithmetic: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for CellArithmetic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CellArithmetic::Wrap => write!(f, "wrap"),
+            CellArithmetic::Saturate => write!(f, "saturate"),
+            CellArithmetic::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bulk_past_boundaries() {
+        assert_eq!(CellArithmetic::Wrap.add(250, 10), Some(4));
+        assert_eq!(CellArithmetic::Saturate.add(250, 10), Some(255));
+        assert_eq!(CellArithmetic::Error.add(250, 10), None);
+        assert_eq!(CellArithmetic::Error.add(250, 5), Some(255));
+
+        assert_eq!(CellArithmetic::Wrap.sub(3, 10), Some(249));
+        assert_eq!(CellArithmetic::Saturate.sub(3, 10), Some(0));
+        assert_eq!(CellArithmetic::Error.sub(3, 10), None);
+    }
+}
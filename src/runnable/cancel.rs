@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A handle for stopping a running program from elsewhere, such as another
+/// thread, a timer or a signal handler.
+///
+/// Clones share one flag, so cancelling any of them cancels them all. The
+/// interpreter checks it before every instruction, compiled code only when
+/// built with `JITOptions::interruptible`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop every program running with this token.
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    /// Let programs run with this token again.
+    pub fn reset(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+
+    /// The flag the backends poll.
+    pub(crate) fn flag(&self) -> Arc<AtomicBool> {
+        self.flag.clone()
+    }
+}
+
+/// Wrap a flag that was being passed to `set_interrupt`, sharing it.
+impl From<Arc<AtomicBool>> for CancelToken {
+    fn from(flag: Arc<AtomicBool>) -> Self {
+        CancelToken { flag }
+    }
+}
+
+/// How the last run of a program ended.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExitReason {
+    /// It ran to the end, which is also what is reported before any run.
+    #[default]
+    Finished,
+    /// Its cancel token was cancelled, or its interrupt flag raised.
+    Cancelled,
+    /// It stopped on an error, which was reported on the diagnostics stream.
+    Error,
+    /// It used up the steps `Fucker::set_max_steps` allows.
+    Limit,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_flag() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+
+        token.cancel();
+        assert!(clone.is_cancelled());
+
+        clone.reset();
+        assert!(!token.is_cancelled());
+
+        let flag = Arc::new(AtomicBool::new(false));
+        CancelToken::from(flag.clone()).cancel();
+        assert!(flag.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,259 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// Hash functions `ChecksumWriter` can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    Crc32,
+    Sha256,
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crc32" => Ok(Checksum::Crc32),
+            "sha256" => Ok(Checksum::Sha256),
+            _ => Err(format!("Unknown checksum: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Checksum::Crc32 => write!(f, "crc32"),
+            Checksum::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+/// Output stream that hashes every byte passed on to `inner`, so that long
+/// output can be checked against a known value without keeping it.
+pub struct ChecksumWriter<W: Write> {
+    inner: W,
+    state: State,
+}
+
+enum State {
+    /// The CRC so far, inverted.
+    Crc32(u32),
+    Sha256(Sha256),
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W, checksum: Checksum) -> Self {
+        let state = match checksum {
+            Checksum::Crc32 => State::Crc32(!0),
+            Checksum::Sha256 => State::Sha256(Sha256::new()),
+        };
+
+        ChecksumWriter { inner, state }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The checksum of everything written so far, in hex.
+    pub fn digest(&self) -> String {
+        match self.state {
+            State::Crc32(crc) => format!("{:08x}", !crc),
+            State::Sha256(ref sha) => sha.digest().iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+
+        match self.state {
+            State::Crc32(ref mut crc) => *crc = crc32_update(*crc, &buf[..written]),
+            State::Sha256(ref mut sha) => sha.update(&buf[..written]),
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Add `bytes` to an inverted CRC-32, as used by zlib and PNG.
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+
+    crc
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 of a stream of bytes.
+#[derive(Clone)]
+struct Sha256 {
+    state: [u32; 8],
+    /// Bytes not yet making up a whole block.
+    pending: Vec<u8>,
+    len: u64,
+}
+
+impl Sha256 {
+    fn new() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            pending: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut bytes: &[u8]) {
+        self.len += bytes.len() as u64;
+
+        while !bytes.is_empty() {
+            let taken = bytes.len().min(64 - self.pending.len());
+            self.pending.extend(&bytes[..taken]);
+            bytes = &bytes[taken..];
+
+            if self.pending.len() == 64 {
+                Self::compress(&mut self.state, &self.pending);
+                self.pending.clear();
+            }
+        }
+    }
+
+    /// The hash of what was added so far, leaving the stream open.
+    fn digest(&self) -> [u8; 32] {
+        let mut sha = self.clone();
+        let bits = sha.len.wrapping_mul(8);
+
+        let mut padding = vec![0x80];
+        padding.resize(1 + (119 - sha.pending.len()) % 64, 0);
+        padding.extend(&bits.to_be_bytes());
+        sha.update(&padding);
+
+        let mut digest = [0; 32];
+        for (chunk, word) in digest.chunks_mut(4).zip(&sha.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    /// Mix one 64 byte block into `state`.
+    fn compress(state: &mut [u32; 8], block: &[u8]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (word, value) in state.iter_mut().zip(&[a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(*value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::parser::AST;
+    use super::super::{Fucker, Runnable};
+    use super::*;
+
+    fn digest(checksum: Checksum, bytes: &[u8]) -> String {
+        let mut writer = ChecksumWriter::new(Vec::new(), checksum);
+        // Split to go through more than one write
+        let (start, end) = bytes.split_at(bytes.len() / 3);
+        writer.write_all(start).unwrap();
+        writer.write_all(end).unwrap();
+
+        assert_eq!(writer.get_ref(), bytes);
+        writer.digest()
+    }
+
+    #[test]
+    fn known_values() {
+        assert_eq!(digest(Checksum::Crc32, b"123456789"), "cbf43926");
+        assert_eq!(digest(Checksum::Crc32, b""), "00000000");
+        assert_eq!(
+            digest(Checksum::Sha256, b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            digest(Checksum::Sha256, &[b'a'; 1000]),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+        // The most that fits in one block with the padding, and a full block
+        assert_eq!(
+            digest(Checksum::Sha256, &[b'a'; 55]),
+            "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318"
+        );
+        assert_eq!(
+            digest(Checksum::Sha256, &[b'a'; 64]),
+            "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb"
+        );
+    }
+
+    #[test]
+    fn program_checksum() {
+        let run = |source: &str| {
+            let ast = AST::parse(source).unwrap();
+            let mut output = ChecksumWriter::new(io::sink(), Checksum::Sha256);
+            Fucker::new(&ast.data).run_with_io(&mut io::empty(), &mut output);
+            output.digest()
+        };
+        let source = "++++++++[>++++++++<-]>+[.+]";
+
+        assert_eq!(run(source), run(source));
+        assert_ne!(run(source), run(&source.replace("]>+", "]>++")));
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::str::{self, FromStr};
+
+/// How the bytes a program prints are turned into what is written out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum OutputEncoding {
+    /// Write bytes unchanged.
+    #[default]
+    Raw,
+    /// Treat bytes as UTF-8, replacing invalid sequences with U+FFFD.
+    Utf8,
+    /// Treat each byte as the Unicode code point of the same value, and
+    /// write it as UTF-8.
+    Latin1,
+}
+
+impl FromStr for OutputEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(OutputEncoding::Raw),
+            "utf8" => Ok(OutputEncoding::Utf8),
+            "latin1" => Ok(OutputEncoding::Latin1),
+            _ => Err(format!("Unknown output encoding: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputEncoding::Raw => write!(f, "raw"),
+            OutputEncoding::Utf8 => write!(f, "utf8"),
+            OutputEncoding::Latin1 => write!(f, "latin1"),
+        }
+    }
+}
+
+/// Output stream that re-encodes everything written to it.
+///
+/// With `Utf8` an incomplete sequence is held back until the rest of it is
+/// written, and replaced with U+FFFD if the stream is dropped first.
+pub struct EncodingWriter<W: Write> {
+    inner: W,
+    encoding: OutputEncoding,
+    /// Start of a UTF-8 sequence still waiting for its remaining bytes.
+    pending: Vec<u8>,
+}
+
+impl<W: Write> EncodingWriter<W> {
+    pub fn new(inner: W, encoding: OutputEncoding) -> Self {
+        EncodingWriter {
+            inner,
+            encoding,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Write out as much of `pending` as forms valid or invalid UTF-8,
+    /// keeping only a trailing incomplete sequence.
+    fn write_pending(&mut self) -> io::Result<()> {
+        loop {
+            match str::from_utf8(&self.pending) {
+                Ok(valid) => {
+                    self.inner.write_all(valid.as_bytes())?;
+                    self.pending.clear();
+
+                    return Ok(());
+                }
+                Err(e) => {
+                    let valid_up_to = e.valid_up_to();
+                    self.inner.write_all(&self.pending[..valid_up_to])?;
+
+                    match e.error_len() {
+                        Some(len) => {
+                            self.inner.write_all("\u{FFFD}".as_bytes())?;
+                            self.pending.drain(..valid_up_to + len);
+                        }
+                        None => {
+                            self.pending.drain(..valid_up_to);
+
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for EncodingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.encoding {
+            OutputEncoding::Raw => self.inner.write_all(buf)?,
+            OutputEncoding::Utf8 => {
+                self.pending.extend_from_slice(buf);
+                self.write_pending()?;
+            }
+            OutputEncoding::Latin1 => {
+                let text: String = buf.iter().map(|&byte| byte as char).collect();
+                self.inner.write_all(text.as_bytes())?;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for EncodingWriter<W> {
+    fn drop(&mut self) {
+        if !self.pending.is_empty() {
+            let _ = self.inner.write_all("\u{FFFD}".as_bytes());
+            let _ = self.inner.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `bytes` in one call per byte, as Print does.
+    fn encode(bytes: &[u8], encoding: OutputEncoding) -> Vec<u8> {
+        let mut output = Vec::new();
+        {
+            let mut writer = EncodingWriter::new(&mut output, encoding);
+            for byte in bytes {
+                writer.write_all(&[*byte]).unwrap();
+            }
+        }
+
+        output
+    }
+
+    #[test]
+    fn encodings_differ() {
+        let bytes = [0xc3, 0xa9];
+
+        assert_eq!(encode(&bytes, OutputEncoding::Raw), bytes);
+        assert_eq!(encode(&bytes, OutputEncoding::Utf8), "é".as_bytes());
+        assert_eq!(encode(&bytes, OutputEncoding::Latin1), "Ã©".as_bytes());
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        assert_eq!(
+            encode(b"a\xffb", OutputEncoding::Utf8),
+            "a\u{FFFD}b".as_bytes()
+        );
+        // Cut off in the middle of a sequence
+        assert_eq!(
+            encode(b"a\xc3", OutputEncoding::Utf8),
+            "a\u{FFFD}".as_bytes()
+        );
+        assert_eq!(encode(b"a\xff", OutputEncoding::Raw), b"a\xff");
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// What `,` stores in the current cell once input is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EofMode {
+    /// Store 0.
+    Zero,
+    /// Store -1, which wraps to 255.
+    NegativeOne,
+    /// Leave the cell as it was.
+    Unchanged,
+    /// Store a specific byte.
+    Value(u8),
+}
+
+impl EofMode {
+    /// Value of the current cell after a read that hit EOF.
+    pub fn on_eof(self, current: u8) -> u8 {
+        match self {
+            EofMode::Zero => 0,
+            EofMode::NegativeOne => 255,
+            EofMode::Unchanged => current,
+            EofMode::Value(value) => value,
+        }
+    }
+}
+
+/// Reading EOF as -1 is what C's `getchar` does, and so is what this crate
+/// did before the mode was configurable.
+impl Default for EofMode {
+    fn default() -> Self {
+        EofMode::NegativeOne
+    }
+}
+
+impl FromStr for EofMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(EofMode::Zero),
+            "negative-one" => Ok(EofMode::NegativeOne),
+            "unchanged" => Ok(EofMode::Unchanged),
+            _ => Err(format!("Unknown EOF mode: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for EofMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EofMode::Zero => write!(f, "zero"),
+            EofMode::NegativeOne => write!(f, "negative-one"),
+            EofMode::Unchanged => write!(f, "unchanged"),
+            EofMode::Value(value) => write!(f, "value {}", value),
+        }
+    }
+}
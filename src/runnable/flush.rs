@@ -0,0 +1,142 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// Bytes held back before they are written out regardless of policy.
+const BUFFER_SIZE: usize = 8192;
+
+/// When buffered output is passed on to the underlying stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum FlushPolicy {
+    /// After every newline, for interactive programs.
+    #[default]
+    Line,
+    /// Only once the buffer is full, for bulk output.
+    Full,
+    /// After every write.
+    Byte,
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "line" => Ok(FlushPolicy::Line),
+            "full" => Ok(FlushPolicy::Full),
+            "byte" => Ok(FlushPolicy::Byte),
+            _ => Err(format!("Unknown flush policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FlushPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlushPolicy::Line => write!(f, "line"),
+            FlushPolicy::Full => write!(f, "full"),
+            FlushPolicy::Byte => write!(f, "byte"),
+        }
+    }
+}
+
+/// Output stream that buffers writes and flushes them as its policy says.
+///
+/// Whatever is still buffered is flushed when the stream is dropped.
+pub struct FlushWriter<W: Write> {
+    inner: W,
+    policy: FlushPolicy,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> FlushWriter<W> {
+    pub fn new(inner: W, policy: FlushPolicy) -> Self {
+        FlushWriter {
+            inner,
+            policy,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+        }
+    }
+
+    /// Hand the buffer to the inner stream without flushing it.
+    fn write_buffer(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for FlushWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+
+        match self.policy {
+            FlushPolicy::Byte => self.flush()?,
+            FlushPolicy::Line if buf.contains(&b'\n') => self.flush()?,
+            _ if self.buffer.len() >= BUFFER_SIZE => self.write_buffer()?,
+            _ => {}
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffer()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for FlushWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records how many bytes had arrived at each flush.
+    #[derive(Default)]
+    struct Flushes {
+        written: usize,
+        flushes: Vec<usize>,
+    }
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes.push(self.written);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn line_flushes_on_newline() {
+        let mut writer = FlushWriter::new(Flushes::default(), FlushPolicy::Line);
+        writer.write_all(b"a").unwrap();
+        writer.write_all(b"b\n").unwrap();
+        writer.write_all(b"c").unwrap();
+
+        assert_eq!(writer.inner.flushes, vec![3]);
+        assert_eq!(writer.inner.written, 3);
+    }
+
+    #[test]
+    fn full_waits_for_buffer() {
+        let mut writer = FlushWriter::new(Flushes::default(), FlushPolicy::Full);
+        writer.write_all(b"ab\ncd\n").unwrap();
+
+        assert!(writer.inner.flushes.is_empty());
+        assert_eq!(writer.inner.written, 0);
+
+        writer.write_all(&[b'x'; BUFFER_SIZE]).unwrap();
+        assert!(writer.inner.flushes.is_empty());
+        assert_eq!(writer.inner.written, BUFFER_SIZE + 6);
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// When the JIT turns compiled loops back into deferred ones, checked each
+/// time a run ends, and for `Lru` also each time a loop finishes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum FragmentPolicy {
+    /// Keep every compiled loop, so later runs compile nothing.
+    #[default]
+    Keep,
+    /// Drop every compiled loop once the run is over.
+    FreeAfterRun,
+    /// Keep at most this many compiled loops, dropping the ones that least
+    /// recently finished running.
+    Lru(usize),
+}
+
+impl FromStr for FragmentPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(FragmentPolicy::Keep),
+            "free-after-run" => Ok(FragmentPolicy::FreeAfterRun),
+            _ => match s.strip_prefix("lru:").map(str::parse) {
+                Some(Ok(cap)) => Ok(FragmentPolicy::Lru(cap)),
+                _ => Err(format!("Unknown fragment policy: {}", s)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for FragmentPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FragmentPolicy::Keep => write!(f, "keep"),
+            FragmentPolicy::FreeAfterRun => write!(f, "free-after-run"),
+            FragmentPolicy::Lru(cap) => write!(f, "lru:{}", cap),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_policy() {
+        for &policy in &[
+            FragmentPolicy::Keep,
+            FragmentPolicy::FreeAfterRun,
+            FragmentPolicy::Lru(4),
+        ] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+
+        assert!("lru".parse::<FragmentPolicy>().is_err());
+        assert!("lru:-1".parse::<FragmentPolicy>().is_err());
+    }
+}
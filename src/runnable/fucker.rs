@@ -1,45 +1,600 @@
 use std::cmp;
 use std::collections::VecDeque;
-use std::io::Write;
-
-use libc::getchar;
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
 
+use super::super::error::BfError;
 use super::super::parser::{ASTNode, Instr};
-use super::Runnable;
+use super::{CancelToken, CellArithmetic, EofMode, ExitReason, Io, PointerMode, Runnable, Tape};
+
+/// Steps `RunAsync` takes before giving other tasks a turn.
+const YIELD_INTERVAL: u64 = 0x1000;
+
+/// How long `RunAsync` waits before retrying a read that would block.
+const READ_RETRY: Duration = Duration::from_millis(1);
+
+lazy_static! {
+    /// Wakes the tasks handed to it once `READ_RETRY` has passed, on a
+    /// thread shared by every `RunAsync`.
+    static ref RETRY: Mutex<Sender<Waker>> = {
+        let (sender, receiver) = mpsc::channel::<Waker>();
+        thread::spawn(move || {
+            while let Ok(waker) = receiver.recv() {
+                thread::sleep(READ_RETRY);
+                waker.wake();
+                receiver.try_iter().for_each(Waker::wake);
+            }
+        });
+
+        Mutex::new(sender)
+    };
+}
+
+/// How a call to `Fucker::run_steps` ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    /// The program ended, hit an error or was interrupted, after `steps`
+    /// steps in total.
+    Finished { steps: u64 },
+    /// The step limit was reached. Calling `run_steps` again carries on
+    /// from instruction `pc` with the data pointer on `cell`.
+    Paused { pc: usize, cell: usize, steps: u64 },
+}
+
+/// Why `Fucker::run_slice` returned.
+enum Slice {
+    /// The program ended, hit an error or was interrupted.
+    Finished,
+    /// The next step would go past the limit.
+    Limit,
+    /// The next instruction reads input that is not ready yet.
+    Blocked,
+}
+
+/// Cells on either side of the data pointer in `BreakState::tape`.
+pub const BREAK_WINDOW: usize = 8;
+
+/// Where `Fucker::run_to_breakpoint` stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakState {
+    /// Instruction to carry on from.
+    pub pc: usize,
+    /// Cell the data pointer is on, negative left of cell 0 on a two-sided
+    /// tape.
+    pub cell: isize,
+    /// The cells up to `BREAK_WINDOW` away from the data pointer.
+    pub tape: Vec<u8>,
+    /// Cell the window starts at.
+    pub tape_start: isize,
+    /// Everything printed since the previous break.
+    pub output: Vec<u8>,
+    /// Whether the program ended, hit an error or was interrupted rather
+    /// than reaching a breakpoint.
+    pub finished: bool,
+}
+
+/// Number of times the interpreter looked at or changed a tape cell, see
+/// `Fucker::set_count_accesses`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessStats {
+    pub reads: usize,
+    pub writes: usize,
+}
+
+impl AccessStats {
+    /// Count the accesses `instr` makes to the current cell.
+    fn record(&mut self, instr: Instr) {
+        let (reads, writes) = accesses(instr);
+
+        self.reads += reads;
+        self.writes += writes;
+    }
+}
+
+/// Number of times each tape cell was read and written, see
+/// `Fucker::set_profile_cells`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CellProfile {
+    pub reads: Vec<usize>,
+    pub writes: Vec<usize>,
+}
+
+impl CellProfile {
+    fn new(tape_size: usize) -> Self {
+        CellProfile {
+            reads: vec![0; tape_size],
+            writes: vec![0; tape_size],
+        }
+    }
+
+    /// Count the accesses `instr` makes to `cell`.
+    fn record(&mut self, cell: usize, instr: Instr) {
+        let (reads, writes) = accesses(instr);
+        self.add(cell, reads, writes);
+    }
+
+    /// Count `reads` and `writes` of `cell`.
+    fn add(&mut self, cell: usize, reads: usize, writes: usize) {
+        if reads == 0 && writes == 0 {
+            return;
+        }
+
+        // The tape may have grown since the run started
+        if cell >= self.reads.len() {
+            self.reads.resize(cell + 1, 0);
+            self.writes.resize(cell + 1, 0);
+        }
+
+        self.reads[cell] += reads;
+        self.writes[cell] += writes;
+    }
+
+    /// Up to `n` of the cells that were accessed, busiest first, as
+    /// `(cell, reads, writes)`.
+    pub fn hottest(&self, n: usize) -> Vec<(usize, usize, usize)> {
+        let mut cells: Vec<_> = self
+            .reads
+            .iter()
+            .zip(&self.writes)
+            .enumerate()
+            .filter(|(_, (&reads, &writes))| reads + writes > 0)
+            .map(|(cell, (&reads, &writes))| (cell, reads, writes))
+            .collect();
+        cells.sort_by_key(|&(cell, reads, writes)| (cmp::Reverse(reads + writes), cell));
+        cells.truncate(n);
+
+        cells
+    }
+
+    /// Write a table of the `n` busiest cells.
+    pub fn write_summary(&self, w: &mut dyn Write, n: usize) -> io::Result<()> {
+        writeln!(w, "Hottest cells:")?;
+        for (cell, reads, writes) in self.hottest(n) {
+            writeln!(w, "{:>8}: {} reads, {} writes", cell, reads, writes)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Number of times `instr` reads and writes the current cell.
+fn accesses(instr: Instr) -> (usize, usize) {
+    match instr {
+        Instr::Incr(_) | Instr::Decr(_) => (1, 1),
+        Instr::Set(_) | Instr::Read => (0, 1),
+        // The target cell is counted separately, see `Fucker::step`
+        Instr::Print | Instr::PrintRepeat(_) | Instr::DebugPrintValue | Instr::MulAdd(..) => (1, 0),
+        Instr::BeginLoop(_) | Instr::EndLoop(_) => (1, 0),
+        Instr::AddTo(..) => (0, 0),
+        Instr::Next(_) | Instr::Prev(_) | Instr::PrintLiteral(_) | Instr::Breakpoint => (0, 0),
+        // Each cell is counted separately, see `Fucker::step`
+        Instr::SetRange(..) => (0, 0),
+        instr => match instr.split_move() {
+            Some((_, op)) => accesses(op),
+            None => (0, 0),
+        },
+    }
+}
+
+/// Cells listed when a run with `Fucker::set_profile_cells` ends.
+const PROFILE_CELLS_SHOWN: usize = 16;
 
 /// BrainFuck virtual machine
-pub struct Fucker {
+///
+/// The AST is flattened into a linear `Instr` program when the machine is
+/// created, with each loop's jump offsets resolved up front, so running never
+/// walks the tree.
+pub struct Fucker<T: Tape = Vec<u8>> {
     program: Vec<Instr>,
-    memory: Vec<u8>,
+    /// Bytes printed by each `Instr::PrintLiteral`.
+    literals: Vec<Vec<u8>>,
+    memory: T,
+    /// Index in `memory` of cell 0, which is only past the start once a
+    /// two-sided tape has grown to the left.
+    origin: usize,
     /// Program counter
     pub pc: usize,
     /// Data pointer
     pub dp: usize,
+    /// When set by another thread or a signal handler, execution stops
+    /// before the next instruction.
+    interrupt: Option<Arc<AtomicBool>>,
+    /// Print the tape to stderr once the program stops.
+    dump_tape: bool,
+    /// Cell the data pointer starts at.
+    start_cell: usize,
+    /// What happens when the data pointer moves past the end of the tape.
+    pointer_mode: PointerMode,
+    /// What happens when `+` or `-` cross a cell's limits.
+    arithmetic: CellArithmetic,
+    eof: EofMode,
+    /// Where each executed instruction is logged, if anywhere.
+    trace: Option<Box<dyn Write>>,
+    /// Number of steps taken since the last reset.
+    steps: u64,
+    /// Steps a run by `run_with_io` may take, if limited.
+    max_steps: Option<u64>,
+    /// Called with the loop's id and the current cell whenever a loop body is
+    /// about to run.
+    loop_hook: Option<Box<dyn FnMut(usize, u8)>>,
+    /// Tape accesses so far, if they are being counted.
+    access_stats: Option<AccessStats>,
+    /// Accesses to each cell so far, if they are being counted.
+    cell_profile: Option<CellProfile>,
+    /// Cells whose every change is reported.
+    watches: Vec<usize>,
+    /// Where the tape is written when the data pointer leaves it.
+    core_file: Option<PathBuf>,
+    /// Instruction the data pointer left the tape at during this run.
+    fault: Option<usize>,
+    /// Cells each `.` prints.
+    print_width: usize,
+    /// How the last run ended.
+    exit: ExitReason,
 }
 
 impl Fucker {
     pub fn new(nodes: &VecDeque<ASTNode>) -> Self {
+        Self::with_tape(nodes, vec![0u8; 0x4000])
+    }
+}
+
+impl<T: Tape> Fucker<T> {
+    /// Run on `tape` rather than on a tape on the heap, starting out with as
+    /// many cells as it has.
+    pub fn with_tape(nodes: &VecDeque<ASTNode>, tape: T) -> Self {
+        let mut literals = Vec::new();
+
         Fucker {
-            program: Self::compile(nodes),
-            memory: vec![0u8; 0x4000],
+            program: Self::compile(nodes, &mut literals),
+            literals,
+            memory: tape,
+            origin: 0,
             pc: 0,
             dp: 0,
+            interrupt: None,
+            dump_tape: false,
+            start_cell: 0,
+            pointer_mode: PointerMode::default(),
+            arithmetic: CellArithmetic::default(),
+            eof: EofMode::default(),
+            trace: None,
+            steps: 0,
+            max_steps: None,
+            loop_hook: None,
+            access_stats: None,
+            cell_profile: None,
+            watches: Vec::new(),
+            core_file: None,
+            fault: None,
+            print_width: 1,
+            exit: ExitReason::Finished,
+        }
+    }
+
+    /// Choose what `,` does once input is exhausted.
+    pub fn set_eof_mode(&mut self, eof: EofMode) {
+        self.eof = eof;
+    }
+
+    /// Start the data pointer at `cell` rather than at cell 0.
+    ///
+    /// In the growing pointer modes the tape is grown if it does not already
+    /// contain `cell`, in the others `cell` has to be on the tape.
+    pub fn set_start_cell(&mut self, cell: usize) -> Result<(), BfError> {
+        let len = self.memory.len();
+        let index = self.origin.checked_add(cell).filter(|&index| index < len);
+        let index = match (index, self.pointer_mode) {
+            (Some(index), _) => index,
+            (None, PointerMode::Grow) | (None, PointerMode::TwoSided) => {
+                let cells = self
+                    .origin
+                    .checked_add(cell)
+                    .and_then(|index| index.checked_add(1))
+                    .ok_or(BfError::PointerOutOfBounds {
+                        cell,
+                        tape_size: usize::MAX,
+                    })?;
+                self.memory
+                    .resize(cells)
+                    .map_err(|error| BfError::TapeAlloc { cells, error })?;
+                cells - 1
+            }
+            (None, _) => {
+                return Err(BfError::PointerOutOfBounds {
+                    cell,
+                    tape_size: len - self.origin,
+                })
+            }
+        };
+
+        self.start_cell = cell;
+        self.dp = index;
+
+        Ok(())
+    }
+
+    /// Give the tape `size` cells. Whether it stays that size depends on the
+    /// pointer mode.
+    pub fn set_tape_size(&mut self, size: usize) -> Result<(), BfError> {
+        self.memory
+            .resize(size)
+            .map_err(|error| BfError::TapeAlloc { cells: size, error })
+    }
+
+    /// Choose what happens when `>` moves past the end of the tape.
+    pub fn set_pointer_mode(&mut self, mode: PointerMode) {
+        self.pointer_mode = mode;
+    }
+
+    /// Choose what happens when `+` or `-` go past 255 or below 0.
+    pub fn set_cell_arithmetic(&mut self, arithmetic: CellArithmetic) {
+        self.arithmetic = arithmetic;
+    }
+
+    /// Make each `.` print `width` cells from the current one on, first cell
+    /// first, such as two for big-endian UTF-16. Cells past the end of the
+    /// tape print as 0.
+    ///
+    /// `PrintLiteral` nodes were folded for one cell per `.`, so programs
+    /// for a wider print should leave out the passes that make them.
+    pub fn set_print_width(&mut self, width: usize) {
+        self.print_width = width;
+    }
+
+    /// Stop execution whenever `flag` becomes true.
+    pub fn set_interrupt(&mut self, flag: Arc<AtomicBool>) {
+        self.interrupt = Some(flag);
+    }
+
+    /// Stop execution whenever `token` is cancelled.
+    pub fn set_cancel_token(&mut self, token: &CancelToken) {
+        self.set_interrupt(token.flag());
+    }
+
+    /// Stop each run by `run_with_io` once it has taken `max` steps, with
+    /// `ExitReason::Limit`, or never with `None`.
+    pub fn set_max_steps(&mut self, max: Option<u64>) {
+        self.max_steps = max;
+    }
+
+    /// Print the tape to stderr when a run finishes or is interrupted.
+    pub fn set_dump_tape(&mut self, dump_tape: bool) {
+        self.dump_tape = dump_tape;
+    }
+
+    /// When the data pointer leaves the tape, write the whole tape to `path`
+    /// as raw bytes, and the pointer and faulting instruction as text to
+    /// `path` with `.info` appended.
+    pub fn set_core_on_fault(&mut self, path: PathBuf) {
+        self.core_file = Some(path);
+    }
+
+    /// Log every executed instruction, along with the data pointer and the
+    /// value of the cell it points to afterwards, to `w`.
+    pub fn set_trace(&mut self, w: Box<dyn Write>) {
+        self.trace = Some(w);
+    }
+
+    /// Call `hook` at the start of every loop iteration with the loop's id,
+    /// which is the index of its `BeginLoop` instruction, and the value of
+    /// the current cell.
+    pub fn set_loop_hook(&mut self, hook: Box<dyn FnMut(usize, u8)>) {
+        self.loop_hook = Some(hook);
+    }
+
+    /// Count every read and write of a tape cell, see `access_stats`. Off by
+    /// default to keep stepping cheap.
+    pub fn set_count_accesses(&mut self, count: bool) {
+        self.access_stats = if count {
+            Some(AccessStats::default())
+        } else {
+            None
+        };
+    }
+
+    /// Tape accesses made since the current or last run started, all zero
+    /// unless `set_count_accesses` turned counting on.
+    pub fn access_stats(&self) -> AccessStats {
+        self.access_stats.unwrap_or_default()
+    }
+
+    /// Count the reads and writes of each tape cell, and list the busiest
+    /// cells on the debug stream once the program stops. Off by default, as
+    /// it slows every step down.
+    pub fn set_profile_cells(&mut self, profile: bool) {
+        self.cell_profile = if profile {
+            Some(CellProfile::default())
+        } else {
+            None
+        };
+    }
+
+    /// Accesses to each cell since the current or last run started, if
+    /// `set_profile_cells` turned profiling on.
+    pub fn cell_profile(&self) -> Option<&CellProfile> {
+        self.cell_profile.as_ref()
+    }
+
+    /// Report to the debug stream whenever `cell` changes value, with the
+    /// old and new values and the instruction that changed it.
+    pub fn add_watch(&mut self, cell: usize) {
+        self.watches.push(cell);
+    }
+
+    /// Turn the interpreter into a function that runs the program on a tape
+    /// starting at a given cell and returns the cell it ends on, for driving
+    /// snippets such as REPL lines one at a time. `,` and `.` use stdin and
+    /// stdout.
+    ///
+    /// The tape is copied in and out on every call, and moving past its end
+    /// stops the snippet as in `PointerMode::Halt`. A snippet that stops
+    /// early, or a cell outside of the tape, is an error.
+    pub fn into_fn(mut self) -> impl FnMut(&mut [u8], usize) -> Result<usize, BfError> {
+        self.pointer_mode = PointerMode::Halt;
+
+        move |tape: &mut [u8], cell: usize| {
+            let (mut input, mut output) = (io::stdin(), io::stdout());
+            let mut io = Io::new(&mut input, &mut output);
+            let end = self.run_on(tape, cell, &mut io);
+            if let Err(e) = io.flush() {
+                io.write_diagnostic(e);
+                return end.and_then(|cell| Err(BfError::Halted { cell }));
+            }
+
+            end
+        }
+    }
+
+    /// Run the program from the start on a copy of `tape` with the data
+    /// pointer at `cell`, copy the result back and return the cell it ended
+    /// on, or `BfError::Halted` if it stopped before the end.
+    pub(crate) fn run_on(
+        &mut self,
+        tape: &mut [u8],
+        cell: usize,
+        io: &mut Io,
+    ) -> Result<usize, BfError> {
+        if cell >= tape.len() {
+            return Err(BfError::PointerOutOfBounds {
+                cell,
+                tape_size: tape.len(),
+            });
+        }
+        self.memory
+            .resize(tape.len())
+            .map_err(|error| BfError::TapeAlloc {
+                cells: tape.len(),
+                error,
+            })?;
+        self.memory.copy_from_slice(tape);
+        self.origin = 0;
+        self.pc = 0;
+        self.dp = cell;
+
+        while self.step(io) {}
+
+        tape.copy_from_slice(&self.memory);
+        if self.pc < self.program.len() {
+            return Err(BfError::Halted { cell: self.dp });
+        }
+        Ok(self.dp)
+    }
+
+    /// Whether the interrupt flag has been raised.
+    pub fn interrupted(&self) -> bool {
+        self.interrupt
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// The tape in its current state.
+    pub fn tape(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// The value of `index`, counting from cell 0, or None if the tape
+    /// doesn't reach that far.
+    pub fn cell(&self, index: usize) -> Option<u8> {
+        self.memory.get(self.origin.checked_add(index)?).cloned()
+    }
+
+    /// Index in `tape` of cell 0, see `PointerMode::TwoSided`.
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    /// The cell the data pointer is on, counting from cell 0, so negative
+    /// left of it on a two-sided tape.
+    fn current_cell(&self) -> isize {
+        self.dp as isize - self.origin as isize
+    }
+
+    /// Write the data pointer and the used portion of the tape as hex.
+    ///
+    /// Positions are relative to cell 0, so cells left of it on a two-sided
+    /// tape are negative.
+    pub fn write_tape(&self, w: &mut dyn Write) -> io::Result<()> {
+        /// Format a position relative to cell 0.
+        fn position(index: usize, origin: usize) -> String {
+            if index < origin {
+                format!("-{:04X}", origin - index)
+            } else {
+                format!("{:04X}", index - origin)
+            }
+        }
+
+        let used = self
+            .memory
+            .iter()
+            .rposition(|&cell| cell != 0)
+            .map_or(0, |last| last + 1);
+        let len = cmp::min(cmp::max(used, self.dp + 1), self.memory.len());
+        // Leave out unused cells grown to the left, with rows still starting
+        // a multiple of 16 cells from cell 0 where the tape allows
+        let first_used = self
+            .memory
+            .iter()
+            .position(|&cell| cell != 0)
+            .unwrap_or(self.origin);
+        let lowest = cmp::min(cmp::min(first_used, self.dp), self.origin);
+        let start = self.origin - cmp::min(self.origin, (self.origin - lowest).div_ceil(16) * 16);
+
+        let (sign, dp) = if self.dp < self.origin {
+            ("-", self.origin - self.dp)
+        } else {
+            ("", self.dp - self.origin)
+        };
+        writeln!(w, "pc=0x{:04X} dp={}0x{:04X}", self.pc, sign, dp)?;
+
+        for (row, cells) in self.memory[start..len].chunks(16).enumerate() {
+            write!(w, "{}:", position(start + row * 16, self.origin))?;
+
+            for cell in cells {
+                write!(w, " {:02X}", cell)?;
+            }
+
+            writeln!(w)?;
         }
+
+        Ok(())
     }
 
-    fn compile(nodes: &VecDeque<ASTNode>) -> Vec<Instr> {
+    fn compile(nodes: &VecDeque<ASTNode>, literals: &mut Vec<Vec<u8>>) -> Vec<Instr> {
         let mut instrs = Vec::new();
 
         for node in nodes {
             match node {
                 ASTNode::Incr(n) => instrs.push(Instr::Incr(*n)),
                 ASTNode::Decr(n) => instrs.push(Instr::Decr(*n)),
+                ASTNode::Set(n) => instrs.push(Instr::Set(*n)),
+                ASTNode::SetRange(len, n) => instrs.push(Instr::SetRange(*len, *n)),
+                ASTNode::MulAdd(offset, factor) => instrs.push(Instr::MulAdd(*offset, *factor)),
+                ASTNode::AddTo(offset, n) => instrs.push(Instr::AddTo(*offset, *n)),
                 ASTNode::Next(n) => instrs.push(Instr::Next(*n)),
                 ASTNode::Prev(n) => instrs.push(Instr::Prev(*n)),
                 ASTNode::Print => instrs.push(Instr::Print),
+                ASTNode::PrintRepeat(n) => instrs.push(Instr::PrintRepeat(*n)),
+                ASTNode::PrintLiteral(bytes) => {
+                    literals.push(bytes.clone());
+                    instrs.push(Instr::PrintLiteral(literals.len() - 1));
+                }
                 ASTNode::Read => instrs.push(Instr::Read),
+                ASTNode::DebugPrintValue => instrs.push(Instr::DebugPrintValue),
+                ASTNode::Breakpoint => instrs.push(Instr::Breakpoint),
                 ASTNode::Loop(vec) => {
-                    let inner_loop = Self::compile(vec);
+                    let inner_loop = Self::compile(vec, literals);
                     // Add 1 to the offset to account for the BeginLoop/EndLoop instr
                     let offset = inner_loop.len() + 1;
 
@@ -53,100 +608,1527 @@ impl Fucker {
         instrs
     }
 
+    /// Merge every pointer move that is directly followed by `+`, `-`, a
+    /// clear, `.` or `,` into one instruction, such as `IncrAt(1, 1)` for
+    /// `>+`, so the pair is run in one go. It still counts as two steps.
+    ///
+    /// Call this before running. Loop ids given to the loop hook change along
+    /// with the instructions' positions.
+    pub fn fuse_moves(&mut self) {
+        let mut program = Vec::with_capacity(self.program.len());
+        // Position in the new program of each old instruction
+        let mut moved_to = Vec::with_capacity(self.program.len() + 1);
+        let mut index = 0;
+
+        while index < self.program.len() {
+            let offset = match self.program[index] {
+                Instr::Next(n) => isize::try_from(n).ok(),
+                Instr::Prev(n) => isize::try_from(n).ok().map(|n| -n),
+                _ => None,
+            };
+            let fused = offset.and_then(|offset| {
+                self.program
+                    .get(index + 1)
+                    .and_then(|&next| next.after_move(offset))
+            });
+
+            moved_to.push(program.len());
+            match fused {
+                Some(instr) => {
+                    moved_to.push(program.len());
+                    program.push(instr);
+                    index += 2;
+                }
+                None => {
+                    program.push(self.program[index]);
+                    index += 1;
+                }
+            }
+        }
+        moved_to.push(program.len());
+
+        // Jumps go from one loop instruction to the other, and those are
+        // never fused
+        for (old, &new) in moved_to.iter().enumerate().take(self.program.len()) {
+            match self.program[old] {
+                Instr::BeginLoop(offset) => {
+                    program[new] = Instr::BeginLoop(moved_to[old + offset] - new)
+                }
+                Instr::EndLoop(offset) => {
+                    program[new] = Instr::EndLoop(new - moved_to[old - offset])
+                }
+                _ => {}
+            }
+        }
+
+        self.program = program;
+    }
+
     /// Execute a single instruction on the VM.
     ///
     /// Returns false when the program has terminated.
-    pub fn step(&mut self) -> bool {
+    pub fn step(&mut self, io: &mut Io) -> bool {
         // Terminate if the program counter is outside of the program.
-        if self.pc >= self.program.len() {
+        if self.pc >= self.program.len() || self.interrupted() {
             return false;
         }
 
+        let fused = self.program[self.pc];
+        let mut instr = fused;
+        if let Some((offset, op)) = fused.split_move() {
+            let moved = if offset >= 0 {
+                self.move_right(offset as usize, io)
+            } else {
+                self.move_left(offset.unsigned_abs(), io)
+            };
+            if !moved {
+                return false;
+            }
+
+            instr = op;
+        }
+
         // If the data pointer ends up outside of memory, expand either to a
         // double of the current memory size, or the new data pointer location
         // (whichever is bigger).
-        if self.dp >= self.memory.len() {
-            let new_len = cmp::max(self.memory.len() * 2, self.dp);
-            self.memory.resize(new_len, 0);
+        if self.dp >= self.memory.len()
+            && matches!(self.pointer_mode, PointerMode::Grow | PointerMode::TwoSided)
+        {
+            let new_len = cmp::max(self.memory.len() * 2, self.dp + 1);
+            if let Err(e) = self.memory.resize(new_len) {
+                self.fault(&format!("Could not grow the tape: {}", e), io);
+                return false;
+            }
         }
 
-        let instr = self.program[self.pc];
         let current = self.memory[self.dp];
 
         match instr {
-            Instr::Incr(n) => {
-                self.memory[self.dp] = current.wrapping_add(n);
+            Instr::Incr(n) => match self.arithmetic.add(current, n) {
+                Some(value) => self.memory[self.dp] = value,
+                None => {
+                    io.write_diagnostic(format!(
+                        "Cell {} overflowed: {} + {}",
+                        self.current_cell(),
+                        current,
+                        n
+                    ));
+                    return false;
+                }
+            },
+            Instr::Decr(n) => match self.arithmetic.sub(current, n) {
+                Some(value) => self.memory[self.dp] = value,
+                None => {
+                    io.write_diagnostic(format!(
+                        "Cell {} underflowed: {} - {}",
+                        self.current_cell(),
+                        current,
+                        n
+                    ));
+                    return false;
+                }
+            },
+            // Like the loop it came from, nothing happens on a 0 cell
+            Instr::MulAdd(offset, factor) if current != 0 => {
+                if !self.add_to(offset, current.wrapping_mul(factor), io) {
+                    return false;
+                }
+            }
+            Instr::MulAdd(..) => {}
+            Instr::AddTo(offset, n) => {
+                if !self.add_to(offset, n, io) {
+                    return false;
+                }
+            }
+            // Already split into their move and plain instruction
+            Instr::IncrAt(..)
+            | Instr::DecrAt(..)
+            | Instr::SetAt(..)
+            | Instr::PrintAt(_)
+            | Instr::ReadAt(_) => {}
+            Instr::Set(n) => {
+                self.memory[self.dp] = n;
             }
-            Instr::Decr(n) => {
-                self.memory[self.dp] = current.wrapping_sub(n);
+            Instr::SetRange(len, n) => {
+                if let Some(ref mut stats) = self.access_stats {
+                    stats.writes += len;
+                }
+
+                // A range that stays on the tape with nothing to report on
+                // needs no checks for each cell
+                let end = self.dp.saturating_add(len);
+                if end < self.memory.len() && self.cell_profile.is_none() && self.watches.is_empty()
+                {
+                    self.memory[self.dp..end].fill(n);
+                    self.dp = end;
+                } else {
+                    for _ in 0..len {
+                        self.set_cell(n, io);
+                        if !self.move_right(1, io) {
+                            return false;
+                        }
+                    }
+                }
             }
             Instr::Next(n) => {
-                self.dp += n;
+                if !self.move_right(n, io) {
+                    return false;
+                }
             }
             Instr::Prev(n) => {
-                if self.dp < n {
-                    eprintln!("Attempted to point below memory location 0.");
+                if !self.move_left(n, io) {
                     return false;
                 }
-
-                self.dp -= n;
             }
             Instr::Print => {
-                if let Err(msg) = std::io::stdout()
-                    .write(&[current])
-                    .and_then(|_size| std::io::stdout().flush())
-                {
-                    eprintln!("{}", msg);
+                let result = if self.print_width == 1 {
+                    io.write_byte(current)
+                } else {
+                    io.write_bytes(&self.print_unit())
+                };
+
+                if let Err(msg) = result {
+                    io.write_diagnostic(msg);
+                    return false;
+                }
+            }
+            Instr::PrintRepeat(n) => {
+                let bytes = if self.print_width == 1 {
+                    vec![current; n]
+                } else {
+                    self.print_unit().repeat(n)
+                };
+
+                if let Err(msg) = io.write_bytes(&bytes) {
+                    io.write_diagnostic(msg);
+                    return false;
+                }
+            }
+            Instr::PrintLiteral(index) => {
+                let bytes = &self.literals[index];
+
+                if let Err(msg) = io.write_bytes(bytes) {
+                    io.write_diagnostic(msg);
                     return false;
                 }
             }
             Instr::Read => {
-                self.memory[self.dp] = unsafe { getchar() as u8 };
+                self.memory[self.dp] = io.read_byte().unwrap_or_else(|| self.eof.on_eof(current));
+            }
+            Instr::DebugPrintValue => {
+                if let Err(msg) = io.write_debug(current) {
+                    io.write_diagnostic(msg);
+                    return false;
+                }
             }
+            Instr::Breakpoint => {}
             Instr::BeginLoop(offset) => {
                 if current == 0 {
                     self.pc += offset;
+                } else if let Some(ref mut hook) = self.loop_hook {
+                    hook(self.pc, current);
                 }
             }
             Instr::EndLoop(offset) => {
                 if current != 0 {
                     self.pc -= offset;
+
+                    if let Some(ref mut hook) = self.loop_hook {
+                        hook(self.pc, current);
+                    }
                 }
             }
         }
 
+        if !self.watches.is_empty()
+            && matches!(
+                instr,
+                Instr::Incr(_) | Instr::Decr(_) | Instr::Set(_) | Instr::Read
+            )
+            && self.memory[self.dp] != current
+            && self.dp >= self.origin
+            && self.watches.contains(&(self.dp - self.origin))
+        {
+            io.write_diagnostic(format!(
+                "Watch: cell {} changed from {} to {} at instruction 0x{:04X}",
+                self.dp - self.origin,
+                current,
+                self.memory[self.dp],
+                self.pc
+            ));
+        }
+
         self.pc += 1;
+        self.steps += fused.steps();
+
+        if let Some(ref mut stats) = self.access_stats {
+            stats.record(instr);
+        }
+        if let Some(ref mut profile) = self.cell_profile {
+            profile.record(self.dp, instr);
+        }
+
+        if let Some(ref mut trace) = self.trace {
+            let cell = self.memory.get(self.dp).cloned().unwrap_or(0);
+            let _ = writeln!(
+                trace,
+                "[step {}] {} cell[{}]={}",
+                self.steps,
+                Self::command(fused),
+                self.dp,
+                cell
+            );
+        }
 
         true
     }
 
-    pub fn reset(&mut self) {
-        for i in 0..(self.memory.len() - 1) {
-            self.memory[i] = 0;
+    /// The cells one `.` prints, see `set_print_width`.
+    fn print_unit(&self) -> Vec<u8> {
+        (self.dp..self.dp.saturating_add(self.print_width))
+            .map(|cell| self.memory.get(cell).cloned().unwrap_or(0))
+            .collect()
+    }
+
+    /// Overwrite the current cell as part of a `SetRange`, counting and
+    /// reporting the write.
+    fn set_cell(&mut self, n: u8, io: &mut Io) {
+        let before = self.memory[self.dp];
+        self.memory[self.dp] = n;
+
+        if let Some(ref mut profile) = self.cell_profile {
+            profile.add(self.dp, 0, 1);
         }
+        if before != n && self.dp >= self.origin && self.watches.contains(&(self.dp - self.origin))
+        {
+            io.write_diagnostic(format!(
+                "Watch: cell {} changed from {} to {} at instruction 0x{:04X}",
+                self.dp - self.origin,
+                before,
+                n,
+                self.pc
+            ));
+        }
+    }
 
-        self.pc = 0;
-        self.dp = 0;
+    /// Move the data pointer `n` cells to the right, returning false if the
+    /// pointer mode stops the program.
+    fn move_right(&mut self, n: usize, io: &mut Io) -> bool {
+        let len = self.memory.len();
+
+        // A single bulk move can cross the end by any amount.
+        match self.pointer_mode {
+            PointerMode::Grow | PointerMode::TwoSided => match self.dp.checked_add(n) {
+                Some(dp) => self.dp = dp,
+                None => {
+                    self.fault("Attempted to point past the end of memory.", io);
+                    return false;
+                }
+            },
+            PointerMode::Wrap => self.dp = (self.dp + n % len) % len,
+            PointerMode::Halt if n >= len - self.dp => {
+                self.fault("Attempted to point past the end of the tape.", io);
+                return false;
+            }
+            PointerMode::Halt | PointerMode::Unchecked => self.dp += n,
+        }
+
+        true
     }
-}
 
-impl Runnable for Fucker {
-    fn run(&mut self) {
-        while self.step() {}
-        self.reset();
+    /// Move the data pointer `n` cells to the left, returning false if the
+    /// pointer mode stops the program.
+    fn move_left(&mut self, n: usize, io: &mut Io) -> bool {
+        if self.dp < n {
+            if self.pointer_mode != PointerMode::TwoSided {
+                self.fault("Attempted to point below memory location 0.", io);
+                return false;
+            }
+
+            // Grow the left side the same way as the right
+            if let Err(e) = self.grow_left(cmp::max(self.memory.len(), n - self.dp)) {
+                self.fault(&format!("Could not grow the tape: {}", e), io);
+                return false;
+            }
+        }
+
+        self.dp -= n;
+
+        true
     }
-}
 
-#[cfg(target_arch = "x86_64")]
-#[cfg(test)]
-mod tests {
-    use super::super::super::parser::AST;
-    use super::*;
+    /// Report that the data pointer left the tape at the current instruction.
+    fn fault(&mut self, msg: &str, io: &mut Io) {
+        io.write_diagnostic(msg);
+        self.fault = Some(self.pc);
+    }
 
-    #[test]
-    fn run_hello_world() {
-        let ast = AST::parse(include_str!("../../test/programs/hello_world.bf")).unwrap();
-        let mut fucker = Fucker::new(&ast.data);
-        fucker.run();
+    /// Write the tape to `path` and where the program stopped to its
+    /// sidecar, see `set_core_on_fault`.
+    fn write_core(&self, path: &Path, pc: usize) -> io::Result<()> {
+        fs::write(path, &self.memory[..])?;
+
+        let mut info = OsString::from(path);
+        info.push(".info");
+        fs::write(
+            info,
+            format!(
+                "pc=0x{:04X}\ninstruction={}\ndp={}\norigin={}\n",
+                pc,
+                Self::command(self.program[pc]),
+                self.dp,
+                self.origin
+            ),
+        )
+    }
+
+    /// Add `n` to the cell `offset` cells from the data pointer, counting
+    /// and watching it like the current cell. Returns false if the cell is
+    /// off the tape.
+    fn add_to(&mut self, offset: isize, n: u8, io: &mut Io) -> bool {
+        let target = match self.target_cell(offset) {
+            Ok(target) => target,
+            Err(msg) => {
+                self.fault(msg, io);
+                return false;
+            }
+        };
+        let before = self.memory[target];
+        self.memory[target] = before.wrapping_add(n);
+
+        if let Some(ref mut stats) = self.access_stats {
+            stats.reads += 1;
+            stats.writes += 1;
+        }
+        if let Some(ref mut profile) = self.cell_profile {
+            profile.add(target, 1, 1);
+        }
+        if self.memory[target] != before
+            && target >= self.origin
+            && self.watches.contains(&(target - self.origin))
+        {
+            io.write_diagnostic(format!(
+                "Watch: cell {} changed from {} to {} at instruction 0x{:04X}",
+                target - self.origin,
+                before,
+                self.memory[target],
+                self.pc
+            ));
+        }
+
+        true
+    }
+
+    /// Index in memory of the cell `offset` cells from the data pointer,
+    /// treating the tape's ends the way pointer moves would.
+    fn target_cell(&mut self, offset: isize) -> Result<usize, &'static str> {
+        let len = self.memory.len();
+
+        if offset < 0 {
+            let distance = offset.unsigned_abs();
+            if self.dp >= distance {
+                return Ok(self.dp - distance);
+            }
+            if self.pointer_mode != PointerMode::TwoSided {
+                return Err("Attempted to point below memory location 0.");
+            }
+
+            self.grow_left(cmp::max(len, distance - self.dp))
+                .map_err(|_| "Could not grow the tape.")?;
+            return Ok(self.dp - distance);
+        }
+
+        let target = self
+            .dp
+            .checked_add(offset as usize)
+            .ok_or("Attempted to point past the end of memory.")?;
+        match self.pointer_mode {
+            _ if target < len => Ok(target),
+            PointerMode::Grow | PointerMode::TwoSided => {
+                self.memory
+                    .resize(cmp::max(len * 2, target + 1))
+                    .map_err(|_| "Could not grow the tape.")?;
+                Ok(target)
+            }
+            PointerMode::Wrap => Ok(target % len),
+            PointerMode::Halt => Err("Attempted to point past the end of the tape."),
+            PointerMode::Unchecked => Ok(target),
+        }
+    }
+
+    /// Add `cells` cells to the left end of the tape, keeping the data
+    /// pointer and cell 0 on the same cells as before.
+    fn grow_left(&mut self, cells: usize) -> io::Result<()> {
+        self.memory.grow_left(cells)?;
+        if let Some(ref mut profile) = self.cell_profile {
+            profile.reads.splice(0..0, vec![0; cells]);
+            profile.writes.splice(0..0, vec![0; cells]);
+        }
+
+        self.origin += cells;
+        self.dp += cells;
+
+        Ok(())
+    }
+
+    /// BrainFuck spelling of an instruction for traces, with a count for runs.
+    fn command(instr: Instr) -> String {
+        match instr {
+            Instr::Incr(1) => "+".to_owned(),
+            Instr::Incr(n) => format!("+{}", n),
+            Instr::Decr(1) => "-".to_owned(),
+            Instr::Decr(n) => format!("-{}", n),
+            Instr::Set(0) => "[-]".to_owned(),
+            Instr::Set(n) => format!("={}", n),
+            Instr::SetRange(len, n) => format!("={} x{}", n, len),
+            Instr::MulAdd(offset, factor) => format!("mul {:+} {}", offset, factor),
+            Instr::AddTo(offset, n) => format!("add {:+} {}", offset, n),
+            Instr::Next(1) => ">".to_owned(),
+            Instr::Next(n) => format!(">{}", n),
+            Instr::Prev(1) => "<".to_owned(),
+            Instr::Prev(n) => format!("<{}", n),
+            Instr::Print => ".".to_owned(),
+            Instr::PrintRepeat(n) => format!(".{}", n),
+            Instr::PrintLiteral(index) => format!("literal {}", index),
+            Instr::Read => ",".to_owned(),
+            Instr::DebugPrintValue => "$".to_owned(),
+            Instr::Breakpoint => "#".to_owned(),
+            Instr::BeginLoop(_) => "[".to_owned(),
+            Instr::IncrAt(..)
+            | Instr::DecrAt(..)
+            | Instr::SetAt(..)
+            | Instr::PrintAt(_)
+            | Instr::ReadAt(_) => {
+                let (offset, op) = instr.split_move().unwrap_or((0, instr));
+                let command = if offset >= 0 {
+                    Self::command(Instr::Next(offset as usize))
+                } else {
+                    Self::command(Instr::Prev(offset.unsigned_abs()))
+                };
+                command + &Self::command(op)
+            }
+            Instr::EndLoop(_) => "]".to_owned(),
+        }
+    }
+
+    /// Run like `run_with_io`, but as a future for use with async runtimes.
+    ///
+    /// The future yields every few thousand steps, and whenever `,`
+    /// finds that `input` returns `WouldBlock`. There is no way to be
+    /// notified once input is ready, so such reads are retried after
+    /// `READ_RETRY` rather than on the very next poll.
+    ///
+    /// Only std's `Future` and `Waker` are used, so any executor can drive
+    /// it, and there is no feature for a particular runtime.
+    pub fn run_async<'a>(
+        &'a mut self,
+        input: &'a mut dyn Read,
+        output: &'a mut dyn Write,
+    ) -> RunAsync<'a, T> {
+        self.start_run();
+
+        RunAsync {
+            fucker: self,
+            input,
+            output,
+        }
+    }
+
+    /// Execute at most `max` steps, so that many programs can take turns on
+    /// one thread. A pair fused by `fuse_moves` is never split, so this may
+    /// stop one step short. It also stops before a `,` whose input would
+    /// block, so that other programs get their turn meanwhile.
+    ///
+    /// Unlike `run_with_io` nothing is reset once the program finishes, so
+    /// the tape can still be looked at. Call `reset` before running it again.
+    pub fn run_steps(&mut self, max: u64, io: &mut Io) -> StepResult {
+        match self.run_slice(max, io, true) {
+            Slice::Finished => StepResult::Finished { steps: self.steps },
+            Slice::Limit | Slice::Blocked => StepResult::Paused {
+                pc: self.pc,
+                cell: self.dp - self.origin,
+                steps: self.steps,
+            },
+        }
+    }
+
+    /// Execute at most `max` steps, the loop behind every way of running.
+    ///
+    /// With `wait_for_input` a `,` whose input would block is left for later,
+    /// otherwise it is taken as the end of input.
+    fn run_slice(&mut self, max: u64, io: &mut Io, wait_for_input: bool) -> Slice {
+        let limit = self.steps.saturating_add(max);
+
+        while let Some(&instr) = self.program.get(self.pc) {
+            if self.steps + instr.steps() > limit {
+                return Slice::Limit;
+            }
+            let reads = instr.split_move().map_or(instr, |(_, op)| op) == Instr::Read;
+            // The only error peek_byte reports is WouldBlock
+            if wait_for_input && reads && io.peek_byte().is_err() {
+                return Slice::Blocked;
+            }
+            if !self.step(io) {
+                return Slice::Finished;
+            }
+        }
+
+        Slice::Finished
+    }
+
+    /// Execute up to and including the next `#` breakpoint, collecting what
+    /// the program prints on the way.
+    ///
+    /// As with `run_steps` nothing is reset once the program finishes. The
+    /// program must be parsed with `ParseOptions::breakpoints` for `#` to
+    /// stop it.
+    pub fn run_to_breakpoint(&mut self, input: &mut dyn Read) -> BreakState {
+        let mut output = Vec::new();
+        let finished = {
+            let mut io = Io::new(input, &mut output);
+
+            loop {
+                let at_breakpoint = self.program.get(self.pc) == Some(&Instr::Breakpoint);
+                if !self.step(&mut io) {
+                    break true;
+                }
+                if at_breakpoint {
+                    break false;
+                }
+            }
+        };
+
+        let start = self.dp.saturating_sub(BREAK_WINDOW);
+        let end = cmp::min(self.dp.saturating_add(BREAK_WINDOW + 1), self.memory.len());
+
+        BreakState {
+            pc: self.pc,
+            cell: self.dp as isize - self.origin as isize,
+            tape: self.memory.get(start..end).unwrap_or_default().to_vec(),
+            tape_start: start as isize - self.origin as isize,
+            output,
+            finished,
+        }
+    }
+
+    /// Clear what is kept from the previous run.
+    fn start_run(&mut self) {
+        if self.access_stats.is_some() {
+            self.access_stats = Some(AccessStats::default());
+        }
+        if self.cell_profile.is_some() {
+            self.cell_profile = Some(CellProfile::new(self.memory.len()));
+        }
+    }
+
+    /// Report how a run ended and get ready for the next one.
+    ///
+    /// Output is only flushed here, so that the output stream's own policy
+    /// decides when it is passed on during the run.
+    fn finish(&mut self, io: &mut Io) {
+        self.exit = if self.pc >= self.program.len() {
+            ExitReason::Finished
+        } else if self.interrupted() {
+            ExitReason::Cancelled
+        } else {
+            ExitReason::Error
+        };
+
+        if let Err(e) = io.flush() {
+            io.write_diagnostic(e);
+            if self.exit == ExitReason::Finished {
+                self.exit = ExitReason::Error;
+            }
+        }
+        if self.exit == ExitReason::Cancelled {
+            io.write_diagnostic(format!("Interrupted at instruction 0x{:04X}", self.pc));
+        }
+
+        if self.dump_tape {
+            let _ = self.write_tape(io.debug());
+        }
+        if let (Some(pc), Some(path)) = (self.fault, self.core_file.as_ref()) {
+            if let Err(e) = self.write_core(path, pc) {
+                io.write_diagnostic(format!("Could not write core file: {}", e));
+            }
+        }
+        if let Some(ref profile) = self.cell_profile {
+            let _ = profile.write_summary(io.debug(), PROFILE_CELLS_SHOWN);
+        }
+
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.memory.clear();
+
+        self.pc = 0;
+        self.dp = self.origin + self.start_cell;
+        self.steps = 0;
+        self.fault = None;
+    }
+}
+
+impl<T: Tape> Runnable for Fucker<T> {
+    fn run_with_diagnostics(
+        &mut self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        diagnostics: &mut dyn Write,
+    ) {
+        let mut io = Io::with_debug(input, output, diagnostics);
+
+        self.start_run();
+        let max = self.max_steps.unwrap_or(u64::MAX);
+        let limited = matches!(self.run_slice(max, &mut io, false), Slice::Limit);
+        if limited {
+            io.write_diagnostic(format!(
+                "Step limit of {} reached at instruction 0x{:04X}",
+                max, self.pc
+            ));
+        }
+
+        self.finish(&mut io);
+        if limited && self.exit == ExitReason::Error {
+            self.exit = ExitReason::Limit;
+        }
+    }
+
+    /// How the last run by `run_with_io` or `run_async` ended.
+    fn exit_reason(&self) -> ExitReason {
+        self.exit
+    }
+}
+
+/// Future returned by `Fucker::run_async`.
+pub struct RunAsync<'a, T: Tape = Vec<u8>> {
+    fucker: &'a mut Fucker<T>,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+}
+
+impl<'a, T: Tape> Future for RunAsync<'a, T> {
+    type Output = ExitReason;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<ExitReason> {
+        let this = self.get_mut();
+        let fucker = &mut *this.fucker;
+        let mut io = Io::new(&mut *this.input, &mut *this.output);
+
+        match fucker.run_slice(YIELD_INTERVAL, &mut io, true) {
+            Slice::Finished => {
+                fucker.finish(&mut io);
+                Poll::Ready(fucker.exit)
+            }
+            Slice::Limit => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Slice::Blocked => {
+                let _ = RETRY.lock().unwrap().send(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::atomic::AtomicUsize;
+    use std::task::Wake;
+    use std::time::Instant;
+
+    use super::super::super::parser::{ParseOptions, AST, STEP_LIMIT};
+    use super::super::{FlushPolicy, FlushWriter};
+    use super::*;
+
+    /// Input that never has anything ready.
+    struct Blocked;
+
+    impl Read for Blocked {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::WouldBlock))
+        }
+    }
+
+    /// Step through a program that does no I/O until it terminates.
+    fn run_silent(fucker: &mut Fucker) {
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut io = Io::new(&mut input, &mut output);
+        while fucker.step(&mut io) {}
+    }
+
+    /// Trace output shared with the test after the Fucker takes ownership.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace() {
+        let ast = AST::parse("++>+").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let buffer = SharedBuffer::default();
+        fucker.set_trace(Box::new(buffer.clone()));
+        run_silent(&mut fucker);
+
+        assert_eq!(
+            String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+            "[step 1] +2 cell[0]=2\n[step 2] > cell[1]=0\n[step 3] + cell[1]=1\n"
+        );
+    }
+
+    /// Output that records how many bytes had arrived at each flush.
+    #[derive(Clone, Default)]
+    struct Flushes(Rc<RefCell<(usize, Vec<usize>)>>);
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            let mut flushes = self.0.borrow_mut();
+            let written = flushes.0;
+            flushes.1.push(written);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_policy() {
+        let ast = AST::parse(",.,.,.").unwrap();
+        for &(policy, expected) in &[
+            (FlushPolicy::Line, &[2, 3][..]),
+            (FlushPolicy::Full, &[3][..]),
+            (FlushPolicy::Byte, &[1, 2, 3, 3][..]),
+        ] {
+            let flushes = Flushes::default();
+            let mut output = FlushWriter::new(flushes.clone(), policy);
+            let mut fucker = Fucker::new(&ast.data);
+            fucker.run_with_io(&mut &b"a\nb"[..], &mut output);
+
+            assert_eq!(flushes.0.borrow().1, expected, "{}", policy);
+        }
+    }
+
+    /// Input that has nothing ready on every other read.
+    struct Trickle<'a>(&'a [u8], bool);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1 = !self.1;
+            if self.1 {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+
+            self.0.read(&mut buf[..1])
+        }
+    }
+
+    /// Counts its wakes and unparks the thread that made it.
+    struct Unpark(thread::Thread, AtomicUsize);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.unpark();
+        }
+    }
+
+    /// Minimal executor: poll `future` each time it wakes the thread, until
+    /// it completes, returning how many polls that took.
+    fn block_on<F: Future>(future: F) -> usize {
+        let unpark = Arc::new(Unpark(thread::current(), AtomicUsize::new(0)));
+        let waker = Waker::from(unpark.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        let mut polls = 1;
+
+        while future.as_mut().poll(&mut cx).is_pending() {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while unpark.1.load(Ordering::SeqCst) < polls {
+                assert!(
+                    Instant::now() < deadline,
+                    "pending future never woke its task"
+                );
+                thread::park_timeout(Duration::from_millis(100));
+            }
+            polls += 1;
+        }
+
+        polls
+    }
+
+    #[test]
+    fn debug_print_value() {
+        let options = ParseOptions {
+            debug_ext: true,
+            ..ParseOptions::default()
+        };
+        let (ast, _) = AST::parse_with_options("+++$>-$.", options).unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let (mut input, mut output, mut debug) = (io::empty(), Vec::new(), Vec::new());
+        {
+            let mut io = Io::with_debug(&mut input, &mut output, &mut debug);
+            while fucker.step(&mut io) {}
+        }
+
+        assert_eq!(debug, b"3\n255\n");
+        assert_eq!(output, b"\xff");
+    }
+
+    /// Run a bulk move from cell 0 to cell 10 and an increment on an 8 cell
+    /// tape, returning the tape afterwards.
+    fn cross_end(mode: PointerMode) -> Vec<u8> {
+        let ast = AST::parse(">>>>>>>>>>+").unwrap();
+        assert_eq!(ast.data[0], ASTNode::Next(10));
+
+        let mut fucker = Fucker::new(&ast.data);
+        fucker.set_tape_size(8).unwrap();
+        fucker.set_pointer_mode(mode);
+        run_silent(&mut fucker);
+
+        fucker.tape().to_vec()
+    }
+
+    #[test]
+    fn pointer_modes() {
+        let grown = cross_end(PointerMode::Grow);
+        assert!(grown.len() > 10);
+        assert_eq!(grown[10], 1);
+
+        assert_eq!(cross_end(PointerMode::Wrap), vec![0, 0, 1, 0, 0, 0, 0, 0]);
+        assert_eq!(cross_end(PointerMode::Halt), vec![0; 8]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn pointer_mode_unchecked() {
+        cross_end(PointerMode::Unchecked);
+    }
+
+    #[test]
+    fn run_async() {
+        let ast = AST::parse(",+[-.,+]").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let mut input = Trickle(b"async", false);
+        let mut output = Vec::new();
+
+        let polls = block_on(fucker.run_async(&mut input, &mut output));
+
+        assert_eq!(output, b"async");
+        assert!(polls > 5);
+    }
+
+    #[test]
+    fn run_async_waits_for_input() {
+        let ast = AST::parse(",").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let (mut input, mut output) = (Blocked, io::sink());
+        let unpark = Arc::new(Unpark(thread::current(), AtomicUsize::new(0)));
+        let waker = Waker::from(unpark.clone());
+        let mut future = Box::pin(fucker.run_async(&mut input, &mut output));
+
+        // The task is not woken straight away, which would spin, but after
+        // a while
+        let start = Instant::now();
+        let pending = future.as_mut().poll(&mut Context::from_waker(&waker));
+        assert!(pending.is_pending());
+        while unpark.1.load(Ordering::SeqCst) == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::park_timeout(Duration::from_millis(100));
+        }
+        assert!(start.elapsed() >= READ_RETRY);
+    }
+
+    #[test]
+    fn matches_tree_walker() {
+        // The compile-time evaluator walks the AST directly, so it serves as
+        // a reference for the jump offsets in the bytecode.
+        for source in &[
+            include_str!("../../test/programs/hello_world.bf"),
+            "++[>++[>+++<-]<-]>>.",
+            "+++++[>+++[>+>++<<-]>.>.<<<-]",
+            "-[>+<-----]>.[-]++[>+[>.+<-]<-]",
+        ] {
+            let ast = AST::parse(source).unwrap();
+            let mut output = Vec::new();
+            Fucker::new(&ast.data).run_with_io(&mut io::empty(), &mut output);
+
+            assert_eq!(Some(output), ast.static_output(STEP_LIMIT));
+        }
+    }
+
+    #[test]
+    fn mul_add() {
+        let ast = AST::parse("+++[->++>+++<<]").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        run_silent(&mut fucker);
+
+        assert_eq!(&fucker.tape()[..3], [0, 6, 9]);
+    }
+
+    #[test]
+    fn run_steps() {
+        let ast = AST::parse("+.+.+.+.+.+.+.+.").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let (mut input, mut output) = (io::empty(), Vec::new());
+        {
+            let mut io = Io::new(&mut input, &mut output);
+            assert_eq!(
+                fucker.run_steps(10, &mut io),
+                StepResult::Paused {
+                    pc: 10,
+                    cell: 0,
+                    steps: 10
+                }
+            );
+        }
+        assert_eq!(output, [1, 2, 3, 4, 5]);
+
+        let mut io = Io::new(&mut input, &mut output);
+        assert_eq!(
+            fucker.run_steps(100, &mut io),
+            StepResult::Finished { steps: 16 }
+        );
+        assert_eq!(output, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn run_steps_waits_for_input() {
+        let ast = AST::parse("+,.").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let mut output = Vec::new();
+        {
+            let mut input = Blocked;
+            let mut io = Io::new(&mut input, &mut output);
+            assert_eq!(
+                fucker.run_steps(10, &mut io),
+                StepResult::Paused {
+                    pc: 1,
+                    cell: 0,
+                    steps: 1
+                }
+            );
+        }
+
+        let mut input = &b"x"[..];
+        let mut io = Io::new(&mut input, &mut output);
+        assert_eq!(
+            fucker.run_steps(10, &mut io),
+            StepResult::Finished { steps: 3 }
+        );
+        assert_eq!(output, b"x");
+    }
+
+    #[test]
+    fn max_steps() {
+        let ast = AST::parse("+.+.+.+.").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        fucker.set_max_steps(Some(5));
+        let (mut output, mut diagnostics) = (Vec::new(), Vec::new());
+        fucker.run_with_diagnostics(&mut io::empty(), &mut output, &mut diagnostics);
+
+        assert_eq!(output, [1, 2]);
+        assert_eq!(fucker.exit_reason(), ExitReason::Limit);
+        assert_eq!(
+            String::from_utf8(diagnostics).unwrap(),
+            "Step limit of 5 reached at instruction 0x0005\n"
+        );
+    }
+
+    #[test]
+    fn fuse_moves() {
+        let ast = AST::parse(">+<<-").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        fucker.set_pointer_mode(PointerMode::TwoSided);
+        fucker.fuse_moves();
+        assert!(fucker.program == [Instr::IncrAt(1, 1), Instr::DecrAt(-2, 1)]);
+
+        run_silent(&mut fucker);
+        let origin = fucker.origin();
+        assert_eq!(fucker.tape()[origin - 1..origin + 2], [0xff, 0, 1]);
+
+        // Each pair still counts as two steps, and is not split at the limit
+        fucker.reset();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut io = Io::new(&mut input, &mut output);
+        assert_eq!(
+            fucker.run_steps(3, &mut io),
+            StepResult::Paused {
+                pc: 1,
+                cell: 1,
+                steps: 2
+            }
+        );
+        assert_eq!(
+            fucker.run_steps(3, &mut io),
+            StepResult::Finished { steps: 4 }
+        );
+
+        let ast = AST::parse(include_str!("../../test/programs/hello_world.bf")).unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        fucker.fuse_moves();
+        let mut output = Vec::new();
+        fucker.run_with_io(&mut io::empty(), &mut output);
+        assert_eq!(output, b"Hello World!\n");
+    }
+
+    #[test]
+    fn loop_hook() {
+        // Both loops become multiply-adds, which do not call the hook
+        let ast = AST::parse("+++[>+<-]>>++[-<+>]").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let recorded = calls.clone();
+        fucker.set_loop_hook(Box::new(move |id, cell| {
+            recorded.borrow_mut().push((id, cell))
+        }));
+        run_silent(&mut fucker);
+
+        assert_eq!(fucker.tape()[1], 5);
+        assert!(calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn loop_hook_iterations() {
+        // The prints keep the loops from becoming multiply-adds
+        let ast = AST::parse("+++[>+<-.]>>++[-<+>.]").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let recorded = calls.clone();
+        fucker.set_loop_hook(Box::new(move |id, cell| {
+            recorded.borrow_mut().push((id, cell))
+        }));
+        run_silent(&mut fucker);
+
+        assert_eq!(fucker.tape()[1], 5);
+        let calls = calls.borrow();
+        assert_eq!(calls.iter().filter(|(id, _)| *id == 1).count(), 3);
+        assert_eq!(
+            calls
+                .iter()
+                .filter(|(id, _)| *id == 1)
+                .map(|(_, cell)| *cell)
+                .collect::<Vec<_>>(),
+            vec![3, 2, 1]
+        );
+        assert_eq!(calls.len(), 5);
+    }
+
+    #[test]
+    fn access_stats() {
+        // , writes once, [ reads once and each of the 2 iterations reads 3
+        // times and writes once
+        let ast = AST::parse(",[-.]").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        fucker.set_count_accesses(true);
+        fucker.run_with_io(&mut &[2u8][..], &mut io::sink());
+
+        assert_eq!(
+            fucker.access_stats(),
+            AccessStats {
+                reads: 7,
+                writes: 3
+            }
+        );
+
+        // Counts start over with each run
+        fucker.run_with_io(&mut &[2u8][..], &mut io::sink());
+        assert_eq!(
+            fucker.access_stats(),
+            AccessStats {
+                reads: 7,
+                writes: 3
+            }
+        );
+
+        fucker.set_count_accesses(false);
+        fucker.run_with_io(&mut &[2u8][..], &mut io::sink());
+        assert_eq!(fucker.access_stats(), AccessStats::default());
+    }
+
+    #[test]
+    fn cell_profile() {
+        // Most of the work happens on cells 0 to 3, cell 6 is touched once
+        let ast = AST::parse("++++[>+++[>++[>+<-]<-]<-]>>>>>>+").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let mut diagnostics = Vec::new();
+        fucker.set_profile_cells(true);
+        fucker.run_with_diagnostics(&mut io::empty(), &mut io::sink(), &mut diagnostics);
+
+        let profile = fucker.cell_profile().unwrap();
+        let mut hottest: Vec<_> = profile.hottest(4).iter().map(|h| h.0).collect();
+        hottest.sort();
+        assert_eq!(hottest, vec![0, 1, 2, 3]);
+        assert_eq!(profile.hottest(5)[4], (6, 1, 1));
+        assert_eq!(profile.reads[4] + profile.writes[4], 0);
+
+        let summary = String::from_utf8(diagnostics).unwrap();
+        assert!(summary.starts_with("Hottest cells:\n"));
+        assert_eq!(summary.lines().count(), 6);
+    }
+
+    #[test]
+    fn two_sided_tape() {
+        let ast = AST::parse("<<<<<+++>>>>>+<<<<<.").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let (mut output, mut diagnostics) = (Vec::new(), Vec::new());
+        fucker.set_pointer_mode(PointerMode::TwoSided);
+        fucker.set_tape_size(4).unwrap();
+        fucker.set_dump_tape(true);
+        fucker.run_with_diagnostics(&mut io::empty(), &mut output, &mut diagnostics);
+
+        // Cell -5 kept its value while the pointer was away
+        assert_eq!(output, b"\x03");
+        assert_eq!(
+            String::from_utf8(diagnostics).unwrap(),
+            "pc=0x0006 dp=-0x0005\n-0005: 03 00 00 00 00 01\n"
+        );
+
+        // The next run starts back at cell 0
+        assert_eq!(fucker.origin(), 5);
+        assert_eq!(fucker.dp, 5);
+    }
+
+    #[test]
+    fn diagnostics_captured() {
+        let ast = AST::parse("+.<").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let (mut output, mut diagnostics) = (Vec::new(), Vec::new());
+        fucker.set_dump_tape(true);
+        fucker.run_with_diagnostics(&mut io::empty(), &mut output, &mut diagnostics);
+
+        assert_eq!(output, b"\x01");
+        assert_eq!(
+            String::from_utf8(diagnostics).unwrap(),
+            "Attempted to point below memory location 0.\npc=0x0002 dp=0x0000\n0000: 01\n"
+        );
+    }
+
+    #[test]
+    fn core_on_fault() {
+        let path = std::env::temp_dir().join("fucker-core-on-fault.core");
+        let ast = AST::parse("+>++>+++>++++>").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        fucker.set_tape_size(4).unwrap();
+        fucker.set_pointer_mode(PointerMode::Halt);
+        fucker.set_core_on_fault(path.clone());
+        fucker.run_with_diagnostics(&mut io::empty(), &mut io::sink(), &mut io::sink());
+
+        assert_eq!(fs::read(&path).unwrap(), [1, 2, 3, 4]);
+        assert_eq!(
+            fs::read_to_string(path.with_extension("core.info")).unwrap(),
+            "pc=0x0007\ninstruction=>\ndp=3\norigin=0\n"
+        );
+
+        // A run that stays on the tape leaves no core behind
+        fs::remove_file(&path).unwrap();
+        let ast = AST::parse("+>++").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        fucker.set_core_on_fault(path.clone());
+        fucker.run_with_io(&mut io::empty(), &mut io::sink());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn watches() {
+        let ast = AST::parse("++>,<[-]>>+").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let (mut output, mut diagnostics) = (Vec::new(), Vec::new());
+        fucker.add_watch(0);
+        fucker.add_watch(1);
+        fucker.run_with_diagnostics(&mut &b"a"[..], &mut output, &mut diagnostics);
+
+        assert_eq!(
+            String::from_utf8(diagnostics).unwrap(),
+            "Watch: cell 0 changed from 0 to 2 at instruction 0x0000\n\
+             Watch: cell 1 changed from 0 to 97 at instruction 0x0002\n\
+             Watch: cell 0 changed from 2 to 0 at instruction 0x0004\n"
+        );
+    }
+
+    #[test]
+    fn into_fn() {
+        let mut incr = Fucker::new(&AST::parse("+").unwrap().data).into_fn();
+        let mut tape = [0u8; 4];
+
+        assert_eq!(incr(&mut tape, 2).unwrap(), 2);
+        assert_eq!(incr(&mut tape, 2).unwrap(), 2);
+        assert_eq!(tape, [0, 0, 2, 0]);
+
+        let snippet = AST::parse_snippet("[->+<]>").unwrap();
+        let mut move_right = Fucker::new(&snippet.data).into_fn();
+        assert_eq!(move_right(&mut tape, 2).unwrap(), 3);
+        assert_eq!(tape, [0, 0, 0, 2]);
+
+        // Running off the tape and starting outside of it are errors
+        let mut run_off = Fucker::new(&AST::parse("+>>").unwrap().data).into_fn();
+        assert!(matches!(
+            run_off(&mut tape, 2),
+            Err(BfError::Halted { cell: 2 })
+        ));
+        assert_eq!(tape, [0, 0, 1, 2]);
+        assert!(matches!(
+            incr(&mut tape, 4),
+            Err(BfError::PointerOutOfBounds {
+                cell: 4,
+                tape_size: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn cell_arithmetic() {
+        let options = ParseOptions {
+            arithmetic: CellArithmetic::Saturate,
+            ..ParseOptions::default()
+        };
+        let source = format!("{}>--", "+".repeat(300));
+        let (ast, _) = AST::parse_with_options(&source, options).unwrap();
+        let run = |arithmetic| {
+            let mut fucker = Fucker::new(&ast.data);
+            let mut diagnostics = Vec::new();
+            fucker.set_cell_arithmetic(arithmetic);
+            let mut input = io::empty();
+            let mut output = io::sink();
+            while fucker.step(&mut Io::with_debug(
+                &mut input,
+                &mut output,
+                &mut diagnostics,
+            )) {}
+            (
+                fucker.tape()[..2].to_vec(),
+                String::from_utf8(diagnostics).unwrap(),
+            )
+        };
+
+        assert_eq!(run(CellArithmetic::Wrap), (vec![44, 254], String::new()));
+        assert_eq!(run(CellArithmetic::Saturate), (vec![255, 0], String::new()));
+        assert_eq!(
+            run(CellArithmetic::Error),
+            (vec![255, 0], "Cell 0 overflowed: 255 + 45\n".to_owned())
+        );
+
+        // Cells are named as the program sees them, not by their place in
+        // the tape
+        let underflow = |source: &str, start| {
+            let (ast, _) = AST::parse_with_options(source, options).unwrap();
+            let mut fucker = Fucker::new(&ast.data);
+            let mut diagnostics = Vec::new();
+            fucker.set_cell_arithmetic(CellArithmetic::Error);
+            fucker.set_pointer_mode(PointerMode::TwoSided);
+            fucker.set_start_cell(start).unwrap();
+            fucker.run_with_diagnostics(&mut io::empty(), &mut io::sink(), &mut diagnostics);
+            String::from_utf8(diagnostics).unwrap()
+        };
+        assert_eq!(underflow("-", 3), "Cell 3 underflowed: 0 - 1\n");
+        assert_eq!(underflow("<-", 0), "Cell -1 underflowed: 0 - 1\n");
+    }
+
+    #[test]
+    fn run_hello_world() {
+        let ast = AST::parse(include_str!("../../test/programs/hello_world.bf")).unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let mut output = Vec::new();
+        fucker.run_with_io(&mut io::empty(), &mut output);
+
+        assert_eq!(output, b"Hello World!\n");
+    }
+
+    #[test]
+    fn print_literal() {
+        let mut ast = AST::parse(include_str!("../../test/programs/hello_world.bf")).unwrap();
+        ast.fold_static_output(STEP_LIMIT);
+        let mut fucker = Fucker::new(&ast.data);
+        let mut output = Vec::new();
+        fucker.run_with_io(&mut io::empty(), &mut output);
+
+        assert_eq!(output, b"Hello World!\n");
+    }
+
+    #[test]
+    fn read_eof() {
+        let ast = AST::parse("+++,.,.").unwrap();
+
+        for &(eof, expected) in &[
+            (EofMode::Zero, b"A\x00"),
+            (EofMode::NegativeOne, b"A\xff"),
+            (EofMode::Unchanged, b"AA"),
+            (EofMode::Value(42), b"A*"),
+        ] {
+            let mut fucker = Fucker::new(&ast.data);
+            fucker.set_eof_mode(eof);
+            let mut output = Vec::new();
+            fucker.run_with_io(&mut &b"A"[..], &mut output);
+
+            assert_eq!(&output, expected);
+        }
+    }
+
+    #[test]
+    fn interrupt_stops_loop() {
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        let flag = Arc::new(AtomicBool::new(false));
+        let (done_tx, done_rx) = mpsc::channel();
+
+        let runner_flag = flag.clone();
+        thread::spawn(move || {
+            let ast = AST::parse("+[]").unwrap();
+            let mut fucker = Fucker::new(&ast.data);
+            fucker.set_interrupt(runner_flag);
+            run_silent(&mut fucker);
+            done_tx.send(fucker.interrupted()).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        flag.store(true, Ordering::SeqCst);
+
+        let interrupted = done_rx.recv_timeout(Duration::from_secs(5));
+        assert_eq!(interrupted, Ok(true));
+    }
+
+    #[test]
+    fn cancel_token() {
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        let token = CancelToken::new();
+        let (done_tx, done_rx) = mpsc::channel();
+
+        let runner_token = token.clone();
+        thread::spawn(move || {
+            let ast = AST::parse("+[]").unwrap();
+            let mut fucker = Fucker::new(&ast.data);
+            fucker.set_cancel_token(&runner_token);
+            fucker.run_with_io(&mut io::empty(), &mut io::sink());
+            done_tx.send(fucker.exit_reason()).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        token.cancel();
+
+        let exit = done_rx.recv_timeout(Duration::from_secs(5));
+        assert_eq!(exit, Ok(ExitReason::Cancelled));
+
+        // A run stopped by an error is told apart from one cancelled
+        let ast = AST::parse("+>>+").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        fucker.set_pointer_mode(PointerMode::Halt);
+        fucker.set_tape_size(2).unwrap();
+        let mut diagnostics = Vec::new();
+        fucker.run_with_diagnostics(&mut io::empty(), &mut io::sink(), &mut diagnostics);
+        assert_eq!(fucker.exit_reason(), ExitReason::Error);
+    }
+
+    #[test]
+    fn start_cell() {
+        let ast = AST::parse("+++>+<<-").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        fucker.set_start_cell(5).unwrap();
+        run_silent(&mut fucker);
+
+        assert_eq!(&fucker.memory[..7], &[0, 0, 0, 0, 255, 3, 1]);
+    }
+
+    #[test]
+    fn start_cell_out_of_reach() {
+        let ast = AST::parse("+").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        assert!(matches!(
+            fucker.set_start_cell(usize::MAX),
+            Err(BfError::PointerOutOfBounds { .. })
+        ));
+        assert!(matches!(
+            fucker.set_start_cell(usize::MAX / 2),
+            Err(BfError::TapeAlloc { .. })
+        ));
+
+        fucker.set_pointer_mode(PointerMode::Halt);
+        fucker.set_tape_size(8).unwrap();
+        assert!(matches!(
+            fucker.set_start_cell(8),
+            Err(BfError::PointerOutOfBounds {
+                cell: 8,
+                tape_size: 8
+            })
+        ));
+
+        // The tape is untouched by the failed attempts
+        assert_eq!(fucker.memory.len(), 8);
+        fucker.set_start_cell(7).unwrap();
+        run_silent(&mut fucker);
+        assert_eq!(fucker.memory[7], 1);
+    }
+
+    #[test]
+    fn write_tape() {
+        let ast = AST::parse("++>+++>").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        run_silent(&mut fucker);
+
+        let mut dump = Vec::new();
+        fucker.write_tape(&mut dump).unwrap();
+        assert_eq!(
+            String::from_utf8(dump).unwrap(),
+            "pc=0x0004 dp=0x0002\n0000: 02 03 00\n"
+        );
+    }
+
+    #[test]
+    fn cell() {
+        // 2 * 3 + 1, moved back to cell 0
+        let ast = AST::parse("++[>+++<-]>+[<+>-]").unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        run_silent(&mut fucker);
+
+        assert_eq!(fucker.cell(0), Some(7));
+        assert_eq!(fucker.cell(1), Some(0));
+        assert_eq!(fucker.cell(fucker.tape().len()), None);
+        assert_eq!(fucker.cell(usize::MAX), None);
+    }
+
+    #[test]
+    fn run_to_breakpoint() {
+        let options = ParseOptions {
+            breakpoints: true,
+            ..ParseOptions::default()
+        };
+        let (ast, _) = AST::parse_with_options("+.#>++.#+.", options).unwrap();
+        let mut fucker = Fucker::new(&ast.data);
+        let mut input = io::empty();
+
+        let first = fucker.run_to_breakpoint(&mut input);
+        assert_eq!(first.output, b"\x01");
+        assert_eq!((first.cell, first.tape_start), (0, 0));
+        assert_eq!(first.tape, vec![1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(!first.finished);
+
+        let second = fucker.run_to_breakpoint(&mut input);
+        assert_eq!(second.output, b"\x02");
+        assert_eq!(second.cell, 1);
+        assert_eq!(&second.tape[..3], &[1, 2, 0]);
+        assert!(second.pc > first.pc && !second.finished);
+
+        let last = fucker.run_to_breakpoint(&mut input);
+        assert_eq!(last.output, b"\x03");
+        assert!(last.finished);
     }
 }
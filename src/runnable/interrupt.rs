@@ -0,0 +1,28 @@
+use libc::{c_int, sighandler_t, signal, SIGINT};
+
+use super::CancelToken;
+
+lazy_static! {
+    static ref SIGINT_TOKEN: CancelToken = CancelToken::new();
+}
+
+extern "C" fn handle_sigint(_signal: c_int) {
+    SIGINT_TOKEN.cancel();
+}
+
+/// Replace the default SIGINT behavior with cancelling a token.
+///
+/// Returns the token, which can be given to a backend to stop execution
+/// cleanly.
+pub fn install_sigint_handler() -> CancelToken {
+    let token = SIGINT_TOKEN.clone();
+
+    unsafe {
+        signal(
+            SIGINT,
+            handle_sigint as extern "C" fn(c_int) as sighandler_t,
+        );
+    }
+
+    token
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+use std::io::{self, stderr, ErrorKind, Read, Stderr, Write};
+
+/// Streams standing in for stdin and stdout while a program runs.
+pub struct Io<'a> {
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    /// Where `$` values and error messages go, stderr if not set.
+    debug: Option<&'a mut dyn Write>,
+    stderr: Stderr,
+    /// Result of a `peek_byte` not yet consumed by `read_byte`.
+    peeked: Option<Option<u8>>,
+}
+
+impl<'a> Io<'a> {
+    pub fn new(input: &'a mut dyn Read, output: &'a mut dyn Write) -> Self {
+        Io {
+            input,
+            output,
+            debug: None,
+            stderr: stderr(),
+            peeked: None,
+        }
+    }
+
+    /// Send debug output and error messages to `debug` instead of stderr.
+    pub fn with_debug(
+        input: &'a mut dyn Read,
+        output: &'a mut dyn Write,
+        debug: &'a mut dyn Write,
+    ) -> Self {
+        Io {
+            debug: Some(debug),
+            ..Io::new(input, output)
+        }
+    }
+
+    /// Read a single byte, returning None at end of input.
+    ///
+    /// Read errors other than interruptions are treated as end of input.
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if let Some(byte) = self.peeked.take() {
+            return byte;
+        }
+
+        self.try_read_byte().unwrap_or(None)
+    }
+
+    /// Read the byte the next `read_byte` will return, without consuming it.
+    ///
+    /// Unlike `read_byte` this fails with `WouldBlock` when non-blocking
+    /// input has nothing ready yet.
+    pub fn peek_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.peeked {
+            return Ok(byte);
+        }
+
+        let byte = match self.try_read_byte() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Err(e),
+            result => result.unwrap_or(None),
+        };
+        self.peeked = Some(byte);
+
+        Ok(byte)
+    }
+
+    /// Read a single byte, retrying interruptions but passing on other errors.
+    fn try_read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8];
+
+        loop {
+            return match self.input.read(&mut byte) {
+                Ok(0) => Ok(None),
+                Ok(_) => Ok(Some(byte[0])),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+        }
+    }
+
+    /// Write a single byte.
+    pub fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.write_all(&[byte])
+    }
+
+    /// Write a sequence of bytes.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)
+    }
+
+    /// Write a cell's value as a decimal number on its own line to the debug
+    /// stream.
+    pub fn write_debug(&mut self, value: u8) -> io::Result<()> {
+        writeln!(self.debug(), "{}", value)
+    }
+
+    /// Report a problem on its own line to the debug stream. There is nowhere
+    /// left to report a failure to, so it is ignored.
+    pub fn write_diagnostic<M: fmt::Display>(&mut self, msg: M) {
+        let _ = writeln!(self.debug(), "{}", msg);
+    }
+
+    /// The debug stream.
+    pub fn debug(&mut self) -> &mut dyn Write {
+        match self.debug {
+            Some(ref mut debug) => &mut **debug,
+            None => &mut self.stderr,
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_until_eof() {
+        let mut input: &[u8] = b"ab";
+        let mut output = Vec::new();
+        let mut io = Io::new(&mut input, &mut output);
+
+        assert_eq!(io.read_byte(), Some(b'a'));
+        assert_eq!(io.read_byte(), Some(b'b'));
+        assert_eq!(io.read_byte(), None);
+    }
+
+    #[test]
+    fn peek_then_read() {
+        let mut input: &[u8] = b"a";
+        let mut output = Vec::new();
+        let mut io = Io::new(&mut input, &mut output);
+
+        assert_eq!(io.peek_byte().unwrap(), Some(b'a'));
+        assert_eq!(io.peek_byte().unwrap(), Some(b'a'));
+        assert_eq!(io.read_byte(), Some(b'a'));
+        assert_eq!(io.peek_byte().unwrap(), None);
+    }
+
+    #[test]
+    fn write() {
+        let mut input = io::empty();
+        let mut output = Vec::new();
+        Io::new(&mut input, &mut output).write_byte(b'!').unwrap();
+
+        assert_eq!(output, b"!");
+    }
+}
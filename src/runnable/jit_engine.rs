@@ -0,0 +1,127 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use super::super::parser::ASTNode;
+use super::arena::Code;
+use super::fragment::FragmentPolicy;
+use super::jit_target::JITOptions;
+
+/// Executable bytes of a loop fragment along with the bodies of the loops it
+/// defers.
+#[derive(Debug, Clone)]
+pub struct CachedFragment {
+    pub bytes: Code,
+    pub deferred: Vec<VecDeque<ASTNode>>,
+}
+
+#[derive(Debug, Default)]
+struct FragmentCache {
+    /// Each fragment along with the clock when it was last looked up or
+    /// added.
+    fragments: HashMap<(VecDeque<ASTNode>, JITOptions), (CachedFragment, u64)>,
+    hits: usize,
+    clock: u64,
+}
+
+/// Shares compiled loop fragments between JITTargets.
+///
+/// Cloning an engine is cheap and yields a handle to the same cache, so one
+/// engine can serve many programs across threads.
+#[derive(Debug, Clone, Default)]
+pub struct JITEngine {
+    cache: Arc<Mutex<FragmentCache>>,
+    /// Policy given to targets created with this engine.
+    policy: FragmentPolicy,
+}
+
+impl JITEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Have targets created with this engine free compiled loops as
+    /// `policy` says.
+    ///
+    /// The cache follows it too: `Lru` keeps at most that many fragments,
+    /// dropping the ones least recently used, and `FreeAfterRun` keeps none.
+    pub fn with_fragment_policy(mut self, policy: FragmentPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn fragment_policy(&self) -> FragmentPolicy {
+        self.policy
+    }
+
+    /// Number of distinct fragments held in the cache.
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap().fragments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of times a fragment was reused instead of compiled.
+    pub fn hits(&self) -> usize {
+        self.cache.lock().unwrap().hits
+    }
+
+    /// Look up a previously compiled loop body.
+    pub(crate) fn get(
+        &self,
+        nodes: &VecDeque<ASTNode>,
+        options: JITOptions,
+    ) -> Option<CachedFragment> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.clock += 1;
+        let clock = cache.clock;
+        let fragment =
+            cache
+                .fragments
+                .get_mut(&(nodes.clone(), options))
+                .map(|(fragment, used)| {
+                    *used = clock;
+                    fragment.clone()
+                });
+
+        if fragment.is_some() {
+            cache.hits += 1;
+        }
+
+        fragment
+    }
+
+    /// Make a compiled loop body available to other targets, first making
+    /// room for it as the policy says.
+    pub(crate) fn insert(
+        &self,
+        nodes: &VecDeque<ASTNode>,
+        options: JITOptions,
+        fragment: CachedFragment,
+    ) {
+        let cap = match self.policy {
+            FragmentPolicy::Keep => usize::MAX,
+            FragmentPolicy::FreeAfterRun => 0,
+            FragmentPolicy::Lru(cap) => cap,
+        };
+        let key = (nodes.clone(), options);
+        let mut cache = self.cache.lock().unwrap();
+
+        while cache.fragments.len() >= cap && !cache.fragments.contains_key(&key) {
+            let oldest = cache
+                .fragments
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => cache.fragments.remove(&oldest),
+                None => return,
+            };
+        }
+
+        cache.clock += 1;
+        let clock = cache.clock;
+        cache.fragments.insert(key, (fragment, clock));
+    }
+}
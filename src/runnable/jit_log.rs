@@ -0,0 +1,75 @@
+//! Reporting what the JIT does, for following it from an application's own
+//! logs.
+//!
+//! This is a small hook rather than the `log` crate, which the crate can't
+//! depend on yet. An application using `log` can forward the events from its
+//! `JITLogger`.
+//!
+//! With the `jit-log` feature off `log` does nothing and compiles away, so
+//! the hooks cost nothing.
+
+#[cfg(feature = "jit-log")]
+use std::cell::RefCell;
+use std::fmt;
+
+use super::JITPromiseID;
+
+/// Something the JIT did, see `set_jit_logger`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JITEvent {
+    /// A loop body of `nodes` nodes was deferred as loop `id` of the
+    /// fragment being compiled.
+    Deferred { id: JITPromiseID, nodes: usize },
+    /// Deferred loop `id` was compiled to `bytes` bytes of machine code.
+    Compiled { id: JITPromiseID, bytes: usize },
+    /// A chunk of `size` bytes of executable memory was allocated.
+    ChunkAllocated { size: usize },
+}
+
+impl fmt::Display for JITEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JITEvent::Deferred { id, nodes } => {
+                write!(f, "Deferred loop {} of {} nodes", id, nodes)
+            }
+            JITEvent::Compiled { id, bytes } => {
+                write!(f, "Compiled loop {} to {} bytes", id, bytes)
+            }
+            JITEvent::ChunkAllocated { size } => {
+                write!(f, "Allocated {} bytes of executable memory", size)
+            }
+        }
+    }
+}
+
+/// Receives the events of one thread.
+#[cfg(feature = "jit-log")]
+pub type JITLogger = Box<dyn FnMut(&JITEvent)>;
+
+#[cfg(feature = "jit-log")]
+thread_local! {
+    static LOGGER: RefCell<Option<JITLogger>> = const { RefCell::new(None) };
+}
+
+/// Pass every `JITEvent` on the current thread to `logger`, or stop
+/// reporting them with `None`.
+///
+/// The logger must not use the JIT itself.
+#[cfg(feature = "jit-log")]
+pub fn set_jit_logger(logger: Option<JITLogger>) {
+    LOGGER.with(|current| *current.borrow_mut() = logger);
+}
+
+/// Report `event` to the current thread's logger, if there is one.
+#[cfg(feature = "jit-log")]
+pub fn log(event: JITEvent) {
+    LOGGER.with(|logger| {
+        if let Some(ref mut logger) = *logger.borrow_mut() {
+            logger(&event);
+        }
+    });
+}
+
+#[cfg(not(feature = "jit-log"))]
+#[inline(always)]
+pub fn log(_event: JITEvent) {}
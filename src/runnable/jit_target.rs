@@ -1,76 +1,196 @@
 use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, stdin, stdout, Read, Write};
 use std::mem;
+use std::process;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use super::super::code_gen;
-use super::super::parser::ASTNode;
-use super::immutable::Immutable;
-
-use libc::{sysconf, _SC_PAGESIZE};
+use super::super::error::BfError;
+use super::super::parser::{self, ASTNode};
+use super::arena::{Code, CodeArena};
+use super::fragment::FragmentPolicy;
+use super::jit_engine::{CachedFragment, JITEngine};
+use super::jit_log::{self, JITEvent};
+use super::{CancelToken, CellArithmetic, EofMode, ExitReason, Fucker, Io, PointerMode};
 
 use runnable::Runnable;
 
 const INLINE_THRESHOLD: usize = 0x16;
 
-lazy_static! {
-    static ref PAGE_SIZE: usize = unsafe { sysconf(_SC_PAGESIZE) as usize };
-}
+/// Number of cells in the tape allocated by `run`, and the fewest compiled
+/// code is allowed to run on.
+pub const TAPE_SIZE: usize = 30_000;
+
+/// Deferred loops nested deeper than this run in the interpreter. Each level
+/// of compiled loops running one another takes a few Rust stack frames, so
+/// without a limit adversarial programs could overflow the stack.
+const MAX_CALLBACK_DEPTH: usize = 256;
 
 /// Functions called by JIT-compiled code.
 mod jit_functions {
-    use libc::{c_int, getchar, putchar};
+    use std::io;
+    use std::slice;
+    use std::sync::atomic::Ordering;
+
+    use super::JITContext;
 
-    /// Print a single byte to stdout.
-    pub extern "C" fn print(byte: u8) {
-        unsafe {
-            putchar(byte as c_int);
+    /// Keep the first output error, and have interruptible code stop. Only
+    /// this run's stop flag is raised, never the caller's interrupt flag.
+    fn fail(context: &mut JITContext, result: io::Result<()>) {
+        if let Err(e) = result {
+            context.error = Some(e);
+            context.stop.store(true, Ordering::SeqCst);
         }
     }
 
-    /// Read a single byte from stdin.
-    pub extern "C" fn read() -> u8 {
-        unsafe { getchar() as u8 }
+    /// Print a single byte to the output stream.
+    ///
+    /// Unless compiled to be interruptible, compiled code cannot stop on an
+    /// error, so the first one is kept and the remaining output is dropped,
+    /// as it is once a loop handed to the interpreter stopped early.
+    pub extern "C" fn print(context: &mut JITContext, byte: u8) {
+        if !context.failed() {
+            let result = context.io.write_byte(byte);
+            fail(context, result);
+        }
     }
-}
 
-/// Round up an integer division.
-///
-/// * `numerator` - The upper component of a division
-/// * `denominator` - The lower component of a division
-fn int_ceil(numerator: usize, denominator: usize) -> usize {
-    (numerator / denominator + 1) * denominator
-}
+    /// Print `byte` `count` times with a single write.
+    pub extern "C" fn print_repeat(context: &mut JITContext, byte: u8, count: usize) {
+        if !context.failed() {
+            let result = context.io.write_bytes(&vec![byte; count]);
+            fail(context, result);
+        }
+    }
 
-/// Clone a vector of bytes into new executable memory pages.
-///
-/// The returned vector is immutable because re-allocation could result in lost
-/// memory protection settings.
-fn make_executable(source: &Vec<u8>) -> Immutable<Vec<u8>> {
-    let size = int_ceil(source.len(), *PAGE_SIZE);
-    let mut data: Vec<u8>;
+    /// Print `len` bytes starting at `ptr`.
+    pub extern "C" fn print_literal(context: &mut JITContext, ptr: *const u8, len: usize) {
+        let bytes = unsafe { slice::from_raw_parts(ptr, len) };
 
-    unsafe {
-        let mut ptr: *mut libc::c_void = mem::MaybeUninit::uninit().assume_init();
+        if !context.failed() {
+            let result = context.io.write_bytes(bytes);
+            fail(context, result);
+        }
+    }
 
-        libc::posix_memalign(&mut ptr, *PAGE_SIZE, size);
-        libc::mprotect(
-            ptr,
-            size,
-            libc::PROT_EXEC | libc::PROT_READ | libc::PROT_WRITE,
-        );
-        libc::memset(ptr, 0xc3, size); // for now, prepopulate with 'RET'
+    /// Write the current cell as a number for debugging. A failed write is
+    /// an output error like any other, as it is in the interpreter.
+    pub extern "C" fn debug_print(context: &mut JITContext, value: u8) {
+        if !context.failed() {
+            let result = context.io.write_debug(value);
+            fail(context, result);
+        }
+    }
+
+    /// Hand the tape and the current cell to the step hook, if any, at the
+    /// end of a loop iteration. The program carries on once it returns.
+    pub extern "C" fn loop_step(context: &mut JITContext, mem_ptr: *mut u8) {
+        if let Some(ref mut hook) = context.step_hook {
+            let tape = unsafe { slice::from_raw_parts(context.tape, context.tape_len) };
+            (hook.0)(tape, mem_ptr as usize - context.tape as usize);
+        }
+    }
 
-        data = Vec::from_raw_parts(ptr as *mut u8, source.len(), size);
+    /// Read a single byte from the input stream, handling EOF as configured.
+    pub extern "C" fn read(context: &mut JITContext, current: u8) -> u8 {
+        let eof = context.eof;
+        context
+            .io
+            .read_byte()
+            .unwrap_or_else(|| eof.on_eof(current))
     }
+}
+
+/// State shared by every fragment of a running program, reached by compiled
+/// code through r13.
+pub struct JITContext<'a> {
+    io: Io<'a>,
+    eof: EofMode,
+    /// First error hit while writing output.
+    error: Option<io::Error>,
+    /// The whole tape, for loops handed to the interpreter.
+    tape: *mut u8,
+    tape_len: usize,
+    /// Number of deferred loops currently running one inside the other.
+    depth: usize,
+    /// Polled by compiled code through r14 when built with
+    /// `JITOptions::interruptible`.
+    interrupt: Arc<AtomicBool>,
+    /// Raised when output fails, and polled through r15 along with
+    /// `interrupt`. Unlike that flag it belongs to this run alone.
+    stop: Arc<AtomicBool>,
+    /// Whether a loop handed to the interpreter stopped early, which the
+    /// interpreter already reported.
+    halted: bool,
+    /// Deferred loops run to the end so far, over every run.
+    clock: u64,
+    /// Compiled loops in the whole program.
+    live: usize,
+    /// How many compiled loops `FragmentPolicy::Lru` allows during the run.
+    cap: usize,
+    /// Called after every loop iteration when built with
+    /// `JITOptions::step_loops`.
+    step_hook: Option<StepHook>,
+}
 
-    for (index, &byte) in source.iter().enumerate() {
-        data[index] = byte;
+impl JITContext<'_> {
+    /// Whether the run failed, so that output is dropped from here on.
+    fn failed(&self) -> bool {
+        self.error.is_some() || self.halted
     }
+}
+
+/// Called with the tape and the current cell by code built to step.
+type StepFn = dyn FnMut(&[u8], usize);
 
-    Immutable::new(data)
+/// A step hook, wrapped so that what holds it can still derive Debug.
+struct StepHook(Box<StepFn>);
+
+impl fmt::Debug for StepHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StepHook")
+    }
 }
 
+/// Index of a deferred loop in its JITTarget's loop table.
 pub type JITPromiseID = usize;
 
+/// Interpreters for deferred loops nested too deep to compile, each set up
+/// the first time its loop runs. Wrapped so that what holds them can still
+/// derive Debug.
+#[derive(Default)]
+struct Interpreters(Vec<(JITPromiseID, Fucker)>);
+
+impl Interpreters {
+    /// The interpreter for loop `id` with body `nodes`.
+    #[cfg(target_arch = "x86_64")]
+    fn get(
+        &mut self,
+        id: JITPromiseID,
+        nodes: &VecDeque<ASTNode>,
+        options: JITOptions,
+    ) -> &mut Fucker {
+        let index = match self.0.iter().position(|&(used, _)| used == id) {
+            Some(index) => index,
+            None => {
+                self.0.push((id, JITTarget::interpreter(nodes, options)));
+                self.0.len() - 1
+            }
+        };
+
+        &mut self.0[index].1
+    }
+}
+
+impl fmt::Debug for Interpreters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Interpreters({})", self.0.len())
+    }
+}
+
 /// Holds ASTNodes for later compilation.
 #[derive(Debug)]
 pub enum JITPromise {
@@ -78,78 +198,770 @@ pub enum JITPromise {
     Compiled(JITTarget),
 }
 
+impl JITPromise {
+    /// The body of a loop that has not been compiled yet.
+    pub fn deferred_body(&self) -> Option<&VecDeque<ASTNode>> {
+        match self {
+            JITPromise::Deferred(nodes) => Some(nodes),
+            JITPromise::Compiled(_) => None,
+        }
+    }
+}
+
+/// How many of a target's deferred loops, nested ones included, ran and were
+/// compiled. Loops inside a loop that never ran are not known yet and are
+/// not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PromiseSummary {
+    /// Loops that have not run, or were deferred again since.
+    pub deferred: usize,
+    pub compiled: usize,
+}
+
+impl fmt::Display for PromiseSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} deferred loops compiled",
+            self.compiled,
+            self.deferred + self.compiled
+        )
+    }
+}
+
+/// Code generation settings shared by a JITTarget and all of its fragments.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct JITOptions {
+    /// Keep the current memory cell in a register across runs of arithmetic,
+    /// writing it back only before the next pointer move, I/O or loop.
+    pub cache_cell: bool,
+    /// What `,` stores once input is exhausted.
+    pub eof: EofMode,
+    /// Give every compiled function a standard frame-pointer prologue and
+    /// epilogue so debuggers and profilers can walk through it.
+    pub debug_frames: bool,
+    /// Fill unused executable memory with zeros rather than `ret`, so that
+    /// running off the end of generated code crashes loudly.
+    pub zero_fill: bool,
+    /// What `+` and `-` do past a cell's limits. Compiled code has no way to
+    /// stop, so `CellArithmetic::Error` is refused.
+    pub arithmetic: CellArithmetic,
+    /// Check the interrupt flag at the end of every loop iteration and after
+    /// every deferred loop, returning as soon as it is set. This costs a
+    /// little on every iteration. Failing to write output sets the flag too.
+    pub interruptible: bool,
+    /// Call the hook given to `JITTarget::set_step_hook` at the end of every
+    /// loop iteration, so that a host can follow or pause the program one
+    /// iteration at a time. This costs a call on every iteration.
+    pub step_loops: bool,
+}
+
 /// Container for executable bytes.
 #[derive(Debug)]
 pub struct JITTarget {
-    bytes: Immutable<Vec<u8>>,
+    bytes: Code,
     promises: Vec<JITPromise>,
+    options: JITOptions,
+    engine: Option<JITEngine>,
+    /// Executable memory shared by this target and its fragments.
+    arena: CodeArena,
+    /// Cell the data pointer starts at.
+    start_cell: usize,
+    /// Number of cells on the tape `run_with_io` allocates.
+    tape_size: usize,
+    /// The tape as the last run by `run_with_io` left it. Only the top
+    /// level's is kept.
+    tape: Vec<u8>,
+    /// Stops the program when set, if compiled to be interruptible.
+    interrupt: Option<Arc<AtomicBool>>,
+    /// Called after every loop iteration, if compiled to step. Only the top
+    /// level's is used.
+    step_hook: Option<StepHook>,
+    /// How the last run ended. Only the top level's is kept.
+    exit: ExitReason,
+    /// Nodes this fragment was compiled from, kept so `reset` can defer it
+    /// again. Empty for the top level.
+    source: VecDeque<ASTNode>,
+    /// When compiled loops are deferred again. Only the top level's is used.
+    policy: FragmentPolicy,
+    /// The top level's clock when this fragment last finished running, or
+    /// for the top level the clock itself.
+    clock: u64,
+    /// Runs this fragment's loops past `MAX_CALLBACK_DEPTH`. Boxed, as few
+    /// fragments nest that deep.
+    interpreters: Option<Box<Interpreters>>,
 }
 
 impl JITTarget {
-    /// Initialize a JIT compiled version of a program.
+    /// Initialize a JIT compiled version of a program, refusing nodes that
+    /// `AST::validate` rejects.
+    ///
+    /// When an engine is given, deferred loops are looked up in and added to
+    /// its fragment cache rather than always being compiled from scratch.
     #[cfg(target_arch = "x86_64")]
-    pub fn new(nodes: &VecDeque<ASTNode>) -> Result<Self, String> {
+    pub fn new(
+        nodes: &VecDeque<ASTNode>,
+        options: JITOptions,
+        engine: Option<&JITEngine>,
+    ) -> Result<Self, BfError> {
+        Self::check_options(options)?;
+        parser::validate_nodes(nodes)?;
+
+        let arena = if options.zero_fill {
+            CodeArena::with_fill(0)
+        } else {
+            CodeArena::new()
+        };
         let mut bytes = Vec::new();
         let mut promises = Vec::new();
+        let mut literals = Vec::new();
 
-        code_gen::wrapper(&mut bytes, Self::shallow_compile(nodes, &mut promises));
+        code_gen::wrapper(
+            &mut bytes,
+            Self::shallow_compile(nodes, &mut promises, &mut literals, options, &arena)?,
+            options.debug_frames,
+        );
 
         Ok(Self {
-            bytes: make_executable(&bytes),
+            bytes: arena.alloc_with(&bytes, literals)?,
             promises,
+            options,
+            engine: engine.cloned(),
+            arena,
+            start_cell: 0,
+            tape_size: TAPE_SIZE,
+            tape: Vec::new(),
+            interrupt: None,
+            step_hook: None,
+            exit: ExitReason::Finished,
+            source: VecDeque::new(),
+            policy: engine.map_or_else(FragmentPolicy::default, JITEngine::fragment_policy),
+            clock: 0,
+            interpreters: None,
         })
     }
 
     /// No-op version for unsupported architectures.
     #[cfg(not(target_arch = "x86_64"))]
-    pub fn new(nodes: &VecDeque<ASTNode>) -> Result<Self, String> {
-        Err(format!("Unsupported JIT architecture."))
+    pub fn new(
+        nodes: &VecDeque<ASTNode>,
+        options: JITOptions,
+        engine: Option<&JITEngine>,
+    ) -> Result<Self, BfError> {
+        Err(BfError::JitUnsupported)
+    }
+
+    /// Compile a snippet, such as one line typed into a REPL, into a function
+    /// that runs it on a tape starting at a given cell and returns the cell it
+    /// ends on. `,` and `.` use stdin and stdout.
+    ///
+    /// A snippet that is a single loop is compiled and cached like a deferred
+    /// loop. Compiled code does not check moves past either end of the tape,
+    /// so tapes shorter than `TAPE_SIZE` are run by the interpreter instead,
+    /// which stops such moves as in `PointerMode::Halt`. A snippet that
+    /// stops early or ends outside of the tape, or a cell outside of it, is
+    /// an error. Parse snippets with `AST::parse_snippet`, which keeps a loop
+    /// at the start.
+    #[cfg(target_arch = "x86_64")]
+    pub fn compile_fn(
+        nodes: &VecDeque<ASTNode>,
+        options: JITOptions,
+        engine: Option<&JITEngine>,
+    ) -> Result<impl FnMut(&mut [u8], usize) -> Result<usize, BfError>, BfError> {
+        Self::check_options(options)?;
+        parser::validate_nodes(nodes)?;
+
+        let mut target = match (nodes.len(), nodes.front()) {
+            (1, Some(ASTNode::Loop(body))) => {
+                let arena = CodeArena::new();
+                Self::new_fragment(body, options, engine, &arena)?
+            }
+            _ => Self::new(nodes, options, engine)?,
+        };
+        let mut fucker = Fucker::new(nodes);
+        fucker.set_eof_mode(options.eof);
+        fucker.set_cell_arithmetic(options.arithmetic);
+        let mut interpreted = fucker.into_fn();
+
+        Ok(move |tape: &mut [u8], cell: usize| {
+            if tape.len() < TAPE_SIZE {
+                return interpreted(tape, cell);
+            }
+            if cell >= tape.len() {
+                return Err(BfError::PointerOutOfBounds {
+                    cell,
+                    tape_size: tape.len(),
+                });
+            }
+            let (mut input, mut output) = (stdin(), stdout());
+            let start = tape.as_mut_ptr();
+            let mut context = JITContext {
+                io: Io::new(&mut input, &mut output),
+                eof: target.options.eof,
+                error: None,
+                tape: start,
+                tape_len: tape.len(),
+                depth: 0,
+                interrupt: Arc::new(AtomicBool::new(false)),
+                stop: Arc::new(AtomicBool::new(false)),
+                halted: false,
+                clock: target.clock,
+                live: target.compiled_fragments(),
+                cap: target.run_cap(),
+                step_hook: None,
+            };
+            let end = target.exec(unsafe { start.add(cell) }, &mut context);
+            let error = context.error.take().or_else(|| context.io.flush().err());
+            target.clock = context.clock;
+            target.apply_policy();
+
+            // The pointer may have been moved left of the tape
+            let end = (end as usize)
+                .checked_sub(start as usize)
+                .filter(|&end| end < tape.len())
+                .ok_or(BfError::EndedOutsideTape {
+                    tape_size: tape.len(),
+                })?;
+            if let Some(e) = error {
+                context.io.write_diagnostic(e);
+                return Err(BfError::Halted { cell: end });
+            }
+            if context.halted {
+                return Err(BfError::Halted { cell: end });
+            }
+            Ok(end)
+        })
+    }
+
+    /// Refuse settings compiled code cannot follow.
+    #[cfg(target_arch = "x86_64")]
+    fn check_options(options: JITOptions) -> Result<(), BfError> {
+        match options.arithmetic {
+            CellArithmetic::Error => Err(BfError::ArithmeticUnsupported(options.arithmetic)),
+            CellArithmetic::Wrap | CellArithmetic::Saturate => Ok(()),
+        }
     }
 
     #[cfg(target_arch = "x86_64")]
-    fn new_fragment(nodes: &VecDeque<ASTNode>) -> Self {
+    fn new_fragment(
+        nodes: &VecDeque<ASTNode>,
+        options: JITOptions,
+        engine: Option<&JITEngine>,
+        arena: &CodeArena,
+    ) -> Result<Self, BfError> {
+        if let Some(fragment) = engine.and_then(|engine| engine.get(nodes, options)) {
+            return Ok(Self::from_cached(fragment, options, engine, arena));
+        }
+
         let mut bytes = Vec::new();
         let mut promises = Vec::new();
+        let mut literals = Vec::new();
 
-        code_gen::wrapper(&mut bytes, Self::compile_loop(nodes, &mut promises));
+        code_gen::wrapper(
+            &mut bytes,
+            Self::compile_loop(nodes, &mut promises, &mut literals, options, arena)?,
+            options.debug_frames,
+        );
 
-        Self {
-            bytes: make_executable(&bytes),
+        let target = Self {
+            bytes: arena.alloc_with(&bytes, literals)?,
             promises,
+            options,
+            engine: engine.cloned(),
+            arena: arena.clone(),
+            start_cell: 0,
+            tape_size: TAPE_SIZE,
+            tape: Vec::new(),
+            interrupt: None,
+            step_hook: None,
+            exit: ExitReason::Finished,
+            source: VecDeque::new(),
+            policy: FragmentPolicy::default(),
+            clock: 0,
+            interpreters: None,
+        };
+
+        if let Some(engine) = engine {
+            engine.insert(nodes, options, target.to_cached());
+        }
+
+        Ok(target)
+    }
+
+    /// Rebuild a fragment around shared bytes, with its own deferred loops.
+    #[cfg(target_arch = "x86_64")]
+    fn from_cached(
+        fragment: CachedFragment,
+        options: JITOptions,
+        engine: Option<&JITEngine>,
+        arena: &CodeArena,
+    ) -> Self {
+        Self {
+            bytes: fragment.bytes,
+            promises: fragment
+                .deferred
+                .into_iter()
+                .map(JITPromise::Deferred)
+                .collect(),
+            options,
+            engine: engine.cloned(),
+            arena: arena.clone(),
+            start_cell: 0,
+            tape_size: TAPE_SIZE,
+            tape: Vec::new(),
+            interrupt: None,
+            step_hook: None,
+            exit: ExitReason::Finished,
+            source: VecDeque::new(),
+            policy: FragmentPolicy::default(),
+            clock: 0,
+            interpreters: None,
+        }
+    }
+
+    /// Snapshot a freshly compiled fragment for the engine's cache.
+    #[cfg(target_arch = "x86_64")]
+    fn to_cached(&self) -> CachedFragment {
+        let deferred = self
+            .promises
+            .iter()
+            .filter_map(|promise| match promise {
+                JITPromise::Deferred(nodes) => Some(nodes.clone()),
+                JITPromise::Compiled(_) => None,
+            })
+            .collect();
+
+        CachedFragment {
+            bytes: self.bytes.clone(),
+            deferred,
+        }
+    }
+
+    /// Machine code for the top level of the program.
+    ///
+    /// The code is position dependent. It embeds the absolute addresses of
+    /// the runtime functions it calls and of any literals it prints, so it is
+    /// only valid inside this process.
+    pub fn compiled_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Machine code for the deferred loop with the given id, if it has been
+    /// compiled yet. The same caveats as `compiled_bytes` apply.
+    pub fn fragment_bytes(&self, id: JITPromiseID) -> Option<&[u8]> {
+        match self.promises.get(id)? {
+            JITPromise::Compiled(fragment) => Some(fragment.compiled_bytes()),
+            JITPromise::Deferred(_) => None,
+        }
+    }
+
+    /// Every loop that was deferred rather than compiled inline, indexed by
+    /// `JITPromiseID`. Loops become `Compiled` the first time they run.
+    pub fn loops(&self) -> &[JITPromise] {
+        &self.promises
+    }
+
+    /// Compile every deferred loop now, including loops nested inside them,
+    /// instead of the first time each one runs.
+    ///
+    /// Loops nested too deep to be compiled at all are left deferred.
+    #[cfg(target_arch = "x86_64")]
+    pub fn precompile(&mut self) -> Result<(), BfError> {
+        self.precompile_levels(MAX_CALLBACK_DEPTH)
+    }
+
+    /// Compile deferred loops down to `levels` levels deep.
+    #[cfg(target_arch = "x86_64")]
+    fn precompile_levels(&mut self, levels: usize) -> Result<(), BfError> {
+        if levels == 0 {
+            return Ok(());
+        }
+
+        for (id, promise) in self.promises.iter_mut().enumerate() {
+            if let JITPromise::Deferred(nodes) = promise {
+                let mut fragment =
+                    Self::new_fragment(nodes, self.options, self.engine.as_ref(), &self.arena)?;
+                jit_log::log(JITEvent::Compiled {
+                    id,
+                    bytes: fragment.bytes.len(),
+                });
+                fragment.source = mem::take(nodes);
+                *promise = JITPromise::Compiled(fragment);
+            }
+
+            if let JITPromise::Compiled(fragment) = promise {
+                fragment.precompile_levels(levels - 1)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// No-op version for unsupported architectures.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn precompile(&mut self) -> Result<(), BfError> {
+        Err(BfError::JitUnsupported)
+    }
+
+    /// Describe which deferred loops can call which as a graphviz DOT graph,
+    /// compiling them all first to find the nested ones.
+    ///
+    /// Each loop is named by the `JITPromiseID`s leading to it from the
+    /// root, so `"0.2"` is loop 2 of the fragment compiled for loop 0.
+    pub fn call_graph(&mut self) -> Result<String, BfError> {
+        self.precompile()?;
+
+        let mut dot = String::from("digraph loops {\n    \"root\";\n");
+        self.write_edges(&mut dot, "root", "");
+        dot.push_str("}\n");
+
+        Ok(dot)
+    }
+
+    /// Add an edge from `name` to each of this target's loops, then their
+    /// own edges. `prefix` is what names of those loops start with.
+    fn write_edges(&self, dot: &mut String, name: &str, prefix: &str) {
+        for (id, promise) in self.promises.iter().enumerate() {
+            let child = format!("{}{}", prefix, id);
+            dot.push_str(&format!("    \"{}\" -> \"{}\";\n", name, child));
+
+            if let JITPromise::Compiled(fragment) = promise {
+                fragment.write_edges(dot, &child, &format!("{}.", child));
+            }
+        }
+    }
+
+    /// Number of blocks of executable memory allocated for this program and
+    /// its compiled loops so far.
+    pub fn code_chunks(&self) -> usize {
+        self.arena.chunk_count()
+    }
+
+    /// Start the data pointer at `cell` rather than at cell 0.
+    pub fn set_start_cell(&mut self, cell: usize) -> Result<(), BfError> {
+        if cell >= self.tape_size {
+            return Err(BfError::PointerOutOfBounds {
+                cell,
+                tape_size: self.tape_size,
+            });
+        }
+
+        self.start_cell = cell;
+
+        Ok(())
+    }
+
+    /// Stop the program whenever `flag` becomes true. Only has an effect
+    /// when compiled with `JITOptions::interruptible`.
+    pub fn set_interrupt(&mut self, flag: Arc<AtomicBool>) {
+        self.interrupt = Some(flag);
+    }
+
+    /// Stop the program whenever `token` is cancelled. As with
+    /// `set_interrupt`, only code compiled with `JITOptions::interruptible`
+    /// notices.
+    pub fn set_cancel_token(&mut self, token: &CancelToken) {
+        self.set_interrupt(token.flag());
+    }
+
+    /// Call `hook` with the tape and the current cell at the end of every
+    /// loop iteration, pausing the program until it returns. Only has an
+    /// effect when compiled with `JITOptions::step_loops`, and not on loops
+    /// nested too deep for the JIT, which the interpreter runs.
+    pub fn set_step_hook<F: FnMut(&[u8], usize) + 'static>(&mut self, hook: F) {
+        self.step_hook = Some(StepHook(Box::new(hook)));
+    }
+
+    /// The value of `index` on the tape the last run by `run_with_io` left
+    /// behind, or None if there is no such cell or no run yet.
+    pub fn cell(&self, index: usize) -> Option<u8> {
+        self.tape.get(index).cloned()
+    }
+
+    /// Get the target ready to run again after an earlier run.
+    ///
+    /// Clears the interrupt flag, if any, and unless `keep_compiled` is set
+    /// turns every compiled loop back into a deferred one, so the next run
+    /// compiles them afresh as the first one did. The memory of the dropped
+    /// code is only freed along with the target.
+    pub fn reset(&mut self, keep_compiled: bool) {
+        if let Some(ref interrupt) = self.interrupt {
+            interrupt.store(false, Ordering::SeqCst);
+        }
+
+        if !keep_compiled {
+            self.free_fragments();
+        }
+    }
+
+    /// Free compiled loops after a run as set by `set_fragment_policy`,
+    /// rather than keeping them all for the next run.
+    pub fn set_fragment_policy(&mut self, policy: FragmentPolicy) {
+        self.policy = policy;
+    }
+
+    /// Number of compiled loops, including loops nested inside them.
+    pub fn compiled_fragments(&self) -> usize {
+        self.promise_summary().compiled
+    }
+
+    /// Count the deferred loops that ran and the ones that did not, which
+    /// shows how much of the program's loop code a run reached.
+    pub fn promise_summary(&self) -> PromiseSummary {
+        let mut summary = PromiseSummary::default();
+
+        for promise in &self.promises {
+            match promise {
+                JITPromise::Deferred(_) => summary.deferred += 1,
+                JITPromise::Compiled(fragment) => {
+                    let nested = fragment.promise_summary();
+                    summary.deferred += nested.deferred;
+                    summary.compiled += nested.compiled + 1;
+                }
+            }
+        }
+
+        summary
+    }
+
+    /// Turn every compiled loop back into a deferred one.
+    fn free_fragments(&mut self) {
+        for promise in &mut self.promises {
+            if let JITPromise::Compiled(fragment) = promise {
+                *promise = JITPromise::Deferred(mem::take(&mut fragment.source));
+            }
+        }
+    }
+
+    /// The most compiled loops the policy lets a run keep.
+    fn run_cap(&self) -> usize {
+        match self.policy {
+            FragmentPolicy::Lru(cap) => cap,
+            FragmentPolicy::Keep | FragmentPolicy::FreeAfterRun => usize::MAX,
+        }
+    }
+
+    /// Free the compiled loops inside this fragment that least recently
+    /// finished running, until the program is back within `context.cap` or
+    /// none are left here. Loops further out are still running, or are
+    /// freed by the fragments around them.
+    #[cfg(target_arch = "x86_64")]
+    fn trim(&mut self, context: &mut JITContext) {
+        while context.live > context.cap {
+            match self.least_recent() {
+                Some((_, path)) => context.live -= self.evict(&path),
+                None => break,
+            }
         }
     }
 
-    /// Compile a vector of ASTNodes into executable bytes.
+    /// Free compiled loops as the policy says, once nothing is running.
+    ///
+    /// `Lru` is also kept to during the run, as far as loops that are still
+    /// running allow. The arena reuses the executable memory of freed loops
+    /// for the next ones compiled, but code an engine caches stays alive in
+    /// the engine.
+    fn apply_policy(&mut self) {
+        match self.policy {
+            FragmentPolicy::Keep => {}
+            FragmentPolicy::FreeAfterRun => self.free_fragments(),
+            FragmentPolicy::Lru(cap) => {
+                let mut live = self.compiled_fragments();
+                while live > cap {
+                    let (_, path) = self
+                        .least_recent()
+                        .expect("a loop is compiled while any are live");
+                    live -= self.evict(&path);
+                }
+            }
+        }
+    }
+
+    /// When the compiled loop that least recently finished running did so,
+    /// and the ids leading to it from this target.
+    fn least_recent(&self) -> Option<(u64, Vec<JITPromiseID>)> {
+        self.promises
+            .iter()
+            .enumerate()
+            .filter_map(|(id, promise)| match promise {
+                JITPromise::Compiled(fragment) => {
+                    let nested = fragment.least_recent().map(|(clock, mut path)| {
+                        path.insert(0, id);
+                        (clock, path)
+                    });
+                    nested
+                        .into_iter()
+                        .chain(Some((fragment.clock, vec![id])))
+                        .min()
+                }
+                JITPromise::Deferred(_) => None,
+            })
+            .min()
+    }
+
+    /// Defer the compiled loop at `path` again, returning how many compiled
+    /// loops that freed.
+    fn evict(&mut self, path: &[JITPromiseID]) -> usize {
+        let promise = &mut self.promises[path[0]];
+        match promise {
+            JITPromise::Compiled(fragment) if path.len() > 1 => fragment.evict(&path[1..]),
+            JITPromise::Compiled(fragment) => {
+                let freed = 1 + fragment.compiled_fragments();
+                *promise = JITPromise::Deferred(mem::take(&mut fragment.source));
+                freed
+            }
+            JITPromise::Deferred(_) => 0,
+        }
+    }
+
+    /// Run on a tape of `size` cells rather than `TAPE_SIZE`, which it may
+    /// not be below.
+    ///
+    /// Compiled code does not check the data pointer, so a program that
+    /// moves past the end of the tape is still undefined behavior. Smaller
+    /// tapes are refused as ordinary programs would run off them; use the
+    /// interpreter for those.
+    pub fn set_tape_size(&mut self, size: usize) -> Result<(), BfError> {
+        if size < TAPE_SIZE {
+            return Err(BfError::TapeTooSmall {
+                size,
+                minimum: TAPE_SIZE,
+            });
+        }
+        if self.start_cell >= size {
+            return Err(BfError::PointerOutOfBounds {
+                cell: self.start_cell,
+                tape_size: size,
+            });
+        }
+
+        self.tape_size = size;
+
+        Ok(())
+    }
+
+    /// Compile a vector of ASTNodes into executable bytes. Literals they
+    /// print are added to `literals`, which the bytes must keep alive.
     #[cfg(target_arch = "x86_64")]
-    fn shallow_compile(nodes: &VecDeque<ASTNode>, promises: &mut Vec<JITPromise>) -> Vec<u8> {
+    fn shallow_compile(
+        nodes: &VecDeque<ASTNode>,
+        promises: &mut Vec<JITPromise>,
+        literals: &mut Vec<Code>,
+        options: JITOptions,
+        arena: &CodeArena,
+    ) -> Result<Vec<u8>, BfError> {
         let mut bytes = Vec::new();
+        // Whether the current memory cell is held in the accumulator rather
+        // than in memory.
+        let mut cached = false;
+
+        for (index, node) in nodes.iter().enumerate() {
+            let arith = Self::is_arith(node);
+
+            if cached && !arith {
+                code_gen::store_cell(&mut bytes);
+                cached = false;
+            }
+
+            // Only start caching when at least two arithmetic nodes in a row
+            // will share the load and store.
+            if options.cache_cell && arith && !cached {
+                if let Some(next_node) = nodes.get(index + 1) {
+                    if Self::is_arith(next_node) {
+                        code_gen::load_cell(&mut bytes);
+                        cached = true;
+                    }
+                }
+            }
 
-        for node in nodes {
             match node {
+                ASTNode::Incr(n) if cached => code_gen::cached_incr(&mut bytes, *n),
+                ASTNode::Decr(n) if cached => code_gen::cached_decr(&mut bytes, *n),
+                ASTNode::Set(n) if cached => code_gen::cached_set(&mut bytes, *n),
                 ASTNode::Incr(n) => code_gen::incr(&mut bytes, *n),
                 ASTNode::Decr(n) => code_gen::decr(&mut bytes, *n),
+                ASTNode::Set(n) => code_gen::set(&mut bytes, *n),
+                ASTNode::SetRange(len, n) => code_gen::set_range(&mut bytes, *len, *n),
                 ASTNode::Next(n) => code_gen::next(&mut bytes, *n),
                 ASTNode::Prev(n) => code_gen::prev(&mut bytes, *n),
+                ASTNode::MulAdd(offset, factor) => code_gen::mul_add(&mut bytes, *offset, *factor),
+                ASTNode::AddTo(offset, n) => code_gen::add_to(&mut bytes, *offset, *n),
                 ASTNode::Print => code_gen::print(&mut bytes, jit_functions::print),
+                ASTNode::PrintRepeat(n) => {
+                    code_gen::print_repeat(&mut bytes, jit_functions::print_repeat, *n)
+                }
+                ASTNode::PrintLiteral(literal) => {
+                    // The literal lives alongside the code so that it is
+                    // freed with, and cached with, the code using it.
+                    let literal = arena.alloc(literal)?;
+                    code_gen::print_literal(&mut bytes, jit_functions::print_literal, &literal);
+                    literals.push(literal);
+                }
                 ASTNode::Read => code_gen::read(&mut bytes, jit_functions::read),
-                ASTNode::Loop(nodes) if nodes.len() < INLINE_THRESHOLD => {
-                    bytes.extend(Self::compile_loop(nodes, promises))
+                ASTNode::DebugPrintValue => code_gen::print(&mut bytes, jit_functions::debug_print),
+                // Compiled code has no way to hand control back mid-run
+                ASTNode::Breakpoint => {}
+                ASTNode::Loop(nodes) if nodes.len() < INLINE_THRESHOLD => bytes.extend(
+                    Self::compile_loop(nodes, promises, literals, options, arena)?,
+                ),
+                ASTNode::Loop(nodes) => {
+                    bytes.extend(Self::defer_loop(nodes, promises));
+                    // The loop only returns early when interrupted
+                    if options.interruptible {
+                        code_gen::poll_interrupt(&mut bytes, options.debug_frames);
+                    }
                 }
-                ASTNode::Loop(nodes) => bytes.extend(Self::defer_loop(nodes, promises)),
             };
+
+            if options.arithmetic == CellArithmetic::Saturate {
+                let limit = match node {
+                    ASTNode::Incr(_) => Some(0xff),
+                    ASTNode::Decr(_) => Some(0),
+                    _ => None,
+                };
+
+                match limit {
+                    Some(limit) if cached => code_gen::cached_saturate(&mut bytes, limit),
+                    Some(limit) => code_gen::saturate(&mut bytes, limit),
+                    None => {}
+                }
+            }
         }
 
-        bytes
+        if cached {
+            code_gen::store_cell(&mut bytes);
+        }
+
+        Ok(bytes)
+    }
+
+    /// Whether a node only modifies the current memory cell.
+    #[cfg(target_arch = "x86_64")]
+    fn is_arith(node: &ASTNode) -> bool {
+        matches!(node, ASTNode::Incr(_) | ASTNode::Decr(_) | ASTNode::Set(_))
     }
 
     /// Perform AOT compilation on a loop.
     #[cfg(target_arch = "x86_64")]
-    fn compile_loop(nodes: &VecDeque<ASTNode>, promises: &mut Vec<JITPromise>) -> Vec<u8> {
+    fn compile_loop(
+        nodes: &VecDeque<ASTNode>,
+        promises: &mut Vec<JITPromise>,
+        literals: &mut Vec<Code>,
+        options: JITOptions,
+        arena: &CodeArena,
+    ) -> Result<Vec<u8>, BfError> {
         let mut bytes = Vec::new();
+        let mut body = Self::shallow_compile(nodes, promises, literals, options, arena)?;
+        if options.step_loops {
+            code_gen::loop_step(&mut body, jit_functions::loop_step);
+        }
+        if options.interruptible {
+            code_gen::poll_interrupt(&mut body, options.debug_frames);
+        }
 
-        code_gen::aot_loop(&mut bytes, Self::shallow_compile(nodes, promises));
+        code_gen::aot_loop(&mut bytes, body);
 
-        bytes
+        Ok(bytes)
     }
 
     /// Perform JIT compilation on a loop.
@@ -158,6 +970,10 @@ impl JITTarget {
         let mut bytes = Vec::new();
 
         promises.push(JITPromise::Deferred(nodes.clone()));
+        jit_log::log(JITEvent::Deferred {
+            id: promises.len() - 1,
+            nodes: nodes.len(),
+        });
 
         code_gen::jit_loop(&mut bytes, promises.len() - 1);
 
@@ -166,66 +982,1319 @@ impl JITTarget {
 
     /// Execute the bytes buffer as a function with context.
     #[cfg(target_arch = "x86_64")]
-    fn exec(&mut self, mem_ptr: *mut u8) -> *mut u8 {
-        type JITCallbackType = extern "C" fn(&mut JITTarget, JITPromiseID, *mut u8) -> *mut u8;
-        let func: fn(*mut u8, &mut JITTarget, JITCallbackType) -> *mut u8 =
-            unsafe { mem::transmute(self.bytes.as_ptr()) };
+    fn exec(&mut self, mem_ptr: *mut u8, context: &mut JITContext) -> *mut u8 {
+        type JITCallbackType =
+            extern "C" fn(&mut JITTarget, JITPromiseID, *mut u8, &mut JITContext) -> *mut u8;
+        let func: extern "C" fn(
+            *mut u8,
+            &mut JITTarget,
+            JITCallbackType,
+            &mut JITContext,
+            *const AtomicBool,
+            *const AtomicBool,
+        ) -> *mut u8 = unsafe { mem::transmute(self.bytes.as_ptr()) };
+        let interrupt = Arc::as_ptr(&context.interrupt);
+        let stop = Arc::as_ptr(&context.stop);
+
+        func(mem_ptr, self, Self::jit_callback, context, interrupt, stop)
+    }
+
+    /// Execute the program on `tape`, starting at the configured start cell.
+    ///
+    /// The tape is only borrowed for the run and is left as the program
+    /// ended it, not cleared, so repeated runs can share one allocation by
+    /// zeroing it in between. Loops compiled during a run stay compiled for
+    /// the next one.
+    ///
+    /// Nothing checks the size of `tape`, so it is only for callers in this
+    /// crate that know the program stays on it. Others use `exec_on`.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn run_on(&mut self, tape: &mut [u8], io: Io) {
+        let start = self.start_cell;
+        let _ = self.run_from(tape, start, io);
+    }
+
+    /// Execute the program on a tape owned by the caller, such as memory
+    /// shared with other code, starting at cell `start` rather than the
+    /// configured start cell. Returns the cell the program ends on.
+    ///
+    /// `,` and `.` use stdin and stdout. Compiled code does not check moves
+    /// past either end of the tape, so as with `set_tape_size` tapes shorter
+    /// than `TAPE_SIZE` are refused. A program that still ends outside of
+    /// the tape is an error, as the run's `exit_reason` also says.
+    #[cfg(target_arch = "x86_64")]
+    pub fn exec_on(&mut self, tape: &mut [u8], start: usize) -> Result<usize, BfError> {
+        if tape.len() < TAPE_SIZE {
+            return Err(BfError::TapeTooSmall {
+                size: tape.len(),
+                minimum: TAPE_SIZE,
+            });
+        }
+
+        let (mut input, mut output) = (stdin(), stdout());
+        self.run_from(tape, start, Io::new(&mut input, &mut output))
+    }
+
+    /// Run on `tape` from cell `start`, returning the cell the run ends on.
+    /// Starting or ending outside of the tape is an error, which is also
+    /// written to the diagnostics.
+    #[cfg(target_arch = "x86_64")]
+    fn run_from(&mut self, tape: &mut [u8], start: usize, mut io: Io) -> Result<usize, BfError> {
+        if start >= tape.len() {
+            self.exit = ExitReason::Error;
+            let error = BfError::PointerOutOfBounds {
+                cell: start,
+                tape_size: tape.len(),
+            };
+            io.write_diagnostic(&error);
+            return Err(error);
+        }
+        let mem_ptr = unsafe { tape.as_mut_ptr().add(start) };
+        let mut context = JITContext {
+            io,
+            eof: self.options.eof,
+            error: None,
+            tape: tape.as_mut_ptr(),
+            tape_len: tape.len(),
+            depth: 0,
+            interrupt: self
+                .interrupt
+                .clone()
+                .unwrap_or_else(|| Arc::new(AtomicBool::new(false))),
+            stop: Arc::new(AtomicBool::new(false)),
+            halted: false,
+            clock: self.clock,
+            live: self.compiled_fragments(),
+            cap: self.run_cap(),
+            step_hook: self.step_hook.take(),
+        };
+
+        let end = self.exec(mem_ptr, &mut context);
+
+        let JITContext {
+            mut io,
+            error,
+            halted,
+            clock,
+            interrupt,
+            step_hook,
+            ..
+        } = context;
+        self.step_hook = step_hook;
+        // The pointer may have been moved left of the tape, so the
+        // subtraction can underflow
+        let cell = (end as usize)
+            .checked_sub(tape.as_ptr() as usize)
+            .filter(|&cell| cell < tape.len());
+        // An output error wins over a cancel that came in at the same time
+        let error = error.or_else(|| io.flush().err());
+        self.exit = if error.is_some() || halted || cell.is_none() {
+            ExitReason::Error
+        } else if self.options.interruptible && interrupt.load(Ordering::SeqCst) {
+            ExitReason::Cancelled
+        } else {
+            ExitReason::Finished
+        };
+        if let Some(msg) = error {
+            io.write_diagnostic(msg);
+        } else if cell.is_none() {
+            io.write_diagnostic(BfError::EndedOutsideTape {
+                tape_size: tape.len(),
+            });
+        }
+
+        self.clock = clock;
+        self.apply_policy();
+
+        cell.ok_or(BfError::EndedOutsideTape {
+            tape_size: tape.len(),
+        })
+    }
+
+    /// No-op version for unsupported architectures.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub(crate) fn run_on(&mut self, tape: &mut [u8], io: Io) {}
 
-        func(mem_ptr, self, Self::jit_callback)
+    /// Unsupported architectures have nothing to execute.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn exec_on(&mut self, tape: &mut [u8], start: usize) -> Result<usize, BfError> {
+        Err(BfError::JitUnsupported)
     }
 
     /// Callback passed into compiled code. Allows for deferred compilation
     /// targets to be compiled, ran, and later re-ran.
+    ///
+    /// Past `MAX_CALLBACK_DEPTH` nested loops, the loop and everything inside
+    /// it is interpreted instead, so the stack stops growing.
     #[cfg(target_arch = "x86_64")]
-    extern "C" fn jit_callback(&mut self, loop_index: JITPromiseID, mem_ptr: *mut u8) -> *mut u8 {
+    extern "C" fn jit_callback(
+        &mut self,
+        loop_index: JITPromiseID,
+        mem_ptr: *mut u8,
+        context: &mut JITContext,
+    ) -> *mut u8 {
         let promise = &mut self.promises[loop_index];
         let return_ptr;
 
+        // A loop skipped on a 0 cell has no need to be compiled yet
+        if matches!(promise, JITPromise::Deferred(_)) && unsafe { *mem_ptr } == 0 {
+            return mem_ptr;
+        }
+
+        if context.depth >= MAX_CALLBACK_DEPTH {
+            if let JITPromise::Deferred(nodes) = promise {
+                let fucker = self.interpreters.get_or_insert_with(Box::default).get(
+                    loop_index,
+                    nodes,
+                    self.options,
+                );
+                return Self::interpret(fucker, self.options, mem_ptr, context);
+            }
+        }
+
+        context.depth += 1;
         match promise {
             JITPromise::Deferred(nodes) => {
-                let mut new_target = Self::new_fragment(nodes);
-                return_ptr = new_target.exec(mem_ptr);
+                // There is no way to hand an error back through compiled
+                // code, and the root fragment's memory was already granted.
+                let mut new_target =
+                    Self::new_fragment(nodes, self.options, self.engine.as_ref(), &self.arena)
+                        .unwrap_or_else(|e| {
+                            context.io.write_diagnostic(format!(
+                                "Error occurred while compiling loop: {}",
+                                e
+                            ));
+                            process::abort()
+                        });
+                jit_log::log(JITEvent::Compiled {
+                    id: loop_index,
+                    bytes: new_target.bytes.len(),
+                });
+                context.live += 1;
+                return_ptr = new_target.exec(mem_ptr, context);
+                new_target.source = mem::take(nodes);
+                context.clock += 1;
+                new_target.clock = context.clock;
                 *promise = JITPromise::Compiled(new_target);
             }
             JITPromise::Compiled(jit_target) => {
-                return_ptr = jit_target.exec(mem_ptr);
+                return_ptr = jit_target.exec(mem_ptr, context);
+                context.clock += 1;
+                jit_target.clock = context.clock;
             }
         };
+        context.depth -= 1;
+        self.trim(context);
 
         return_ptr
     }
+
+    /// Set up an interpreter for the loop with body `nodes`, which needs no
+    /// stack for nesting.
+    #[cfg(target_arch = "x86_64")]
+    fn interpreter(nodes: &VecDeque<ASTNode>, options: JITOptions) -> Fucker {
+        let mut fucker = Fucker::new(&VecDeque::from(vec![ASTNode::Loop(nodes.clone())]));
+        fucker.set_eof_mode(options.eof);
+        fucker.set_cell_arithmetic(options.arithmetic);
+        fucker.set_pointer_mode(PointerMode::Halt);
+        fucker
+    }
+
+    /// Run a loop in its interpreter. Stopping early, such as on an output
+    /// error or a move off the tape, fails the run as an output error in
+    /// compiled code does, while a stop by the interrupt flag is left for
+    /// compiled code to notice.
+    #[cfg(target_arch = "x86_64")]
+    fn interpret(
+        fucker: &mut Fucker,
+        options: JITOptions,
+        mem_ptr: *mut u8,
+        context: &mut JITContext,
+    ) -> *mut u8 {
+        if context.failed() {
+            return mem_ptr;
+        }
+        if options.interruptible {
+            fucker.set_interrupt(context.interrupt.clone());
+        }
+
+        let tape = unsafe { slice::from_raw_parts_mut(context.tape, context.tape_len) };
+        // Compiled code may have moved the pointer off the tape, which the
+        // interpreter then refuses
+        let cell = (mem_ptr as usize).wrapping_sub(context.tape as usize);
+        let end = match fucker.run_on(tape, cell, &mut context.io) {
+            Ok(end) => return unsafe { context.tape.add(end) },
+            Err(BfError::Halted { cell: end }) => unsafe { context.tape.add(end) },
+            Err(e) => {
+                context.io.write_diagnostic(e);
+                mem_ptr
+            }
+        };
+
+        if !fucker.interrupted() {
+            context.halted = true;
+            context.stop.store(true, Ordering::SeqCst);
+        }
+        end
+    }
 }
 
 impl Runnable for JITTarget {
     #[cfg(target_arch = "x86_64")]
-    fn run(&mut self) {
-        let mut bf_mem = vec![0u8; 30_000]; // Memory space used by BrainFuck
-        let mem_ptr = bf_mem.as_mut_ptr();
+    fn run_with_diagnostics(
+        &mut self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        diagnostics: &mut dyn Write,
+    ) {
+        // Memory space used by BrainFuck, reusing the last run's
+        let mut bf_mem = mem::take(&mut self.tape);
+        bf_mem.clear();
+        bf_mem.resize(self.tape_size, 0);
 
-        self.exec(mem_ptr);
+        self.run_on(&mut bf_mem, Io::with_debug(input, output, diagnostics));
+        self.tape = bf_mem;
     }
 
     #[cfg(not(target_arch = "x86_64"))]
-    fn run(&mut self) {}
+    fn run_with_diagnostics(
+        &mut self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        diagnostics: &mut dyn Write,
+    ) {
+    }
+
+    fn exit_reason(&self) -> ExitReason {
+        self.exit
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
 #[cfg(test)]
 mod tests {
-    use super::super::super::parser::AST;
+    use super::super::super::parser::{ParseOptions, AST, STEP_LIMIT};
+    use super::super::arena;
+    use super::super::{EncodingWriter, OutputEncoding};
     use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Adds 1 to the next cell. Unlike `>+<` the skipped `[]` keeps repeats
+    /// from being merged, so a loop of enough of them is always deferred.
+    const DEFERRED_STEP: &str = ">>[]<+<";
+
+    /// Run a program that does no I/O on `tape`.
+    fn run_silent(jit_target: &mut JITTarget, tape: &mut [u8]) {
+        let (mut input, mut output) = (io::empty(), io::sink());
+        jit_target.run_on(tape, Io::new(&mut input, &mut output));
+    }
 
     #[test]
     fn run_hello_world() {
         let ast = AST::parse(include_str!("../../test/programs/hello_world.bf")).unwrap();
-        let mut jit_target = JITTarget::new(&ast.data).unwrap();
-        jit_target.run();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let mut output = Vec::new();
+        jit_target.run_with_io(&mut io::empty(), &mut output);
+
+        assert_eq!(output, b"Hello World!\n");
     }
 
     #[test]
-    fn run_mandelbrot() {
-        let ast = AST::parse(include_str!("../../test/programs/mandelbrot.bf")).unwrap();
-        let mut jit_target = JITTarget::new(&ast.data).unwrap();
-        jit_target.run();
+    fn print_literal() {
+        let mut ast = AST::parse(include_str!("../../test/programs/hello_world.bf")).unwrap();
+        ast.fold_static_output(STEP_LIMIT);
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let mut output = Vec::new();
+        jit_target.run_with_io(&mut io::empty(), &mut output);
+
+        assert_eq!(output, b"Hello World!\n");
+    }
+
+    #[test]
+    fn read_eof() {
+        let ast = AST::parse("+++,.,.").unwrap();
+
+        for &(eof, expected) in &[
+            (EofMode::Zero, b"A\x00"),
+            (EofMode::NegativeOne, b"A\xff"),
+            (EofMode::Unchanged, b"AA"),
+            (EofMode::Value(42), b"A*"),
+        ] {
+            let options = JITOptions {
+                eof,
+                ..JITOptions::default()
+            };
+            let mut jit_target = JITTarget::new(&ast.data, options, None).unwrap();
+            let mut output = Vec::new();
+            jit_target.run_with_io(&mut &b"A"[..], &mut output);
+
+            assert_eq!(&output, expected);
+        }
+    }
+
+    #[test]
+    fn loop_table() {
+        let source = format!("+[>+<-]+[{}-]", DEFERRED_STEP.repeat(INLINE_THRESHOLD));
+        let ast = AST::parse(&source).unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+
+        // Only the large loop is deferred
+        assert_eq!(jit_target.loops().len(), 1);
+        assert!(
+            matches!(&jit_target.loops()[0], JITPromise::Deferred(body) if body.len() > INLINE_THRESHOLD)
+        );
+
+        let mut tape = vec![0u8; 64];
+        run_silent(&mut jit_target, &mut tape);
+
+        assert!(matches!(jit_target.loops()[0], JITPromise::Compiled(_)));
+    }
+
+    #[test]
+    fn promise_summary() {
+        let big = format!("[{}-]", DEFERRED_STEP.repeat(INLINE_THRESHOLD));
+        // The first loop runs, and the one inside it clears the cell so the
+        // last never does
+        let source = format!(
+            "+[{}{}]{}",
+            DEFERRED_STEP.repeat(INLINE_THRESHOLD),
+            big,
+            big
+        );
+        let ast = AST::parse(&source).unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        assert_eq!(
+            jit_target.promise_summary(),
+            PromiseSummary {
+                deferred: 2,
+                compiled: 0
+            }
+        );
+
+        let mut tape = vec![0u8; 64];
+        run_silent(&mut jit_target, &mut tape);
+
+        let summary = jit_target.promise_summary();
+        assert_eq!(
+            summary,
+            PromiseSummary {
+                deferred: 1,
+                compiled: 2
+            }
+        );
+        assert_eq!(summary.to_string(), "2 of 3 deferred loops compiled");
+        assert!(jit_target.loops()[1].deferred_body().is_some());
+    }
+
+    #[test]
+    fn deferred_body() {
+        let body = DEFERRED_STEP.repeat(INLINE_THRESHOLD) + "-";
+        let ast = AST::parse(&format!("+[->+<]+[{}]", body)).unwrap();
+        let jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+
+        let expected = match ast.data.back() {
+            Some(ASTNode::Loop(nodes)) => nodes,
+            _ => panic!("program does not end in a loop"),
+        };
+        let deferred: Vec<_> = jit_target
+            .loops()
+            .iter()
+            .filter_map(JITPromise::deferred_body)
+            .collect();
+        assert_eq!(deferred, vec![expected]);
+    }
+
+    #[cfg(feature = "jit-log")]
+    #[test]
+    fn jit_log() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        use super::super::set_jit_logger;
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        {
+            let events = events.clone();
+            set_jit_logger(Some(Box::new(move |event| {
+                events.borrow_mut().push(*event)
+            })));
+        }
+
+        let body = DEFERRED_STEP.repeat(INLINE_THRESHOLD) + "-";
+        let ast = AST::parse(&format!("+[{}]", body)).unwrap();
+        let nodes = match &ast.data[1] {
+            ASTNode::Loop(nodes) => nodes.len(),
+            _ => panic!("program does not end in a loop"),
+        };
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let mut tape = vec![0u8; 64];
+        run_silent(&mut jit_target, &mut tape);
+        set_jit_logger(None);
+
+        let events = events.borrow();
+        assert_eq!(events[0], JITEvent::Deferred { id: 0, nodes });
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, JITEvent::ChunkAllocated { size } if *size > 0)));
+        assert!(matches!(
+            events.last(),
+            Some(&JITEvent::Compiled { id: 0, bytes }) if bytes > 0
+        ));
+    }
+
+    #[test]
+    fn precompile() {
+        // A deferred loop at cell 3 nested in a deferred loop at cell 0
+        let steps = DEFERRED_STEP.repeat(INLINE_THRESHOLD);
+        let source = format!("+[{}>>>+[{}-]<<<-]", steps, steps);
+        let ast = AST::parse(&source).unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        jit_target.precompile().unwrap();
+
+        match &jit_target.loops()[0] {
+            JITPromise::Compiled(fragment) => {
+                assert_eq!(fragment.loops().len(), 1);
+                assert!(matches!(fragment.loops()[0], JITPromise::Compiled(_)));
+            }
+            JITPromise::Deferred(_) => panic!("loop was not compiled"),
+        }
+
+        let mut tape = vec![0u8; 64];
+        run_silent(&mut jit_target, &mut tape);
+        assert_eq!(
+            &tape[..5],
+            &[0, INLINE_THRESHOLD as u8, 0, 0, INLINE_THRESHOLD as u8]
+        );
+    }
+
+    #[test]
+    fn call_graph() {
+        let steps = DEFERRED_STEP.repeat(INLINE_THRESHOLD);
+        let source = format!("+[{}>>>+[{}-]<<<-]+[{}-]", steps, steps, steps);
+        let ast = AST::parse(&source).unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+
+        assert_eq!(
+            jit_target.call_graph().unwrap(),
+            "digraph loops {\n    \"root\";\n    \"root\" -> \"0\";\n    \"0\" -> \"0.0\";\n    \"root\" -> \"1\";\n}\n"
+        );
+    }
+
+    #[test]
+    fn deeply_nested_deferred_loops() {
+        // Each level runs once on the next cell, after adding 5 to the cell
+        // after that, which the next level clears again
+        let depth = 2 * MAX_CALLBACK_DEPTH;
+        let steps = DEFERRED_STEP.repeat(5);
+        let source = format!(
+            "{}{}",
+            format!(">+[{}", steps).repeat(depth),
+            "[-]]<".repeat(depth)
+        );
+        let ast = AST::parse(&source).unwrap();
+
+        let mut tape = vec![0u8; depth + 3];
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        run_silent(&mut jit_target, &mut tape);
+
+        let mut expected = vec![0u8; depth + 3];
+        expected[depth + 1] = 5;
+        assert_eq!(tape, expected);
+
+        // Loops past the limit are left for the interpreter, which is set up
+        // once however often they run
+        let mut tape = vec![0u8; depth + 3];
+        run_silent(&mut jit_target, &mut tape);
+        assert_eq!(tape, expected);
+        jit_target.precompile().unwrap();
+        let mut fragment = &jit_target;
+        for _ in 0..MAX_CALLBACK_DEPTH {
+            fragment = match &fragment.loops()[0] {
+                JITPromise::Compiled(nested) => nested,
+                JITPromise::Deferred(_) => panic!("loop within the limit not compiled"),
+            };
+        }
+        assert!(matches!(fragment.loops()[0], JITPromise::Deferred(_)));
+        let interpreters = fragment.interpreters.as_ref().unwrap();
+        assert_eq!(interpreters.0.len(), 1);
+    }
+
+    #[test]
+    fn deep_loop_output_error() {
+        // The innermost loop is interpreted, and its print fails for want of
+        // room, which fails the run and drops the print after it
+        let depth = MAX_CALLBACK_DEPTH + 1;
+        let source = format!(
+            "{}.{}.",
+            format!(">+[{}", DEFERRED_STEP.repeat(5)).repeat(depth),
+            "[-]]<".repeat(depth)
+        );
+        let ast = AST::parse(&source).unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+
+        let mut diagnostics = Vec::new();
+        let mut tape = vec![0u8; depth + 3];
+        jit_target.run_on(
+            &mut tape,
+            Io::with_debug(&mut io::empty(), &mut &mut [][..], &mut diagnostics),
+        );
+
+        assert_eq!(jit_target.exit_reason(), ExitReason::Error);
+        assert_eq!(String::from_utf8(diagnostics).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn pointer_survives_calls() {
+        // Every print is a call that may clobber caller-saved registers
+        let source = "+>++>+++<<".to_owned() + &".>+.>-.<<".repeat(200);
+        let ast = AST::parse(&source).unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let mut output = Vec::new();
+        let mut tape = vec![0u8; 4];
+        jit_target.run_on(&mut tape, Io::new(&mut io::empty(), &mut output));
+
+        let expected: Vec<u8> = (0..200u32)
+            .flat_map(|i| vec![1, (i + 3) as u8, (2 - i as i32) as u8])
+            .collect();
+        assert_eq!(output, expected);
+        assert_eq!(tape, [1, 202, 59, 0]);
+    }
+
+    #[test]
+    fn prints_after_pointer_moves_match_interpreter() {
+        // A deferred loop that walks far along the tape and prints on the
+        // way back, then prints from the far end
+        let source = format!(
+            "++++[{}{}-]{}.{}",
+            ">.+".repeat(40),
+            "<.".repeat(40),
+            ">".repeat(40),
+            "<<<.".repeat(13)
+        );
+        let ast = AST::parse(&source).unwrap();
+
+        let mut expected = Vec::new();
+        Fucker::new(&ast.data).run_with_io(&mut io::empty(), &mut expected);
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let mut output = Vec::new();
+        jit_target.run_with_io(&mut io::empty(), &mut output);
+
+        assert_eq!(output.len(), 4 * 80 + 14);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn print_repeat() {
+        let ast = AST::parse(",.....>,...").unwrap();
+        assert_eq!(ast.data[1], ASTNode::PrintRepeat(5));
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let mut output = Vec::new();
+        jit_target.run_with_io(&mut &b"ab"[..], &mut output);
+
+        assert_eq!(output, b"aaaaabbb");
+    }
+
+    #[test]
+    fn set_range() {
+        // Clears cells 1 to 5, then cells 6 and 7 from a loop, checking
+        // where the pointer ends up after each
+        let source = "+>+>+>+>+>+>+<<<<<[-]>[-]>[-]>[-]>[-]>+>>+[<<[-]>[-]>-]>+";
+        let mut ast = AST::parse(source).unwrap();
+        ast.fuse_set_ranges();
+        assert!(ast.data.contains(&ASTNode::SetRange(5, 0)));
+
+        let mut fucker = Fucker::new(&ast.data);
+        let (mut input, mut output) = (io::empty(), io::sink());
+        while fucker.step(&mut Io::new(&mut input, &mut output)) {}
+        let expected = &fucker.tape()[..12];
+        assert_eq!(expected, &[1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+
+        for &cache_cell in &[false, true] {
+            let options = JITOptions {
+                cache_cell,
+                ..JITOptions::default()
+            };
+            let mut jit_target = JITTarget::new(&ast.data, options, None).unwrap();
+            let mut tape = vec![0u8; 12];
+            run_silent(&mut jit_target, &mut tape);
+
+            assert_eq!(tape, expected, "cache_cell: {}", cache_cell);
+        }
+    }
+
+    #[test]
+    fn run_twice() {
+        let source = format!("++++++[{}-]>+.>,.", DEFERRED_STEP.repeat(INLINE_THRESHOLD));
+        let ast = AST::parse(&source).unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let run = |jit_target: &mut JITTarget, tape: &mut [u8]| {
+            let mut output = Vec::new();
+            for cell in tape.iter_mut() {
+                *cell = 0;
+            }
+            jit_target.run_on(tape, Io::new(&mut &b"x"[..], &mut output));
+            output
+        };
+
+        let mut tape = vec![0u8; 8];
+        let first = run(&mut jit_target, &mut tape);
+        assert_eq!(first, b"\x85x");
+        assert!(matches!(jit_target.loops()[0], JITPromise::Compiled(_)));
+        assert_eq!(run(&mut jit_target, &mut tape), first);
+
+        jit_target.reset(true);
+        assert!(matches!(jit_target.loops()[0], JITPromise::Compiled(_)));
+
+        jit_target.reset(false);
+        assert!(matches!(jit_target.loops()[0], JITPromise::Deferred(_)));
+        assert_eq!(run(&mut jit_target, &mut tape), first);
+    }
+
+    #[test]
+    fn fragment_policy() {
+        let step = format!("+[{}-]", DEFERRED_STEP.repeat(INLINE_THRESHOLD));
+        let ast = AST::parse(&format!("{}>.", step.repeat(6))).unwrap();
+        let run = |jit_target: &mut JITTarget| {
+            let (mut tape, mut output) = (vec![0u8; 64], Vec::new());
+            jit_target.run_on(&mut tape, Io::new(&mut io::empty(), &mut output));
+            output
+        };
+
+        let mut keep = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        assert_eq!(run(&mut keep), [6 * INLINE_THRESHOLD as u8]);
+        assert_eq!(keep.compiled_fragments(), 6);
+
+        let mut free = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        free.set_fragment_policy(FragmentPolicy::FreeAfterRun);
+        assert_eq!(run(&mut free), [6 * INLINE_THRESHOLD as u8]);
+        assert_eq!(free.compiled_fragments(), 0);
+
+        // Loops compiled again reuse the memory of the ones freed, where
+        // without that these runs would fill several chunks
+        let fragment_bytes = {
+            keep.loops()
+                .iter()
+                .filter_map(|promise| match promise {
+                    JITPromise::Compiled(fragment) => Some(fragment.compiled_bytes().len()),
+                    JITPromise::Deferred(_) => None,
+                })
+                .sum::<usize>()
+        };
+        let runs = 4 * 0x10000 / fragment_bytes;
+        for mut target in [free, keep] {
+            target.set_fragment_policy(FragmentPolicy::Lru(2));
+            let chunks = target.code_chunks();
+            for _ in 0..runs {
+                run(&mut target);
+            }
+            assert_eq!(target.code_chunks(), chunks);
+        }
+
+        let engine = JITEngine::new().with_fragment_policy(FragmentPolicy::Lru(2));
+        let mut lru = JITTarget::new(&ast.data, JITOptions::default(), Some(&engine)).unwrap();
+        for _ in 0..3 {
+            assert_eq!(run(&mut lru), [6 * INLINE_THRESHOLD as u8]);
+            assert_eq!(lru.compiled_fragments(), 2);
+        }
+        // The last two loops ran last
+        assert!(lru.loops()[..4]
+            .iter()
+            .all(|promise| promise.deferred_body().is_some()));
+        assert!(lru.loops()[4..]
+            .iter()
+            .all(|promise| promise.deferred_body().is_none()));
+    }
+
+    #[test]
+    fn lru_during_run() {
+        // One run of more loops than fit in a few chunks stays within the
+        // cap as it goes, reusing the memory of the loops it frees
+        let step = format!("+[{}-]", DEFERRED_STEP.repeat(INLINE_THRESHOLD));
+        let mut one = JITTarget::new(
+            &AST::parse(&step).unwrap().data,
+            JITOptions::default(),
+            None,
+        )
+        .unwrap();
+        run_silent(&mut one, &mut [0u8; 64]);
+        let fragment_bytes = match &one.loops()[0] {
+            JITPromise::Compiled(fragment) => fragment.compiled_bytes().len(),
+            JITPromise::Deferred(_) => unreachable!("the loop ran"),
+        };
+
+        let ast = AST::parse(&step.repeat(4 * 0x10000 / fragment_bytes)).unwrap();
+        let mut lru = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        lru.set_fragment_policy(FragmentPolicy::Lru(2));
+        let chunks = lru.code_chunks();
+        run_silent(&mut lru, &mut [0u8; 64]);
+        assert_eq!(lru.code_chunks(), chunks);
+        assert_eq!(lru.compiled_fragments(), 2);
+    }
+
+    #[test]
+    fn engine_cache_policy() {
+        let loops: Vec<String> = (0..4)
+            .map(|i| format!("+[{}-]", DEFERRED_STEP.repeat(INLINE_THRESHOLD + i)))
+            .collect();
+        let run = |engine: &JITEngine, loops: &[String]| {
+            let ast = AST::parse(&loops.concat()).unwrap();
+            let mut target =
+                JITTarget::new(&ast.data, JITOptions::default(), Some(engine)).unwrap();
+            run_silent(&mut target, &mut [0u8; 64]);
+        };
+
+        let keep = JITEngine::new();
+        run(&keep, &loops);
+        assert_eq!(keep.len(), 4);
+
+        let lru = JITEngine::new().with_fragment_policy(FragmentPolicy::Lru(2));
+        run(&lru, &loops);
+        assert_eq!(lru.len(), 2);
+        // The two loops compiled last are the ones still cached
+        run(&lru, &loops[2..]);
+        assert_eq!(lru.hits(), 2);
+        run(&lru, &loops[..1]);
+        assert_eq!((lru.len(), lru.hits()), (2, 2));
+
+        let free = JITEngine::new().with_fragment_policy(FragmentPolicy::FreeAfterRun);
+        run(&free, &loops);
+        assert!(free.is_empty());
+    }
+
+    #[test]
+    fn mul_add() {
+        let ast = AST::parse("+++[->++>+++<<]>>>[-]+[-<+>]").unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let mut tape = vec![0u8; 4];
+        jit_target.run_on(&mut tape, Io::new(&mut io::empty(), &mut io::sink()));
+
+        assert_eq!(tape, [0, 6, 10, 0]);
+    }
+
+    #[test]
+    fn add_to() {
+        let mut ast = AST::parse("+++[->++>+++<<]>>>[-]++[-<+>]").unwrap();
+        ast.fold_known_multiplies();
+        assert_eq!(ast.data[0], ASTNode::AddTo(1, 6));
+        assert!(ast.data.contains(&ASTNode::AddTo(-1, 2)));
+
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let mut tape = vec![0u8; 4];
+        jit_target.run_on(&mut tape, Io::new(&mut io::empty(), &mut io::sink()));
+
+        assert_eq!(tape, [0, 6, 11, 0]);
+    }
+
+    #[test]
+    fn compile_fn() {
+        let ast = AST::parse("+").unwrap();
+        let mut incr = JITTarget::compile_fn(&ast.data, JITOptions::default(), None).unwrap();
+        let mut tape = vec![0u8; TAPE_SIZE];
+
+        assert_eq!(incr(&mut tape, 2).unwrap(), 2);
+        assert_eq!(incr(&mut tape, 2).unwrap(), 2);
+        assert_eq!(tape[..4], [0, 0, 2, 0]);
+        assert!(matches!(
+            incr(&mut tape, TAPE_SIZE),
+            Err(BfError::PointerOutOfBounds { .. })
+        ));
+
+        // A copy loop is a multiply-add by now, which is compiled whole
+        let engine = JITEngine::new();
+        let ast = AST::parse_snippet("[->+<]").unwrap();
+        let mut move_right =
+            JITTarget::compile_fn(&ast.data, JITOptions::default(), Some(&engine)).unwrap();
+
+        assert_eq!(move_right(&mut tape, 2).unwrap(), 2);
+        assert_eq!(tape[..4], [0, 0, 0, 2]);
+        assert_eq!(engine.len(), 0);
+
+        // Moving left of the tape is only found once the snippet ends
+        let ast = AST::parse("<").unwrap();
+        let mut back = JITTarget::compile_fn(&ast.data, JITOptions::default(), None).unwrap();
+        assert!(matches!(
+            back(&mut tape, 0),
+            Err(BfError::EndedOutsideTape { .. })
+        ));
+
+        // Short tapes are interpreted, stopping at the end of the tape
+        let ast = AST::parse(">>>>>+").unwrap();
+        let mut run_off = JITTarget::compile_fn(&ast.data, JITOptions::default(), None).unwrap();
+        let mut short = [0u8; 4];
+        assert_eq!(incr(&mut short, 1).unwrap(), 1);
+        assert!(matches!(
+            run_off(&mut short, 0),
+            Err(BfError::Halted { cell: 0 })
+        ));
+        assert_eq!(short, [0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn compile_fn_loop() {
+        // A single loop goes through the fragment cache. Stepping by 2 keeps
+        // it from becoming a multiply-add.
+        let engine = JITEngine::new();
+        let ast = AST::parse_snippet("[-->++<]").unwrap();
+        let mut move_right =
+            JITTarget::compile_fn(&ast.data, JITOptions::default(), Some(&engine)).unwrap();
+        let mut tape = vec![0u8; TAPE_SIZE];
+        tape[2] = 2;
+
+        assert_eq!(move_right(&mut tape, 2).unwrap(), 2);
+        assert_eq!(tape[..4], [0, 0, 0, 2]);
+        assert_eq!(engine.len(), 1);
+    }
+
+    #[test]
+    fn debug_print_value() {
+        let options = ParseOptions {
+            debug_ext: true,
+            ..ParseOptions::default()
+        };
+        let (ast, _) = AST::parse_with_options("+++$>-$.", options).unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let (mut input, mut output, mut debug) = (io::empty(), Vec::new(), Vec::new());
+        let mut tape = vec![0u8; 8];
+        jit_target.run_on(
+            &mut tape,
+            Io::with_debug(&mut input, &mut output, &mut debug),
+        );
+
+        assert_eq!(debug, b"3\n255\n");
+        assert_eq!(output, b"\xff");
+
+        // With room for the first value only, both backends stop there
+        let mut debug = [0u8; 2];
+        let mut output = Vec::new();
+        jit_target.run_on(
+            &mut [0u8; 8],
+            Io::with_debug(&mut input, &mut output, &mut &mut debug[..]),
+        );
+        assert_eq!(jit_target.exit_reason(), ExitReason::Error);
+        assert!(output.is_empty());
+
+        let mut fucker = Fucker::new(&ast.data);
+        let mut output = Vec::new();
+        fucker.run_with_diagnostics(&mut input, &mut output, &mut &mut debug[..]);
+        assert_eq!(fucker.exit_reason(), ExitReason::Error);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn encoded_output() {
+        // Re-encoding allocates inside the print callback, which needs the
+        // stack aligned the way Rust code expects.
+        let ast = AST::parse(",.,.").unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let mut output = Vec::new();
+        {
+            let mut writer = EncodingWriter::new(&mut output, OutputEncoding::Latin1);
+            jit_target.run_with_io(&mut &b"\xc3\xa9"[..], &mut writer);
+        }
+
+        assert_eq!(output, "Ã©".as_bytes());
+    }
+
+    /// Run a program on a fresh tape and return the tape afterwards.
+    fn exec_tape(source: &str, options: JITOptions) -> Vec<u8> {
+        let ast = AST::parse(source).unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, options, None).unwrap();
+        let mut bf_mem = vec![0u8; 64];
+        run_silent(&mut jit_target, &mut bf_mem);
+
+        bf_mem
+    }
+
+    #[test]
+    fn interruptible() {
+        let deferred = format!("+[{}]", DEFERRED_STEP.repeat(INLINE_THRESHOLD));
+
+        // An inline loop, then one that is compiled as a fragment
+        for (source, debug_frames) in &[("+[>+<]", false), (deferred.as_str(), true)] {
+            let ast = AST::parse(source).unwrap();
+            let options = JITOptions {
+                interruptible: true,
+                debug_frames: *debug_frames,
+                ..JITOptions::default()
+            };
+            let mut jit_target = JITTarget::new(&ast.data, options, None).unwrap();
+            let flag = Arc::new(AtomicBool::new(false));
+            jit_target.set_interrupt(flag.clone());
+
+            let setter = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                flag.store(true, Ordering::SeqCst);
+            });
+            let mut tape = vec![0u8; 4];
+            run_silent(&mut jit_target, &mut tape);
+            setter.join().unwrap();
+
+            assert_eq!(tape[0], 1, "{}", source);
+        }
+    }
+
+    #[test]
+    fn step_loops() {
+        use std::sync::mpsc;
+
+        let (cell_tx, cell_rx) = mpsc::channel();
+        let (resume_tx, resume_rx) = mpsc::channel::<()>();
+
+        // The host answers each iteration before the program carries on
+        let runner = thread::spawn(move || {
+            let ast = AST::parse("++++[>+.<-]").unwrap();
+            let options = JITOptions {
+                step_loops: true,
+                cache_cell: true,
+                ..JITOptions::default()
+            };
+            let mut jit_target = JITTarget::new(&ast.data, options, None).unwrap();
+            jit_target.set_step_hook(move |tape, cell| {
+                cell_tx.send((cell, tape[0], tape[1])).unwrap();
+                resume_rx.recv().unwrap();
+            });
+            jit_target.run_with_io(&mut io::empty(), &mut io::sink());
+        });
+
+        for iteration in 1..=4 {
+            let step = cell_rx.recv_timeout(Duration::from_secs(5));
+            assert_eq!(step, Ok((0, 4 - iteration, iteration)));
+            resume_tx.send(()).unwrap();
+        }
+        runner.join().unwrap();
+        assert!(cell_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn cancel_token() {
+        let ast = AST::parse("+[>+<]").unwrap();
+        let options = JITOptions {
+            interruptible: true,
+            ..JITOptions::default()
+        };
+        let mut jit_target = JITTarget::new(&ast.data, options, None).unwrap();
+        let token = CancelToken::new();
+        jit_target.set_cancel_token(&token);
+
+        let canceller = token.clone();
+        let setter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            canceller.cancel();
+        });
+        let mut tape = vec![0u8; 4];
+        run_silent(&mut jit_target, &mut tape);
+        setter.join().unwrap();
+        assert_eq!(jit_target.exit_reason(), ExitReason::Cancelled);
+
+        // A run to the end after the token is reset
+        token.reset();
+        let ast = AST::parse("+>+").unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, options, None).unwrap();
+        jit_target.set_cancel_token(&token);
+        run_silent(&mut jit_target, &mut tape);
+        assert_eq!(jit_target.exit_reason(), ExitReason::Finished);
+    }
+
+    #[test]
+    fn output_error_keeps_token() {
+        let ast = AST::parse("+[.]").unwrap();
+        let options = JITOptions {
+            interruptible: true,
+            ..JITOptions::default()
+        };
+        let mut jit_target = JITTarget::new(&ast.data, options, None).unwrap();
+        let token = CancelToken::new();
+        jit_target.set_cancel_token(&token);
+
+        // Stops once the two bytes of room are used up
+        let mut output = [0u8; 2];
+        let mut tape = vec![0u8; 4];
+        jit_target.run_on(
+            &mut tape,
+            Io::with_debug(&mut io::empty(), &mut &mut output[..], &mut io::sink()),
+        );
+
+        assert_eq!(jit_target.exit_reason(), ExitReason::Error);
+        assert!(!token.is_cancelled());
+    }
+
+    #[test]
+    fn cache_cell_matches_naive() {
+        let source = "+++-+>++--+-<[->+++-+<]>>+-+-+-+-+-+-+-+-+-+-+-+-+-+-+[-+-]+<--+";
+        let naive = exec_tape(source, JITOptions::default());
+        let cached = exec_tape(
+            source,
+            JITOptions {
+                cache_cell: true,
+                ..JITOptions::default()
+            },
+        );
+
+        assert_eq!(naive, cached);
+        assert_eq!(&naive[..3], &[0, 8, 1]);
+    }
+
+    #[test]
+    fn boundary_arithmetic_matches_interpreter() {
+        let sources = [
+            "-",
+            "-+",
+            "-->-<+",
+            &"+".repeat(255),
+            &"+".repeat(256),
+            &"+".repeat(257),
+            &"-".repeat(255),
+            &format!("{}>{}", "+".repeat(255), "-".repeat(510)),
+            "-[->++<]",
+            "-[->+>-<<]>>-",
+            &format!("+[>{}<-]>+", DEFERRED_STEP.repeat(INLINE_THRESHOLD)),
+        ];
+
+        for source in sources.iter() {
+            let ast = AST::parse(source).unwrap();
+            let mut fucker = Fucker::new(&ast.data);
+            let (mut input, mut output) = (io::empty(), io::sink());
+            while fucker.step(&mut Io::new(&mut input, &mut output)) {}
+            let expected = &fucker.tape()[..8];
+
+            for &cache_cell in &[false, true] {
+                let options = JITOptions {
+                    cache_cell,
+                    ..JITOptions::default()
+                };
+                let mut jit_target = JITTarget::new(&ast.data, options, None).unwrap();
+                let mut tape = vec![0u8; 8];
+                run_silent(&mut jit_target, &mut tape);
+
+                assert_eq!(tape, expected, "{} (cache_cell: {})", source, cache_cell);
+            }
+        }
+    }
+
+    #[test]
+    fn cell_arithmetic() {
+        let options = ParseOptions {
+            arithmetic: CellArithmetic::Saturate,
+            ..ParseOptions::default()
+        };
+        let source = format!("{}>--", "+".repeat(300));
+        let (ast, _) = AST::parse_with_options(&source, options).unwrap();
+
+        for &cache_cell in &[false, true] {
+            let run = |arithmetic| {
+                let options = JITOptions {
+                    arithmetic,
+                    cache_cell,
+                    ..JITOptions::default()
+                };
+                let mut jit_target = JITTarget::new(&ast.data, options, None).unwrap();
+                let mut tape = vec![0u8; 4];
+                run_silent(&mut jit_target, &mut tape);
+                tape[..2].to_vec()
+            };
+
+            assert_eq!(run(CellArithmetic::Wrap), [44, 254]);
+            assert_eq!(run(CellArithmetic::Saturate), [255, 0]);
+        }
+
+        let options = JITOptions {
+            arithmetic: CellArithmetic::Error,
+            ..JITOptions::default()
+        };
+        assert!(matches!(
+            JITTarget::new(&ast.data, options, None),
+            Err(BfError::ArithmeticUnsupported(CellArithmetic::Error))
+        ));
+    }
+
+    #[test]
+    fn engine_reuses_fragments() {
+        // Loop body long enough to be deferred rather than inlined
+        let idiom = format!("[{}-]", DEFERRED_STEP.repeat(INLINE_THRESHOLD));
+        let engine = JITEngine::new();
+
+        for prefix in &["+++", "++>+<"] {
+            let ast = AST::parse(&format!("{}{}", prefix, idiom)).unwrap();
+            let mut jit_target =
+                JITTarget::new(&ast.data, JITOptions::default(), Some(&engine)).unwrap();
+            let mut bf_mem = vec![0u8; 64];
+            run_silent(&mut jit_target, &mut bf_mem);
+        }
+
+        assert_eq!(engine.len(), 1);
+        assert_eq!(engine.hits(), 1);
+    }
+
+    #[test]
+    fn debug_frames() {
+        let program = format!("+++[{}-]", DEFERRED_STEP.repeat(INLINE_THRESHOLD));
+        let ast = AST::parse(&program).unwrap();
+        let options = JITOptions {
+            debug_frames: true,
+            ..JITOptions::default()
+        };
+        let mut jit_target = JITTarget::new(&ast.data, options, None).unwrap();
+        let mut bf_mem = vec![0u8; 64];
+        run_silent(&mut jit_target, &mut bf_mem);
+
+        // push rbp; mov rbp,rsp
+        let prologue = [0x55, 0x48, 0x89, 0xe5];
+        assert!(jit_target.bytes.starts_with(&prologue));
+        match &jit_target.promises[0] {
+            JITPromise::Compiled(fragment) => assert!(fragment.bytes.starts_with(&prologue)),
+            JITPromise::Deferred(_) => panic!("loop was not compiled"),
+        }
+        assert_eq!(bf_mem[1], 3 * INLINE_THRESHOLD as u8);
+    }
+
+    #[test]
+    fn exec_memory_denied() {
+        let ast = AST::parse(include_str!("../../test/programs/hello_world.bf")).unwrap();
+
+        arena::deny_exec(true);
+        let result = JITTarget::new(&ast.data, JITOptions::default(), None);
+        arena::deny_exec(false);
+
+        assert!(matches!(result, Err(BfError::ExecMemoryDenied)));
+    }
+
+    #[test]
+    fn compiled_bytes() {
+        let program = format!("+++[{}-]", DEFERRED_STEP.repeat(INLINE_THRESHOLD));
+        let ast = AST::parse(&program).unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        // push rbx; push rbp; push rdi; push rsi
+        let prologue = [0x53, 0x55, 0x57, 0x56];
+
+        assert_eq!(jit_target.compiled_bytes().len(), jit_target.bytes.len());
+        assert!(jit_target.compiled_bytes().starts_with(&prologue));
+        assert_eq!(jit_target.fragment_bytes(0), None);
+
+        let mut bf_mem = vec![0u8; 64];
+        run_silent(&mut jit_target, &mut bf_mem);
+
+        assert!(jit_target.fragment_bytes(0).unwrap().starts_with(&prologue));
+        assert_eq!(jit_target.fragment_bytes(1), None);
+    }
+
+    #[test]
+    fn fragments_share_chunks() {
+        // Many distinct loops, each long enough to be deferred
+        let program: String = (0..20)
+            .map(|i| format!("+[{}-]", DEFERRED_STEP.repeat(INLINE_THRESHOLD + i)))
+            .collect();
+        let ast = AST::parse(&program).unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let mut bf_mem = vec![0u8; 0x1000];
+        run_silent(&mut jit_target, &mut bf_mem);
+
+        let compiled = jit_target
+            .promises
+            .iter()
+            .filter(|promise| matches!(promise, JITPromise::Compiled(_)))
+            .count();
+        assert_eq!(compiled, 20);
+        assert_eq!(jit_target.code_chunks(), 1);
+    }
+
+    #[test]
+    fn engine_is_thread_safe() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<JITEngine>();
+    }
+
+    #[test]
+    fn start_cell() {
+        let ast = AST::parse("+++>+<<-").unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        jit_target.set_start_cell(5).unwrap();
+
+        let mut bf_mem = vec![0u8; 8];
+        run_silent(&mut jit_target, &mut bf_mem);
+        assert_eq!(bf_mem, vec![0, 0, 0, 0, 255, 3, 1, 0]);
+    }
+
+    #[test]
+    fn start_cell_outside_tape() {
+        let ast = AST::parse("+").unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        assert!(matches!(
+            jit_target.set_start_cell(TAPE_SIZE),
+            Err(BfError::PointerOutOfBounds {
+                cell: TAPE_SIZE,
+                tape_size: TAPE_SIZE
+            })
+        ));
+
+        assert!(matches!(
+            jit_target.set_tape_size(10),
+            Err(BfError::TapeTooSmall { size: 10, .. })
+        ));
+        jit_target.set_tape_size(TAPE_SIZE + 11).unwrap();
+        jit_target.set_start_cell(TAPE_SIZE + 10).unwrap();
+        assert!(jit_target.set_tape_size(TAPE_SIZE + 10).is_err());
+        assert!(jit_target.set_tape_size(TAPE_SIZE + 11).is_ok());
+    }
+
+    #[test]
+    fn cell() {
+        let ast = AST::parse("++[>+++<-]>+[<+>-]").unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        assert_eq!(jit_target.cell(0), None);
+
+        jit_target.run_with_io(&mut io::empty(), &mut io::sink());
+        assert_eq!(jit_target.cell(0), Some(7));
+        assert_eq!(jit_target.cell(TAPE_SIZE - 1), Some(0));
+        assert_eq!(jit_target.cell(TAPE_SIZE), None);
+
+        // Each run starts on a cleared tape
+        jit_target.run_with_io(&mut io::empty(), &mut io::sink());
+        assert_eq!(jit_target.cell(0), Some(7));
+    }
+
+    #[test]
+    fn exec_on() {
+        // Adds cell 1 into cell 2 and stops there
+        let ast = AST::parse(">[->+<]>").unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+
+        let mut memory = vec![0u8; TAPE_SIZE + 2];
+        memory[..6].copy_from_slice(&[9, 3, 4, 5, 6, 7]);
+        assert_eq!(jit_target.exec_on(&mut memory[2..], 0).unwrap(), 2);
+        assert_eq!(memory[..6], [9, 3, 4, 0, 11, 7]);
+
+        assert_eq!(jit_target.exec_on(&mut memory, 3).unwrap(), 5);
+        assert_eq!(memory[..6], [9, 3, 4, 0, 0, 18]);
+        assert_eq!(jit_target.exit_reason(), ExitReason::Finished);
+
+        assert!(matches!(
+            jit_target.exec_on(&mut memory[..6], 0),
+            Err(BfError::TapeTooSmall { size: 6, .. })
+        ));
+        assert!(matches!(
+            jit_target.exec_on(&mut memory, TAPE_SIZE + 2),
+            Err(BfError::PointerOutOfBounds { .. })
+        ));
+
+        // Moving off the left end touches no cell, but is still reported
+        let ast = AST::parse("+<").unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        assert!(matches!(
+            jit_target.exec_on(&mut memory, 0),
+            Err(BfError::EndedOutsideTape { .. })
+        ));
+        assert_eq!(jit_target.exit_reason(), ExitReason::Error);
+    }
+
+    #[test]
+    fn run_mandelbrot() {
+        let ast = AST::parse(include_str!("../../test/programs/mandelbrot.bf")).unwrap();
+        let mut jit_target = JITTarget::new(&ast.data, JITOptions::default(), None).unwrap();
+        let mut output = Vec::new();
+        jit_target.run_with_io(&mut io::empty(), &mut output);
+
+        assert_eq!(output.len(), 6240);
+        assert!(output.ends_with(b"DDDDCCCCCCCCCBBBBBBBBBBBBBBBBBBBBBBBBBB\n"));
     }
 }
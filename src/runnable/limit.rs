@@ -0,0 +1,92 @@
+use std::io::{self, Write};
+
+/// Output stream that passes on at most a fixed number of bytes, so that a
+/// program printing forever can be stopped.
+///
+/// Once the limit is reached every write fails, which stops the interpreter
+/// and, when compiled to be interruptible, the JIT.
+pub struct LimitWriter<W: Write> {
+    inner: W,
+    limit: usize,
+    written: usize,
+    exceeded: bool,
+}
+
+impl<W: Write> LimitWriter<W> {
+    pub fn new(inner: W, limit: usize) -> Self {
+        LimitWriter {
+            inner,
+            limit,
+            written: 0,
+            exceeded: false,
+        }
+    }
+
+    /// Whether something was refused for going over the limit.
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+impl<W: Write> Write for LimitWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let allowed = buf.len().min(self.limit - self.written);
+        if allowed == 0 && !buf.is_empty() {
+            self.exceeded = true;
+            return Err(io::Error::other(format!(
+                "Output limit of {} bytes reached",
+                self.limit
+            )));
+        }
+
+        let written = self.inner.write(&buf[..allowed])?;
+        self.written += written;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::parser::AST;
+    use super::super::Runnable;
+    use super::*;
+
+    #[test]
+    fn stops_at_limit() {
+        let mut writer = LimitWriter::new(Vec::new(), 4);
+        assert!(writer.write_all(b"ab").is_ok());
+        assert!(writer.write_all(b"cdef").is_err());
+
+        assert!(writer.exceeded());
+        assert_eq!(writer.inner, b"abcd");
+    }
+
+    #[test]
+    fn infinite_print_stopped() {
+        let ast = AST::parse("+++[.]").unwrap();
+        let mut runnables: Vec<Box<dyn Runnable>> =
+            vec![Box::new(super::super::Fucker::new(&ast.data))];
+        #[cfg(target_arch = "x86_64")]
+        {
+            let options = super::super::JITOptions {
+                interruptible: true,
+                ..Default::default()
+            };
+            let jit_target = super::super::JITTarget::new(&ast.data, options, None).unwrap();
+            runnables.push(Box::new(jit_target));
+        }
+
+        for runnable in &mut runnables {
+            let mut output = LimitWriter::new(Vec::new(), 100);
+            runnable.run_with_diagnostics(&mut io::empty(), &mut output, &mut io::sink());
+
+            assert!(output.exceeded());
+            assert_eq!(output.inner, [3; 100]);
+        }
+    }
+}
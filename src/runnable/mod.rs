@@ -1,15 +1,72 @@
+use std::io::{stderr, stdin, stdout, Read, Write};
+
 /// Simple interface for an type that can be invoked without any arguments and
 /// with no return value.
 ///
 /// Rather than this trait FnMut would have been used were it a stable feature.
 pub trait Runnable {
-    /// Invoke this type.
-    fn run(&mut self) -> ();
+    /// Invoke this type, reading from stdin and writing to stdout.
+    fn run(&mut self) {
+        self.run_with_io(&mut stdin(), &mut stdout())
+    }
+
+    /// Invoke this type with replacements for stdin and stdout.
+    fn run_with_io(&mut self, input: &mut dyn Read, output: &mut dyn Write) {
+        self.run_with_diagnostics(input, output, &mut stderr())
+    }
+
+    /// Invoke this type with replacements for stdin and stdout, sending
+    /// error messages and debug output to `diagnostics` instead of stderr.
+    fn run_with_diagnostics(
+        &mut self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        diagnostics: &mut dyn Write,
+    );
+
+    /// How the last run ended.
+    fn exit_reason(&self) -> ExitReason;
 }
 
+mod arena;
+mod arithmetic;
+mod cancel;
+mod checksum;
+mod encoding;
+mod eof;
+mod flush;
+mod fragment;
 mod fucker;
-mod immutable;
+mod interrupt;
+mod io;
+mod jit_engine;
+mod jit_log;
 mod jit_target;
+mod limit;
+mod pointer;
+mod ring;
+mod tape;
 
-pub use self::fucker::Fucker;
-pub use self::jit_target::{JITPromiseID, JITTarget};
+pub use self::arithmetic::CellArithmetic;
+pub use self::cancel::{CancelToken, ExitReason};
+pub use self::checksum::{Checksum, ChecksumWriter};
+pub use self::encoding::{EncodingWriter, OutputEncoding};
+pub use self::eof::EofMode;
+pub use self::flush::{FlushPolicy, FlushWriter};
+pub use self::fragment::FragmentPolicy;
+pub use self::fucker::{
+    AccessStats, BreakState, CellProfile, Fucker, RunAsync, StepResult, BREAK_WINDOW,
+};
+pub use self::interrupt::install_sigint_handler;
+pub use self::io::Io;
+pub use self::jit_engine::JITEngine;
+pub use self::jit_log::JITEvent;
+#[cfg(feature = "jit-log")]
+pub use self::jit_log::{set_jit_logger, JITLogger};
+pub use self::jit_target::{
+    JITContext, JITOptions, JITPromise, JITPromiseID, JITTarget, PromiseSummary, TAPE_SIZE,
+};
+pub use self::limit::LimitWriter;
+pub use self::pointer::PointerMode;
+pub use self::ring::RingWriter;
+pub use self::tape::{MappedTape, Tape};
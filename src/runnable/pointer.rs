@@ -0,0 +1,47 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// What the interpreter does when `>` moves the data pointer past the last
+/// cell of the tape. Only `TwoSided` lets `<` move left of cell 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum PointerMode {
+    /// Extend the tape to fit.
+    #[default]
+    Grow,
+    /// Continue from the first cell.
+    Wrap,
+    /// Stop the program with an error.
+    Halt,
+    /// Don't check, so the next access to the cell panics.
+    Unchecked,
+    /// Extend the tape at whichever end the pointer moves past, so cells
+    /// left of the starting cell 0 exist too.
+    TwoSided,
+}
+
+impl FromStr for PointerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grow" => Ok(PointerMode::Grow),
+            "wrap" => Ok(PointerMode::Wrap),
+            "halt" => Ok(PointerMode::Halt),
+            "unchecked" => Ok(PointerMode::Unchecked),
+            "two-sided" => Ok(PointerMode::TwoSided),
+            _ => Err(format!("Unknown pointer mode: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for PointerMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PointerMode::Grow => write!(f, "grow"),
+            PointerMode::Wrap => write!(f, "wrap"),
+            PointerMode::Halt => write!(f, "halt"),
+            PointerMode::Unchecked => write!(f, "unchecked"),
+            PointerMode::TwoSided => write!(f, "two-sided"),
+        }
+    }
+}
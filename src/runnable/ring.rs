@@ -0,0 +1,90 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+/// Output stream that keeps only the last bytes written to it, for programs
+/// whose output is too big to keep but whose end is worth a look.
+///
+/// Unlike `LimitWriter` every write succeeds, so the program runs to the end.
+pub struct RingWriter {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    /// Bytes written in total, kept or not.
+    written: usize,
+}
+
+impl RingWriter {
+    /// Keep at most `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        RingWriter {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            written: 0,
+        }
+    }
+
+    /// The last `n` bytes written, or all that are kept if there are fewer.
+    pub fn last_output(&self, n: usize) -> Vec<u8> {
+        let skipped = self.buffer.len().saturating_sub(n);
+        self.buffer.iter().skip(skipped).cloned().collect()
+    }
+
+    /// Number of bytes written in total, including ones no longer kept.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+}
+
+impl Write for RingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let kept = &buf[buf.len().saturating_sub(self.capacity)..];
+        let overflow = (self.buffer.len() + kept.len()).saturating_sub(self.capacity);
+
+        self.buffer.drain(..overflow);
+        self.buffer.extend(kept);
+        self.written += buf.len();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::parser::AST;
+    use super::super::Runnable;
+    use super::*;
+
+    #[test]
+    fn keeps_tail() {
+        let mut writer = RingWriter::new(4);
+        writer.write_all(b"ab").unwrap();
+        assert_eq!(writer.last_output(10), b"ab");
+
+        writer.write_all(b"cdefgh").unwrap();
+        writer.write_all(b"i").unwrap();
+        assert_eq!(writer.last_output(10), b"fghi");
+        assert_eq!(writer.last_output(2), b"hi");
+        assert_eq!(writer.written(), 9);
+
+        let mut empty = RingWriter::new(0);
+        empty.write_all(b"abc").unwrap();
+        assert!(empty.last_output(1).is_empty());
+    }
+
+    #[test]
+    fn long_output() {
+        // Prints the bytes 1 to 1000, wrapping
+        let source = format!("{}[>+.<-]", "+".repeat(250));
+        let ast = AST::parse(&source.repeat(4)).unwrap();
+
+        let mut ring = RingWriter::new(10);
+        super::super::Fucker::new(&ast.data).run_with_io(&mut io::empty(), &mut ring);
+
+        let expected: Vec<u8> = (991..=1000).map(|n: u32| n as u8).collect();
+        assert_eq!(ring.written(), 1000);
+        assert_eq!(ring.last_output(10), expected);
+    }
+}
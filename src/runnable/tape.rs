@@ -0,0 +1,354 @@
+use std::io;
+use std::iter;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::slice;
+
+/// Memory the interpreter keeps its cells in.
+pub trait Tape: DerefMut<Target = [u8]> {
+    /// Grow or shrink to `len` cells, new cells starting at 0. On failure
+    /// the tape is left as it was.
+    fn resize(&mut self, len: usize) -> io::Result<()>;
+
+    /// Add `cells` cells at 0 to the left end. On failure the tape is left
+    /// as it was.
+    fn grow_left(&mut self, cells: usize) -> io::Result<()>;
+
+    /// Set every cell back to 0.
+    fn clear(&mut self);
+}
+
+impl Tape for Vec<u8> {
+    fn resize(&mut self, len: usize) -> io::Result<()> {
+        self.try_reserve(len.saturating_sub(self.len()))
+            .map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))?;
+        Vec::resize(self, len, 0);
+
+        Ok(())
+    }
+
+    fn grow_left(&mut self, cells: usize) -> io::Result<()> {
+        self.try_reserve(cells)
+            .map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))?;
+        self.splice(0..0, iter::repeat_n(0, cells));
+
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        for cell in self.iter_mut() {
+            *cell = 0;
+        }
+    }
+}
+
+/// Tape backed by an anonymous memory mapping.
+///
+/// The system hands out zeroed pages only once they are first touched, so a
+/// tape of many gigabytes only takes up memory for the parts a program
+/// uses. Growing remaps the pages already there rather than copying cells.
+pub struct MappedTape {
+    /// Start of the mapping.
+    ptr: *mut u8,
+    /// Bytes of the mapping before cell 0, left over from growing left by
+    /// whole pages.
+    offset: usize,
+    /// Cells in use.
+    len: usize,
+    /// Bytes mapped at `ptr`, at least `offset + len` and never 0. Every
+    /// byte outside of the cells in use is 0.
+    mapped: usize,
+    /// Where each separately mapped run of pages starts, from 0. The system
+    /// only remaps one of these at a time.
+    pieces: Vec<usize>,
+}
+
+impl MappedTape {
+    /// Map a tape of `len` cells, all 0.
+    pub fn new(len: usize) -> io::Result<Self> {
+        let mapped = len.max(1);
+
+        Ok(MappedTape {
+            ptr: map(mapped)?,
+            offset: 0,
+            len,
+            mapped,
+            pieces: vec![0],
+        })
+    }
+
+    /// Zero the bytes from `start` to `end` of the mapping, handing whole
+    /// pages back to the system rather than writing to them.
+    fn discard(&mut self, start: usize, end: usize) {
+        let page = page_size();
+        let (first, last) = (start.div_ceil(page) * page, end / page * page);
+
+        if first < last {
+            unsafe {
+                ptr::write_bytes(self.ptr.add(start), 0, first - start);
+                libc::madvise(
+                    self.ptr.add(first) as *mut libc::c_void,
+                    last - first,
+                    libc::MADV_DONTNEED,
+                );
+                ptr::write_bytes(self.ptr.add(last), 0, end - last);
+            }
+        } else {
+            unsafe { ptr::write_bytes(self.ptr.add(start), 0, end - start) };
+        }
+    }
+
+    /// Move every piece of the mapping to the same place relative to `to`,
+    /// which must be mapped for at least `self.mapped` bytes. On failure the
+    /// pieces already moved are put back.
+    fn move_pieces(&self, to: *mut u8) -> io::Result<()> {
+        let end = self.mapped.div_ceil(page_size()) * page_size();
+        let bounds: Vec<(usize, usize)> = self
+            .pieces
+            .iter()
+            .zip(self.pieces.iter().skip(1).chain(iter::once(&end)))
+            .map(|(&start, &stop)| (start, stop - start))
+            .collect();
+
+        for (moved, &(start, size)) in bounds.iter().enumerate() {
+            if let Err(error) = unsafe { remap(self.ptr.add(start), size, to.add(start)) } {
+                for &(start, size) in &bounds[..moved] {
+                    let _ = unsafe { remap(to.add(start), size, self.ptr.add(start)) };
+                }
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Deref for MappedTape {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.add(self.offset), self.len) }
+    }
+}
+
+impl DerefMut for MappedTape {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.add(self.offset), self.len) }
+    }
+}
+
+impl Tape for MappedTape {
+    fn resize(&mut self, len: usize) -> io::Result<()> {
+        let end = self.offset.checked_add(len).ok_or_else(too_large)?;
+
+        if len < self.len {
+            // Dropped cells must read as 0 if the tape grows again
+            self.discard(end, self.offset + self.len);
+        } else if end > self.mapped && self.pieces.len() == 1 {
+            let grown = unsafe {
+                libc::mremap(
+                    self.ptr as *mut libc::c_void,
+                    self.mapped,
+                    end,
+                    libc::MREMAP_MAYMOVE,
+                )
+            };
+            if grown == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+
+            self.ptr = grown as *mut u8;
+            self.mapped = end;
+        } else if end > self.mapped {
+            // Move the pieces into a bigger mapping, whose zeroed tail
+            // becomes a new piece
+            let fresh = map(end)?;
+            if let Err(error) = self.move_pieces(fresh) {
+                unsafe { unmap(fresh, end) };
+                return Err(error);
+            }
+
+            let tail = self.mapped.div_ceil(page_size()) * page_size();
+            if tail < end {
+                self.pieces.push(tail);
+            }
+            self.ptr = fresh;
+            self.mapped = end;
+        }
+
+        self.len = len;
+
+        Ok(())
+    }
+
+    fn grow_left(&mut self, cells: usize) -> io::Result<()> {
+        if cells > self.offset {
+            // Map room in front in whole pages, then move the existing pieces
+            // behind it
+            let extra = (cells - self.offset)
+                .checked_next_multiple_of(page_size())
+                .ok_or_else(too_large)?;
+            let mapped = self.mapped.checked_add(extra).ok_or_else(too_large)?;
+            let fresh = map(mapped)?;
+            if let Err(error) = self.move_pieces(unsafe { fresh.add(extra) }) {
+                unsafe { unmap(fresh, mapped) };
+                return Err(error);
+            }
+
+            self.pieces = iter::once(0)
+                .chain(self.pieces.iter().map(|start| start + extra))
+                .collect();
+            self.ptr = fresh;
+            self.offset += extra;
+            self.mapped = mapped;
+        }
+
+        // The bytes before cell 0 are already zeroed
+        self.offset -= cells;
+        self.len += cells;
+
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        // A fresh mapping is zeroed without touching every page
+        match map(self.mapped) {
+            Ok(fresh) => {
+                unsafe { unmap(self.ptr, self.mapped) };
+                self.ptr = fresh;
+                self.pieces = vec![0];
+            }
+            Err(_) => self.fill(0),
+        }
+    }
+}
+
+impl Drop for MappedTape {
+    fn drop(&mut self) {
+        unsafe { unmap(self.ptr, self.mapped) };
+    }
+}
+
+/// Map `size` bytes of zeroed, private memory.
+fn map(size: usize) -> io::Result<*mut u8> {
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        )
+    };
+
+    if ptr == libc::MAP_FAILED {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ptr as *mut u8)
+    }
+}
+
+/// Move `size` bytes of mapped pages from `from` to `to`, replacing whatever
+/// was mapped there.
+unsafe fn remap(from: *mut u8, size: usize, to: *mut u8) -> io::Result<()> {
+    let moved = libc::mremap(
+        from as *mut libc::c_void,
+        size,
+        size,
+        libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED,
+        to as *mut libc::c_void,
+    );
+
+    if moved == libc::MAP_FAILED {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+unsafe fn unmap(ptr: *mut u8, size: usize) {
+    libc::munmap(ptr as *mut libc::c_void, size);
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::OutOfMemory, "tape too large")
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::parser::AST;
+    use super::super::{Fucker, Io};
+    use super::*;
+
+    #[test]
+    fn sparse_mapped_tape() {
+        const SIZE: usize = 1 << 30;
+        let mut tape = MappedTape::new(SIZE).unwrap();
+        assert_eq!(tape.len(), SIZE);
+
+        tape[0] = 1;
+        tape[SIZE / 2] = 2;
+        tape[SIZE - 1] = 3;
+        assert_eq!((tape[0], tape[SIZE / 2], tape[SIZE - 1]), (1, 2, 3));
+        assert_eq!(tape[SIZE / 4], 0);
+
+        tape.resize(16).unwrap();
+        tape.grow_left(2).unwrap();
+        tape.resize(32).unwrap();
+        assert_eq!(tape.len(), 32);
+        assert_eq!(&tape[..4], &[0, 0, 1, 0]);
+        assert!(tape[4..].iter().all(|&cell| cell == 0));
+
+        tape.clear();
+        assert!(tape.iter().all(|&cell| cell == 0));
+    }
+
+    #[test]
+    fn mapped_tape_growth() {
+        let mut tape = MappedTape::new(3).unwrap();
+        tape.copy_from_slice(&[1, 2, 3]);
+
+        // Neither growth is a whole number of pages, so the second one has
+        // to reuse the slack left by the first.
+        tape.grow_left(5).unwrap();
+        tape.grow_left(7).unwrap();
+        assert_eq!(tape.len(), 15);
+        assert_eq!(&tape[12..], &[1, 2, 3]);
+        assert!(tape[..12].iter().all(|&cell| cell == 0));
+
+        // Cells given up by shrinking come back as 0.
+        tape[0] = 9;
+        tape.resize(13).unwrap();
+        tape.resize(1 << 20).unwrap();
+        assert_eq!(&tape[..13], &[9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert!(tape[13..].iter().all(|&cell| cell == 0));
+    }
+
+    #[test]
+    fn vec_tape_growth() {
+        let mut tape: Vec<u8> = vec![1, 2];
+        Tape::grow_left(&mut tape, 3).unwrap();
+        assert_eq!(tape, [0, 0, 0, 1, 2]);
+        assert!(Tape::resize(&mut tape, usize::MAX).is_err());
+        assert_eq!(tape, [0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn interpreter_on_mapped_tape() {
+        const SIZE: usize = 1 << 24;
+        let source = format!("+{}++{}+++", ">".repeat(SIZE / 2), ">".repeat(SIZE / 2 - 1));
+        let ast = AST::parse(&source).unwrap();
+        let mut fucker = Fucker::with_tape(&ast.data, MappedTape::new(SIZE).unwrap());
+        let (mut input, mut output) = (io::empty(), io::sink());
+        while fucker.step(&mut Io::new(&mut input, &mut output)) {}
+
+        let tape = fucker.tape();
+        assert_eq!(tape.len(), SIZE);
+        assert_eq!((tape[0], tape[SIZE / 2], tape[SIZE - 1]), (1, 2, 3));
+    }
+}
@@ -0,0 +1,122 @@
+//! Built-in programs with known output, used to check that both backends work
+//! on the current machine.
+
+use std::io::{self, Write};
+
+use parser::AST;
+use runnable::{Fucker, JITOptions, JITTarget, Runnable};
+
+/// A program along with the input it's given and the output it must produce.
+pub struct Case {
+    pub name: &'static str,
+    pub program: &'static str,
+    pub input: &'static [u8],
+    pub expected: &'static [u8],
+}
+
+pub const CASES: [Case; 6] = [
+    Case {
+        name: "arithmetic",
+        program: "++++++++[>++++++++<-]>+.-.",
+        input: b"",
+        expected: b"A@",
+    },
+    Case {
+        name: "wrapping",
+        program: "-.+.",
+        input: b"",
+        expected: b"\xff\x00",
+    },
+    Case {
+        name: "nested",
+        program: "++[>++[>+++<-]<-]>>.",
+        input: b"",
+        expected: b"\x0c",
+    },
+    Case {
+        name: "deferred",
+        // The skipped [] stops the body being merged into something short
+        program: concat!(
+            "+++[",
+            ">>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<",
+            ">>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<>>[]<+<",
+            "-]>."
+        ),
+        input: b"",
+        expected: b"B",
+    },
+    Case {
+        name: "io",
+        program: ",+[-.,+]",
+        input: b"echo",
+        expected: b"echo",
+    },
+    Case {
+        name: "hello_world",
+        program: include_str!("../test/programs/hello_world.bf"),
+        input: b"",
+        expected: b"Hello World!\n",
+    },
+];
+
+/// Run a case on one backend and collect its output.
+fn output_of(runnable: &mut dyn Runnable, case: &Case) -> Vec<u8> {
+    let mut output = Vec::new();
+    runnable.run_with_io(&mut &case.input[..], &mut output);
+
+    output
+}
+
+/// Run every case through the interpreter and the JIT, writing one line per
+/// case to `out`. Returns whether every case passed.
+pub fn run(out: &mut dyn Write) -> io::Result<bool> {
+    let mut passed = true;
+
+    for case in CASES.iter() {
+        let ast = AST::parse(case.program).expect("self-test programs are valid");
+        let interpreted = output_of(&mut Fucker::new(&ast.data), case);
+        let compiled = JITTarget::new(&ast.data, JITOptions::default(), None)
+            .map(|mut jit_target| output_of(&mut jit_target, case));
+
+        let failures: Vec<String> = vec![("interpreter", Ok(interpreted)), ("jit", compiled)]
+            .into_iter()
+            .filter_map(|(backend, output)| match output {
+                Ok(ref output) if output[..] == case.expected[..] => None,
+                Ok(output) => Some(format!("{} printed {:?}", backend, output)),
+                Err(msg) => Some(format!("{} failed: {}", backend, msg)),
+            })
+            .collect();
+
+        if failures.is_empty() {
+            writeln!(out, "{:<12} ok", case.name)?;
+        } else {
+            passed = false;
+            writeln!(
+                out,
+                "{:<12} FAIL expected {:?}, {}",
+                case.name,
+                case.expected,
+                failures.join(", ")
+            )?;
+        }
+    }
+
+    Ok(passed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn selftest_passes() {
+        let mut report = Vec::new();
+
+        assert!(run(&mut report).unwrap());
+        assert_eq!(
+            String::from_utf8(report).unwrap().lines().count(),
+            CASES.len()
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Checking that optimization passes leave a program's behavior alone.
+//!
+//! The program is run in the interpreter on the same sample input before and
+//! after each pass, and the outputs are compared. Every pass costs two runs,
+//! so this is only meant for trying out new or changed passes.
+
+use std::fmt;
+use std::io;
+
+use parser::AST;
+use runnable::{CellArithmetic, EofMode, Fucker, Io, StepResult};
+
+/// Instructions each sample run executes before it is cut short.
+pub const VERIFY_STEPS: u64 = 10_000_000;
+
+/// A pass that changed what the program prints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassMismatch {
+    pub pass: String,
+    /// Offset of the first byte that differs.
+    pub offset: usize,
+    /// The bytes there before and after the pass, `None` where that output
+    /// had already ended.
+    pub before: Option<u8>,
+    pub after: Option<u8>,
+}
+
+impl fmt::Display for PassMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        /// Describe an output byte, which may be missing.
+        fn byte(byte: &Option<u8>) -> String {
+            byte.map_or("end of output".to_owned(), |byte| format!("0x{:02X}", byte))
+        }
+
+        write!(
+            f,
+            "Pass {} changed the output at byte {}: {} before, {} after",
+            self.pass,
+            self.offset,
+            byte(&self.before),
+            byte(&self.after)
+        )
+    }
+}
+
+/// Runs programs on one sample input to compare them.
+pub struct Verifier {
+    input: Vec<u8>,
+    eof: EofMode,
+    arithmetic: CellArithmetic,
+}
+
+impl Verifier {
+    pub fn new(input: &[u8], eof: EofMode, arithmetic: CellArithmetic) -> Self {
+        Verifier {
+            input: input.to_vec(),
+            eof,
+            arithmetic,
+        }
+    }
+
+    /// Apply `pass` to `program`, checking that the output is the same
+    /// before and after, and return what the pass returned.
+    pub fn run_pass<R, F: FnOnce(&mut AST) -> R>(
+        &self,
+        name: &str,
+        program: &mut AST,
+        pass: F,
+    ) -> Result<R, PassMismatch> {
+        let before = program.clone();
+        let result = pass(program);
+
+        self.check(name, &before, program).map(|_| result)
+    }
+
+    /// Check that `after` prints what `before` does.
+    ///
+    /// When either run is cut short after `VERIFY_STEPS` steps, the shorter
+    /// output only has to be the start of the longer one, since the two
+    /// programs may get through different amounts of work in as many steps.
+    pub fn check(&self, name: &str, before: &AST, after: &AST) -> Result<(), PassMismatch> {
+        let (before, before_finished) = self.sample(before);
+        let (after, after_finished) = self.sample(after);
+
+        let len = if before_finished && after_finished {
+            before.len().max(after.len())
+        } else {
+            before.len().min(after.len())
+        };
+
+        match (0..len).find(|&i| before.get(i) != after.get(i)) {
+            Some(offset) => Err(PassMismatch {
+                pass: name.to_owned(),
+                offset,
+                before: before.get(offset).cloned(),
+                after: after.get(offset).cloned(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// The output of `program` on the sample input, and whether it finished.
+    fn sample(&self, program: &AST) -> (Vec<u8>, bool) {
+        let mut fucker = Fucker::new(&program.data);
+        fucker.set_eof_mode(self.eof);
+        fucker.set_cell_arithmetic(self.arithmetic);
+
+        let mut input = &self.input[..];
+        let (mut output, mut diagnostics) = (Vec::new(), io::sink());
+        let result = fucker.run_steps(
+            VERIFY_STEPS,
+            &mut Io::with_debug(&mut input, &mut output, &mut diagnostics),
+        );
+
+        (output, matches!(result, StepResult::Finished { .. }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use parser::ASTNode;
+
+    fn verifier() -> Verifier {
+        Verifier::new(b"hi", EofMode::Zero, CellArithmetic::Wrap)
+    }
+
+    #[test]
+    fn sound_passes_pass() {
+        let mut program = AST::parse(",[.,]++++++[>++++++++<-]>.").unwrap();
+        let verifier = verifier();
+
+        verifier
+            .run_pass(
+                "fuse_print_literals",
+                &mut program,
+                AST::fuse_print_literals,
+            )
+            .unwrap();
+
+        let mut program = AST::parse("++++[>++++++++<-]>+.").unwrap();
+        let folded = verifier
+            .run_pass("fold_static_output", &mut program, |program| {
+                program.fold_static_output(100)
+            })
+            .unwrap();
+        assert!(folded);
+    }
+
+    #[test]
+    fn broken_pass_caught() {
+        let mut program = AST::parse(",.+.,.").unwrap();
+
+        // A faulty run-length pass that merges across a print
+        let mismatch = verifier()
+            .run_pass("merge", &mut program, |program| {
+                program.data = VecDeque::from(vec![
+                    ASTNode::Read,
+                    ASTNode::Incr(1),
+                    ASTNode::Print,
+                    ASTNode::Print,
+                    ASTNode::Read,
+                    ASTNode::Print,
+                ]);
+            })
+            .unwrap_err();
+
+        assert_eq!(
+            mismatch,
+            PassMismatch {
+                pass: "merge".to_owned(),
+                offset: 0,
+                before: Some(b'h'),
+                after: Some(b'i'),
+            }
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! Runs the binary as a script would and checks how it exits.
+
+extern crate libc;
+
+use std::env;
+use std::fs;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// A command running the binary on `source` after `flags`.
+fn fucker(name: &str, source: &str, flags: &[&str]) -> Command {
+    let path = env::temp_dir().join(name);
+    fs::write(&path, source).unwrap();
+
+    let mut command = Command::new(env!("CARGO_BIN_EXE_fucker"));
+    command.args(flags).arg(path).stdin(Stdio::null());
+    command
+}
+
+/// Run the binary to the end.
+fn run(name: &str, source: &str, flags: &[&str]) -> Output {
+    fucker(name, source, flags).output().unwrap()
+}
+
+#[test]
+fn finished() {
+    let output = run("fucker-cli-finished.bf", "++++++++[>++++++<-]>.", &[]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"0");
+}
+
+#[test]
+fn errors() {
+    let halt = run("fucker-cli-halt.bf", "+.<", &["--int"]);
+    assert_eq!(halt.status.code(), Some(1));
+    assert_eq!(halt.stdout, [1]);
+
+    let jit_halt = run("fucker-cli-jit-halt.bf", "+.<", &[]);
+    assert_eq!(jit_halt.status.code(), Some(1));
+
+    let overflow = run(
+        "fucker-cli-overflow.bf",
+        "-",
+        &["--cell-arithmetic", "error"],
+    );
+    assert_eq!(overflow.status.code(), Some(1));
+}
+
+#[test]
+fn step_limit() {
+    let limited = run("fucker-cli-limit.bf", "+.+.+.", &["--max-steps", "3"]);
+    assert_eq!(limited.status.code(), Some(124));
+    assert_eq!(limited.stdout, [1]);
+
+    let enough = run("fucker-cli-limit.bf", "+.+.+.", &["--max-steps", "6"]);
+    assert_eq!(enough.status.code(), Some(0));
+    assert_eq!(enough.stdout, [1, 2, 3]);
+}
+
+#[test]
+fn cancelled() {
+    let mut child = fucker("fucker-cli-cancelled.bf", "+[]", &["--int"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Give the handler time to be installed
+    thread::sleep(Duration::from_millis(200));
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+
+    assert_eq!(child.wait().unwrap().code(), Some(130));
+}